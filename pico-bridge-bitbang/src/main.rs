@@ -0,0 +1,282 @@
+//! OpenOCD remote_bitbang server for the pico-bridge JTAG master
+//! Serves OpenOCD on localhost and clocks its TCKs on the bridge over the USB serial port in binary mode: shifts as
+//! `jtag ir` and `jtag dr` scans, their TDI and TDO streamed in data frames, and the TMS transitions around them as
+//! `jtag tck` requests. Run on the host with:
+//!
+//! cargo bitbang /dev/ttyACM0 [port]
+//!
+//! then point OpenOCD at it:
+//!
+//! adapter driver remote_bitbang
+//! remote_bitbang host localhost
+//! remote_bitbang port 3335
+
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, SerialPort};
+
+use pico_bridge_core::bitbang::{Bitbang, BitbangEvent, BitbangRequest};
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::frame::{cobs_decode, decode_data, decode_response, encode_data, encode_request, Data, Response,
+    BINARY_MODE_ENTER, FRAME_DELIMITER, MAX_ENCODED_FRAME, MAX_FRAME};
+use pico_bridge_core::protocol::host::{ValidInterfaces, ValidOps};
+use pico_bridge_core::stream::{Chunk, CHUNK_LEN, XON};
+
+const DEFAULT_PORT: u16 = 3335;
+// Requests sent ahead of their responses, the bridge queues two
+const PIPELINE: usize = 2;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// A frame from the bridge, the response to a request or a data frame of it
+enum Frame {
+    Response(Response),
+    Data(Data),
+}
+
+// A request of a batch sent to the bridge, until its response is taken
+struct InFlight {
+    proc_id: u8,
+    request: BitbangRequest,
+    // TDO bytes a long scan streams ahead of its response
+    tdo: Vec<u8>,
+    response: Option<Response>,
+}
+
+// The bridge in binary mode on its USB serial port
+struct Bridge {
+    port: Box<dyn SerialPort>,
+    proc_id: u8,
+    // Bytes received after the last frame delimiter
+    rx: Vec<u8>,
+}
+
+impl Bridge {
+    fn open(path: &str) -> io::Result<Bridge> {
+        let port = serialport::new(path, 115_200).timeout(TIMEOUT).open()?;
+        let mut bridge = Bridge { port, proc_id: 0, rx: Vec::new() };
+        // A delimiter drops whatever frame an earlier session left, then STX switches the text console to binary
+        // mode. The console takes a byte per USB packet
+        for byte in [FRAME_DELIMITER, BINARY_MODE_ENTER, FRAME_DELIMITER] {
+            bridge.port.write_all(&[byte])?;
+            thread::sleep(Duration::from_millis(20));
+        }
+        thread::sleep(Duration::from_millis(100));
+        bridge.port.clear(ClearBuffer::Input)?;
+        Ok(bridge)
+    }
+
+    // Send a JTAG request, returns its proc ID
+    fn send(&mut self, operation: ValidOps, payload: &[u32]) -> io::Result<u8> {
+        let proc_id = self.proc_id;
+        self.proc_id = self.proc_id.wrapping_add(1);
+        let mut out = [0_u8; MAX_ENCODED_FRAME];
+        let len = encode_request(proc_id, ValidInterfaces::JTAG, operation, payload, &mut out);
+        self.port.write_all(&out[..len])?;
+        Ok(proc_id)
+    }
+
+    // Stream bytes to the input of the request with proc_id, a data frame for each CHUNK_LEN of them
+    fn send_data(&mut self, proc_id: u8, bytes: &[u8]) -> io::Result<()> {
+        let mut out = [0_u8; MAX_ENCODED_FRAME];
+        for (seq, bytes) in bytes.chunks(CHUNK_LEN).enumerate() {
+            let mut chunk = Chunk {
+                seq: seq as u16,
+                offset: (seq * CHUNK_LEN) as u32,
+                width: 1,
+                len: bytes.len() as u8,
+                data: [0; CHUNK_LEN],
+            };
+            chunk.data[..bytes.len()].copy_from_slice(bytes);
+            let len = encode_data(proc_id, &chunk, &mut out);
+            self.port.write_all(&out[..len])?;
+        }
+        Ok(())
+    }
+
+    // The payload of the response to proc_id, frames left from an earlier request are dropped
+    fn receive(&mut self, proc_id: u8) -> io::Result<u32> {
+        loop {
+            if let Frame::Response(response) = self.frame()? {
+                if response.proc_id == proc_id {
+                    return answer(&response)
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, operation: ValidOps, payload: &[u32]) -> io::Result<u32> {
+        let proc_id = self.send(operation, payload)?;
+        self.receive(proc_id)
+    }
+
+    // The next well formed response or data frame
+    fn frame(&mut self) -> io::Result<Frame> {
+        loop {
+            if let Some(end) = self.rx.iter().position(|byte| *byte == FRAME_DELIMITER) {
+                let mut frame = [0_u8; MAX_FRAME];
+                let decoded = cobs_decode(&self.rx[..end], &mut frame);
+                self.rx.drain(..=end);
+                // Anything else is console output or a corrupted frame
+                let frame = decoded.map(|size| {
+                    decode_response(&frame[..size]).map(Frame::Response)
+                        .or_else(|_| decode_data(&frame[..size]).map(Frame::Data))
+                });
+                if let Some(Ok(frame)) = frame {
+                    return Ok(frame)
+                }
+                continue;
+            }
+            let mut buf = [0_u8; 64];
+            let count = self.port.read(&mut buf)?;
+            self.rx.extend_from_slice(&buf[..count]);
+        }
+    }
+}
+
+fn bridge_error(err: BridgeError) -> io::Error {
+    io::Error::other(err.as_str())
+}
+
+fn answer(response: &Response) -> io::Result<u32> {
+    match response.status {
+        None => Ok(response.payload),
+        Some(err) => Err(bridge_error(err)),
+    }
+}
+
+// Frames from the bridge until the oldest request in flight is answered. A scan streams its TDI once the bridge sends
+// XON, a batch holds far less than the bridge takes before XOFF
+fn answered(bridge: &mut Bridge, bitbang: &Bitbang, in_flight: &mut VecDeque<InFlight>) -> io::Result<()> {
+    while in_flight.front().is_some_and(|oldest| oldest.response.is_none()) {
+        match bridge.frame()? {
+            Frame::Response(response) => {
+                if let Some(entry) = in_flight.iter_mut().find(|entry| entry.proc_id == response.proc_id) {
+                    entry.response = Some(response);
+                }
+            }
+            Frame::Data(data) => {
+                if let Some(entry) = in_flight.iter_mut().find(|entry| entry.proc_id == data.proc_id) {
+                    if data.len != 0 {
+                        entry.tdo.extend_from_slice(data.bytes());
+                    }
+                    else if data.seq == XON as u16 {
+                        let tdi: Vec<u8> = bitbang.input(&entry.request).collect();
+                        bridge.send_data(entry.proc_id, &tdi)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Clock every batch that can be cut from the queue, with requests pipelined, and answer OpenOCD's samples
+fn flush(bridge: &mut Bridge, bitbang: &mut Bitbang, client: &mut TcpStream) -> io::Result<()> {
+    let mut answers = String::new();
+    while bitbang.cut(&mut answers) {
+        let mut in_flight = VecDeque::new();
+        while !bitbang.done() {
+            while in_flight.len() < PIPELINE {
+                match bitbang.pending() {
+                    Some(request) => {
+                        let proc_id = bridge.send(request.operation, request.payload())?;
+                        in_flight.push_back(InFlight { proc_id, request, tdo: Vec::new(), response: None });
+                        bitbang.sent();
+                    }
+                    None => break,
+                }
+            }
+            answered(bridge, bitbang, &mut in_flight)?;
+            match in_flight.pop_front() {
+                Some(InFlight { tdo, response: Some(response), .. }) => {
+                    bitbang.on_data(&tdo);
+                    bitbang.on_tdo(answer(&response)?);
+                }
+                _ => break,
+            }
+        }
+        bitbang.finish(&mut answers);
+    }
+    client.write_all(answers.as_bytes())
+}
+
+// Bytes OpenOCD already sent, None once it sent nothing more and waits
+fn read_ready(client: &mut TcpStream, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    client.set_nonblocking(true)?;
+    let read = client.read(buf);
+    client.set_nonblocking(false)?;
+    match read {
+        Ok(count) => Ok(Some(count)),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// One OpenOCD session, until it quits or disconnects. TCKs are only clocked once OpenOCD waits or the queue is full
+fn serve(bridge: &mut Bridge, mut client: TcpStream) -> io::Result<()> {
+    client.set_nodelay(true)?;
+    // The TAP starts in Run-Test/Idle
+    bridge.request(ValidOps::Reset, &[])?;
+    let mut bitbang = Bitbang::new();
+    let mut buf = [0_u8; 4096];
+    let mut count = client.read(&mut buf)?;
+    while count != 0 {
+        for byte in &buf[..count] {
+            if bitbang.full() {
+                flush(bridge, &mut bitbang, &mut client)?;
+            }
+            if bitbang.push(*byte) == BitbangEvent::Quit {
+                return flush(bridge, &mut bitbang, &mut client)
+            }
+        }
+        count = match read_ready(&mut client, &mut buf)? {
+            Some(count) => count,
+            None => {
+                flush(bridge, &mut bitbang, &mut client)?;
+                client.read(&mut buf)?
+            }
+        };
+    }
+    Ok(())
+}
+
+fn run(path: &str, port: u16) -> io::Result<()> {
+    let mut bridge = Bridge::open(path)?;
+    // Localhost only, whoever connects drives the JTAG chain
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("remote_bitbang on localhost:{}, bridge on {}", port, path);
+    for client in listener.incoming() {
+        let client = client?;
+        match serve(&mut bridge, client) {
+            Ok(()) => println!("OpenOCD disconnected"),
+            Err(err) => eprintln!("OpenOCD session ended: {}", err),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let parsed = match args.as_slice() {
+        [path] => Some((path, DEFAULT_PORT)),
+        [path, port] => port.parse().ok().map(|port| (path, port)),
+        _ => None,
+    };
+    let (path, port) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            eprintln!("usage: pico-bridge-bitbang <serial port> [tcp port, {} by default]", DEFAULT_PORT);
+            process::exit(2);
+        }
+    };
+    if let Err(err) = run(path, port) {
+        eprintln!("pico-bridge-bitbang: {}", err);
+        process::exit(1);
+    }
+}
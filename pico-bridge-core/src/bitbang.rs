@@ -0,0 +1,455 @@
+// OpenOCD remote_bitbang, translated by the host into scans and raw TCK requests
+//
+// OpenOCD sets the pins a byte at a time, '0' to '7' with TCK in bit 2, TMS in bit 1 and TDI in bit 0, and reads TDO
+// with 'R', answered with '0' or '1'. Each rising edge of TCK is a TCK, and R samples TDO on the next one. TCKs are
+// queued until OpenOCD waits for its samples or stops sending, then a batch is cut from the queue and clocked.
+//
+// A scan in the batch from a state where the TAP holds, by the shortest TMS path through Shift-IR or Shift-DR and on
+// to Run-Test/Idle as a `jtag ir` or `jtag dr` request takes it, becomes that request. Its first 3 words of TDI bits
+// go with it and the rest are streamed to the bridge, its TDO bits come back in the response or streamed from the
+// bridge. The TCKs around the scans are clocked with `jtag tck` requests, 32 TCKs to a request.
+//
+// The state machine clocks whole words of 16 TCKs, so a batch ends where the TCKs padding it change nothing: in
+// Test-Logic-Reset, Run-Test/Idle or a pause state. A batch ending in a shift is parked instead: its last TCK leaves
+// the shift with TMS high and the padding holds the TAP in Pause, then the next TCKs go back through Exit2 to where
+// OpenOCD left it. A queue full of TCKs that never reach those states, looping through Capture and Update, is cut
+// where it enters Update and padded in Run-Test/Idle, which goes on to the same states.
+//
+// TDO is only driven while shifting, and a TCK out of a shift state is always a place to cut, so the samples queued
+// past the last cut are answered '0' at once rather than waiting for a cut after them.
+use core::fmt::Write;
+
+use crate::jtag::{TapState, TmsPath, JTAG_WORD_BITS, MAX_TCKS};
+use crate::protocol::host::ValidOps;
+
+// TCKs queued before a batch must be cut
+pub const MAX_BATCH: u32 = 4096;
+// The queue, and the TCKs back to a parked shift
+const WORDS: usize = (MAX_BATCH / 32) as usize + 1;
+// TDI words that go with a scan request
+const SCAN_WORDS: u32 = 3;
+
+// What a byte from OpenOCD asks for
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BitbangEvent {
+    // Pins set, or a byte without effect like the LED
+    None,
+    // TDO sampled, the answer is written once the TCK after it is clocked
+    Sample,
+    Quit,
+}
+
+// A request of a batch for the bridge
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BitbangRequest {
+    pub operation: ValidOps,
+    payload: [u32; 4],
+    size: usize,
+    // TDI bits of the batch streamed after a scan request
+    input_from: u32,
+    input_to: u32,
+}
+
+impl BitbangRequest {
+    pub fn payload(&self) -> &[u32] {
+        &self.payload[..self.size]
+    }
+
+    // Bytes of TDI bits streamed after the request, a byte for each 8 bits LSB first
+    pub fn input(&self) -> Option<u32> {
+        (self.input_to > self.input_from).then(|| (self.input_to - self.input_from).div_ceil(8))
+    }
+}
+
+// The TCKs of a batch a request clocks
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Segment {
+    Tcks { count: u32 },
+    // A scan, its bits from TCK first, and the TCK after the path to Run-Test/Idle
+    Scan { ir: bool, first: u32, len: u32, end: u32 },
+}
+
+// TMS and TDI of TCKs, LSB first, and the ones sampled
+struct Tcks {
+    tms: [u32; WORDS],
+    tdi: [u32; WORDS],
+    sampled: [u32; WORDS],
+    len: u32,
+}
+
+impl Tcks {
+    const fn new() -> Tcks {
+        Tcks { tms: [0; WORDS], tdi: [0; WORDS], sampled: [0; WORDS], len: 0 }
+    }
+
+    fn push(&mut self, tms: bool, tdi: bool, sampled: bool) {
+        let (word, mask) = ((self.len / 32) as usize, 1 << (self.len % 32));
+        for (bits, set) in [(&mut self.tms, tms), (&mut self.tdi, tdi), (&mut self.sampled, sampled)] {
+            bits[word] = if set { bits[word] | mask } else { bits[word] & !mask };
+        }
+        self.len += 1;
+    }
+
+    fn get(&self, i: u32) -> (bool, bool, bool) {
+        let bit = |bits: &[u32; WORDS]| bits[(i / 32) as usize] >> (i % 32) & 1 != 0;
+        (bit(&self.tms), bit(&self.tdi), bit(&self.sampled))
+    }
+
+    fn answered(&mut self, i: u32) {
+        self.sampled[(i / 32) as usize] &= !(1 << (i % 32));
+    }
+}
+
+pub struct Bitbang {
+    // Pins as OpenOCD left them, and TRST
+    tck: bool,
+    tms: bool,
+    tdi: bool,
+    trst: bool,
+    // R seen, it samples the next TCK
+    sample: bool,
+    queue: Tcks,
+    // State of the TAP before the first TCK queued
+    state: TapState,
+    // State OpenOCD left the TAP in when a batch was parked, until the next TCK
+    parked: Option<TapState>,
+    // The batch clocked: TCKs cut from the queue, the TMS of the padding and whether the last TCK parks
+    batch: u32,
+    padded: u32,
+    pad_tms: bool,
+    park: bool,
+    // The state OpenOCD leaves the TAP in, and the one it is actually left in
+    left: TapState,
+    end: TapState,
+    // TCKs of the batch sent and answered, and the state before the next of each
+    sent: u32,
+    sent_state: TapState,
+    received: u32,
+    received_state: TapState,
+    // TDO bits of the scan being answered, streamed ahead of its response
+    streamed: u32,
+    tdo: [u32; WORDS],
+}
+
+impl Default for Bitbang {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bitbang {
+    // The TAP starts in Run-Test/Idle, after a `jtag reset`
+    pub const fn new() -> Bitbang {
+        Bitbang {
+            tck: false,
+            tms: false,
+            tdi: false,
+            trst: false,
+            sample: false,
+            queue: Tcks::new(),
+            state: TapState::RunTestIdle,
+            parked: None,
+            batch: 0,
+            padded: 0,
+            pad_tms: false,
+            park: false,
+            left: TapState::RunTestIdle,
+            end: TapState::RunTestIdle,
+            sent: 0,
+            sent_state: TapState::RunTestIdle,
+            received: 0,
+            received_state: TapState::RunTestIdle,
+            streamed: 0,
+            tdo: [0; WORDS],
+        }
+    }
+
+    // A byte from OpenOCD. The queue must not be full
+    pub fn push(&mut self, byte: u8) -> BitbangEvent {
+        match byte {
+            b'0'..=b'7' => {
+                let pins = byte - b'0';
+                let tck = pins & 4 != 0;
+                self.tms = pins & 2 != 0;
+                self.tdi = pins & 1 != 0;
+                if tck && !self.tck {
+                    self.queue_tck(self.tms, self.tdi, self.sample);
+                    self.sample = false;
+                }
+                self.tck = tck;
+                BitbangEvent::None
+            }
+            b'R' => {
+                self.sample = true;
+                BitbangEvent::Sample
+            }
+            b'Q' => BitbangEvent::Quit,
+            // TRST and SRST, bits 1 and 0. There is no TRST pin, five TCKs with TMS high reset the TAP as well
+            b'r'..=b'u' => {
+                let trst = (byte - b'r') & 2 != 0;
+                if trst && !self.trst {
+                    for _ in 0..5 {
+                        self.queue_tck(true, self.tdi, false);
+                    }
+                }
+                self.trst = trst;
+                BitbangEvent::None
+            }
+            _ => BitbangEvent::None,
+        }
+    }
+
+    // The TCKs back from a parked batch go first, from Pause through Exit2
+    fn queue_tck(&mut self, tms: bool, tdi: bool, sampled: bool) {
+        match self.parked.take() {
+            Some(TapState::ShiftDr | TapState::ShiftIr) => {
+                self.queue.push(true, false, false);
+                self.queue.push(false, false, false);
+            }
+            // From Exit1 TMS high goes on to Update, low stays in Pause
+            Some(_) if tms => self.queue.push(true, false, false),
+            _ => {}
+        }
+        self.queue.push(tms, tdi, sampled);
+    }
+
+    // No room for another byte until a batch is cut
+    pub fn full(&self) -> bool {
+        self.queue.len >= MAX_BATCH
+    }
+
+    // Cut a batch from the queue, up to the last TCK it can end on, and start clocking it. False if there is none, the
+    // samples queued are then answered to out
+    pub fn cut<W: Write>(&mut self, out: &mut W) -> bool {
+        if self.padded != 0 {
+            return false
+        }
+        let mut state = self.state;
+        let (mut cut, mut update) = (None, None);
+        for i in 0..self.queue.len {
+            let next = state.next(self.queue.get(i).0);
+            if next.idle() {
+                cut = Some((i + 1, next, false));
+            }
+            else if matches!(state, TapState::ShiftDr | TapState::ShiftIr) {
+                cut = Some((i + 1, next, true));
+            }
+            else if matches!(next, TapState::UpdateDr | TapState::UpdateIr) {
+                update = Some((i + 1, next, false));
+            }
+            state = next;
+        }
+        let (batch, next, park) = match cut.or(update.filter(|_| self.full())) {
+            Some(cut) => cut,
+            None => {
+                for i in 0..self.queue.len {
+                    if self.queue.get(i).2 {
+                        self.queue.answered(i);
+                        let _ = out.write_char('0');
+                    }
+                }
+                return false
+            }
+        };
+        // Parked, the padding goes from Exit1 to Pause, and from Update to Run-Test/Idle, at least one TCK of it
+        let end = match next {
+            _ if park => pause(next),
+            TapState::UpdateDr | TapState::UpdateIr => TapState::RunTestIdle,
+            _ => next,
+        };
+        let tcks = if end != next { batch + 1 } else { batch };
+        self.batch = batch;
+        self.padded = tcks.div_ceil(JTAG_WORD_BITS) * JTAG_WORD_BITS;
+        self.pad_tms = end.hold_tms();
+        self.park = park;
+        self.left = next;
+        self.end = end;
+        self.sent = 0;
+        self.sent_state = self.state;
+        self.received = 0;
+        self.received_state = self.state;
+        self.streamed = 0;
+        true
+    }
+
+    // TMS and TDI of TCK i of the batch
+    fn tck(&self, i: u32) -> (bool, bool) {
+        if i >= self.batch {
+            return (self.pad_tms, false)
+        }
+        let (tms, tdi, _) = self.queue.get(i);
+        (tms || self.park && i == self.batch - 1, tdi)
+    }
+
+    // The scan from TCK i of the batch in state, from a state the TAP holds in, by the shortest path to Shift-IR or
+    // Shift-DR and from Exit1 to Run-Test/Idle as the bridge clocks it
+    fn scan_at(&self, i: u32, state: TapState) -> Option<Segment> {
+        if !state.idle() {
+            return None
+        }
+        let matches = |from: u32, path: TmsPath| {
+            from + path.len as u32 <= self.batch && (0..path.len as u32).all(|bit| self.tck(from + bit).0 == path.tms(bit))
+        };
+        for (ir, shift, exit) in [(false, TapState::ShiftDr, TapState::Exit1Dr), (true, TapState::ShiftIr, TapState::Exit1Ir)] {
+            let enter = TmsPath::between(Some(state), shift);
+            if !matches(i, enter) {
+                continue
+            }
+            let first = i + enter.len as u32;
+            let last = (first..self.batch).find(|tck| self.tck(*tck).0)?;
+            let leave = TmsPath::between(Some(exit), TapState::RunTestIdle);
+            if !matches(last + 1, leave) {
+                return None
+            }
+            return Some(Segment::Scan { ir, first, len: last + 1 - first, end: last + 1 + leave.len as u32 })
+        }
+        None
+    }
+
+    // The request from TCK i of the batch in state, a scan or the raw TCKs up to the next one
+    fn segment(&self, i: u32, state: TapState) -> Segment {
+        if let Some(scan) = self.scan_at(i, state) {
+            return scan
+        }
+        let (mut count, mut state) = (0, state);
+        while count < MAX_TCKS && i + count < self.padded {
+            if count != 0 && self.scan_at(i + count, state).is_some() {
+                break
+            }
+            state = state.next(self.tck(i + count).0);
+            count += 1;
+        }
+        Segment::Tcks { count }
+    }
+
+    // The TCK after the request from TCK i and the state before it. The padding after a scan that ends the batch is
+    // not clocked, the TAP holds in Run-Test/Idle
+    fn after(&self, i: u32, state: TapState, segment: Segment) -> (u32, TapState) {
+        match segment {
+            Segment::Tcks { count } => (i + count, (i..i + count).fold(state, |state, tck| state.next(self.tck(tck).0))),
+            Segment::Scan { end, .. } if end == self.batch => (self.padded, TapState::RunTestIdle),
+            Segment::Scan { end, .. } => (end, TapState::RunTestIdle),
+        }
+    }
+
+    // TDI bits of the batch, from TCK from up to 32
+    fn tdi_bits(&self, from: u32, count: u32) -> u32 {
+        (0..count).fold(0, |bits, bit| bits | (self.tck(from + bit).1 as u32) << bit)
+    }
+
+    // The next request of the batch: a scan, its length and the first words of its TDI bits, or raw TCKs, their count,
+    // TMS bits and TDI bits
+    pub fn pending(&self) -> Option<BitbangRequest> {
+        if self.sent == self.padded {
+            return None
+        }
+        let mut request = BitbangRequest { operation: ValidOps::Tck, payload: [0; 4], size: 3, input_from: 0, input_to: 0 };
+        match self.segment(self.sent, self.sent_state) {
+            Segment::Tcks { count } => {
+                let tms = (0..count).fold(0, |bits, bit| bits | (self.tck(self.sent + bit).0 as u32) << bit);
+                request.payload[..3].copy_from_slice(&[count, tms, self.tdi_bits(self.sent, count)]);
+            }
+            Segment::Scan { ir, first, len, .. } => {
+                request.operation = if ir { ValidOps::ShiftIr } else { ValidOps::ShiftDr };
+                let words = len.div_ceil(32).min(SCAN_WORDS);
+                request.payload[0] = len;
+                for word in 0..words {
+                    request.payload[1 + word as usize] = self.tdi_bits(first + 32 * word, (len - 32 * word).min(32));
+                }
+                request.size = 1 + words as usize;
+                request.input_from = first + 32 * words;
+                request.input_to = first + len;
+            }
+        }
+        Some(request)
+    }
+
+    // The TDI bytes streamed after a request of the batch, before finish
+    pub fn input<'a>(&'a self, request: &BitbangRequest) -> impl Iterator<Item = u8> + 'a {
+        let (from, to) = (request.input_from, request.input_to);
+        (from..to).step_by(8).map(move |tck| self.tdi_bits(tck, (to - tck).min(8)) as u8)
+    }
+
+    pub fn sent(&mut self) {
+        let segment = self.segment(self.sent, self.sent_state);
+        (self.sent, self.sent_state) = self.after(self.sent, self.sent_state, segment);
+    }
+
+    fn set_tdo(&mut self, i: u32, tdo: bool) {
+        let mask = 1 << (i % 32);
+        let word = &mut self.tdo[(i / 32) as usize];
+        *word = if tdo { *word | mask } else { *word & !mask };
+    }
+
+    // TDO bits of the oldest request sent, a scan longer than its response, as they are streamed a byte for each 8
+    // bits LSB first
+    pub fn on_data(&mut self, bytes: &[u8]) {
+        if let Segment::Scan { first, len, .. } = self.segment(self.received, self.received_state) {
+            for byte in bytes {
+                for bit in 0..8.min(len.saturating_sub(self.streamed)) {
+                    self.set_tdo(first + self.streamed + bit, byte >> bit & 1 != 0);
+                }
+                self.streamed = (self.streamed + 8).min(len);
+            }
+        }
+    }
+
+    // The response to the oldest request sent, its TDO bits, or the count of those a scan streamed. The TCKs of a scan
+    // out of the shift read 0
+    pub fn on_tdo(&mut self, tdo: u32) {
+        let segment = self.segment(self.received, self.received_state);
+        match segment {
+            Segment::Tcks { count } => {
+                for bit in 0..count {
+                    if self.received + bit < self.batch {
+                        self.set_tdo(self.received + bit, tdo >> bit & 1 != 0);
+                    }
+                }
+            }
+            Segment::Scan { first, len, end, .. } => {
+                for i in self.received..end {
+                    let shifted = (first..first + len).contains(&i);
+                    if !shifted || len <= 32 {
+                        self.set_tdo(i, shifted && tdo >> (i - first) & 1 != 0);
+                    }
+                }
+            }
+        }
+        (self.received, self.received_state) = self.after(self.received, self.received_state, segment);
+        self.streamed = 0;
+    }
+
+    // Every request of the batch is answered
+    pub fn done(&self) -> bool {
+        self.padded != 0 && self.received == self.padded
+    }
+
+    // Answer the samples of the batch, '0' or '1' each, and drop it from the queue
+    pub fn finish<W: Write>(&mut self, out: &mut W) {
+        for i in 0..self.batch {
+            if self.queue.get(i).2 {
+                let _ = out.write_char(if self.tdo[(i / 32) as usize] >> (i % 32) & 1 != 0 { '1' } else { '0' });
+            }
+        }
+        let queue = core::mem::replace(&mut self.queue, Tcks::new());
+        self.state = self.end;
+        if self.park {
+            self.parked = Some(self.left);
+        }
+        for i in self.batch..queue.len {
+            let (tms, tdi, sampled) = queue.get(i);
+            self.queue_tck(tms, tdi, sampled);
+        }
+        self.batch = 0;
+        self.padded = 0;
+        self.park = false;
+    }
+}
+
+// The pause state of the register shifted
+fn pause(state: TapState) -> TapState {
+    match state {
+        TapState::ShiftIr | TapState::Exit1Ir => TapState::PauseIr,
+        _ => TapState::PauseDr,
+    }
+}
@@ -0,0 +1,179 @@
+// Clause 22 standard registers
+//
+// Names of the IEEE 802.3 Clause 22 registers, accepted in place of register numbers on the console, and the
+// bit fields of those a read can be decoded into.
+use core::fmt::{self, Write};
+
+pub const BMCR: u8 = 0;
+pub const BMSR: u8 = 1;
+pub const PHYID1: u8 = 2;
+pub const PHYID2: u8 = 3;
+pub const ANAR: u8 = 4;
+pub const ANLPAR: u8 = 5;
+pub const ANER: u8 = 6;
+// 1000BASE-T control and status
+pub const GBCR: u8 = 9;
+pub const GBSR: u8 = 10;
+pub const ESR: u8 = 15;
+
+// Register names, the first of a register is the one it is printed with
+const NAMES: [(&str, u8); 13] = [
+    ("BMCR", BMCR),
+    ("BMSR", BMSR),
+    ("PHYID1", PHYID1),
+    ("PHYID2", PHYID2),
+    ("ANAR", ANAR),
+    ("ANLPAR", ANLPAR),
+    ("ANER", ANER),
+    ("GBCR", GBCR),
+    ("GBSR", GBSR),
+    ("ESR", ESR),
+    // Linux mii.h names
+    ("CTRL1000", GBCR),
+    ("STAT1000", GBSR),
+    ("ESTATUS", ESR),
+];
+
+// A bit field, shift of its lowest bit and width in bits
+struct Field {
+    name: &'static str,
+    shift: u8,
+    width: u8,
+}
+
+const fn bit(name: &'static str, shift: u8) -> Field {
+    Field { name, shift, width: 1 }
+}
+
+const BMCR_FIELDS: [Field; 11] = [
+    bit("Reset", 15),
+    bit("Loopback", 14),
+    bit("Speed Select (LSB)", 13),
+    bit("AN Enable", 12),
+    bit("Power Down", 11),
+    bit("Isolate", 10),
+    bit("Restart AN", 9),
+    bit("Full Duplex", 8),
+    bit("Collision Test", 7),
+    bit("Speed Select (MSB)", 6),
+    bit("Unidirectional Enable", 5),
+];
+
+const BMSR_FIELDS: [Field; 16] = [
+    bit("100BASE-T4", 15),
+    bit("100BASE-X FD", 14),
+    bit("100BASE-X HD", 13),
+    bit("10 Mb/s FD", 12),
+    bit("10 Mb/s HD", 11),
+    bit("100BASE-T2 FD", 10),
+    bit("100BASE-T2 HD", 9),
+    bit("Extended Status", 8),
+    bit("Unidirectional Ability", 7),
+    bit("MF Preamble Suppression", 6),
+    bit("AN Complete", 5),
+    bit("Remote Fault", 4),
+    bit("AN Ability", 3),
+    bit("Link Status", 2),
+    bit("Jabber Detect", 1),
+    bit("Extended Capability", 0),
+];
+
+const PHYID1_FIELDS: [Field; 1] = [
+    Field { name: "OUI bits 3-18", shift: 0, width: 16 },
+];
+
+const PHYID2_FIELDS: [Field; 3] = [
+    Field { name: "OUI bits 19-24", shift: 10, width: 6 },
+    Field { name: "Model", shift: 4, width: 6 },
+    Field { name: "Revision", shift: 0, width: 4 },
+];
+
+// Base page, advertised in ANAR and received in ANLPAR
+const BASE_PAGE_FIELDS: [Field; 12] = [
+    bit("Next Page", 15),
+    bit("Acknowledge", 14),
+    bit("Remote Fault", 13),
+    bit("Extended Next Page", 12),
+    bit("Asymmetric Pause", 11),
+    bit("Pause", 10),
+    bit("100BASE-T4", 9),
+    bit("100BASE-TX FD", 8),
+    bit("100BASE-TX", 7),
+    bit("10BASE-T FD", 6),
+    bit("10BASE-T", 5),
+    Field { name: "Selector", shift: 0, width: 5 },
+];
+
+const ANER_FIELDS: [Field; 5] = [
+    bit("Parallel Detection Fault", 4),
+    bit("LP Next Page Able", 3),
+    bit("Next Page Able", 2),
+    bit("Page Received", 1),
+    bit("LP AN Able", 0),
+];
+
+const GBCR_FIELDS: [Field; 6] = [
+    Field { name: "Test Mode", shift: 13, width: 3 },
+    bit("Master-Slave Manual", 12),
+    bit("Master", 11),
+    bit("Multiport", 10),
+    bit("1000BASE-T FD", 9),
+    bit("1000BASE-T HD", 8),
+];
+
+const GBSR_FIELDS: [Field; 7] = [
+    bit("Master-Slave Fault", 15),
+    bit("Master", 14),
+    bit("Local Receiver OK", 13),
+    bit("Remote Receiver OK", 12),
+    bit("LP 1000BASE-T FD", 11),
+    bit("LP 1000BASE-T HD", 10),
+    Field { name: "Idle Error Count", shift: 0, width: 8 },
+];
+
+const ESR_FIELDS: [Field; 4] = [
+    bit("1000BASE-X FD", 15),
+    bit("1000BASE-X HD", 14),
+    bit("1000BASE-T FD", 13),
+    bit("1000BASE-T HD", 12),
+];
+
+// Register number of a name, in any case
+pub fn register_number(name: &str) -> Option<u8> {
+    NAMES.iter().find(|(known, _)| known.eq_ignore_ascii_case(name)).map(|(_, reg)| *reg)
+}
+
+pub fn register_name(reg: u8) -> Option<&'static str> {
+    NAMES.iter().find(|(_, known)| *known == reg).map(|(name, _)| *name)
+}
+
+fn fields(reg: u8) -> &'static [Field] {
+    match reg {
+        BMCR => &BMCR_FIELDS,
+        BMSR => &BMSR_FIELDS,
+        PHYID1 => &PHYID1_FIELDS,
+        PHYID2 => &PHYID2_FIELDS,
+        ANAR | ANLPAR => &BASE_PAGE_FIELDS,
+        ANER => &ANER_FIELDS,
+        GBCR => &GBCR_FIELDS,
+        GBSR => &GBSR_FIELDS,
+        ESR => &ESR_FIELDS,
+        _ => &[],
+    }
+}
+
+// Write the value of a register with a line per field. Registers without a name only get the value
+pub fn decode<W: Write>(reg: u8, value: u16, out: &mut W) -> fmt::Result {
+    match register_name(reg) {
+        Some(name) => write!(out, "{} ({:#04x}): {:#06x}\n\r", name, reg, value)?,
+        None => write!(out, "{:#04x}: {:#06x}\n\r", reg, value)?,
+    }
+    for field in fields(reg) {
+        let bits = value >> field.shift & ((1 << field.width) - 1) as u16;
+        match field.width {
+            1 => write!(out, "  {}: {}\n\r", field.name, bits)?,
+            _ => write!(out, "  {}: {:#x}\n\r", field.name, bits)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use crate::c22::register_number;
+use crate::error::BridgeError;
+use crate::i2c::MAX_WRITE;
+use crate::smbus::MAX_BLOCK_WRITE;
+use crate::spi::SPI_HOLD;
+use crate::protocol::{ValidHostInterfaces,  host::{self, HostRequest, ValidInterfaces, ValidOps}};
+
+use core::str;
+use core::str::SplitWhitespace;
+
+// Outcome of a line typed on the serial console
+#[derive(Debug)]
+pub enum Command {
+    // A request for one of the interfaces, not yet validated
+    Request(HostRequest<host::Unclean>),
+    // Informational command answered by the console itself
+    Menu,
+}
+
+// Parse a line typed on the serial console into a Command
+pub fn parse_command(input: &str) -> Result<Command, BridgeError> {
+    if slice_contains(input, "menu") {
+        Ok(Command::Menu)
+    }
+    else {
+        message_parse_build(input).map(Command::Request)
+    }
+}
+
+pub fn slice_contains(haystack: &str, needle: &str) -> bool {
+    if haystack.len() < needle.len() {
+        return false;
+    }
+
+    for i in 0..=(haystack.len() - needle.len()) {
+        if &haystack[i..(i + needle.len())] == needle {
+            return true;
+        }
+    }
+    false
+}
+
+// Helper function that takes list of bytes and deconstructs
+// into HostRequest fields.
+// NOTE: Preliminary behavior is to drop message and log to serial an invalid message
+// if fields are missing or invalid
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.bar")] // Execute from IRAM
+pub fn message_parse_build<'input>(input: &'input str)
+    -> Result<HostRequest<host::Unclean>, BridgeError>{
+    let mut args = [0u32; 2 + MAX_WRITE];
+
+    // Split up the given string
+    let mut hr = HostRequest::new();
+    hr.set_host_config(ValidHostInterfaces::Serial);
+
+    // Serial commands arrive in a zero padded buffer, only the characters before the padding are the command
+    let input = input.trim_end_matches('\0');
+    let words = |input: &'input str| -> SplitWhitespace<'input>  {input.split_whitespace()};
+    let mut command = words(input);
+    let command_count = command.clone().count();
+    if command_count > 2 + args.len() {
+        return Err(BridgeError::BadArgCount)
+    }
+    // Match on the first word
+    match command.next() {
+        Some("smi" | "SMI") => {
+            hr.set_interface(ValidInterfaces::SMI);
+        }
+        Some("smi45" | "SMI45") => {
+            hr.set_interface(ValidInterfaces::SMI45);
+        }
+        Some("mmd" | "MMD") => {
+            hr.set_interface(ValidInterfaces::MMD);
+        }
+        Some("cfg" | "CFG") => {
+            hr.set_interface(ValidInterfaces::Config);
+        }
+        Some("gpio" | "GPIO") => {
+            hr.set_interface(ValidInterfaces::GPIO);
+        }
+        Some("jtag" | "JTAG") => {
+            hr.set_interface(ValidInterfaces::JTAG);
+        }
+        Some("i2c" | "I2C") => {
+            hr.set_interface(ValidInterfaces::I2C);
+        }
+        Some("smbus" | "SMBUS") => {
+            hr.set_interface(ValidInterfaces::SMBus);
+        }
+        Some("spi" | "SPI") => {
+            hr.set_interface(ValidInterfaces::SPI);
+        }
+        Some("flash" | "FLASH") => {
+            hr.set_interface(ValidInterfaces::Flash);
+        }
+        _ => {
+            return Err(BridgeError::InvalidInterface)
+        }
+    }
+    // Match on the second word. This should be an operation. If not log incorrect
+    let mut hold = false;
+    match command.next() {
+        Some("r" | "R") => {
+            hr.set_operation(ValidOps::Read);
+        }
+        Some("w" | "W") => {
+            hr.set_operation(ValidOps::Write);
+        }
+        Some("scan" | "SCAN") => {
+            hr.set_operation(ValidOps::Scan);
+        }
+        Some("dump" | "DUMP") => {
+            hr.set_operation(ValidOps::Dump);
+        }
+        Some("diff" | "DIFF") => {
+            hr.set_operation(ValidOps::Diff);
+        }
+        Some("rmw" | "RMW") => {
+            hr.set_operation(ValidOps::Rmw);
+        }
+        Some("setbits" | "SETBITS") => {
+            hr.set_operation(ValidOps::SetBits);
+        }
+        Some("clrbits" | "CLRBITS") => {
+            hr.set_operation(ValidOps::ClrBits);
+        }
+        Some("decode" | "DECODE") => {
+            hr.set_operation(ValidOps::Decode);
+        }
+        Some("poll" | "POLL") => {
+            hr.set_operation(ValidOps::Poll);
+        }
+        Some("pr" | "PR") => {
+            hr.set_operation(ValidOps::PagedRead);
+        }
+        Some("pw" | "PW") => {
+            hr.set_operation(ValidOps::PagedWrite);
+        }
+        Some("page" | "PAGE") => {
+            hr.set_operation(ValidOps::PageSel);
+        }
+        Some("ri" | "RI") => {
+            hr.set_operation(ValidOps::ReadInc);
+        }
+        Some("smiset" | "SMISET") => {
+            hr.set_operation(ValidOps::SmiSet);
+        }
+        Some("wr" | "WR") => {
+            hr.set_operation(ValidOps::WriteRead);
+        }
+        Some("recover" | "RECOVER") => {
+            hr.set_operation(ValidOps::Recover);
+        }
+        Some("i2cset" | "I2CSET") => {
+            hr.set_operation(ValidOps::I2cSet);
+        }
+        Some("send" | "SEND") => {
+            hr.set_operation(ValidOps::SendByte);
+        }
+        Some("rb" | "RB") => {
+            hr.set_operation(ValidOps::ReadByte);
+        }
+        Some("wb" | "WB") => {
+            hr.set_operation(ValidOps::WriteByte);
+        }
+        Some("rw" | "RW") => {
+            hr.set_operation(ValidOps::ReadWord);
+        }
+        Some("ww" | "WW") => {
+            hr.set_operation(ValidOps::WriteWord);
+        }
+        Some("br" | "BR") => {
+            hr.set_operation(ValidOps::BlockRead);
+        }
+        Some("bw" | "BW") => {
+            hr.set_operation(ValidOps::BlockWrite);
+        }
+        Some("pc" | "PC") => {
+            hr.set_operation(ValidOps::ProcCall);
+        }
+        Some("l11" | "L11") => {
+            hr.set_operation(ValidOps::Linear11);
+        }
+        Some("l16" | "L16") => {
+            hr.set_operation(ValidOps::Linear16);
+        }
+        Some("pec" | "PEC") => {
+            hr.set_operation(ValidOps::PecSet);
+        }
+        Some("xfer" | "XFER") => {
+            hr.set_operation(ValidOps::Transfer);
+        }
+        // SPI transfers and reads keeping the chip select asserted
+        Some("xferh" | "XFERH") => {
+            hr.set_operation(ValidOps::Transfer);
+            hold = true;
+        }
+        Some("rh" | "RH") => {
+            hr.set_operation(ValidOps::Read);
+            hold = true;
+        }
+        Some("spimode" | "SPIMODE") => {
+            hr.set_operation(ValidOps::SpiMode);
+        }
+        Some("spiset" | "SPISET") => {
+            hr.set_operation(ValidOps::SpiSet);
+        }
+        Some("read" | "READ") => {
+            hr.set_operation(ValidOps::Read);
+        }
+        Some("write" | "WRITE") => {
+            hr.set_operation(ValidOps::Write);
+        }
+        Some("id" | "ID") => {
+            hr.set_operation(ValidOps::Id);
+        }
+        Some("erase" | "ERASE") => {
+            hr.set_operation(ValidOps::Erase);
+        }
+        Some("verify" | "VERIFY") => {
+            hr.set_operation(ValidOps::Verify);
+        }
+        Some("reset" | "RESET") => {
+            hr.set_operation(ValidOps::Reset);
+        }
+        Some("ir" | "IR") => {
+            hr.set_operation(ValidOps::ShiftIr);
+        }
+        Some("dr" | "DR") => {
+            hr.set_operation(ValidOps::ShiftDr);
+        }
+        Some("svf" | "SVF") => {
+            hr.set_operation(ValidOps::Svf);
+        }
+        Some("tck" | "TCK") => {
+            hr.set_operation(ValidOps::Tck);
+        }
+        _ => {
+            return Err(BridgeError::InvalidOperation);
+        }
+    }
+    // Argument that may be a snapshot name instead of a number
+    let name_at = match hr.operation {
+        ValidOps::Dump => Some(3),
+        ValidOps::Diff => Some(0),
+        _ => None,
+    };
+    // SMI registers may be given by their Clause 22 name, the second argument or the end of a dump range.
+    // Paged registers are vendor registers and have no names
+    let smi = hr.interface == ValidInterfaces::SMI
+        && !matches!(hr.operation, ValidOps::PagedRead | ValidOps::PagedWrite);
+    let dump = hr.operation == ValidOps::Dump;
+    let mut size: usize = 0;
+    for val in command {
+        // A register range start..end is two arguments
+        let (first, second) = match val.split_once("..") {
+            Some((start, end)) => (start, Some(end)),
+            None => (val, None),
+        };
+        for word in core::iter::once(first).chain(second) {
+            if size == args.len() {
+                return Err(BridgeError::BadArgCount)
+            }
+            args[size] = match bytes_to_number(word) {
+                Ok(value) => value,
+                Err(_) if name_at == Some(size) => pack_name(word)?,
+                Err(err) if smi && (size == 1 || dump && size == 2) => {
+                    register_number(word).ok_or(err)? as u32
+                }
+                Err(err) => return Err(err),
+            };
+            size += 1;
+        }
+    }
+    // A request holds 4 words. Polls take 6 arguments, the PHY address and register share a word,
+    // as do the mask and expected value. I2C writes take the address and up to 8 bytes, the bytes are
+    // counted and packed 4 to a word, as are the command code and data of SMBus block writes
+    let payload = match (hr.operation, size) {
+        (ValidOps::Poll, 6) => {
+            size = 4;
+            [pack_halves(args[0], args[1])?, pack_halves(args[2], args[3])?, args[4], args[5]]
+        }
+        (ValidOps::BlockWrite, _) if size > 2 + MAX_BLOCK_WRITE => return Err(BridgeError::BadArgCount),
+        (ValidOps::Write | ValidOps::BlockWrite, 1..)
+            if matches!(hr.interface, ValidInterfaces::I2C | ValidInterfaces::SMBus) => {
+            let (low, high) = args[1..size].split_at((size - 1).min(4));
+            let count = size as u32 - 1;
+            size = 4;
+            [args[0], count, pack_bytes(low)?, pack_bytes(high)?]
+        }
+        _ if size > 4 => return Err(BridgeError::BadArgCount),
+        // The hold flag goes with the chip select
+        _ if hold => {
+            if hr.interface != ValidInterfaces::SPI {
+                return Err(BridgeError::InvalidOperation)
+            }
+            [args[0] | SPI_HOLD, args[1], args[2], args[3]]
+        }
+        _ => [args[0], args[1], args[2], args[3]],
+    };
+    hr.set_size(size as u8);
+    hr.set_payload(payload);
+    // Serial commands carry no checksum of their own
+    hr.set_checksum(hr.compute_checksum());
+    Ok(hr)
+}
+
+// Up to 4 byte arguments in one word, the first in the low byte
+fn pack_bytes(bytes: &[u32]) -> Result<u32, BridgeError> {
+    if bytes.len() > 4 {
+        return Err(BridgeError::BadArgCount)
+    }
+    let mut word = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte > 0xFF {
+            return Err(BridgeError::NumberTooLarge)
+        }
+        word |= byte << (8 * i);
+    }
+    Ok(word)
+}
+
+// Two 16 bit arguments in one word, the first in the low half
+fn pack_halves(low: u32, high: u32) -> Result<u32, BridgeError> {
+    if low > 0xFFFF || high > 0xFFFF {
+        return Err(BridgeError::NumberTooLarge)
+    }
+    Ok(high << 16 | low)
+}
+
+// Pack a name of 1 to 4 letters and digits into a word, first character in the low byte
+pub fn pack_name(s: &str) -> Result<u32, BridgeError> {
+    if s.is_empty() || s.len() > 4 {
+        return Err(BridgeError::OutOfRange)
+    }
+    let mut name = 0;
+    for (i, c) in s.bytes().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            return Err(BridgeError::InvalidNumber)
+        }
+        name |= (c as u32) << (8 * i);
+    }
+    Ok(name)
+}
+
+// Helper function to take &str in decimal or hex form
+// and return u32.
+// ie: s = "0xFF"  will return decimal value 255
+#[inline(never)]
+#[cfg_attr(target_os = "none", link_section = ".data.bar")] // Execute from IRAM
+pub fn bytes_to_number(s: &str) -> Result<u32, BridgeError> {
+    let mut result: u32 = 0;
+    // Check if the input is hex or decimal
+    let mut chars = s.chars();
+    if let Some(c) = chars.next() {
+        if c != '0' || chars.next() != Some('x') {
+            if c.is_ascii_digit() {
+                result += c as u32 - '0' as u32;
+                for c in chars {
+                    let digit= match c {
+                        '0'..='9' => c as u32 - '0' as u32,
+                        _ => return Err(BridgeError::InvalidNumber),
+                    };
+                    if result >= 429_496_720 {
+                        return Err(BridgeError::NumberTooLarge)
+                    }
+                    result = result * 10 + digit;
+
+                }
+                return Ok(result)
+            }
+            return Err(BridgeError::InvalidNumber)
+        }
+    }
+    if chars.clone().count() > 8 {
+        return Err(BridgeError::NumberTooLarge)
+    }
+    for c in chars {
+        let digit =  match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='f' => c as u32 - 'a' as u32 + 10,
+            'A'..='F' => c as u32 - 'A' as u32 + 10,
+            _ => return Err(BridgeError::InvalidNumber),
+        };
+        result = result * 16 + digit;
+    }
+    Ok(result)
+}
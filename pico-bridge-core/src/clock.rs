@@ -0,0 +1,39 @@
+// PIO clock divisors
+//
+// A state machine runs at sys_clk / (int + frac / 256). The interface clock it generates is that rate divided by the
+// number of PIO cycles its program takes per clock period.
+use crate::error::BridgeError;
+
+// Largest integer part of a PIO divisor, 0 in the hardware register means 65536 and is not used
+const MAX_DIV_INT: u64 = 0xFFFF;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ClockDivisor {
+    pub int: u16,
+    pub frac: u8,
+}
+
+impl ClockDivisor {
+    // The divisor giving the frequency closest to target_hz for a program taking cycles PIO cycles per period
+    pub fn for_frequency(sys_hz: u32, target_hz: u32, cycles: u32) -> Result<ClockDivisor, BridgeError> {
+        if target_hz == 0 || cycles == 0 {
+            return Err(BridgeError::OutOfRange)
+        }
+        let per_period = target_hz as u64 * cycles as u64;
+        // Divisor in 16.8 fixed point, rounded to nearest
+        let div = (((sys_hz as u64) << 8) + per_period / 2) / per_period;
+        if !(1 << 8..=(MAX_DIV_INT << 8) + 0xFF).contains(&div) {
+            return Err(BridgeError::OutOfRange)
+        }
+        Ok(ClockDivisor {
+            int: (div >> 8) as u16,
+            frac: (div & 0xFF) as u8,
+        })
+    }
+
+    // Frequency actually produced by this divisor, rounded to the nearest Hz
+    pub fn frequency(&self, sys_hz: u32, cycles: u32) -> u32 {
+        let div = (((self.int as u64) << 8 | self.frac as u64) * cycles as u64).max(1);
+        ((((sys_hz as u64) << 8) + div / 2) / div) as u32
+    }
+}
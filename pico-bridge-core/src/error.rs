@@ -0,0 +1,121 @@
+// Errors of the HostRequest/SlaveResponse pipeline
+//
+// The numeric codes are sent to the host as the status of SPI and binary mode responses, where 0 means
+// success. They are part of the wire format: never renumber a variant, only add new ones at the end.
+use core::convert::TryFrom;
+use core::fmt;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BridgeError {
+    // The request names an interface that does not exist or is not supported
+    InvalidInterface = 1,
+    // The operation does not exist or is not supported by the interface
+    InvalidOperation = 2,
+    // Wrong number of arguments for the interface and operation
+    BadArgCount = 3,
+    // An argument is not a hex or decimal number
+    InvalidNumber = 4,
+    // An argument does not fit in 32 bits
+    NumberTooLarge = 5,
+    // The request checksum or frame CRC does not match its contents
+    ChecksumMismatch = 6,
+    // A binary frame could not be decoded
+    InvalidFrame = 7,
+    // A queue between tasks had no room for the request or response
+    QueueFull = 8,
+    // The device did not complete the operation in time
+    Timeout = 9,
+    // The device did not answer
+    NoResponse = 10,
+    // A request with the same proc_id is still waiting on the state machine
+    DuplicateProcId = 11,
+    // An argument is outside the range the interface supports
+    OutOfRange = 12,
+    // No PHY drove the MDIO turnaround bit low, nothing answers at the address
+    NoPhy = 13,
+    // The request names something that does not exist, like an unknown snapshot
+    NotFound = 14,
+    // An I2C device did not ACK its address or a byte written to it
+    Nack = 15,
+    // The Packet Error Code read from an SMBus device does not match the bytes of the transaction
+    PecMismatch = 16,
+    // The CRC-32 of a flash range does not match the one expected
+    VerifyMismatch = 17,
+    // An SVF statement is not valid or not supported
+    SvfSyntax = 18,
+    // The TDO bits of an SVF scan do not match the ones expected
+    TdoMismatch = 19,
+}
+
+impl BridgeError {
+    // Stable numeric code reported in binary responses
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BridgeError::InvalidInterface => "Invalid Interface",
+            BridgeError::InvalidOperation => "Invalid Operation",
+            BridgeError::BadArgCount => "Invalid number of arguments",
+            BridgeError::InvalidNumber => "Not a hex or decimal number",
+            BridgeError::NumberTooLarge => "Integer number too large!",
+            BridgeError::ChecksumMismatch => "Checksum mismatch",
+            BridgeError::InvalidFrame => "Malformed frame",
+            BridgeError::QueueFull => "Request queue is full",
+            BridgeError::Timeout => "Timed out",
+            BridgeError::NoResponse => "No response from device",
+            BridgeError::DuplicateProcId => "Proc ID already in flight",
+            BridgeError::OutOfRange => "Value out of range",
+            BridgeError::NoPhy => "No PHY response",
+            BridgeError::NotFound => "Not found",
+            BridgeError::Nack => "No ACK from I2C device",
+            BridgeError::PecMismatch => "SMBus PEC mismatch",
+            BridgeError::VerifyMismatch => "Flash verify mismatch",
+            BridgeError::SvfSyntax => "Bad SVF statement",
+            BridgeError::TdoMismatch => "TDO mismatch",
+        }
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Decode the status of a response on the host, 0 (success) is not an error
+impl TryFrom<u8> for BridgeError {
+    type Error = ();
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            1 => Ok(BridgeError::InvalidInterface),
+            2 => Ok(BridgeError::InvalidOperation),
+            3 => Ok(BridgeError::BadArgCount),
+            4 => Ok(BridgeError::InvalidNumber),
+            5 => Ok(BridgeError::NumberTooLarge),
+            6 => Ok(BridgeError::ChecksumMismatch),
+            7 => Ok(BridgeError::InvalidFrame),
+            8 => Ok(BridgeError::QueueFull),
+            9 => Ok(BridgeError::Timeout),
+            10 => Ok(BridgeError::NoResponse),
+            11 => Ok(BridgeError::DuplicateProcId),
+            12 => Ok(BridgeError::OutOfRange),
+            13 => Ok(BridgeError::NoPhy),
+            14 => Ok(BridgeError::NotFound),
+            15 => Ok(BridgeError::Nack),
+            16 => Ok(BridgeError::PecMismatch),
+            17 => Ok(BridgeError::VerifyMismatch),
+            18 => Ok(BridgeError::SvfSyntax),
+            19 => Ok(BridgeError::TdoMismatch),
+            // ... add more variants here
+            _ => Err(()),
+        }
+    }
+}
+
+// Status byte of a response: 0 on success, otherwise the error code
+pub fn status_code(status: Option<BridgeError>) -> u8 {
+    status.map_or(0, BridgeError::code)
+}
@@ -0,0 +1,579 @@
+// SPI NOR flash on the SPI master
+//
+// A flash request runs as a sequence of commands on one chip select, the chip select framing each: the opcode,
+// address and data bytes go out, then the bytes read are clocked in while 0xFF goes out. The firmware releases the
+// chip select once all bytes of a command are back and asks for the next command, sent right away or after a delay
+// while the flash is busy erasing or programming. Needs 8 bit words MSB first, in mode 0 or 3. Addresses are
+// 3 bytes, up to 16 MiB.
+// The bytes of a write are streamed from the host, each page program starts once the input holds all its bytes. The
+// bytes of a read longer than the response are streamed to the host.
+// Flash id learns the size, page and erase sizes of a chip from the Basic Flash Parameter Table of its SFDP, later
+// requests on the chip select use them. Until then common defaults are assumed.
+use core::fmt::{self, Write};
+
+use crate::error::BridgeError;
+use crate::protocol::host::{Clean, HostRequest, ValidOps};
+use crate::spi::{SpiConfig, MAX_CS};
+use crate::stream::{Chunk, StreamIn, StreamOut};
+
+// Reach of 3 byte addresses
+pub const MAX_FLASH_SIZE: u32 = 1 << 24;
+// Bytes between the progress lines of a write or verify
+pub const VERIFY_PROGRESS: u32 = 0x10000;
+
+const READ_ID: u8 = 0x9F;
+const READ_SFDP: u8 = 0x5A;
+const READ: u8 = 0x03;
+const WRITE_ENABLE: u8 = 0x06;
+const READ_STATUS: u8 = 0x05;
+const PAGE_PROGRAM: u8 = 0x02;
+const CHIP_ERASE: u8 = 0xC7;
+// Write In Progress bit of the status register
+const WIP: u8 = 0x01;
+// "SFDP", first in the header
+const SFDP_SIGNATURE: u32 = 0x5044_4653;
+// Dwords of the Basic Flash Parameter Table decoded, up to the page size
+const BFPT_DWORDS: usize = 11;
+// Bytes kept of what a command reads
+const KEPT: usize = 4 * BFPT_DWORDS;
+// Opcode, address and dummy byte of the longest command
+const HEADER: usize = 5;
+// Bytes of a page program, shorter than a page if need be. Below the level the input asks the host for more at, so
+// the input always comes to hold them
+const MAX_PROGRAM: u32 = 256;
+
+// Status polls while busy, and how long the flash may stay busy. Chip erases of large chips take minutes
+const PROGRAM_POLL_US: u32 = 100;
+const PROGRAM_TIMEOUT_US: u64 = 100_000;
+const ERASE_POLL_US: u32 = 1_000;
+const ERASE_TIMEOUT_US: u64 = 10_000_000;
+const CHIP_ERASE_POLL_US: u32 = 10_000;
+const CHIP_ERASE_TIMEOUT_US: u64 = 400_000_000;
+// Time between the progress lines while the flash is busy
+const BUSY_REPORT_US: u64 = 1_000_000;
+
+// CRC-32 of bytes as zlib computes it, continuing from crc. 0 to start
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FlashGeometry {
+    // Bytes, 0 while unknown
+    pub size: u32,
+    pub page: u32,
+    // Erase sizes in bytes and their opcodes, size 0 for none
+    pub erase: [(u32, u8); 4],
+}
+
+impl FlashGeometry {
+    // 256 byte pages, 4 KiB sectors, 32 KiB and 64 KiB blocks, as on most chips
+    pub const DEFAULT: FlashGeometry = FlashGeometry {
+        size: 0,
+        page: 256,
+        erase: [(0x1000, 0x20), (0x8000, 0x52), (0x10000, 0xD8), (0, 0)],
+    };
+
+    // Decode the Basic Flash Parameter Table, None if it is shorter than the 9 dwords of its first revision.
+    // The page size is in the 11th dword of later revisions, 256 bytes without it
+    pub fn from_bfpt(dwords: &[u32]) -> Option<FlashGeometry> {
+        if dwords.len() < 9 {
+            return None
+        }
+        let density = dwords[1];
+        let bits = if density & 0x8000_0000 == 0 {
+            density as u64 + 1
+        }
+        else {
+            1_u64.checked_shl(density & 0x7FFF_FFFF).unwrap_or(0)
+        };
+        let mut erase = [(0, 0); 4];
+        for (i, entry) in erase.iter_mut().enumerate() {
+            let [exponent, opcode] = ((dwords[7 + i / 2] >> (16 * (i % 2))) as u16).to_le_bytes();
+            if exponent != 0 && exponent < 32 {
+                *entry = (1 << exponent, opcode);
+            }
+        }
+        let page = match dwords.get(10) {
+            Some(dword) => 1 << ((dword >> 4) & 0xF),
+            None => 256,
+        };
+        Some(FlashGeometry { size: (bits / 8).min(u32::MAX as u64) as u32, page, erase })
+    }
+
+    // Opcode erasing this many bytes
+    pub fn erase_opcode(&self, size: u32) -> Option<u8> {
+        self.erase.iter().find(|(bytes, _)| *bytes != 0 && *bytes == size).map(|(_, opcode)| *opcode)
+    }
+
+    // Bytes addressable, all of them while the size is unknown
+    fn limit(&self) -> u32 {
+        if self.size == 0 { MAX_FLASH_SIZE } else { self.size.min(MAX_FLASH_SIZE) }
+    }
+}
+
+impl fmt::Display for FlashGeometry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_bytes(f, self.size)?;
+        write!(f, ", {} byte pages, erase", self.page)?;
+        for (i, (size, opcode)) in self.erase.iter().filter(|(size, _)| *size != 0).enumerate() {
+            write!(f, "{}", if i == 0 { " " } else { ", " })?;
+            write_bytes(f, *size)?;
+            write!(f, " 0x{:02x}", opcode)?;
+        }
+        Ok(())
+    }
+}
+
+// Opcode and 3 byte address
+fn addressed(opcode: u8, addr: u32) -> [u8; 4] {
+    let [_, high, mid, low] = addr.to_be_bytes();
+    [opcode, high, mid, low]
+}
+
+// A size in the largest unit it is a whole number of
+fn write_bytes<W: Write>(out: &mut W, bytes: u32) -> fmt::Result {
+    match bytes {
+        0 => write!(out, "Unknown size"),
+        _ if bytes.is_multiple_of(1 << 20) => write!(out, "{} MiB", bytes >> 20),
+        _ if bytes.is_multiple_of(1 << 10) => write!(out, "{} KiB", bytes >> 10),
+        _ => write!(out, "{} bytes", bytes),
+    }
+}
+
+// What to do once the bytes of a command are all back
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FlashStep {
+    // Select the chip and send the next command
+    Next,
+    // The same, after this many us
+    Wait(u32),
+    // Finished, the value and its size in bytes for the SlaveResponse, or the error
+    Done(Result<(u32, u8), BridgeError>),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Stage {
+    ReadId,
+    SfdpHeader,
+    Bfpt,
+    WriteEnable,
+    // Page program or erase, then status reads until the flash is no longer busy
+    Modify,
+    Status,
+    // The data of a read or verify
+    Read,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct FlashJob {
+    config: SpiConfig,
+    cs: u8,
+    op: ValidOps,
+    stage: Stage,
+    geometry: FlashGeometry,
+    addr: u32,
+    // Bytes read, verified, erased (0 for the whole chip) or written
+    len: u32,
+    // Opcode of an erase
+    opcode: u8,
+    // Bytes written so far, and those of the page program running
+    programmed: u32,
+    chunk: u32,
+    expected: u32,
+    // The command being sent: opcode and address, the count of bytes taken from the input, then of bytes read
+    out: [u8; HEADER],
+    out_len: u32,
+    in_len: u32,
+    read_len: u32,
+    sent: u32,
+    received: u32,
+    // Byte taken from the input, sent next
+    next: Option<u8>,
+    // The first bytes read by the command
+    kept: [u8; KEPT],
+    // Bytes of a read longer than the response, and bytes of a verify listed so far
+    stream: Option<StreamOut>,
+    written: u32,
+    crc: u32,
+    // JEDEC manufacturer and device ID, 3 bytes
+    id: u32,
+    // When the flash went busy, and the last progress line while it is
+    busy_us: Option<u64>,
+    reported_us: u64,
+    failed: Option<BridgeError>,
+}
+
+impl FlashJob {
+    // Chip select, and the arguments of the operation, checked against the geometry known for the chip
+    pub fn start(hr: &HostRequest<Clean>, config: SpiConfig, chips: &[FlashGeometry; MAX_CS])
+        -> Result<FlashJob, BridgeError> {
+        // Modes 0 and 3 sample on the rising edge of SCK
+        if config.bits != 8 || config.lsb_first || config.cpol() != config.cpha() {
+            return Err(BridgeError::OutOfRange)
+        }
+        let cs = (hr.payload[0] & 0xFF) as usize;
+        let geometry = *chips.get(cs).ok_or(BridgeError::OutOfRange)?;
+        let mut job = FlashJob {
+            config,
+            cs: cs as u8,
+            op: hr.operation,
+            stage: Stage::ReadId,
+            geometry,
+            addr: hr.payload[1],
+            len: 0,
+            opcode: CHIP_ERASE,
+            programmed: 0,
+            chunk: 0,
+            expected: 0,
+            out: [0; HEADER],
+            out_len: 0,
+            in_len: 0,
+            read_len: 0,
+            sent: 0,
+            received: 0,
+            next: None,
+            kept: [0; KEPT],
+            stream: None,
+            written: 0,
+            crc: 0,
+            id: 0,
+            busy_us: None,
+            reported_us: 0,
+            failed: None,
+        };
+        match (hr.operation, hr.size) {
+            (ValidOps::Id, 1) => {
+                job.command(Stage::ReadId, &[READ_ID], 3);
+                return Ok(job)
+            }
+            (ValidOps::Erase, 1) => {
+                job.addr = 0;
+            }
+            (ValidOps::Erase, 3) => {
+                job.len = hr.payload[2];
+                job.opcode = geometry.erase_opcode(job.len).ok_or(BridgeError::OutOfRange)?;
+                if !job.addr.is_multiple_of(job.len) {
+                    return Err(BridgeError::OutOfRange)
+                }
+            }
+            (ValidOps::Read | ValidOps::Write, 3) => {
+                job.len = hr.payload[2];
+            }
+            (ValidOps::Verify, 4) => {
+                job.len = hr.payload[2];
+                job.expected = hr.payload[3];
+            }
+            (ValidOps::Id | ValidOps::Erase | ValidOps::Write | ValidOps::Read | ValidOps::Verify, _) => {
+                return Err(BridgeError::BadArgCount)
+            }
+            _ => return Err(BridgeError::InvalidOperation),
+        }
+        let chip = job.op == ValidOps::Erase && job.len == 0;
+        if !chip && (job.len == 0 || job.addr as u64 + job.len as u64 > geometry.limit() as u64) {
+            return Err(BridgeError::OutOfRange)
+        }
+        if job.op == ValidOps::Read && job.len > 4 {
+            job.stream = Some(StreamOut::new(1));
+        }
+        match job.op {
+            ValidOps::Read | ValidOps::Verify => job.read_data(),
+            _ => job.command(Stage::WriteEnable, &[WRITE_ENABLE], 0),
+        }
+        Ok(job)
+    }
+
+    pub fn cs(&self) -> u8 {
+        self.cs
+    }
+
+    pub fn op(&self) -> ValidOps {
+        self.op
+    }
+
+    // The geometry of the chip, as learnt by flash id
+    pub fn geometry(&self) -> FlashGeometry {
+        self.geometry
+    }
+
+    // Bytes of a write the request takes from the host
+    pub fn input(&self) -> Option<u32> {
+        (self.op == ValidOps::Write).then_some(self.len)
+    }
+
+    fn command(&mut self, stage: Stage, out: &[u8], read_len: u32) {
+        self.stage = stage;
+        self.out[..out.len()].copy_from_slice(out);
+        self.out_len = out.len() as u32;
+        self.in_len = 0;
+        self.read_len = read_len;
+        self.sent = 0;
+        self.received = 0;
+    }
+
+    fn read_data(&mut self) {
+        self.command(Stage::Read, &addressed(READ, self.addr), self.len);
+    }
+
+    // Bytes of the next page program, up to the end of their page
+    fn program_len(&self) -> u32 {
+        let addr = self.addr + self.programmed;
+        let page = self.geometry.page.max(1);
+        (self.len - self.programmed).min(page - addr % page).min(MAX_PROGRAM)
+    }
+
+    // Write enable was sent, the page program of the next bytes of the input or the erase follows
+    fn modify(&mut self) {
+        if self.op == ValidOps::Write {
+            self.chunk = self.program_len();
+            self.command(Stage::Modify, &addressed(PAGE_PROGRAM, self.addr + self.programmed), 0);
+            self.in_len = self.chunk;
+        }
+        else if self.len == 0 {
+            self.command(Stage::Modify, &[CHIP_ERASE], 0);
+        }
+        else {
+            self.command(Stage::Modify, &addressed(self.opcode, self.addr), 0);
+        }
+    }
+
+    // A verify lists its progress on the console
+    fn listed(&self) -> bool {
+        self.stage == Stage::Read && self.op == ValidOps::Verify
+    }
+
+    fn total(&self) -> u32 {
+        self.out_len + self.in_len + self.read_len
+    }
+
+    // The next TX FIFO word, None once all bytes of the command are sent, while a progress line or a chunk of a read
+    // waits to be written out, or until the input holds the bytes of the next page program. A host that overran the
+    // input fails the write, nothing more is sent
+    pub fn pending(&mut self, input: &mut StreamIn) -> Option<u32> {
+        let read = self.sent.saturating_sub(self.out_len);
+        if self.sent == self.total() || self.listed() && read >= self.written + VERIFY_PROGRESS {
+            return None
+        }
+        if let (Some(stream), Stage::Read) = (&self.stream, self.stage) {
+            if read >= stream.limit() {
+                return None
+            }
+        }
+        if self.op == ValidOps::Write && self.stage == Stage::WriteEnable && self.sent == 0 {
+            if input.overrun() {
+                self.failed = Some(BridgeError::QueueFull);
+                self.command(Stage::WriteEnable, &[], 0);
+                return None
+            }
+            if (input.len() as u32) < self.program_len() {
+                return None
+            }
+        }
+        let byte = if self.sent < self.out_len {
+            self.out[self.sent as usize]
+        }
+        else if self.sent < self.out_len + self.in_len {
+            *self.next.get_or_insert_with(|| input.pop().unwrap_or(0xFF))
+        }
+        else {
+            0xFF
+        };
+        Some(self.config.tx_word(byte as u32))
+    }
+
+    pub fn sent(&mut self) {
+        self.sent = (self.sent + 1).min(self.total());
+        self.next = None;
+    }
+
+    // An RX FIFO word, one per byte sent
+    pub fn on_rx(&mut self, word: u32) {
+        if self.received == self.total() {
+            return
+        }
+        self.received += 1;
+        if self.received <= self.out_len + self.in_len {
+            return
+        }
+        let byte = self.config.rx_word(word) as u8;
+        let i = self.got() - 1;
+        if let Some(kept) = self.kept.get_mut(i as usize) {
+            *kept = byte;
+        }
+        match (self.op, self.stream.as_mut()) {
+            (ValidOps::Read, Some(stream)) => {
+                stream.push(&[byte]);
+                if i + 1 == self.read_len {
+                    stream.finish();
+                }
+            }
+            (ValidOps::Verify, _) => self.crc = crc32(self.crc, &[byte]),
+            _ => {}
+        }
+    }
+
+    // Bytes of the command read so far
+    fn got(&self) -> u32 {
+        self.received.saturating_sub(self.out_len + self.in_len)
+    }
+
+    // Write the progress of a verify. Returns false if there is none. Nothing more is sent until line_written
+    pub fn line<W: Write>(&self, out: &mut W) -> bool {
+        let end = (self.written + VERIFY_PROGRESS).min(self.read_len);
+        if !self.listed() || self.written == end || self.got() < end {
+            return false
+        }
+        let _ = write!(out, "Verified 0x{:06x} of 0x{:06x}\n\r", end, self.len);
+        true
+    }
+
+    pub fn line_written(&mut self) {
+        self.written = (self.written + VERIFY_PROGRESS).min(self.got());
+    }
+
+    // The chunk of a read to write out, nothing more is sent until chunk_written
+    pub fn chunk(&self) -> Option<&Chunk> {
+        self.stream.as_ref()?.chunk()
+    }
+
+    pub fn chunk_written(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.written();
+        }
+    }
+
+    // All bytes of the command are back, and those of a read written out or a verify listed
+    pub fn command_done(&self) -> bool {
+        let read = self.stage != Stage::Read || self.stream.iter().all(StreamOut::done);
+        self.received == self.total() && (!self.listed() || self.written == self.read_len) && read
+    }
+
+    // Nothing of the command was sent yet, the chip select is still to be asserted
+    pub fn starting(&self) -> bool {
+        self.sent == 0
+    }
+
+    fn dwords(&self, count: usize) -> impl Iterator<Item = u32> + '_ {
+        self.kept.chunks_exact(4).take(count).map(|dword| u32::from_le_bytes([dword[0], dword[1], dword[2], dword[3]]))
+    }
+
+    // Once a command is done, with the chip select released: the next command of the sequence, at now_us on any
+    // clock counting us. Console output goes to report
+    pub fn next<W: Write>(&mut self, now_us: u64, report: &mut W) -> FlashStep {
+        if let Some(err) = self.failed {
+            return FlashStep::Done(Err(err))
+        }
+        match self.stage {
+            Stage::ReadId => {
+                self.id = u32::from_be_bytes([0, self.kept[0], self.kept[1], self.kept[2]]);
+                self.command(Stage::SfdpHeader, &[READ_SFDP, 0, 0, 0, 0], 16);
+                FlashStep::Next
+            }
+            Stage::SfdpHeader => {
+                let _ = write!(report, "JEDEC ID 0x{:06x}\n\r", self.id);
+                let signature = self.dwords(1).next().unwrap_or(0);
+                // The first parameter header is the Basic Flash Parameter Table, ID 0x00, major revision 1
+                let [id, _, major, dwords] = [self.kept[8], self.kept[9], self.kept[10], self.kept[11]];
+                if signature != SFDP_SIGNATURE || id != 0 || major != 1 {
+                    let _ = write!(report, "No SFDP\n\r");
+                    return FlashStep::Done(Ok((self.id, 3)))
+                }
+                let dwords = (dwords as usize).min(BFPT_DWORDS) as u32;
+                let [pointer_low, pointer_mid, pointer_high] = [self.kept[12], self.kept[13], self.kept[14]];
+                self.command(Stage::Bfpt, &[READ_SFDP, pointer_high, pointer_mid, pointer_low, 0], 4 * dwords);
+                FlashStep::Next
+            }
+            Stage::Bfpt => {
+                let mut table = [0_u32; BFPT_DWORDS];
+                let count = (self.read_len / 4) as usize;
+                for (dword, value) in table.iter_mut().zip(self.dwords(count)) {
+                    *dword = value;
+                }
+                match FlashGeometry::from_bfpt(&table[..count]) {
+                    Some(geometry) => {
+                        self.geometry = geometry;
+                        let _ = write!(report, "{}\n\r", geometry);
+                    }
+                    None => {
+                        let _ = write!(report, "No Basic Flash Parameter Table\n\r");
+                    }
+                }
+                FlashStep::Done(Ok((self.id, 3)))
+            }
+            Stage::WriteEnable => {
+                self.modify();
+                FlashStep::Next
+            }
+            Stage::Modify => {
+                self.busy_us = Some(now_us);
+                self.reported_us = now_us;
+                self.command(Stage::Status, &[READ_STATUS], 1);
+                FlashStep::Next
+            }
+            Stage::Status => self.on_status(now_us, report),
+            Stage::Read => {
+                // The bytes packed, or the count of bytes streamed
+                if self.op == ValidOps::Read {
+                    if self.stream.is_some() {
+                        return FlashStep::Done(Ok((self.len, 4)))
+                    }
+                    let first = self.dwords(1).next().unwrap_or(0);
+                    return FlashStep::Done(Ok((first, self.len as u8)))
+                }
+                let _ = write!(report, "CRC-32 0x{:08x}\n\r", self.crc);
+                if self.crc != self.expected {
+                    return FlashStep::Done(Err(BridgeError::VerifyMismatch))
+                }
+                FlashStep::Done(Ok((self.crc, 4)))
+            }
+        }
+    }
+
+    fn on_status<W: Write>(&mut self, now_us: u64, report: &mut W) -> FlashStep {
+        let busy_us = now_us.saturating_sub(self.busy_us.unwrap_or(now_us));
+        let (poll_us, timeout_us) = match (self.op, self.len) {
+            (ValidOps::Write, _) => (PROGRAM_POLL_US, PROGRAM_TIMEOUT_US),
+            (_, 0) => (CHIP_ERASE_POLL_US, CHIP_ERASE_TIMEOUT_US),
+            _ => (ERASE_POLL_US, ERASE_TIMEOUT_US),
+        };
+        if self.kept[0] & WIP != 0 {
+            if busy_us >= timeout_us {
+                return FlashStep::Done(Err(BridgeError::Timeout))
+            }
+            if now_us.saturating_sub(self.reported_us) >= BUSY_REPORT_US {
+                self.reported_us = now_us;
+                let doing = if self.op == ValidOps::Write { "Programming" } else { "Erasing" };
+                let _ = write!(report, "{}, {} s\n\r", doing, busy_us / 1_000_000);
+            }
+            // The chip select goes low again for the next status read
+            self.sent = 0;
+            self.received = 0;
+            return FlashStep::Wait(poll_us)
+        }
+        if self.op == ValidOps::Write {
+            self.programmed += self.chunk;
+            if self.programmed < self.len {
+                if self.programmed / VERIFY_PROGRESS != (self.programmed - self.chunk) / VERIFY_PROGRESS {
+                    let _ = write!(report, "Programmed 0x{:06x} of 0x{:06x}\n\r", self.programmed, self.len);
+                }
+                self.command(Stage::WriteEnable, &[WRITE_ENABLE], 0);
+                return FlashStep::Next
+            }
+            let _ = write!(report, "Programmed {} bytes at 0x{:06x}\n\r", self.len, self.addr);
+        }
+        else {
+            let _ = write!(report, "Erased ");
+            if self.len == 0 {
+                let _ = write!(report, "chip");
+            }
+            else {
+                let _ = write_bytes(report, self.len);
+                let _ = write!(report, " at 0x{:06x}", self.addr);
+            }
+            let _ = write!(report, " in {} ms\n\r", busy_us / 1000);
+        }
+        FlashStep::Done(Ok((0, 0)))
+    }
+}
@@ -0,0 +1,46 @@
+//! Fixed size text buffer for formatting responses without an allocator
+
+use core::fmt;
+
+pub struct FmtBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Default for FmtBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FmtBuf<N> {
+    pub const fn new() -> FmtBuf<N> {
+        FmtBuf { buf: [0_u8; N], len: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever written through write_str, so always valid UTF-8
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> fmt::Write for FmtBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        // Check if there is space remaining (return error instead of panicing)
+        if N - self.len < bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+}
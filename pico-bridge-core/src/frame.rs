@@ -0,0 +1,325 @@
+// Binary framing for the USB serial port
+//
+// Request frame, before COBS encoding:
+//      Proc ID (1) | Interface (1) | Operation (1) | Length (1) | Payload (Length bytes) | CRC-16 (2)
+// Response frame, before COBS encoding:
+//      Proc ID (1) | Status (1) | Length (1) | Payload (Length bytes) | CRC-16 (2)
+// Status is 0 on success, otherwise a BridgeError code
+// Data frame, a chunk of the data a request streams ahead of its response, or takes from the host after it (see stream):
+//      Proc ID (1) | DATA_STATUS (1) | Length (1) | Seq (2) | Data (Length bytes) | CRC-16 (2)
+//
+// Payloads are 32 bit words sent little endian, so the request Length is a multiple of 4 up to 16.
+// The CRC-16 is CRC-16/CCITT-FALSE over every byte before it, sent little endian.
+// Frames are COBS encoded and terminated by a single 0x00 delimiter byte.
+//
+// The text console and binary mode share the port: BINARY_MODE_ENTER typed on the console switches
+// to binary mode, and a frame holding the single byte BINARY_MODE_EXIT switches back.
+use core::convert::TryFrom;
+
+use crate::protocol::ValidHostInterfaces;
+use crate::protocol::host::{HostRequest, Unclean, ValidInterfaces, ValidOps};
+use crate::error::{status_code, BridgeError};
+use crate::protocol::slave::{Ready, SlaveResponse};
+use crate::stream::{Chunk, CHUNK_LEN, DATA_STATUS};
+
+// Sent on the text console to switch the port to binary mode (ASCII STX)
+pub const BINARY_MODE_ENTER: u8 = 0x02;
+// Sent as the only byte of a frame to switch the port back to the text console (ASCII ETX)
+pub const BINARY_MODE_EXIT: u8 = 0x03;
+
+pub const FRAME_DELIMITER: u8 = 0x00;
+// Largest payload carried by a request frame, 4 words
+pub const MAX_PAYLOAD_BYTES: usize = 16;
+// Proc ID, Interface, Operation, Length, Payload and CRC
+pub const MAX_FRAME: usize = 4 + MAX_PAYLOAD_BYTES + 2;
+// COBS adds one byte per 254, plus the delimiter
+pub const MAX_ENCODED_FRAME: usize = MAX_FRAME + 1 + 1;
+
+// Result of feeding bytes of a binary frame into a FrameReader
+#[derive(Debug)]
+pub enum FrameEvent {
+    // A well formed request, not yet validated
+    Request(HostRequest<Unclean>),
+    // A frame that could not be turned into a request, answered with the status
+    Rejected { proc_id: u8, error: BridgeError },
+    // Data for the input of the request with its proc_id
+    Data(Data),
+    // The host asked to go back to the text console
+    ExitBinary,
+}
+
+// Accumulates bytes received on the serial port until a frame delimiter
+pub struct FrameReader {
+    buf: [u8; MAX_ENCODED_FRAME],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameReader {
+    pub const fn new() -> FrameReader {
+        FrameReader {
+            buf: [0_u8; MAX_ENCODED_FRAME],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    // Feed a single received byte, returns an event once a complete frame has been received
+    pub fn push(&mut self, byte: u8) -> Option<FrameEvent> {
+        if byte != FRAME_DELIMITER {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None
+        }
+        let len = self.len;
+        let overflow = self.overflow;
+        self.len = 0;
+        self.overflow = false;
+
+        if len == 0 {
+            // Back to back delimiters, nothing to do
+            return None
+        }
+        let mut frame = [0_u8; MAX_FRAME];
+        match cobs_decode(&self.buf[..len], &mut frame) {
+            Some(size) if !overflow => Some(decode_request(&frame[..size])),
+            _ => Some(FrameEvent::Rejected { proc_id: 0, error: BridgeError::InvalidFrame }),
+        }
+    }
+}
+
+// Decode an unstuffed request frame into a HostRequest, or the data frame in its place
+pub fn decode_request(frame: &[u8]) -> FrameEvent {
+    if frame == [BINARY_MODE_EXIT] {
+        return FrameEvent::ExitBinary
+    }
+    let proc_id = frame.first().copied().unwrap_or(0);
+    if frame.get(1) == Some(&DATA_STATUS) {
+        return match decode_data(frame) {
+            Ok(data) => FrameEvent::Data(data),
+            Err(error) => FrameEvent::Rejected { proc_id, error },
+        }
+    }
+    let invalid = FrameEvent::Rejected { proc_id, error: BridgeError::InvalidFrame };
+    if frame.len() < 6 {
+        return invalid
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return FrameEvent::Rejected { proc_id, error: BridgeError::ChecksumMismatch }
+    }
+    let length = body[3] as usize;
+    let data = &body[4..];
+    if length != data.len() || length & 0b11 != 0 || length > MAX_PAYLOAD_BYTES {
+        return invalid
+    }
+    let interface = match ValidInterfaces::try_from(body[1] as u16) {
+        Ok(interface) => interface,
+        _ => return FrameEvent::Rejected { proc_id, error: BridgeError::InvalidInterface },
+    };
+    let operation = match ValidOps::try_from(body[2] as u16) {
+        Ok(operation) => operation,
+        _ => return FrameEvent::Rejected { proc_id, error: BridgeError::InvalidOperation },
+    };
+
+    let mut payload = [0_u32; 4];
+    for (word, bytes) in payload.iter_mut().zip(data.chunks(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let mut hr = HostRequest::new();
+    hr.set_proc_id(proc_id);
+    hr.set_interface(interface);
+    hr.set_operation(operation);
+    hr.set_host_config(ValidHostInterfaces::SerialFramed);
+    hr.set_size((length / 4) as u8);
+    hr.set_payload(payload);
+    // The frame integrity is covered by the CRC
+    hr.set_checksum(hr.compute_checksum());
+    FrameEvent::Request(hr)
+}
+
+// Host side encoder for a request frame, returns the number of bytes written to out including the delimiter
+pub fn encode_request(proc_id: u8, interface: ValidInterfaces, operation: ValidOps, payload: &[u32],
+    out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    let words = payload.len().min(MAX_PAYLOAD_BYTES / 4);
+    let mut frame = [0_u8; MAX_FRAME];
+    frame[0] = proc_id;
+    frame[1] = interface as u8;
+    frame[2] = operation as u8;
+    frame[3] = (words * 4) as u8;
+    for (i, word) in payload.iter().take(words).enumerate() {
+        frame[4 + i * 4..8 + i * 4].copy_from_slice(&word.to_le_bytes());
+    }
+    finish_frame(&mut frame, 4 + words * 4, out)
+}
+
+// Encode a response frame, returns the number of bytes written to out including the delimiter
+pub fn encode_response(sr: &SlaveResponse<Ready>, out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    encode_status(sr.proc_id, sr.status, sr.payload, out)
+}
+
+// Encode a response frame for a request that never became a SlaveResponse, status is None on success
+pub fn encode_status(proc_id: u8, status: Option<BridgeError>, payload: u32, out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    let mut frame = [0_u8; MAX_FRAME];
+    frame[0] = proc_id;
+    frame[1] = status_code(status);
+    frame[2] = 4;
+    frame[3..7].copy_from_slice(&payload.to_le_bytes());
+    finish_frame(&mut frame, 7, out)
+}
+
+// A response frame as read by the host
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Response {
+    pub proc_id: u8,
+    pub status: Option<BridgeError>,
+    pub payload: u32,
+}
+
+// Host side decoder for an unstuffed response frame
+pub fn decode_response(frame: &[u8]) -> Result<Response, BridgeError> {
+    if frame.len() != 9 || frame[2] != 4 {
+        return Err(BridgeError::InvalidFrame)
+    }
+    let (body, crc) = frame.split_at(7);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(BridgeError::ChecksumMismatch)
+    }
+    let status = match body[1] {
+        0 => None,
+        code => Some(BridgeError::try_from(code).map_err(|_| BridgeError::InvalidFrame)?),
+    };
+    Ok(Response { proc_id: body[0], status, payload: u32::from_le_bytes([body[3], body[4], body[5], body[6]]) })
+}
+
+// Encode a data frame for a chunk of the data of the request with proc_id, on either side
+pub fn encode_data(proc_id: u8, chunk: &Chunk, out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    let mut frame = [0_u8; MAX_FRAME];
+    frame[0] = proc_id;
+    frame[1] = DATA_STATUS;
+    frame[2] = chunk.len;
+    frame[3..5].copy_from_slice(&chunk.seq.to_le_bytes());
+    frame[5..5 + chunk.len as usize].copy_from_slice(chunk.bytes());
+    finish_frame(&mut frame, 5 + chunk.len as usize, out)
+}
+
+// A data frame as read by the host, or by the firmware
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Data {
+    pub proc_id: u8,
+    pub seq: u16,
+    pub len: u8,
+    pub data: [u8; CHUNK_LEN],
+}
+
+impl Data {
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+// Decoder for an unstuffed data frame. A frame from the firmware with another status is a response
+pub fn decode_data(frame: &[u8]) -> Result<Data, BridgeError> {
+    if frame.len() < 7 || frame[1] != DATA_STATUS {
+        return Err(BridgeError::InvalidFrame)
+    }
+    let len = frame[2] as usize;
+    if len > CHUNK_LEN || frame.len() != 7 + len {
+        return Err(BridgeError::InvalidFrame)
+    }
+    let (body, crc) = frame.split_at(5 + len);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(BridgeError::ChecksumMismatch)
+    }
+    let mut data = [0_u8; CHUNK_LEN];
+    data[..len].copy_from_slice(&body[5..]);
+    Ok(Data { proc_id: body[0], seq: u16::from_le_bytes([body[3], body[4]]), len: len as u8, data })
+}
+
+// Append the CRC to the first len bytes of frame, then COBS encode and delimit it into out
+fn finish_frame(frame: &mut [u8; MAX_FRAME], len: usize, out: &mut [u8; MAX_ENCODED_FRAME]) -> usize {
+    let crc = crc16(&frame[..len]);
+    frame[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+    // A MAX_FRAME sized frame always fits the encoded buffer
+    let size = cobs_encode(&frame[..len + 2], &mut out[..MAX_ENCODED_FRAME - 1]).unwrap_or(0);
+    out[size] = FRAME_DELIMITER;
+    size + 1
+}
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, no reflection
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Consistent Overhead Byte Stuffing, removes every zero from src so 0x00 can delimit frames
+// Returns the encoded length, or None if dst is too small
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut code_index = 0;
+    let mut out = 1;
+    let mut code: u8 = 1;
+    for byte in src {
+        if *byte == 0 {
+            *dst.get_mut(code_index)? = code;
+            code_index = out;
+            out += 1;
+            code = 1;
+            continue;
+        }
+        *dst.get_mut(out)? = *byte;
+        out += 1;
+        code += 1;
+        if code == 0xFF {
+            *dst.get_mut(code_index)? = code;
+            code_index = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    *dst.get_mut(code_index)? = code;
+    Some(out)
+}
+
+// Reverse of cobs_encode, src must not include the delimiter
+// Returns the decoded length, or None if src is malformed or dst is too small
+pub fn cobs_decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut index = 0;
+    let mut out = 0;
+    while index < src.len() {
+        let code = src[index];
+        if code == 0 {
+            return None
+        }
+        index += 1;
+        for _ in 1..code {
+            let byte = *src.get(index)?;
+            if byte == 0 {
+                return None
+            }
+            *dst.get_mut(out)? = byte;
+            out += 1;
+            index += 1;
+        }
+        if code != 0xFF && index < src.len() {
+            *dst.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
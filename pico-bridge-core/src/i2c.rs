@@ -0,0 +1,586 @@
+// I2C master transfers
+//
+// The I2C state machine runs the PIO I2C program of the pico examples, changed to report the ACK bit of every
+// byte instead of stopping on a NAK. Each TX FIFO word is a 16 bit record in the upper half of the word:
+//
+// | 15:10 | 9      | 8:1  | 0   |
+// | Instr | Unused | Data | NAK |
+//
+// A record with Instr n > 0 has no data, the next n + 1 words hold an instruction each in their upper half, run
+// as they are reached. START, STOP and repeated START are made of these, and the IRQ that ends a transfer. Any
+// other record shifts out its 8 data bits, all ones on reads, and then its NAK bit, 0 to ACK a byte read.
+// Every byte pushes a 9 bit RX word, the 8 bits seen on SDA followed by the ACK bit, 0 when the byte was ACKed.
+use core::fmt::Write;
+
+use crate::error::BridgeError;
+use crate::protocol::host::{Clean, HostRequest, ValidInterfaces, ValidOps};
+use crate::smbus::{self, crc8, linear11, linear16, write_linear, MAX_BLOCK};
+use crate::stream::{Chunk, StreamOut};
+
+// PIO cycles per SCL period of the I2C program
+pub const I2C_CYCLES_PER_BIT: u32 = 32;
+pub const I2C_DEFAULT_HZ: u32 = 100_000;
+// Fast-mode Plus
+pub const I2C_MAX_HZ: u32 = 1_000_000;
+// Bytes written by one transfer, and read. Up to PACKED_READ bytes read are returned in the 4 byte response, longer
+// reads are streamed to the host and answered with their count
+pub const MAX_WRITE: usize = 8;
+pub const MAX_READ: usize = 0xFFFF;
+pub const PACKED_READ: usize = 4;
+// Addresses probed by a scan, the others are reserved
+pub const FIRST_SCAN_ADDR: u8 = 0x08;
+pub const LAST_SCAN_ADDR: u8 = 0x77;
+// SCL pulses of a bus recovery
+pub const RECOVERY_PULSES: usize = 9;
+
+// Instructions run from the TX FIFO: set pindirs of SDA, with SCL in the side set, [7] delay.
+// The pins are driven low when their pindir is 0, OE is inverted in the IO controls
+const SCL0_SDA0: u16 = 0xF780;
+const SCL0_SDA1: u16 = 0xF781;
+const SCL1_SDA0: u16 = 0xFF80;
+const SCL1_SDA1: u16 = 0xFF81;
+// irq nowait 0 rel, the state machine raises the flag of its own index when the transfer is done
+const IRQ_REL: u16 = 0xC010;
+// in pins, 1 samples SDA, in null, 8 pads the sample to the 9 bit autopush threshold
+const SAMPLE_SDA: u16 = 0x4001;
+const PAD_SAMPLE: u16 = 0x4068;
+
+const START: [u16; 2] = [SCL1_SDA0, SCL0_SDA0];
+const RESTART: [u16; 4] = [SCL0_SDA1, SCL1_SDA1, SCL1_SDA0, SCL0_SDA0];
+const STOP_IRQ: [u16; 4] = [SCL0_SDA0, SCL1_SDA0, SCL1_SDA1, IRQ_REL];
+
+// Bytes of the longest transfer, an SMBus block read: address, command code, address, count, data and PEC
+const MAX_BYTES: usize = 4 + MAX_BLOCK + 1;
+// Longest stream: START, the bytes with a repeated START and STOP
+const TRANSFER_WORDS: usize = 3 + MAX_BYTES + 5 + 5;
+// A recovery: SDA sampled before the pulses and after each, then STOP
+const RECOVERY_WORDS: usize = 3 + 5 * RECOVERY_PULSES + 5;
+const MAX_WORDS: usize = if TRANSFER_WORDS > RECOVERY_WORDS { TRANSFER_WORDS } else { RECOVERY_WORDS };
+
+// TX FIFO word of a record, the state machine takes the upper half
+pub fn tx_word(record: u16) -> u32 {
+    (record as u32) << 16
+}
+
+// One transfer: address with the bytes to write, then with a repeated START the bytes to read.
+// Without bytes to write or read only the address is sent, to probe it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct I2cTransfer {
+    words: [u16; MAX_WORDS],
+    len: usize,
+    // Next word for the TX FIFO
+    sent: usize,
+    // RX words expected, one per byte
+    bytes: u8,
+    // Bytes at the end of the transfer that are read
+    read_len: u8,
+    received: u8,
+    nack: bool,
+    // Every byte as seen on SDA, the addresses and bytes written included
+    seen: [u8; MAX_BYTES],
+    // SMBus PEC, written last or read last and checked
+    pec: bool,
+    // SMBus block read, the bytes read after the count byte are sent once it is received
+    block: bool,
+    count_pending: bool,
+    bad_count: bool,
+    // A recovery, and the SCL pulses it took once a sample saw SDA released
+    recovery: bool,
+    released: Option<u32>,
+    // A streamed read: its records go after the first split words as the stream has room for their bytes,
+    // more of them in all, asked of them so far
+    split: usize,
+    more: u32,
+    asked: u32,
+    stream: Option<StreamOut>,
+}
+
+impl I2cTransfer {
+    fn empty(pec: bool) -> I2cTransfer {
+        I2cTransfer {
+            words: [0; MAX_WORDS],
+            len: 0,
+            sent: 0,
+            bytes: 0,
+            read_len: 0,
+            received: 0,
+            nack: false,
+            seen: [0; MAX_BYTES],
+            pec,
+            block: false,
+            count_pending: false,
+            bad_count: false,
+            recovery: false,
+            released: None,
+            split: usize::MAX,
+            more: 0,
+            asked: 0,
+            stream: None,
+        }
+    }
+
+    pub fn new(addr: u8, write: &[u8], read_len: usize) -> Result<I2cTransfer, BridgeError> {
+        if addr > 0x7F || write.len() > MAX_WRITE || read_len > MAX_READ {
+            return Err(BridgeError::OutOfRange)
+        }
+        Ok(I2cTransfer::build(addr, write, read_len, false))
+    }
+
+    // The PEC of an SMBus write follows the bytes written, the PEC of a read follows the bytes read
+    pub(crate) fn build(addr: u8, write: &[u8], read_len: usize, pec: bool) -> I2cTransfer {
+        let mut transfer = I2cTransfer::empty(pec);
+        transfer.instructions(&START);
+        if !write.is_empty() || read_len == 0 {
+            transfer.byte(addr << 1, false);
+            for byte in write {
+                transfer.byte(*byte, false);
+            }
+            if pec && read_len == 0 {
+                transfer.byte(crc8(crc8(0, &[addr << 1]), write), false);
+            }
+            if read_len > 0 {
+                transfer.instructions(&RESTART);
+            }
+        }
+        if read_len > PACKED_READ {
+            transfer.byte(addr << 1 | 1, false);
+            transfer.split = transfer.len;
+            transfer.more = read_len as u32;
+            transfer.stream = Some(StreamOut::new(1));
+        }
+        else if read_len > 0 {
+            transfer.byte(addr << 1 | 1, false);
+            transfer.reads(read_len + pec as usize);
+        }
+        transfer.instructions(&STOP_IRQ);
+        transfer
+    }
+
+    // SMBus block read of a command code. Only the count byte is read until it tells how many follow
+    pub(crate) fn block_read(addr: u8, command: u8, pec: bool) -> I2cTransfer {
+        let mut transfer = I2cTransfer::empty(pec);
+        transfer.instructions(&START);
+        transfer.byte(addr << 1, false);
+        transfer.byte(command, false);
+        transfer.instructions(&RESTART);
+        transfer.byte(addr << 1 | 1, false);
+        transfer.byte(0xFF, true);
+        transfer.read_len = 1;
+        transfer.block = true;
+        transfer.count_pending = true;
+        transfer
+    }
+
+    // Pulse SCL with SDA released, sampling SDA before the first pulse and after each, then STOP.
+    // A device holding SDA low in the middle of a byte lets go within the pulses. Each pulse is sent once the
+    // sample before it saw SDA still low, the STOP follows the first sample that saw it released
+    pub fn recovery() -> I2cTransfer {
+        let mut transfer = I2cTransfer::empty(false);
+        transfer.recovery = true;
+        transfer.instructions(&[SAMPLE_SDA, PAD_SAMPLE]);
+        transfer.bytes = 1;
+        transfer
+    }
+
+    // Write and read requests, packed as the console parser does
+    pub fn from_request(hr: &HostRequest<Clean>) -> Result<I2cTransfer, BridgeError> {
+        let addr = hr.payload[0] as u8;
+        match hr.operation {
+            // Address, count and up to 8 bytes in two words, first byte in the low byte
+            ValidOps::Write => {
+                let bytes = unpack_bytes(hr);
+                let count = (hr.payload[1] as usize).min(MAX_WRITE);
+                I2cTransfer::new(addr, &bytes[..count], 0)
+            }
+            ValidOps::Read => I2cTransfer::new(addr, &[], hr.payload[1] as usize),
+            // Address, the register written first, and the count read
+            ValidOps::WriteRead => I2cTransfer::new(addr, &[hr.payload[1] as u8], hr.payload[2] as usize),
+            _ => Err(BridgeError::InvalidOperation),
+        }
+    }
+
+    fn push(&mut self, record: u16) {
+        self.words[self.len] = record;
+        self.len += 1;
+    }
+
+    fn instructions(&mut self, instructions: &[u16]) {
+        self.push(((instructions.len() - 1) as u16) << 10);
+        for instruction in instructions {
+            self.push(*instruction);
+        }
+    }
+
+    // A byte, ack when the master ACKs it, otherwise SDA is released for the device to ACK or for the NAK
+    // ending a read
+    fn byte(&mut self, data: u8, ack: bool) {
+        self.push((data as u16) << 1 | !ack as u16);
+        self.bytes += 1;
+    }
+
+    // Bytes read, ACKed but the last
+    fn reads(&mut self, count: usize) {
+        for i in 0..count {
+            self.byte(0xFF, i + 1 < count);
+        }
+        self.read_len += count as u8;
+    }
+
+    // Whether the next record is a streamed read
+    fn streaming(&self) -> bool {
+        self.sent == self.split && self.asked < self.more
+    }
+
+    // The next record for the TX FIFO, None once all are sent or while the bytes already asked for fill the stream.
+    // Once the device NAKed its address the bytes read are dropped
+    pub fn pending(&self) -> Option<u16> {
+        if self.streaming() {
+            let stream = self.stream.as_ref()?;
+            if !self.nack && self.asked >= stream.limit() {
+                return None
+            }
+            // ACKed but the last
+            return Some(0x1FE | (self.asked + 1 == self.more) as u16)
+        }
+        self.words[..self.len].get(self.sent).copied()
+    }
+
+    // The pending record went into the TX FIFO
+    pub fn sent(&mut self) {
+        if self.streaming() {
+            self.asked += 1;
+            return
+        }
+        self.sent = (self.sent + 1).min(self.len);
+    }
+
+    // The chunk of a streamed read to write out
+    pub fn chunk(&self) -> Option<&Chunk> {
+        self.stream.as_ref()?.chunk()
+    }
+
+    pub fn chunk_written(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.written();
+        }
+    }
+
+    // An RX word of the state machine, one per byte in the order they are sent
+    pub fn on_rx(&mut self, word: u32) {
+        let index = self.received;
+        if index >= self.bytes {
+            // The bytes of a streamed read come after the address
+            if let (Some(stream), false) = (self.stream.as_mut(), self.nack) {
+                if stream.len() < self.more {
+                    stream.push(&[(word >> 1) as u8]);
+                }
+                if stream.len() == self.more {
+                    stream.finish();
+                }
+            }
+            return
+        }
+        self.received += 1;
+        if self.recovery {
+            // The sample is shifted in first and ends up in the highest bit, after as many pulses as samples before it
+            let pulses = index as usize;
+            if word & 0x100 != 0 {
+                self.released = Some(pulses as u32);
+                self.instructions(&STOP_IRQ);
+            }
+            else if pulses < RECOVERY_PULSES {
+                self.instructions(&[SCL0_SDA1, SCL1_SDA1, SAMPLE_SDA, PAD_SAMPLE]);
+                self.bytes += 1;
+            }
+            else {
+                self.instructions(&STOP_IRQ);
+            }
+            return
+        }
+        self.seen[index as usize] = (word >> 1) as u8;
+        if index < self.bytes - self.read_len {
+            // The device ACKs its address and the bytes written to it
+            self.nack |= word & 1 != 0;
+        }
+        if self.count_pending && index + 1 == self.bytes {
+            // The state machine holds SCL low until the bytes after the count are sent. A count out of range
+            // still needs a byte read with a NAK to end the transfer
+            self.count_pending = false;
+            let count = self.seen[index as usize] as usize;
+            if (1..=MAX_BLOCK).contains(&count) {
+                self.reads(count + self.pec as usize);
+            }
+            else {
+                self.bad_count = true;
+                self.reads(1);
+            }
+            self.instructions(&STOP_IRQ);
+        }
+    }
+
+    // SCL pulses it took for SDA to be released, 0 if it was not held low, Timeout if it still is
+    fn recovery_result(&self) -> Result<(u32, u8), BridgeError> {
+        if !self.recovery || self.received < self.bytes {
+            return Err(BridgeError::NoResponse)
+        }
+        self.released.map(|pulses| (pulses, 1)).ok_or(BridgeError::Timeout)
+    }
+
+    // The data bytes read, without the count of a block read and the PEC
+    pub fn read_bytes(&self) -> &[u8] {
+        let end = self.bytes as usize - (self.pec && self.read_len > 0) as usize;
+        let start = (self.bytes - self.read_len) as usize + self.block as usize;
+        &self.seen[start.min(end)..end]
+    }
+
+    // Result once the state machine raised its IRQ: the bytes read, first in the low byte, and their count, or the
+    // count of a streamed read
+    pub fn result(&self) -> Result<(u32, u8), BridgeError> {
+        if self.nack {
+            return Err(BridgeError::Nack)
+        }
+        if self.received < self.bytes {
+            return Err(BridgeError::NoResponse)
+        }
+        if self.bad_count {
+            return Err(BridgeError::OutOfRange)
+        }
+        if let Some(stream) = self.stream {
+            if stream.len() < self.more {
+                return Err(BridgeError::NoResponse)
+            }
+            return Ok((self.more, 4))
+        }
+        // The PEC read covers the whole transfer, addresses included
+        if self.pec && self.read_len > 0 {
+            let (covered, pec) = self.seen[..self.bytes as usize].split_at(self.bytes as usize - 1);
+            if crc8(0, covered) != pec[0] {
+                return Err(BridgeError::PecMismatch)
+            }
+        }
+        let read = self.read_bytes();
+        let data = read.iter().take(4).rev().fold(0, |data, byte| data << 8 | *byte as u32);
+        Ok((data, read.len().min(4) as u8))
+    }
+}
+
+// Up to 8 bytes of a write, packed 4 to a word after the address and count
+pub(crate) fn unpack_bytes(hr: &HostRequest<Clean>) -> [u8; MAX_WRITE] {
+    let mut bytes = [0_u8; MAX_WRITE];
+    bytes[..4].copy_from_slice(&hr.payload[2].to_le_bytes());
+    bytes[4..].copy_from_slice(&hr.payload[3].to_le_bytes());
+    bytes
+}
+
+// What the firmware does once the state machine raised its IRQ
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum I2cStep {
+    // Send the records of the next transfer
+    Next,
+    // Finished, the value and its size in bytes for the SlaveResponse, or the error
+    Done(Result<(u32, u8), BridgeError>),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Kind {
+    Single,
+    // Probe every address, streaming a bitmap of those that ACK a byte at a time: bit n of byte k for address
+    // 8 * k + n, the reserved addresses 0. found holds the bits of the byte being probed
+    Scan { addr: u8, found: u8, count: u32, out: StreamOut },
+    Recovery,
+    // SMBus block read. More bytes than the response holds are streamed from those read, next is the first one not
+    // pushed to out yet
+    Block { out: StreamOut, next: usize },
+    // PMBus reading decoded on the console, LINEAR16 reads VOUT_MODE first
+    Linear11 { command: u8 },
+    Linear16 { addr: u8, command: u8, pec: bool, vout_mode: Option<u8> },
+}
+
+// An I2C request running on the state machine, made of one or more transfers run one after another
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct I2cJob {
+    kind: Kind,
+    transfer: I2cTransfer,
+    // Answer of the request once the last transfer ended, held back until the data it streams is written out
+    answer: Option<Result<(u32, u8), BridgeError>>,
+}
+
+impl I2cJob {
+    // SMBus transactions end with a PEC when pec is set
+    pub fn start(hr: &HostRequest<Clean>, pec: bool) -> Result<I2cJob, BridgeError> {
+        if hr.interface == ValidInterfaces::SMBus {
+            let kind = match hr.operation {
+                ValidOps::BlockRead => Kind::Block { out: StreamOut::new(1), next: 0 },
+                ValidOps::Linear11 => Kind::Linear11 { command: hr.payload[1] as u8 },
+                ValidOps::Linear16 => Kind::Linear16 {
+                    addr: hr.payload[0] as u8,
+                    command: hr.payload[1] as u8,
+                    pec,
+                    vout_mode: None,
+                },
+                _ => Kind::Single,
+            };
+            return Ok(I2cJob { kind, transfer: smbus::transfer(hr, pec)?, answer: None })
+        }
+        match hr.operation {
+            ValidOps::Scan => {
+                let mut out = StreamOut::new(1);
+                // Addresses 0 to 7 are reserved
+                out.push(&[0]);
+                Ok(I2cJob {
+                    kind: Kind::Scan { addr: FIRST_SCAN_ADDR, found: 0, count: 0, out },
+                    transfer: I2cTransfer::new(FIRST_SCAN_ADDR, &[], 0)?,
+                    answer: None,
+                })
+            }
+            ValidOps::Recover => Ok(I2cJob { kind: Kind::Recovery, transfer: I2cTransfer::recovery(), answer: None }),
+            _ => Ok(I2cJob { kind: Kind::Single, transfer: I2cTransfer::from_request(hr)?, answer: None }),
+        }
+    }
+
+    // The next record for the TX FIFO, None until the next transfer once all are sent. A scan probes no more
+    // addresses while its bitmap waits to be written out
+    pub fn pending(&self) -> Option<u16> {
+        if matches!(&self.kind, Kind::Scan { out, .. } if out.chunk().is_some()) {
+            return None
+        }
+        self.transfer.pending()
+    }
+
+    pub fn sent(&mut self) {
+        self.transfer.sent()
+    }
+
+    pub fn on_rx(&mut self, word: u32) {
+        self.transfer.on_rx(word)
+    }
+
+    // The chunk of data to write out to the host, nothing more is read until chunk_written
+    pub fn chunk(&self) -> Option<&Chunk> {
+        match &self.kind {
+            Kind::Scan { out, .. } | Kind::Block { out, .. } => out.chunk(),
+            _ => self.transfer.chunk(),
+        }
+    }
+
+    pub fn chunk_written(&mut self) {
+        match &mut self.kind {
+            Kind::Scan { out, .. } => out.written(),
+            Kind::Block { out, .. } => {
+                out.written();
+                self.fill_block();
+            }
+            _ => self.transfer.chunk_written(),
+        }
+    }
+
+    // Push the bytes of a long block read that fit the chunk being filled, the stream ends with the last one
+    fn fill_block(&mut self) {
+        if let Kind::Block { out, next } = &mut self.kind {
+            let read = self.transfer.read_bytes();
+            if read.len() <= PACKED_READ {
+                return
+            }
+            let end = read.len().min(*next + (out.limit() - out.len()) as usize);
+            out.push(&read[*next..end]);
+            *next = end;
+            if end == read.len() {
+                out.finish();
+            }
+        }
+    }
+
+    // The answer once on_irq was Done and the data streamed is written out
+    pub fn answer(&self) -> Option<Result<(u32, u8), BridgeError>> {
+        self.answer.filter(|_| self.chunk().is_none())
+    }
+
+    // The transfer running ended with the IRQ of the state machine. Lines for console hosts are written to report
+    pub fn on_irq<W: Write>(&mut self, report: &mut W) -> I2cStep {
+        let step = self.step(report);
+        if let I2cStep::Done(answer) = step {
+            self.answer = Some(answer);
+            if answer.is_ok() {
+                self.fill_block();
+            }
+        }
+        step
+    }
+
+    fn step<W: Write>(&mut self, report: &mut W) -> I2cStep {
+        let result = self.transfer.result();
+        match &mut self.kind {
+            Kind::Single => I2cStep::Done(result),
+            Kind::Scan { addr, found, count, out } => {
+                // Nothing at the address NAKs it
+                match result {
+                    Ok(_) => {
+                        *found |= 1 << (*addr % 8);
+                        *count += 1;
+                        let _ = write!(report, "I2C {:#04x}\n\r", addr);
+                    }
+                    Err(BridgeError::Nack) => {}
+                    Err(err) => return I2cStep::Done(Err(err)),
+                }
+                if *addr % 8 == 7 {
+                    out.push(&[*found]);
+                    *found = 0;
+                }
+                // The bitmap ends with the reserved addresses 0x78 to 0x7F, the number of devices found is returned
+                if *addr == LAST_SCAN_ADDR {
+                    out.push(&[0]);
+                    out.finish();
+                    return I2cStep::Done(Ok((*count, 1)))
+                }
+                *addr += 1;
+                match I2cTransfer::new(*addr, &[], 0) {
+                    Ok(transfer) => self.transfer = transfer,
+                    Err(err) => return I2cStep::Done(Err(err)),
+                }
+                I2cStep::Next
+            }
+            Kind::Recovery => I2cStep::Done(self.transfer.recovery_result()),
+            // Up to PACKED_READ bytes are returned, first byte in the low byte, the count of longer reads
+            Kind::Block { .. } => {
+                let count = self.transfer.read_bytes().len();
+                match result {
+                    Ok(_) if count > PACKED_READ => I2cStep::Done(Ok((count as u32, 4))),
+                    _ => I2cStep::Done(result),
+                }
+            }
+            Kind::Linear11 { command } => {
+                if let Ok((word, _)) = result {
+                    let (mantissa, exponent) = linear11(word as u16);
+                    let _ = write!(report, "{:#04x}: {:#06x} = ", command, word);
+                    let _ = write_linear(report, mantissa, exponent);
+                    let _ = write!(report, "\n\r");
+                }
+                I2cStep::Done(result)
+            }
+            Kind::Linear16 { addr, command, pec, vout_mode } => {
+                let (value, _) = match result {
+                    Ok(read) => read,
+                    Err(err) => return I2cStep::Done(Err(err)),
+                };
+                let mode = match vout_mode {
+                    Some(mode) => *mode,
+                    // VOUT_MODE was read, now the value
+                    None => {
+                        *vout_mode = Some(value as u8);
+                        self.transfer = I2cTransfer::build(*addr, &[*command], 2, *pec);
+                        return I2cStep::Next
+                    }
+                };
+                match linear16(value as u16, mode) {
+                    Ok((mantissa, exponent)) => {
+                        let _ = write!(report, "{:#04x}: {:#06x} = ", command, value);
+                        let _ = write_linear(report, mantissa, exponent);
+                        let _ = write!(report, " (VOUT_MODE {:#04x})\n\r", mode);
+                        I2cStep::Done(Ok((value, 2)))
+                    }
+                    Err(err) => I2cStep::Done(Err(err)),
+                }
+            }
+        }
+    }
+}
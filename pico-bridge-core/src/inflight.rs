@@ -0,0 +1,125 @@
+// Requests waiting on a state machine to answer
+//
+// Entries are keyed by the state machine running the request, the host it came from and the proc_id it
+// has there, so several interfaces and hosts can have requests outstanding at once. Each host numbers its
+// requests on its own, the same proc_id from two hosts are two requests. A state machine answers its requests in
+// the order they were sent to its TX FIFO, so a completion is matched to the oldest entry of that
+// state machine, while different state machines may complete in any order.
+use crate::error::BridgeError;
+use crate::protocol::slave::{NotReady, SlaveResponse};
+use crate::protocol::ValidHostInterfaces;
+
+// A PIO state machine: PIO block (0 or 1) and state machine index (0 to 3)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StateMachine {
+    pub pio: u8,
+    pub sm: u8,
+}
+
+impl StateMachine {
+    pub const fn new(pio: u8, sm: u8) -> StateMachine {
+        StateMachine { pio, sm }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    sm: StateMachine,
+    // Order the request was sent in, used to find the oldest entry of a state machine
+    seq: u32,
+    response: SlaveResponse<NotReady>,
+}
+
+impl Entry {
+    fn is(&self, sm: StateMachine, host: ValidHostInterfaces, proc_id: u8) -> bool {
+        self.sm == sm && self.response.host_config == host && self.response.proc_id == proc_id
+    }
+}
+
+// Fixed size table of SlaveResponses waiting on a state machine
+pub struct InFlight<const N: usize> {
+    entries: [Option<Entry>; N],
+    next_seq: u32,
+}
+
+impl<const N: usize> Default for InFlight<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> InFlight<N> {
+    const EMPTY: Option<Entry> = None;
+
+    pub const fn new() -> InFlight<N> {
+        InFlight {
+            entries: [Self::EMPTY; N],
+            next_seq: 0,
+        }
+    }
+
+    // Check a request can be tracked before it is sent to its state machine
+    pub fn check(&self, sm: StateMachine, host: ValidHostInterfaces, proc_id: u8) -> Result<(), BridgeError> {
+        if self.is_pending(sm, host, proc_id) {
+            return Err(BridgeError::DuplicateProcId)
+        }
+        if self.entries.iter().all(Option::is_some) {
+            return Err(BridgeError::QueueFull)
+        }
+        Ok(())
+    }
+
+    // Track a response until its state machine answers
+    pub fn insert(&mut self, sm: StateMachine, response: SlaveResponse<NotReady>) -> Result<(), BridgeError> {
+        self.check(sm, response.host_config, response.proc_id)?;
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        // check() made sure there is a free slot
+        if let Some(slot) = self.entries.iter_mut().find(|entry| entry.is_none()) {
+            *slot = Some(Entry { sm, seq, response });
+        }
+        Ok(())
+    }
+
+    // The state machine answered, take its oldest outstanding response
+    pub fn complete(&mut self, sm: StateMachine) -> Option<SlaveResponse<NotReady>> {
+        let index = self.oldest_index(sm)?;
+        self.entries[index].take().map(|entry| entry.response)
+    }
+
+    // The response the next answer of the state machine belongs to
+    pub fn oldest(&self, sm: StateMachine) -> Option<&SlaveResponse<NotReady>> {
+        let index = self.oldest_index(sm)?;
+        self.entries[index].as_ref().map(|entry| &entry.response)
+    }
+
+    fn oldest_index(&self, sm: StateMachine) -> Option<usize> {
+        let next_seq = self.next_seq;
+        self.entries.iter().enumerate()
+            .filter_map(|(index, entry)| entry.as_ref().filter(|entry| entry.sm == sm).map(|entry| (index, entry.seq)))
+            // Sequence numbers wrap, the oldest entry is the furthest behind next_seq
+            .max_by_key(|(_, seq)| next_seq.wrapping_sub(*seq))
+            .map(|(index, _)| index)
+    }
+
+    // Take a specific response, for state machines that report the proc_id or to give up on a request
+    pub fn take(&mut self, sm: StateMachine, host: ValidHostInterfaces, proc_id: u8) -> Option<SlaveResponse<NotReady>> {
+        self.entries.iter_mut()
+            .find(|entry| matches!(entry, Some(entry) if entry.is(sm, host, proc_id)))?
+            .take()
+            .map(|entry| entry.response)
+    }
+
+    pub fn is_pending(&self, sm: StateMachine, host: ValidHostInterfaces, proc_id: u8) -> bool {
+        self.entries.iter().flatten().any(|entry| entry.is(sm, host, proc_id))
+    }
+
+    // Number of outstanding responses
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
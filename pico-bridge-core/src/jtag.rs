@@ -0,0 +1,869 @@
+// JTAG master scans
+//
+// The JTAG state machine clocks one TCK per pair of bits of a TX FIFO word, LSB first: TDI in the low bit and TMS in
+// the high bit, set while TCK is low, and samples TDO on the rising edge. Each word holds 16 TCKs and each RX FIFO word
+// the 16 TDO bits sampled, in its high half. A request is a stream of TCKs: the TMS path from the state the TAP was
+// left in to Shift-IR or Shift-DR, the bits shifted, TMS high on the last, then the path to the end state, padded to
+// whole words with TCKs that keep the TAP in it. Raw TCKs carry the TMS bits given instead, their end state
+// followed from them.
+//
+// The TDI bits of a short scan come with the request, up to 3 words LSB first, the last bit repeated past them. Those
+// of a scan given no bits, or longer than the words hold, are streamed from the host past the bits given, a byte for
+// each 8 bits LSB first, and taken as the TCKs go out. The TDO bits of a scan longer than the response are streamed
+// to the host the same way.
+//
+// A chain scan is three shifts from Test-Logic-Reset: DR with ones, which brings out the IDCODE, or the BYPASS bit,
+// of each device nearest TDO first, IR with zeros, which brings out the IR capture bits, then IR with ones from
+// Pause-IR, where the zeros come out ahead of the first one after the total IR length. Without a device asked for,
+// the IDCODE and IR length of each device are streamed to the host as two 32 bit words while the devices are listed.
+use core::fmt::Write;
+
+use crate::error::BridgeError;
+use crate::protocol::host::{Clean, HostRequest, ValidOps};
+use crate::stream::{Chunk, StreamIn, StreamOut, CHUNK_LEN};
+
+// PIO cycles per TCK period
+pub const JTAG_CYCLES_PER_BIT: u32 = 4;
+pub const JTAG_DEFAULT_HZ: u32 = 1_000_000;
+// TCKs of a TX FIFO word
+pub const JTAG_WORD_BITS: u32 = 16;
+// Bits of a scan
+pub const MAX_SCAN_BITS: u32 = 0x10000;
+// TCKs of a raw TCK request, one TDO bit each in the response
+pub const MAX_TCKS: u32 = 32;
+// TDO bits kept, the last ones shifted out. A streamed shift clocks no more than half of them ahead of those written
+// out
+pub const KEPT_BITS: u32 = 512;
+// Bytes streamed for each device of a chain scan
+const DEVICE_BYTES: u32 = 8;
+// TDO bits of a chunk
+const CHUNK_BITS: u32 = 8 * CHUNK_LEN as u32;
+// Devices of a chain scan, their IDCODEs and the end marker fill the TDO bits kept
+pub const MAX_DEVICES: usize = 15;
+// Bits of the IR shifts of a chain scan
+const MAX_IR_BITS: u32 = 256;
+// TDI words of a scan, after the length
+const MAX_TDI: usize = 3;
+const MAX_TDI_BITS: u32 = 32 * MAX_TDI as u32;
+
+// JEDEC JEP106 manufacturers, by bank and ID without parity
+const MANUFACTURERS: [(u8, u8, &str); 13] = [
+    (0, 0x01, "AMD"),
+    (0, 0x09, "Intel"),
+    (0, 0x0E, "Freescale"),
+    (0, 0x15, "NXP"),
+    (0, 0x17, "Texas Instruments"),
+    (0, 0x1F, "Atmel"),
+    (0, 0x20, "STMicroelectronics"),
+    (0, 0x21, "Lattice"),
+    (0, 0x29, "Microchip"),
+    (0, 0x34, "Cypress"),
+    (0, 0x49, "Xilinx"),
+    (0, 0x6E, "Altera"),
+    (4, 0x3B, "ARM Ltd"),
+];
+
+// The manufacturer of an IDCODE, bits 11:8 are the JEP106 bank and bits 7:1 the ID in it
+pub fn manufacturer(idcode: u32) -> Option<&'static str> {
+    let (bank, id) = ((idcode >> 8 & 0xF) as u8, (idcode >> 1 & 0x7F) as u8);
+    MANUFACTURERS.iter().find(|entry| entry.0 == bank && entry.1 == id).map(|entry| entry.2)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDr,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIr,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    pub const ALL: [TapState; 16] = [
+        TapState::TestLogicReset, TapState::RunTestIdle,
+        TapState::SelectDr, TapState::CaptureDr, TapState::ShiftDr, TapState::Exit1Dr,
+        TapState::PauseDr, TapState::Exit2Dr, TapState::UpdateDr,
+        TapState::SelectIr, TapState::CaptureIr, TapState::ShiftIr, TapState::Exit1Ir,
+        TapState::PauseIr, TapState::Exit2Ir, TapState::UpdateIr,
+    ];
+
+    // The state after a TCK with TMS
+    pub fn next(self, tms: bool) -> TapState {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) | (RunTestIdle, false) | (UpdateDr, false) | (UpdateIr, false) => RunTestIdle,
+            (TestLogicReset, true) | (SelectIr, true) => TestLogicReset,
+            (RunTestIdle, true) | (UpdateDr, true) | (UpdateIr, true) => SelectDr,
+            (SelectDr, false) => CaptureDr,
+            (SelectDr, true) => SelectIr,
+            (CaptureDr, false) | (ShiftDr, false) | (Exit2Dr, false) => ShiftDr,
+            (CaptureDr, true) | (ShiftDr, true) => Exit1Dr,
+            (Exit1Dr, false) | (PauseDr, false) => PauseDr,
+            (Exit1Dr, true) | (Exit2Dr, true) => UpdateDr,
+            (PauseDr, true) => Exit2Dr,
+            (SelectIr, false) => CaptureIr,
+            (CaptureIr, false) | (ShiftIr, false) | (Exit2Ir, false) => ShiftIr,
+            (CaptureIr, true) | (ShiftIr, true) => Exit1Ir,
+            (Exit1Ir, false) | (PauseIr, false) => PauseIr,
+            (Exit1Ir, true) | (Exit2Ir, true) => UpdateIr,
+            (PauseIr, true) => Exit2Ir,
+        }
+    }
+
+    // The states the TAP stays in while TMS holds, TMS high in Test-Logic-Reset and low in the others
+    pub fn stable(self) -> bool {
+        matches!(self, TapState::TestLogicReset | TapState::RunTestIdle | TapState::ShiftDr | TapState::PauseDr
+            | TapState::ShiftIr | TapState::PauseIr)
+    }
+
+    pub fn hold_tms(self) -> bool {
+        self == TapState::TestLogicReset
+    }
+
+    // The states where TCKs that hold the TAP change nothing, unlike the shift states
+    pub fn idle(self) -> bool {
+        self.stable() && !matches!(self, TapState::ShiftDr | TapState::ShiftIr)
+    }
+
+    // The state after count TCKs with the TMS bits, LSB first. From an unknown state five TMS high reach
+    // Test-Logic-Reset
+    pub fn after(state: Option<TapState>, tms: u32, count: u32) -> Option<TapState> {
+        let mut high = 0;
+        (0..count).fold(state, |state, i| {
+            let tms = tms >> i & 1 != 0;
+            high = if tms { high + 1 } else { 0 };
+            match state {
+                Some(state) => Some(state.next(tms)),
+                None if high >= 5 => Some(TapState::TestLogicReset),
+                None => None,
+            }
+        })
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// A TMS sequence, first TCK in bit 0
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TmsPath {
+    pub bits: u16,
+    pub len: u8,
+}
+
+impl TmsPath {
+    pub const EMPTY: TmsPath = TmsPath { bits: 0, len: 0 };
+    // Five TCKs with TMS high reach Test-Logic-Reset from any state
+    pub const RESET: TmsPath = TmsPath { bits: 0x1F, len: 5 };
+
+    fn push(self, tms: bool) -> TmsPath {
+        TmsPath { bits: self.bits | (tms as u16) << self.len, len: self.len + 1 }
+    }
+
+    // The shortest TMS sequence from one state to another, by a breadth first search of the TAP state graph.
+    // From an unknown state the TAP is reset first
+    pub fn between(from: Option<TapState>, to: TapState) -> TmsPath {
+        let from = match from {
+            Some(from) => from,
+            None => return TmsPath::RESET.join(TmsPath::between(Some(TapState::TestLogicReset), to)),
+        };
+        let mut paths: [Option<TmsPath>; 16] = [None; 16];
+        let mut queue = [from; 16];
+        let (mut head, mut tail) = (0, 1);
+        paths[from.index()] = Some(TmsPath::EMPTY);
+        while head < tail {
+            let state = queue[head];
+            head += 1;
+            let path = paths[state.index()].unwrap_or(TmsPath::EMPTY);
+            if state == to {
+                return path
+            }
+            for tms in [false, true] {
+                let next = state.next(tms);
+                if paths[next.index()].is_none() {
+                    paths[next.index()] = Some(path.push(tms));
+                    queue[tail] = next;
+                    tail += 1;
+                }
+            }
+        }
+        TmsPath::EMPTY
+    }
+
+    pub fn join(self, other: TmsPath) -> TmsPath {
+        TmsPath { bits: self.bits | other.bits << self.len, len: self.len + other.len }
+    }
+
+    pub fn tms(&self, i: u32) -> bool {
+        self.bits >> i & 1 != 0
+    }
+}
+
+// A shift of TDI bits through Shift-IR or Shift-DR, or a reset, as a stream of TCKs. The TDO bits shifted out are
+// returned, those of shifts longer than 32 bits streamed a chunk at a time unless quiet
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Shift {
+    // TMS path to the shift state, or to the end state of a reset
+    enter: TmsPath,
+    // TMS path from Exit1, or from the state held, to the end state
+    leave: TmsPath,
+    end: TapState,
+    len: u32,
+    // Stable state the len TCKs are held in, instead of shifting
+    hold: Option<TapState>,
+    // TMS of each of the len TCKs, instead of high on the last
+    tms: Option<u32>,
+    // TDI bits of raw TCKs, LSB first. Those of a scan are given to pending_with
+    tdi: u32,
+    // TCKs of the request, padded to whole words
+    total: u32,
+    sent: u32,
+    received: u32,
+    // First TDO bits for the response
+    data: u32,
+    // The last TDO bits, and bits already written out
+    kept: [u32; (KEPT_BITS / 32) as usize],
+    written: u32,
+    quiet: bool,
+}
+
+impl Shift {
+    // Test-Logic-Reset with TMS high, then Run-Test/Idle
+    pub fn reset() -> Shift {
+        Shift::path(TmsPath::RESET.push(false), TapState::RunTestIdle)
+    }
+
+    // Follow a TMS path ending in end, a stable state
+    pub fn path(path: TmsPath, end: TapState) -> Shift {
+        Shift::new(path, TmsPath::EMPTY, end, 0)
+    }
+
+    // Go to run, a stable state, hold it for count TCKs, then go to end
+    pub fn run(state: Option<TapState>, run: TapState, count: u32, end: TapState) -> Result<Shift, BridgeError> {
+        if !run.stable() || !end.stable() {
+            return Err(BridgeError::OutOfRange)
+        }
+        let mut job = Shift::new(TmsPath::between(state, run), TmsPath::between(Some(run), end), end, count);
+        job.hold = Some(run);
+        Ok(job)
+    }
+
+    // Clock count TCKs, up to 32, with the TMS and TDI bits given, LSB first, then hold end
+    pub fn bits(count: u32, tms: u32, tdi: u32, end: TapState) -> Result<Shift, BridgeError> {
+        if !(1..=MAX_TCKS).contains(&count) {
+            return Err(BridgeError::OutOfRange)
+        }
+        let mut job = Shift::new(TmsPath::EMPTY, TmsPath::EMPTY, end, count);
+        job.tms = Some(tms);
+        job.tdi = tdi;
+        Ok(job)
+    }
+
+    // Shift len bits through shift, Shift-IR or Shift-DR, then go to end, a stable state. The TDI bits are given to
+    // pending_with
+    pub fn scan(state: Option<TapState>, shift: TapState, len: u32, end: TapState) -> Result<Shift, BridgeError> {
+        if !(1..=MAX_SCAN_BITS).contains(&len) || !end.stable() {
+            return Err(BridgeError::OutOfRange)
+        }
+        let exit = if shift == TapState::ShiftIr { TapState::Exit1Ir } else { TapState::Exit1Dr };
+        let enter = TmsPath::between(state, shift);
+        let leave = TmsPath::between(Some(exit), end);
+        Ok(Shift::new(enter, leave, end, len))
+    }
+
+    fn new(enter: TmsPath, leave: TmsPath, end: TapState, len: u32) -> Shift {
+        let tcks = enter.len as u32 + len + leave.len as u32;
+        Shift {
+            enter,
+            leave,
+            end,
+            len,
+            hold: None,
+            tms: None,
+            tdi: 0,
+            total: tcks.div_ceil(JTAG_WORD_BITS) * JTAG_WORD_BITS,
+            sent: 0,
+            received: 0,
+            data: 0,
+            kept: [0; (KEPT_BITS / 32) as usize],
+            written: 0,
+            quiet: false,
+        }
+    }
+
+    // Nothing streamed, all TDO bits of shifts up to KEPT_BITS are kept for tdo
+    pub fn quiet(mut self) -> Shift {
+        self.quiet = true;
+        self
+    }
+
+    // The state the TAP is left in
+    pub fn end_state(&self) -> TapState {
+        self.end
+    }
+
+    // The bits of the shift the next TX word takes TDI bits for, from the first up to the one past the last
+    fn tdi_needed(&self) -> (u32, u32) {
+        let start = self.enter.len as u32;
+        let bit = |tck: u32| tck.saturating_sub(start).min(self.len);
+        (bit(self.sent), bit(self.sent + JTAG_WORD_BITS))
+    }
+
+    // TDI and TMS of a TCK, tdi gives bit i of the shift
+    fn tck(&self, i: u32, tdi: &impl Fn(u32) -> bool) -> (bool, bool) {
+        let enter = self.enter.len as u32;
+        let leave = enter + self.len;
+        if i < enter {
+            (false, self.enter.tms(i))
+        }
+        else if i < leave {
+            match self.hold {
+                Some(run) => (false, run.hold_tms()),
+                // TMS high on the last bit moves to Exit1
+                None => (tdi(i - enter), self.tms.map_or(i == leave - 1, |tms| tms >> (i - enter) & 1 != 0)),
+            }
+        }
+        else if i < leave + self.leave.len as u32 {
+            (false, self.leave.tms(i - leave))
+        }
+        else {
+            (false, self.end.hold_tms())
+        }
+    }
+
+    // The TDO bits are streamed to the host
+    fn streamed(&self) -> bool {
+        self.len > 32 && !self.quiet && self.hold.is_none()
+    }
+
+    // The next TX FIFO word of TCKs that shift nothing or of raw TCKs, None once all are sent
+    pub fn pending(&self) -> Option<u32> {
+        self.pending_with(|i| self.tdi.checked_shr(i).unwrap_or(0) & 1 != 0)
+    }
+
+    // The next TX FIFO word, with bit i of the shift from tdi. None once all are sent or while the TDO bits clocked
+    // ahead wait to be written out
+    pub fn pending_with(&self, tdi: impl Fn(u32) -> bool) -> Option<u32> {
+        let start = self.enter.len as u32;
+        if self.sent == self.total || self.streamed() && self.sent >= start + self.written + KEPT_BITS / 2 {
+            return None
+        }
+        let mut word = 0;
+        for bit in 0..JTAG_WORD_BITS {
+            let (tdi, tms) = self.tck(self.sent + bit, &tdi);
+            word |= (tdi as u32 | (tms as u32) << 1) << (2 * bit);
+        }
+        Some(word)
+    }
+
+    pub fn sent(&mut self) {
+        self.sent = (self.sent + JTAG_WORD_BITS).min(self.total);
+    }
+
+    // An RX FIFO word, the TDO bits of a TX word in its high half
+    pub fn on_rx(&mut self, word: u32) {
+        self.on_rx_with(word, |_, _| {})
+    }
+
+    // An RX FIFO word, each TDO bit of the shift also given to tdo with its index
+    pub fn on_rx_with(&mut self, word: u32, mut tdo: impl FnMut(u32, bool)) {
+        if self.received == self.total {
+            return
+        }
+        let start = self.enter.len as u32;
+        for bit in 0..JTAG_WORD_BITS {
+            let i = self.received + bit;
+            if i < start || i >= start + self.len {
+                continue
+            }
+            let bit = word >> (16 + bit) & 1;
+            let j = i - start;
+            tdo(j, bit != 0);
+            if j < 32 {
+                self.data |= bit << j;
+            }
+            let slot = j % KEPT_BITS;
+            let mask = 1 << (slot % 32);
+            let kept = &mut self.kept[(slot / 32) as usize];
+            *kept = if bit != 0 { *kept | mask } else { *kept & !mask };
+        }
+        self.received += JTAG_WORD_BITS;
+    }
+
+    // TDO bit i of a quiet shift
+    pub fn tdo(&self, i: u32) -> bool {
+        let slot = i % KEPT_BITS;
+        self.kept[(slot / 32) as usize] >> (slot % 32) & 1 != 0
+    }
+
+    // TDO bits of the shift received so far
+    fn captured(&self) -> u32 {
+        self.received.saturating_sub(self.enter.len as u32).min(self.len)
+    }
+
+    // The chunk of TDO bits to write out once it is full, or the last one, a byte for each 8 bits LSB first. The
+    // consoles get it as 32 bit words. Nothing more is sent until chunk_written
+    pub fn chunk(&self) -> Option<Chunk> {
+        let end = (self.written + CHUNK_BITS).min(self.len);
+        if !self.streamed() || self.written == end || self.captured() < end {
+            return None
+        }
+        let len = (end - self.written).div_ceil(8);
+        let mut data = [0_u8; CHUNK_LEN];
+        for i in 0..end - self.written {
+            data[(i / 8) as usize] |= (self.tdo(self.written + i) as u8) << (i % 8);
+        }
+        Some(Chunk { seq: (self.written / CHUNK_BITS) as u16, offset: self.written / 8, width: 4, len: len as u8, data })
+    }
+
+    pub fn chunk_written(&mut self) {
+        self.written = (self.written + CHUNK_BITS).min(self.captured());
+    }
+
+    // All TCKs are back, and the TDO bits written out
+    pub fn done(&self) -> bool {
+        self.received == self.total && (!self.streamed() || self.written == self.len)
+    }
+
+    // The TDO bits and their size in bytes, or the count of bits streamed
+    pub fn result(&self) -> (u32, u8) {
+        if self.streamed() {
+            return (self.len, 4)
+        }
+        (self.data, self.len.div_ceil(8).min(4) as u8)
+    }
+}
+
+// What the firmware does once the TCKs of a shift are all back and its lines written out
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JtagStep {
+    // Send the TCKs of the next shift, or write out the next console line
+    Next,
+    // Finished, the value and its size in bytes for the SlaveResponse, or the error
+    Done(Result<(u32, u8), BridgeError>),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Stage {
+    Idcodes,
+    IrCapture,
+    IrLength,
+    // A console line per device, then the totals
+    Listing { listed: usize },
+}
+
+// The devices of a chain scan, nearest TDO first. An IDCODE of 0 is a device in BYPASS, an IR length of 0 one that
+// could not be told from the capture bits
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Chain {
+    stage: Stage,
+    // Device and field of the response, the totals without them
+    device: Option<u32>,
+    field: u32,
+    idcodes: [u32; MAX_DEVICES],
+    ir_len: [u16; MAX_DEVICES],
+    count: usize,
+    capture: [u32; (MAX_IR_BITS / 32) as usize],
+    total_ir: u32,
+    // IDCODE and IR length of each device, streamed while listing them for the totals
+    out: StreamOut,
+}
+
+impl Chain {
+    // TDI of the shift running: ones, which BYPASS brings out after the IDCODEs and leave every IR selecting BYPASS,
+    // and zeros while the IR capture bits come out
+    fn tdi(&self) -> bool {
+        self.stage != Stage::IrCapture
+    }
+
+    // The IDCODEs and BYPASS bits, up to the ones shifted in behind them
+    fn read_idcodes(&mut self, shift: &Shift) -> Result<(), BridgeError> {
+        let mut i = 0;
+        loop {
+            if i + 32 > KEPT_BITS {
+                return Err(BridgeError::NoResponse)
+            }
+            let idcode = if shift.tdo(i) {
+                (0..32).fold(0, |word, bit| word | (shift.tdo(i + bit) as u32) << bit)
+            }
+            else {
+                0
+            };
+            if idcode == u32::MAX {
+                break
+            }
+            // TDO stuck low reads as BYPASS devices without end
+            if self.count == MAX_DEVICES {
+                return Err(BridgeError::NoResponse)
+            }
+            self.idcodes[self.count] = idcode;
+            self.count += 1;
+            i += if idcode == 0 { 1 } else { 32 };
+        }
+        // TDO stuck high, or nothing connected
+        if self.count == 0 {
+            return Err(BridgeError::NoResponse)
+        }
+        Ok(())
+    }
+
+    fn capture_bit(&self, i: u32) -> bool {
+        i < self.total_ir && self.capture[(i / 32) as usize] >> (i % 32) & 1 != 0
+    }
+
+    // The total IR length is where the first one comes out. Each IR captures 01 in its low bits, when there are as
+    // many 10 boundaries as devices they give the IR length of each
+    fn read_ir_lengths(&mut self, shift: &Shift) -> Result<(), BridgeError> {
+        self.total_ir = (0..MAX_IR_BITS).find(|i| shift.tdo(*i)).ok_or(BridgeError::OutOfRange)?;
+        if self.total_ir < 2 * self.count as u32 {
+            return Err(BridgeError::OutOfRange)
+        }
+        let mut starts = [0_u32; MAX_DEVICES + 1];
+        let mut found = 0;
+        for i in 0..self.total_ir - 1 {
+            if self.capture_bit(i) && !self.capture_bit(i + 1) {
+                // More boundaries than devices leave the lengths unknown
+                if found == self.count {
+                    found += 1;
+                    break
+                }
+                starts[found] = i;
+                found += 1;
+            }
+        }
+        if found == self.count && starts[0] == 0 {
+            starts[found] = self.total_ir;
+            for device in 0..self.count {
+                self.ir_len[device] = (starts[device + 1] - starts[device]) as u16;
+            }
+        }
+        else if self.count == 1 {
+            self.ir_len[0] = self.total_ir as u16;
+        }
+        Ok(())
+    }
+
+    // Stream the devices up to the chunk limit
+    fn fill(&mut self) {
+        if self.device.is_some() {
+            return
+        }
+        let end = DEVICE_BYTES * self.count as u32;
+        while self.out.len() < end.min(self.out.limit()) {
+            let i = self.out.len();
+            let device = (i / DEVICE_BYTES) as usize;
+            let word = if i % DEVICE_BYTES < 4 { self.idcodes[device] } else { self.ir_len[device] as u32 };
+            self.out.push(&[(word >> (8 * (i % 4))) as u8]);
+        }
+        if self.out.len() == end {
+            self.out.finish();
+        }
+    }
+
+    fn line<W: Write>(&self, listed: usize, out: &mut W) {
+        if listed == self.count {
+            let _ = write!(out, "{} device{}, IR {} bits\n\r", self.count, if self.count == 1 { "" } else { "s" },
+                self.total_ir);
+            return
+        }
+        let idcode = self.idcodes[listed];
+        let _ = write!(out, "{}: ", listed);
+        if idcode == 0 {
+            let _ = write!(out, "BYPASS");
+        }
+        else {
+            let _ = write!(out, "{:#010x} ", idcode);
+            match manufacturer(idcode) {
+                Some(name) => { let _ = write!(out, "{}", name); }
+                None => { let _ = write!(out, "bank {} id {:#04x}", idcode >> 8 & 0xF, idcode >> 1 & 0x7F); }
+            }
+            let _ = write!(out, " part {:#06x} version {}", idcode >> 12 & 0xFFFF, idcode >> 28);
+        }
+        match self.ir_len[listed] {
+            0 => { let _ = write!(out, " IR ?\n\r"); }
+            len => { let _ = write!(out, " IR {}\n\r", len); }
+        }
+    }
+
+    fn result(&self) -> Result<(u32, u8), BridgeError> {
+        let device = match self.device {
+            Some(device) => device as usize,
+            // Device count in the low byte, total IR length above it
+            None => return Ok((self.count as u32 | self.total_ir << 8, 3)),
+        };
+        if device >= self.count {
+            return Err(BridgeError::NotFound)
+        }
+        match self.field {
+            0 => Ok((self.idcodes[device], 4)),
+            _ => Ok((self.ir_len[device] as u32, 2)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Kind {
+    Single,
+    // A scan of the TDI bits given, or streamed from the host
+    Scan,
+    Chain(Chain),
+    // Raw TCKs, and the state they leave the TAP in, None if still unknown
+    Bits(Option<TapState>),
+}
+
+// A JTAG request running on the state machine, made of one or more shifts run one after another
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JtagJob {
+    kind: Kind,
+    shift: Shift,
+    // TDI bits given with a scan, and whether the bits past them are streamed instead of the last one repeated
+    given: [u32; MAX_TDI],
+    given_bits: u32,
+    streamed: bool,
+    // TDI bits of a streamed scan, from bit tdi_from of the shift up to tdi_to
+    tdi: u32,
+    tdi_from: u32,
+    tdi_to: u32,
+    failed: Option<BridgeError>,
+}
+
+// A scan takes its TDI bits from the host when none are given or they are fewer than its length and the words hold
+pub fn streams_tdi(hr: &HostRequest<Clean>) -> bool {
+    matches!(hr.operation, ValidOps::ShiftIr | ValidOps::ShiftDr) && (hr.size == 1 || hr.payload[0] > MAX_TDI_BITS)
+}
+
+impl JtagJob {
+    // The request from the state the TAP is in, None while unknown. Scans end in Run-Test/Idle
+    pub fn start(hr: &HostRequest<Clean>, state: Option<TapState>) -> Result<JtagJob, BridgeError> {
+        let job = |kind, shift| JtagJob {
+            kind,
+            shift,
+            given: [0; MAX_TDI],
+            given_bits: 0,
+            streamed: false,
+            tdi: 0,
+            tdi_from: 0,
+            tdi_to: 0,
+            failed: None,
+        };
+        let shift = match (hr.operation, hr.size) {
+            (ValidOps::Reset, 0) => Shift::reset(),
+            (ValidOps::ShiftIr | ValidOps::ShiftDr, 1..=4) => {
+                let shift = if hr.operation == ValidOps::ShiftIr { TapState::ShiftIr } else { TapState::ShiftDr };
+                let mut job = job(Kind::Scan, Shift::scan(state, shift, hr.payload[0], TapState::RunTestIdle)?);
+                let words = hr.size as usize - 1;
+                job.given[..words].copy_from_slice(&hr.payload[1..=words]);
+                job.given_bits = 32 * words as u32;
+                job.streamed = streams_tdi(hr);
+                return Ok(job)
+            }
+            // Padding TCKs must not change anything, unless the TCKs fill whole words
+            (ValidOps::Tck, 3) => {
+                let (count, tms, tdi) = (hr.payload[0], hr.payload[1], hr.payload[2]);
+                let end = TapState::after(state, tms, count);
+                let padded = !count.is_multiple_of(JTAG_WORD_BITS);
+                if padded && !end.is_some_and(TapState::idle) {
+                    return Err(BridgeError::OutOfRange)
+                }
+                let shift = Shift::bits(count, tms, tdi, end.unwrap_or(TapState::TestLogicReset))?;
+                return Ok(job(Kind::Bits(end), shift))
+            }
+            // From Test-Logic-Reset, whatever state the TAP was left in
+            (ValidOps::Scan, 0..=2) => {
+                let chain = Chain {
+                    stage: Stage::Idcodes,
+                    device: (hr.size >= 1).then_some(hr.payload[0]),
+                    field: if hr.size == 2 { hr.payload[1] } else { 0 },
+                    idcodes: [0; MAX_DEVICES],
+                    ir_len: [0; MAX_DEVICES],
+                    count: 0,
+                    capture: [0; (MAX_IR_BITS / 32) as usize],
+                    total_ir: 0,
+                    out: StreamOut::new(4),
+                };
+                let shift = Shift::scan(None, TapState::ShiftDr, KEPT_BITS, TapState::RunTestIdle)?;
+                return Ok(job(Kind::Chain(chain), shift.quiet()))
+            }
+            (ValidOps::Reset | ValidOps::ShiftIr | ValidOps::ShiftDr | ValidOps::Scan | ValidOps::Tck, _) => {
+                return Err(BridgeError::BadArgCount)
+            }
+            _ => return Err(BridgeError::InvalidOperation),
+        };
+        Ok(job(Kind::Single, shift))
+    }
+
+    // Bytes of TDI bits the request takes from the host
+    pub fn input(&self) -> Option<u32> {
+        self.streamed.then(|| (self.shift.len - self.given_bits).div_ceil(8))
+    }
+
+    // Bit i of the TDI bits given
+    fn given_bit(&self, i: u32) -> bool {
+        self.given[(i / 32) as usize] >> (i % 32) & 1 != 0
+    }
+
+    // The next TX FIFO word, None once all are sent, while TDO bits wait to be written out or until the input has the
+    // TDI bits of the word. A host that overran the input fails the scan, its TCKs go on with TDI low so the TAP still
+    // ends in the state tracked
+    pub fn pending(&mut self, input: &mut StreamIn) -> Option<u32> {
+        match &self.kind {
+            Kind::Chain(chain) => {
+                let tdi = chain.tdi();
+                return self.shift.pending_with(|_| tdi)
+            }
+            Kind::Scan if !self.streamed => {
+                let last = self.given_bits - 1;
+                return self.shift.pending_with(|i| self.given_bit(i.min(last)))
+            }
+            Kind::Scan => {}
+            Kind::Single | Kind::Bits(_) => return self.shift.pending(),
+        }
+        if self.failed.is_none() && input.overrun() {
+            self.failed = Some(BridgeError::QueueFull);
+        }
+        if self.failed.is_some() {
+            return self.shift.pending_with(|_| false)
+        }
+        let (from, to) = self.shift.tdi_needed();
+        // Bytes of bits already sent are dropped
+        while self.tdi_from + 8 <= from {
+            self.tdi >>= 8;
+            self.tdi_from += 8;
+        }
+        while self.tdi_to < to {
+            // The bits given first, in whole words
+            let byte = match self.tdi_to < self.given_bits {
+                true => (self.given[(self.tdi_to / 32) as usize] >> (self.tdi_to % 32)) as u8,
+                false => input.pop()?,
+            };
+            self.tdi |= (byte as u32) << (self.tdi_to - self.tdi_from);
+            self.tdi_to += 8;
+        }
+        let (tdi, tdi_from) = (self.tdi, self.tdi_from);
+        self.shift.pending_with(|i| tdi >> (i - tdi_from) & 1 != 0)
+    }
+
+    pub fn sent(&mut self) {
+        self.shift.sent()
+    }
+
+    pub fn on_rx(&mut self, word: u32) {
+        self.shift.on_rx(word)
+    }
+
+    // The next console line, of a device found by a chain scan
+    pub fn line<W: Write>(&self, out: &mut W) -> bool {
+        match &self.kind {
+            Kind::Chain(chain) => match chain.stage {
+                Stage::Listing { listed } if listed <= chain.count => {
+                    chain.line(listed, out);
+                    true
+                }
+                _ => false,
+            },
+            Kind::Single | Kind::Scan | Kind::Bits(_) => false,
+        }
+    }
+
+    pub fn line_written(&mut self) {
+        if let Kind::Chain(Chain { stage: Stage::Listing { listed }, .. }) = &mut self.kind {
+            *listed += 1;
+        }
+    }
+
+    // The chunk of TDO bits of a scan, or of the devices of a chain scan, to write out, nothing more is sent until
+    // chunk_written
+    pub fn chunk(&self) -> Option<Chunk> {
+        match &self.kind {
+            Kind::Chain(chain) => chain.out.chunk().copied(),
+            _ => self.shift.chunk(),
+        }
+    }
+
+    pub fn chunk_written(&mut self) {
+        match &mut self.kind {
+            Kind::Chain(chain) => {
+                chain.out.written();
+                chain.fill();
+            }
+            _ => self.shift.chunk_written(),
+        }
+    }
+
+    // The shift running is over and its lines and chunks written out, time for on_done
+    pub fn done(&self) -> bool {
+        match &self.kind {
+            Kind::Chain(chain @ Chain { stage: Stage::Listing { listed }, count, .. }) => {
+                *listed > *count && (chain.device.is_some() || chain.out.done())
+            }
+            _ => self.shift.done(),
+        }
+    }
+
+    // Go on once done, with the next shift of a chain scan or the response
+    pub fn on_done(&mut self) -> JtagStep {
+        let chain = match &mut self.kind {
+            Kind::Single | Kind::Bits(_) => return JtagStep::Done(Ok(self.shift.result())),
+            Kind::Scan => return JtagStep::Done(self.failed.map_or(Ok(self.shift.result()), Err)),
+            Kind::Chain(chain) => chain,
+        };
+        let shift = match chain.stage {
+            Stage::Idcodes => {
+                if let Err(err) = chain.read_idcodes(&self.shift) {
+                    return JtagStep::Done(Err(err))
+                }
+                chain.stage = Stage::IrCapture;
+                // Zeros stop in Pause-IR, they never reach Update-IR
+                Shift::scan(Some(self.shift.end_state()), TapState::ShiftIr, MAX_IR_BITS, TapState::PauseIr)
+            }
+            Stage::IrCapture => {
+                for i in 0..MAX_IR_BITS {
+                    chain.capture[(i / 32) as usize] |= (self.shift.tdo(i) as u32) << (i % 32);
+                }
+                chain.stage = Stage::IrLength;
+                Shift::scan(Some(self.shift.end_state()), TapState::ShiftIr, MAX_IR_BITS, TapState::RunTestIdle)
+            }
+            Stage::IrLength => {
+                if let Err(err) = chain.read_ir_lengths(&self.shift) {
+                    return JtagStep::Done(Err(err))
+                }
+                chain.stage = Stage::Listing { listed: 0 };
+                chain.fill();
+                return JtagStep::Next
+            }
+            Stage::Listing { .. } => return JtagStep::Done(chain.result()),
+        };
+        match shift {
+            Ok(shift) => {
+                self.shift = shift.quiet();
+                JtagStep::Next
+            }
+            Err(err) => JtagStep::Done(Err(err)),
+        }
+    }
+
+    // The state the TAP is left in
+    pub fn end_state(&self) -> TapState {
+        self.shift.end_state()
+    }
+
+    // The state the TAP is left in, None when raw TCKs did not tell
+    pub fn state(&self) -> Option<TapState> {
+        match self.kind {
+            Kind::Bits(end) => end,
+            _ => Some(self.shift.end_state()),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+#![no_std]
+
+//! pico-bridge core protocol library
+//! HostRequest/SlaveResponse typestates, wire format encoders/decoders and the serial command parser
+//! shared by the pico-bridge firmware and host tooling. This crate has no HAL dependencies so it
+//! can be built and tested on the host:
+//!
+//! cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+pub mod error;
+pub mod fmt;
+pub mod protocol;
+pub mod cli;
+pub mod frame;
+pub mod stream;
+pub mod inflight;
+pub mod clock;
+pub mod smi;
+pub mod poll;
+pub mod c22;
+pub mod i2c;
+pub mod smbus;
+pub mod spi;
+pub mod flash;
+pub mod jtag;
+pub mod svf;
+pub mod bitbang;
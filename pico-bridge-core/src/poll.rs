@@ -0,0 +1,55 @@
+// Polling a register until a condition holds
+//
+// The firmware reads the register, gives the value and the time to check, and reads again after the delay
+// it is told, until the bits in mask equal expected or the timeout runs out. It does not depend on the
+// interface the register is read from.
+use crate::error::BridgeError;
+
+// Longest timeout, the elapsed time is returned in 16 bits of ms
+pub const MAX_TIMEOUT_MS: u32 = 0xFFFF;
+
+// What to do after checking a value
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PollStep {
+    // Read again after this many us
+    Again(u32),
+    // Finished, the value in the high half and the ms elapsed since the first read in the low half,
+    // or Timeout
+    Done(Result<u32, BridgeError>),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Poll {
+    mask: u16,
+    expected: u16,
+    timeout_us: u64,
+    interval_us: u32,
+    // Time of the first read
+    started_us: Option<u64>,
+}
+
+impl Poll {
+    pub fn new(mask: u16, expected: u16, timeout_ms: u32, interval_us: u32) -> Result<Poll, BridgeError> {
+        // A condition that can never hold would always time out
+        if timeout_ms > MAX_TIMEOUT_MS || expected & !mask != 0 {
+            return Err(BridgeError::OutOfRange)
+        }
+        Ok(Poll { mask, expected, timeout_us: timeout_ms as u64 * 1000, interval_us, started_us: None })
+    }
+
+    // Check a value read at now_us, on any clock counting us
+    pub fn check(&mut self, value: u16, now_us: u64) -> PollStep {
+        let started_us = *self.started_us.get_or_insert(now_us);
+        let elapsed_us = now_us.saturating_sub(started_us);
+        if value & self.mask == self.expected {
+            let elapsed_ms = (elapsed_us / 1000).min(MAX_TIMEOUT_MS as u64) as u32;
+            return PollStep::Done(Ok((value as u32) << 16 | elapsed_ms))
+        }
+        if elapsed_us >= self.timeout_us {
+            return PollStep::Done(Err(BridgeError::Timeout))
+        }
+        // The last read is at the timeout, not an interval past it
+        let left_us = self.timeout_us - elapsed_us;
+        PollStep::Again((self.interval_us as u64).min(left_us) as u32)
+    }
+}
@@ -0,0 +1,958 @@
+// Check if this must implement send and sync
+    use core::result::Result;
+
+    use crate::error::BridgeError;
+    use self::slave::{SlaveResponse, NotReady};
+
+    pub trait Send{
+        fn exchange_for_slave_response(&mut self) -> Result<SlaveResponse<NotReady>, BridgeError> {
+            // Match on the device facing interface and send payload to its TX FIFO
+            // Return the constructed SlaveResponse
+            Ok (SlaveResponse::new())
+        }
+    }
+
+    // Host facing interfaces a response can be written back to.
+    // Implemented by the task that has access to all host facing interfaces
+    pub trait HostTransport {
+        // USB serial port, text console or binary mode frames
+        fn write_serial(&mut self, bytes: &[u8]) -> Result<(), BridgeError>;
+        // UART text console
+        fn write_uart(&mut self, bytes: &[u8]) -> Result<(), BridgeError>;
+        // The SPI slave can not initiate a transfer, the frame is shifted out on the next one from the master
+        fn queue_spi(&mut self, frame: [u8; 18]) -> Result<(), BridgeError>;
+    }
+
+    // Intermediate output of a request that takes a while, like the PHYs found by a scan.
+    // Only console hosts get it, the others just get the final response
+    pub fn write_report<H: HostTransport>(host: &mut H, host_config: ValidHostInterfaces, text: &[u8]) -> Result<(), BridgeError> {
+        match host_config {
+            ValidHostInterfaces::Serial => host.write_serial(text),
+            ValidHostInterfaces::UART => host.write_uart(text),
+            _ => Ok(()),
+        }
+    }
+
+    pub trait Respond {
+        // Match on host_interface and send payload back on that channel
+        fn respond_to_host<H: HostTransport>(&self, host: &mut H) -> Result<(), BridgeError>;
+    }
+    
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum ValidHostInterfaces {
+        Serial = 0b00,
+        UART = 0b01,
+        SPI = 0b10,
+        None = 0b11,
+        // COBS/CRC-16 framed binary mode on the USB serial port
+        SerialFramed = 0b100,
+    }
+
+pub mod host {
+    use super::{checksum, combine_u16_to_u32, combine_u8_to_u32, encode_mmd, encode_smi, encode_smi45, header_byte, Clause45Op};
+    use core::{marker::PhantomData};
+    use core::convert::TryFrom;
+    use super::Send;
+    use super::{BridgeError, SlaveResponse, ValidHostInterfaces};
+    use crate::i2c::{MAX_READ, MAX_WRITE};
+    use crate::poll::Poll;
+    use crate::spi::{SpiConfig, MAX_CS, MAX_WORDS, SPI_HOLD};
+    use crate::flash::MAX_FLASH_SIZE;
+    use crate::jtag::{MAX_DEVICES, MAX_SCAN_BITS, MAX_TCKS};
+    use crate::smi::PHY_REGISTERS;
+
+    // State of the request
+    pub trait State {}
+    // request has not been validated
+    #[derive(Debug)]
+    pub struct Unclean {
+        __private: (),
+    }
+
+    // The request has been validated
+    #[derive(Debug)]
+    pub struct Clean {
+        __private: (),
+    }
+
+    impl State for Unclean {}
+    impl State for Clean {}
+
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum ValidOps  {
+        None,
+        Read,
+        Write, 
+        SmiSet,
+        SmiGet,
+        ReadInc,   // Clause 45 post-read-increment-address
+        Scan,
+        Dump,
+        Diff,
+        Rmw,       // Read-modify-write with a mask
+        SetBits,
+        ClrBits,
+        Poll,      // Read until a masked value matches or a timeout
+        Decode,    // Read with the fields of a Clause 22 register listed on the console
+        PagedRead,
+        PagedWrite,
+        PageSel,   // Set the page select register of a PHY
+        WriteRead, // Write, then read after a repeated START
+        I2cSet,
+        Recover,   // Free a bus held by a device
+        SendByte,
+        ReadByte,
+        WriteByte,
+        ReadWord,
+        WriteWord,
+        BlockRead,
+        BlockWrite,
+        ProcCall,  // Write a word and read one back
+        Linear11,  // Read word decoded as PMBus LINEAR11 on the console
+        Linear16,
+        PecSet,    // Enable SMBus Packet Error Codes
+        Transfer,  // Full-duplex SPI transfer
+        SpiMode,   // SPI mode, word width and bit order
+        SpiSet,
+        Id,        // Flash JEDEC ID and SFDP geometry
+        Erase,
+        Verify,    // Compare the CRC-32 of a flash range
+        Reset,     // JTAG TAP reset
+        ShiftIr,
+        ShiftDr,
+        Svf,       // Play SVF streamed from the host
+        Tck,       // Raw TCKs with the TMS and TDI bits given
+    }
+
+    impl TryFrom<u16> for ValidOps {
+        type Error = ();
+    
+        fn try_from(num: u16) -> Result<Self, Self::Error> {
+            match num {
+                0 => Ok(ValidOps::None),
+                1 => Ok(ValidOps::Read),
+                2 => Ok(ValidOps::Write),
+                3 => Ok(ValidOps::SmiSet),
+                4 =>  Ok(ValidOps::SmiGet),
+                5 => Ok(ValidOps::ReadInc),
+                6 => Ok(ValidOps::Scan),
+                7 => Ok(ValidOps::Dump),
+                8 => Ok(ValidOps::Diff),
+                9 => Ok(ValidOps::Rmw),
+                10 => Ok(ValidOps::SetBits),
+                11 => Ok(ValidOps::ClrBits),
+                12 => Ok(ValidOps::Poll),
+                13 => Ok(ValidOps::Decode),
+                14 => Ok(ValidOps::PagedRead),
+                15 => Ok(ValidOps::PagedWrite),
+                16 => Ok(ValidOps::PageSel),
+                17 => Ok(ValidOps::WriteRead),
+                18 => Ok(ValidOps::I2cSet),
+                19 => Ok(ValidOps::Recover),
+                20 => Ok(ValidOps::SendByte),
+                21 => Ok(ValidOps::ReadByte),
+                22 => Ok(ValidOps::WriteByte),
+                23 => Ok(ValidOps::ReadWord),
+                24 => Ok(ValidOps::WriteWord),
+                25 => Ok(ValidOps::BlockRead),
+                26 => Ok(ValidOps::BlockWrite),
+                27 => Ok(ValidOps::ProcCall),
+                28 => Ok(ValidOps::Linear11),
+                29 => Ok(ValidOps::Linear16),
+                30 => Ok(ValidOps::PecSet),
+                31 => Ok(ValidOps::Transfer),
+                32 => Ok(ValidOps::SpiMode),
+                33 => Ok(ValidOps::SpiSet),
+                34 => Ok(ValidOps::Id),
+                35 => Ok(ValidOps::Erase),
+                36 => Ok(ValidOps::Verify),
+                37 => Ok(ValidOps::Reset),
+                38 => Ok(ValidOps::ShiftIr),
+                39 => Ok(ValidOps::ShiftDr),
+                40 => Ok(ValidOps::Svf),
+                41 => Ok(ValidOps::Tck),
+                // ... add more variants here
+                _ => Err(()),
+            }
+        }
+    }
+    
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum ValidInterfaces  {
+        None,
+        SMI,
+        JTAG, 
+        I2C,
+        SPI,
+        Config,
+        GPIO,
+        SMI45,      // Clause 45 MDIO frames on the SMI state machine
+        MMD,        // Clause 45 MMD registers through the Clause 22 registers 13/14
+        SMBus,      // SMBus and PMBus transactions on the I2C state machine
+        Flash,      // SPI NOR flash on the SPI master
+    }
+
+    impl TryFrom<u16> for ValidInterfaces {
+        type Error = ();
+    
+        fn try_from(num: u16) -> Result<Self, Self::Error> {
+            match num {
+                0 => Ok(ValidInterfaces::None),
+                1 => Ok(ValidInterfaces::SMI),
+                2 => Ok(ValidInterfaces::JTAG),
+                3 => Ok(ValidInterfaces::I2C),
+                4 => Ok(ValidInterfaces::SPI),
+                5 => Ok(ValidInterfaces::Config),
+                6 => Ok(ValidInterfaces::GPIO),
+                7 => Ok(ValidInterfaces::SMI45),
+                8 => Ok(ValidInterfaces::MMD),
+                9 => Ok(ValidInterfaces::SMBus),
+                10 => Ok(ValidInterfaces::Flash),
+                // ... add more variants here
+                _ => Err(()),
+            }
+        }
+    }
+    #[derive(Debug)]
+    pub struct HostRequest<S: State> {
+        state: PhantomData<S>,
+        proc_id: u8,
+        pub  interface: ValidInterfaces,
+        host_config: ValidHostInterfaces,
+        pub operation: ValidOps,
+        checksum: u8,         // Wrapping checksum
+        pub size: u8,             // A value between 0 and 4
+        pub payload: [u32; 4],     // Max payload size over SPI is 4 bytes 
+
+    }
+
+    impl <S: State> HostRequest<S>{
+        fn transition<To: State>(self, _: To) -> Result<HostRequest<To>, BridgeError> {
+           Ok(HostRequest {
+                state: PhantomData,
+                proc_id: self.proc_id,
+                interface: self.interface,
+                host_config: self.host_config,
+                operation: self.operation,
+                checksum: self.checksum,
+                size: self.size,       
+                payload: self.payload,
+            })
+        }
+
+        pub fn proc_id(&self) -> u8 {
+            self.proc_id
+        }
+
+        pub fn host_config(&self) -> ValidHostInterfaces {
+            self.host_config
+        }
+
+        // The checksum the header and payload fields of this request should carry
+        pub fn compute_checksum(&self) -> u8 {
+            let header = header_byte(self.interface as u8, self.operation as u8, self.size);
+            checksum(header, &self.payload[..self.size as usize])
+        }
+    }
+    
+    impl Send for HostRequest<Clean> {
+        fn exchange_for_slave_response(&mut self) -> Result<super::slave::SlaveResponse<super::slave::NotReady>, BridgeError> {
+            let mut sr = SlaveResponse::new();
+            sr.set_host_config( self.host_config);
+            sr.set_proc_id(self.proc_id);
+            Ok(sr)
+        }
+    }
+    impl Default for HostRequest<Unclean> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl HostRequest<Unclean> {
+        pub fn new() -> HostRequest<Unclean> {
+            HostRequest {
+                state: PhantomData,
+                proc_id: 0_u8,
+                interface: ValidInterfaces::None,
+                host_config: ValidHostInterfaces::None,
+                operation: ValidOps::None,
+                checksum: 0_u8,
+                size: 0_u8,           
+                payload: [0_u32; 4],
+            }
+        }
+        pub fn set_proc_id(&mut self, proc_id: u8) {
+            self.proc_id =  proc_id;
+        }
+
+        pub fn set_operation(&mut self, op: ValidOps) {
+            self.operation =  op;
+        }
+
+        pub fn set_size(&mut self, size: u8) {
+            // Assert or log that data size too large
+            self.size = size.min(4);
+        }
+
+        pub fn set_host_config(&mut self, cfg: ValidHostInterfaces) {
+            self.host_config = cfg
+        }
+
+        pub fn set_payload(&mut self, payload: [u32; 4]) {
+            self.payload =  payload;
+        }
+
+        pub fn set_checksum(&mut self, checksum: u8) {
+            self.checksum =  checksum;
+        }
+
+        pub fn set_interface(&mut self, interface: ValidInterfaces) {
+            self.interface = interface;
+        }
+
+        pub fn build_from_16bit_spi(mut self, buf: &[u16]) -> Result<HostRequest<Clean>, BridgeError> {
+            // Interface first 3 bits of Packet 1
+            let interface = (buf[0] >> 13) & 0b111;
+            match ValidInterfaces::try_from(interface) {
+                Ok(interface) => {
+                    self.set_interface(interface);
+                }
+                _ => {
+                    return Err(BridgeError::InvalidInterface);
+                }
+            }
+            // Operation next 3 bits of Packet 1
+            let operation = (buf[0] >> 10) & 0b111;
+            match ValidOps::try_from(operation) {
+                Ok(op) => {
+                    self.set_operation(op);
+                }
+                _ => {
+                    return Err(BridgeError::InvalidOperation);
+                }
+            }
+    
+            // Default 1
+            let size = ((buf[0] >> 8) & 0b11) as u8 + 1;
+            let checksum = (buf[0] & 0xFF) as u8;
+            let payload = combine_u16_to_u32(&buf[1..]);
+
+            self.set_size(size);
+            self.set_host_config(ValidHostInterfaces::SPI);
+            self.set_payload(payload);
+            self.set_checksum(checksum);
+
+            self.init_clean()
+        }
+
+        pub fn build_from_8bit_spi(mut self, buf: &[u8]) -> Result<HostRequest<Clean>, BridgeError> {
+            // Interface first 3 bits of Packet 1
+            let interface = ((buf[0] >> 5) & 0b111) as u16;
+            match ValidInterfaces::try_from(interface) {
+                Ok(interface) => {
+                    self.set_interface(interface);
+                }
+                _ => {
+                    return Err(BridgeError::InvalidInterface);
+                }
+            }
+            // Operation next 3 bits of Packet 1
+            let operation = ((buf[0] >> 2) & 0b111) as u16;
+            match ValidOps::try_from(operation) {
+                Ok(op) => {
+                    self.set_operation(op);
+                }
+                _ => {
+                    return Err(BridgeError::InvalidOperation);
+                }
+            }
+    
+            // Default 1
+            let size = ((buf[0]) & 0b11) + 1;
+            let checksum = buf[1];
+            let payload = combine_u8_to_u32(&buf[2..]);
+
+            self.set_size(size);
+            self.set_host_config(ValidHostInterfaces::SPI);
+            self.set_payload(payload);
+            self.set_checksum(checksum);
+
+            self.init_clean()
+        }
+
+        // This will validate all the interface rules for our HostRequest
+        pub fn init_clean(mut self) -> Result<HostRequest<Clean>, BridgeError> {
+            // The checksum covers the fields as the host sent them, so it is verified before any
+            // interface rule rewrites the payload
+            if self.checksum != self.compute_checksum() {
+                return Err(BridgeError::ChecksumMismatch)
+            }
+
+            match self.interface {
+                ValidInterfaces::SMI => {
+                    // If it is SMI Read, we need PHY address and REG address
+                    if self.operation == ValidOps::Read {
+                        if self.size != 2 {return Err(BridgeError::BadArgCount)}
+                                                        // Opcode    PhyAddr               RegAddr
+                        self.payload[0] = encode_smi(true, self.payload[0] as u8, self.payload[1] as u8, 0_u16);
+                        self.size = 1;
+                    }
+                    // If it is SMI Write, we need PHY address and REG address + Data
+                    else if self.operation == ValidOps::Write {
+                        if self.size != 3 {return Err(BridgeError::BadArgCount)}
+                        self.payload[0] = encode_smi(false, self.payload[0] as u8, self.payload[1] as u8, self.payload[2] as u16);
+
+                        self.size = 1;
+                    }
+                    // Decode is a read that runs as a sequence in the firmware to report the fields
+                    else if self.operation == ValidOps::Decode {
+                        if self.size != 2 {return Err(BridgeError::BadArgCount)}
+                    }
+                    // Paged accesses take PHY address, page and register, plus the data of a write
+                    else if self.operation == ValidOps::PagedRead {
+                        if self.size != 3 {return Err(BridgeError::BadArgCount)}
+                    }
+                    else if self.operation == ValidOps::PagedWrite {
+                        if self.size != 4 {return Err(BridgeError::BadArgCount)}
+                    }
+                    // Scan runs as a sequence in the firmware, it takes no arguments
+                    else if self.operation == ValidOps::Scan {
+                        if self.size != 0 {return Err(BridgeError::BadArgCount)}
+                    }
+                    // Dump takes the PHY address, the first and last register and optionally a snapshot name
+                    else if self.operation == ValidOps::Dump {
+                        if self.size != 3 && self.size != 4 {return Err(BridgeError::BadArgCount)}
+                        let (start, end) = (self.payload[1], self.payload[2]);
+                        if start > end || end >= PHY_REGISTERS as u32 {return Err(BridgeError::OutOfRange)}
+                    }
+                    // Diff takes the snapshot name
+                    else if self.operation == ValidOps::Diff {
+                        if self.size != 1 {return Err(BridgeError::BadArgCount)}
+                    }
+                    // Read-modify-write takes PHY address, register, mask and value. Setting or clearing bits
+                    // is the same with the bits as mask, and rewritten to it here
+                    else if self.operation == ValidOps::Rmw {
+                        if self.size != 4 {return Err(BridgeError::BadArgCount)}
+                        if self.payload[2] > 0xFFFF || self.payload[3] > 0xFFFF {return Err(BridgeError::NumberTooLarge)}
+                    }
+                    else if self.operation == ValidOps::SetBits || self.operation == ValidOps::ClrBits {
+                        if self.size != 3 {return Err(BridgeError::BadArgCount)}
+                        if self.payload[2] > 0xFFFF {return Err(BridgeError::NumberTooLarge)}
+                        let bits = self.payload[2];
+                        self.payload[3] = if self.operation == ValidOps::SetBits {bits} else {0};
+                        self.size = 4;
+                    }
+                    // Poll takes PHY address and register, mask and expected value in the low and high half
+                    // of a word each, the timeout in ms and the interval between reads in us
+                    else if self.operation == ValidOps::Poll {
+                        if self.size != 4 {return Err(BridgeError::BadArgCount)}
+                        Poll::new(self.payload[1] as u16, (self.payload[1] >> 16) as u16, self.payload[2], self.payload[3])?;
+                    }
+                    else {
+                        return Err(BridgeError::InvalidOperation)
+                    }
+                }
+                // Clause 45 takes PHY address, MMD and register, plus the data of a write.
+                // Each is rewritten into the address frame followed by the access frame,
+                // post-read-increment only sends the address frame when given a register
+                ValidInterfaces::SMI45 => {
+                    let (phy, mmd) = (self.payload[0] as u8, self.payload[1] as u8);
+                    let address = encode_smi45(Clause45Op::Address, phy, mmd, self.payload[2] as u16);
+                    match (self.operation, self.size) {
+                        (ValidOps::Read, 3) => {
+                            self.payload = [address, encode_smi45(Clause45Op::Read, phy, mmd, 0), 0, 0];
+                            self.size = 2;
+                        }
+                        (ValidOps::Write, 4) => {
+                            let write = encode_smi45(Clause45Op::Write, phy, mmd, self.payload[3] as u16);
+                            self.payload = [address, write, 0, 0];
+                            self.size = 2;
+                        }
+                        (ValidOps::ReadInc, 3) => {
+                            self.payload = [address, encode_smi45(Clause45Op::PostReadIncrement, phy, mmd, 0), 0, 0];
+                            self.size = 2;
+                        }
+                        (ValidOps::ReadInc, 2) => {
+                            self.payload = [encode_smi45(Clause45Op::PostReadIncrement, phy, mmd, 0), 0, 0, 0];
+                            self.size = 1;
+                        }
+                        (ValidOps::Read | ValidOps::Write | ValidOps::ReadInc, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    }
+                }
+                // MMD takes PHY address, device address and register, plus the data of a write.
+                // Rewritten into the four Clause 22 frames of the register 13/14 sequence, the state machine
+                // runs them back to back
+                ValidInterfaces::MMD => {
+                    let data = match (self.operation, self.size) {
+                        (ValidOps::Read, 3) => None,
+                        (ValidOps::Write, 4) => Some(self.payload[3] as u16),
+                        (ValidOps::Read | ValidOps::Write, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    };
+                    self.payload = encode_mmd(self.payload[0] as u8, self.payload[1] as u8, self.payload[2] as u16, data);
+                    self.size = 4;
+                }
+                ValidInterfaces::Config if self.operation == ValidOps::SmiSet && self.size != 1 => {
+                    return Err(BridgeError::BadArgCount)
+                }
+                // PHY address and its page select register
+                ValidInterfaces::Config if self.operation == ValidOps::PageSel && self.size != 2 => {
+                    return Err(BridgeError::BadArgCount)
+                }
+                ValidInterfaces::Config if self.operation == ValidOps::I2cSet && self.size != 1 => {
+                    return Err(BridgeError::BadArgCount)
+                }
+                // Mode, word width in bits and LSB first
+                ValidInterfaces::Config if self.operation == ValidOps::SpiMode => {
+                    if !(2..=3).contains(&self.size) {return Err(BridgeError::BadArgCount)}
+                    SpiConfig::new(self.payload[0], self.payload[1], if self.size == 3 {self.payload[2]} else {0})?;
+                }
+                ValidInterfaces::Config if self.operation == ValidOps::SpiSet && self.size != 1 => {
+                    return Err(BridgeError::BadArgCount)
+                }
+                ValidInterfaces::Config if self.operation == ValidOps::PecSet => {
+                    if self.size != 1 {return Err(BridgeError::BadArgCount)}
+                    if self.payload[0] > 1 {return Err(BridgeError::OutOfRange)}
+                }
+                // Writes take the address, the count and up to 8 bytes packed 4 to a word, reads the address and
+                // count, write-reads the address, the register written and the count read
+                ValidInterfaces::I2C => {
+                    let read_len = match (self.operation, self.size) {
+                        (ValidOps::Write, 4) => {
+                            if self.payload[1] as usize > MAX_WRITE {return Err(BridgeError::OutOfRange)}
+                            None
+                        }
+                        (ValidOps::Read, 2) => Some(self.payload[1]),
+                        (ValidOps::WriteRead, 3) => {
+                            if self.payload[1] > 0xFF {return Err(BridgeError::NumberTooLarge)}
+                            Some(self.payload[2])
+                        }
+                        (ValidOps::Scan | ValidOps::Recover, 0) => None,
+                        (ValidOps::Write | ValidOps::Read | ValidOps::WriteRead | ValidOps::Scan | ValidOps::Recover, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    };
+                    if self.payload[0] > 0x7F || matches!(read_len, Some(n) if n == 0 || n as usize > MAX_READ) {
+                        return Err(BridgeError::OutOfRange)
+                    }
+                }
+
+                // SMBus transactions take the address and command code, plus the byte or word written. Send byte
+                // takes the byte in place of the command code. Block writes are packed as I2C writes, the command
+                // code first
+                ValidInterfaces::SMBus => {
+                    let max = match (self.operation, self.size) {
+                        (ValidOps::SendByte | ValidOps::ReadByte | ValidOps::ReadWord | ValidOps::BlockRead
+                            | ValidOps::Linear11 | ValidOps::Linear16, 2) => 0xFF,
+                        (ValidOps::WriteByte, 3) => {
+                            if self.payload[2] > 0xFF {return Err(BridgeError::NumberTooLarge)}
+                            0xFF
+                        }
+                        (ValidOps::WriteWord | ValidOps::ProcCall, 3) => {
+                            if self.payload[2] > 0xFFFF {return Err(BridgeError::NumberTooLarge)}
+                            0xFF
+                        }
+                        (ValidOps::BlockWrite, 4) => {
+                            if self.payload[1] == 0 || self.payload[1] as usize > MAX_WRITE {
+                                return Err(BridgeError::OutOfRange)
+                            }
+                            MAX_WRITE as u32
+                        }
+                        (ValidOps::SendByte | ValidOps::ReadByte | ValidOps::ReadWord | ValidOps::BlockRead
+                            | ValidOps::Linear11 | ValidOps::Linear16 | ValidOps::WriteByte | ValidOps::WriteWord
+                            | ValidOps::ProcCall | ValidOps::BlockWrite, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    };
+                    if self.payload[0] > 0x7F {return Err(BridgeError::OutOfRange)}
+                    if self.payload[1] > max {return Err(BridgeError::NumberTooLarge)}
+                }
+
+                // Transfers take the chip select and the count of words streamed after the request, reads the chip
+                // select, the count and the word clocked out. The word is checked against the width once the request
+                // runs
+                ValidInterfaces::SPI => {
+                    match (self.operation, self.size) {
+                        (ValidOps::Transfer, 2) | (ValidOps::Read, 2..=3) => {}
+                        (ValidOps::Transfer | ValidOps::Read, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    }
+                    if (self.payload[0] & !SPI_HOLD) as usize >= MAX_CS {
+                        return Err(BridgeError::OutOfRange)
+                    }
+                    if !(1..=MAX_WORDS).contains(&self.payload[1]) {
+                        return Err(BridgeError::OutOfRange)
+                    }
+                }
+
+                // Flash requests take the chip select, then the address and length of reads, writes and verifies,
+                // with the CRC-32 expected by a verify, or the address and size of an erase, none for a chip erase.
+                // The bytes of a write are streamed from the host. The geometry of the chip is checked once the
+                // request runs
+                ValidInterfaces::Flash => {
+                    let (cs, end) = match (self.operation, self.size) {
+                        (ValidOps::Id | ValidOps::Erase, 1) => (self.payload[0], 0),
+                        (ValidOps::Read | ValidOps::Write | ValidOps::Erase, 3) | (ValidOps::Verify, 4) => {
+                            if self.payload[2] == 0 {return Err(BridgeError::OutOfRange)}
+                            (self.payload[0], self.payload[1] as u64 + self.payload[2] as u64)
+                        }
+                        (ValidOps::Id | ValidOps::Read | ValidOps::Erase | ValidOps::Write | ValidOps::Verify, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    };
+                    if cs as usize >= MAX_CS || end > MAX_FLASH_SIZE as u64 {
+                        return Err(BridgeError::OutOfRange)
+                    }
+                }
+
+                // Scans take the length in bits and up to 3 words of TDI bits, without them the TDI bits are
+                // streamed from the host.
+                // A chain scan takes the device nearest TDO counting from 0, and 0 for its IDCODE or 1 for its IR
+                // length. Raw TCKs take their count and a word each of TMS and TDI bits
+                ValidInterfaces::JTAG => {
+                    match (self.operation, self.size) {
+                        (ValidOps::Reset | ValidOps::Svf, 0) => {}
+                        (ValidOps::ShiftIr | ValidOps::ShiftDr, 1..=4) => {
+                            if !(1..=MAX_SCAN_BITS).contains(&self.payload[0]) {return Err(BridgeError::OutOfRange)}
+                        }
+                        (ValidOps::Tck, 3) => {
+                            if !(1..=MAX_TCKS).contains(&self.payload[0]) {return Err(BridgeError::OutOfRange)}
+                        }
+                        (ValidOps::Scan, 0..=2) => {
+                            if self.size >= 1 && self.payload[0] as usize >= MAX_DEVICES
+                                || self.size == 2 && self.payload[1] > 1 {
+                                return Err(BridgeError::OutOfRange)
+                            }
+                        }
+                        (ValidOps::Reset | ValidOps::ShiftIr | ValidOps::ShiftDr | ValidOps::Scan | ValidOps::Svf
+                            | ValidOps::Tck, _) => {
+                            return Err(BridgeError::BadArgCount)
+                        }
+                        _ => {
+                            return Err(BridgeError::InvalidOperation)
+                        }
+                    }
+                }
+
+                // So far, only support output High and Low
+                ValidInterfaces::GPIO if self.size != 1 => {
+                    return Err(BridgeError::BadArgCount)
+                }
+
+                ValidInterfaces::None => {
+                    return Err(BridgeError::InvalidInterface)
+                }
+                _ => {
+
+                }
+            }
+            self.transition(Clean {__private: () })
+        } 
+    }
+
+    // Host side encoder for the 8-bit SPI wire format decoded by build_from_8bit_spi
+    // Packet 1: Interface (3 bits) | Operation (3 bits) | Size - 1 (2 bits)
+    // Interfaces and operations numbered 8 and up do not fit the header, they are only reachable from the
+    // serial console, the UART and binary frames
+    // Packet 2: Checksum
+    // Packet 3..18: Payload words, little endian
+    pub fn encode_8bit_spi(interface: ValidInterfaces, operation: ValidOps, payload: &[u32]) -> [u8; 18] {
+        let size = payload.len().clamp(1, 4);
+        let mut buf = [0_u8; 18];
+        buf[0] = header_byte(interface as u8, operation as u8, size as u8);
+        buf[1] = checksum(buf[0], &payload[..size.min(payload.len())]);
+        for (i, word) in payload.iter().take(size).enumerate() {
+            buf[2 + i * 4..6 + i * 4].copy_from_slice(&word.to_le_bytes());
+        }
+        buf
+    }
+}
+
+pub mod slave {
+    use core::{marker::PhantomData};
+    use core::fmt::Write;
+    use crate::error::status_code;
+    use crate::fmt::FmtBuf;
+    use crate::frame::{encode_response, MAX_ENCODED_FRAME};
+    use super::{checksum, BridgeError, HostTransport, Respond, ValidHostInterfaces};
+
+        // State of the request
+    pub trait State {}
+    // The response is ready to go back to the host
+    pub struct Ready {
+        __private: (),
+    }
+    #[derive(Debug)]
+    // The response is not ready to send back to host
+    pub struct NotReady {
+        __private: (),
+    }
+
+    impl State for NotReady {}
+    impl State for Ready {}
+
+    #[derive(Debug)]
+    pub struct SlaveResponse<S: State> {
+        state: PhantomData<S>,
+        pub proc_id: u8,
+        pub host_config: ValidHostInterfaces,
+        pub status: Option<BridgeError>,   // None if the request succeeded
+        pub size: u8,             // Number of valid payload bytes, a value between 0 and 4
+        pub payload: u32,     // Max payload size over SPI is 4 bytes 
+    }
+
+    impl <S: State> SlaveResponse<S>{
+        fn transition<To: State>(self, _: To) -> Result<SlaveResponse<To>, BridgeError> {
+            Ok(SlaveResponse {
+                state: PhantomData,
+                proc_id: self.proc_id,
+                host_config: self.host_config,
+                status: self.status,
+                size: self.size,       
+                payload: self.payload,
+            })
+        }
+    }
+
+    impl SlaveResponse<Ready> {
+        // Console text for the response, the payload in hex (as wide as its size in bytes) or the error,
+        // followed by the prompt
+        pub fn format_text(&self) -> FmtBuf<48> {
+            let mut text = FmtBuf::new();
+            let _ = match self.status {
+                Some(err) => write!(text, "{}\n\r->", err),
+                None if self.size == 0 => write!(text, "OK\n\r->"),
+                None => write!(text, "{:#0width$x}\n\r->", self.payload, width = 2 + 2 * self.size.min(4) as usize),
+            };
+            text
+        }
+
+        // Encode the response in the 8-bit SPI wire format clocked out on MISO
+        // Packet 1: Proc ID
+        // Packet 2: Status
+        // Packet 3: Checksum over status and payload (same wrapping checksum as HostRequest)
+        // Packet 4..7: Payload, little endian
+        pub fn encode_8bit_spi(&self) -> [u8; 18] {
+            let mut buf = [0_u8; 18];
+            buf[0] = self.proc_id;
+            buf[1] = status_code(self.status);
+            buf[2] = checksum(buf[1], &[self.payload]);
+            buf[3..7].copy_from_slice(&self.payload.to_le_bytes());
+            buf
+        }
+    }
+
+    impl Respond for SlaveResponse<Ready> {
+        fn respond_to_host<H: HostTransport>(&self, host: &mut H) -> Result<(), BridgeError> {
+            match self.host_config {
+                ValidHostInterfaces::Serial => {
+                    host.write_serial(self.format_text().as_bytes())
+                }
+                ValidHostInterfaces::UART => {
+                    host.write_uart(self.format_text().as_bytes())
+                }
+                ValidHostInterfaces::SerialFramed => {
+                    let mut frame = [0_u8; MAX_ENCODED_FRAME];
+                    let len = encode_response(self, &mut frame);
+                    host.write_serial(&frame[..len])
+                }
+                ValidHostInterfaces::SPI => {
+                    host.queue_spi(self.encode_8bit_spi())
+                }
+                ValidHostInterfaces::None => {
+                    // Should never happen
+                    Err(BridgeError::NoResponse)
+                }
+            }
+        }
+    }
+
+    impl Default for SlaveResponse<NotReady> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl SlaveResponse<NotReady> {
+        pub fn new() -> SlaveResponse<NotReady> {
+            SlaveResponse { 
+                state: PhantomData,
+                proc_id: 0_u8,
+                host_config: ValidHostInterfaces::None,
+                status: None,
+                size: 0_u8,       
+                payload: 0,
+            }
+        }
+
+        pub fn set_error(&mut self, err: BridgeError) {
+            self.status = Some(err);
+        }
+
+        pub fn set_proc_id(&mut self, proc_id: u8) {
+            self.proc_id = proc_id;
+        }
+
+        pub fn set_host_config(&mut self, interface: ValidHostInterfaces) {
+            self.host_config = interface;
+        }
+
+        pub fn set_size(&mut self, size: u8) {
+            self.size = size;
+        }
+
+        pub fn set_payload(&mut self, payload: u32) {
+            self.payload = payload;
+        }
+    
+        pub fn init_ready(self) -> Result<SlaveResponse<Ready>, BridgeError> {
+            // if let valid_packet = checksum(self.checksum) ...
+            // This will validate all the interface rules for our HostRequest, 
+            // Any other kind of packet sanitizing
+            self.transition(Ready {__private: () })
+        }
+    }
+}
+
+// Header byte shared by the SPI wire formats
+// Interface (3 bits) | Operation (3 bits) | Size - 1 (2 bits)
+pub fn header_byte(interface: u8, operation: u8, size: u8) -> u8 {
+    ((interface & 0b111) << 5) | ((operation & 0b111) << 2) | (size.wrapping_sub(1) & 0b11)
+}
+
+// Wrapping checksum over the header byte and the little endian bytes of the payload words.
+// This is the two's complement of the wrapping sum, so header + payload + checksum sums to zero
+pub fn checksum(header: u8, payload: &[u32]) -> u8 {
+    let sum = payload.iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(header, |sum, byte| sum.wrapping_add(byte));
+    sum.wrapping_neg()
+}
+
+// Helper function that converts a list of u16 words into a payload of 4 32 bit words
+fn combine_u16_to_u32(values: &[u16]) -> [u32; 4] {
+    let mut result = [0u32; 4];
+
+    for (i, chunk) in values.chunks(2).enumerate() {
+        let combined = ((chunk[1] as u32) << 16) | chunk[0] as u32;
+        result[i] = combined;
+    }
+
+    result
+}
+
+fn combine_u8_to_u32(values: &[u8]) -> [u32; 4] {
+    let mut result = [0u32; 4];
+
+    for (i, chunk) in values.chunks(4).enumerate() {
+        let combined = ((chunk[3] as u32) << 24) | ((chunk[2] as u32) << 16) | ((chunk[1] as u32) << 8) | (chunk[0] as u32);
+        result[i] = combined;
+    }
+
+    result
+}
+
+
+// Start of frame of Clause 22 and Clause 45 MDIO frames
+const ST_CLAUSE22: u16 = 0b01;
+const ST_CLAUSE45: u16 = 0b00;
+
+// Clause 45 frame opcodes
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Clause45Op {
+    Address = 0b00,
+    Write = 0b01,
+    PostReadIncrement = 0b10,
+    Read = 0b11,
+}
+
+// Encode an MDIO frame into the word format the SMI PIO program shifts out (LSB first)
+//      Bit 0: Read, the state machine releases MDIO after the header and pushes the 16 bits it reads
+//      Bits 1-14: ST, OP, PHYAD and REGAD (or DEVAD), each MSB first
+//      Bits 15-30: Data (or address) to write, MSB first
+// The preamble and turnaround are generated by the state machine
+pub fn encode_mdio(st: u16, op: u16, phy_addr: u8, reg_addr: u8, data: u16) -> u32 {
+    // Both clauses read with the first opcode bit set
+    let read = op & 0b10 != 0;
+    let header = (st & 0b11) << 12 | (op & 0b11) << 10 | (phy_addr as u16 & 0b11111) << 5 | (reg_addr as u16 & 0b11111);
+    let mut packet = read as u32;
+    // The 14 bit header is reversed in the top of a u16, so its first bit ends up in bit 1
+    packet |= (reverse_u16_bits(header << 2) as u32) << 1;
+    if !read {
+        packet |= (reverse_u16_bits(data) as u32) << 15;
+    }
+    packet
+}
+
+// Decode the word the SMI PIO program pushes for a read: the second turnaround bit in bit 16, then the 16 data bits.
+// The PHY drives the turnaround bit low, if it reads high nothing answered and the data is just the MDIO pull-up
+pub fn decode_smi_read(word: u32) -> Result<u16, BridgeError> {
+    if word & (1 << 16) != 0 {
+        return Err(BridgeError::NoPhy)
+    }
+    Ok(word as u16)
+}
+
+// Encode a Clause 22 SMI frame
+pub fn encode_smi(read: bool, phy_addr: u8, reg_addr: u8, write_data: u16) -> u32 {
+    let op = if read { 0b10 } else { 0b01 };
+    encode_mdio(ST_CLAUSE22, op, phy_addr, reg_addr, write_data)
+}
+
+// Encode a Clause 45 SMI frame, data is the register address of an Address frame and the value of a Write
+pub fn encode_smi45(op: Clause45Op, phy_addr: u8, mmd: u8, data: u16) -> u32 {
+    encode_mdio(ST_CLAUSE45, op as u16, phy_addr, mmd, data)
+}
+
+// Clause 22 registers giving indirect access to the Clause 45 MMDs
+pub const MMD_CTRL_REG: u8 = 13;
+pub const MMD_ADDR_DATA_REG: u8 = 14;
+// Function field of the MMD access control register: data, no post increment
+const MMD_FUNCTION_DATA: u16 = 0b01 << 14;
+
+// Encode the four Clause 22 frames of an indirect MMD access, a read if data is None:
+// select the address function of devad, write the register address, select the data function, access the data
+pub fn encode_mmd(phy_addr: u8, devad: u8, reg_addr: u16, data: Option<u16>) -> [u32; 4] {
+    let devad = devad as u16 & 0b11111;
+    let access = match data {
+        Some(data) => encode_smi(false, phy_addr, MMD_ADDR_DATA_REG, data),
+        None => encode_smi(true, phy_addr, MMD_ADDR_DATA_REG, 0),
+    };
+    [
+        encode_smi(false, phy_addr, MMD_CTRL_REG, devad),
+        encode_smi(false, phy_addr, MMD_ADDR_DATA_REG, reg_addr),
+        encode_smi(false, phy_addr, MMD_CTRL_REG, MMD_FUNCTION_DATA | devad),
+        access,
+    ]
+}
+
+fn reverse_u16_bits(value: u16) -> u16 {
+    let mut result = 0;
+    for i in 0..16 {
+        result |= ((value >> i) & 1) << (15 - i);
+    }
+    result
+}
\ No newline at end of file
@@ -0,0 +1,87 @@
+// SMBus and PMBus transactions
+//
+// SMBus transactions are I2C transfers led by a command code, run by the I2C state machine. With PEC enabled a
+// Packet Error Code follows the bytes written, or is read after the bytes read and checked: the CRC-8 of every
+// byte of the transaction, addresses included, polynomial x^8 + x^2 + x + 1.
+// PMBus devices report readings in the LINEAR11 format, output voltages in LINEAR16 with the exponent held in
+// their VOUT_MODE register.
+use core::fmt::{self, Write};
+
+use crate::error::BridgeError;
+use crate::i2c::{unpack_bytes, I2cTransfer, MAX_WRITE};
+use crate::protocol::host::{Clean, HostRequest, ValidOps};
+
+// Bytes of a block read or write
+pub const MAX_BLOCK: usize = 32;
+// Data bytes of a block write request, packed after the command code as `i2c w` packs its bytes. More are rejected,
+// with BadArgCount on the consoles and OutOfRange in a binary request
+pub const MAX_BLOCK_WRITE: usize = MAX_WRITE - 1;
+// PMBus command code holding the format and exponent of output voltages
+pub const VOUT_MODE: u8 = 0x20;
+
+// CRC-8 of bytes, continuing from crc
+pub fn crc8(crc: u8, bytes: &[u8]) -> u8 {
+    bytes.iter().fold(crc, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { crc << 1 ^ 0x07 } else { crc << 1 })
+    })
+}
+
+// The transfer of a transaction, packed as the console parser does: address, command code and the byte or word
+// written, block writes as I2C writes with the command code as first byte
+pub fn transfer(hr: &HostRequest<Clean>, pec: bool) -> Result<I2cTransfer, BridgeError> {
+    let addr = hr.payload[0] as u8;
+    let command = hr.payload[1] as u8;
+    let [low, high, _, _] = hr.payload[2].to_le_bytes();
+    Ok(match hr.operation {
+        // The byte sent is in place of the command code
+        ValidOps::SendByte => I2cTransfer::build(addr, &[command], 0, pec),
+        ValidOps::WriteByte => I2cTransfer::build(addr, &[command, low], 0, pec),
+        ValidOps::WriteWord => I2cTransfer::build(addr, &[command, low, high], 0, pec),
+        ValidOps::ReadByte => I2cTransfer::build(addr, &[command], 1, pec),
+        ValidOps::ReadWord | ValidOps::Linear11 => I2cTransfer::build(addr, &[command], 2, pec),
+        // The exponent first
+        ValidOps::Linear16 => I2cTransfer::build(addr, &[VOUT_MODE], 1, pec),
+        ValidOps::ProcCall => I2cTransfer::build(addr, &[command, low, high], 2, pec),
+        ValidOps::BlockRead => I2cTransfer::block_read(addr, command, pec),
+        // Command code, count and data
+        ValidOps::BlockWrite => {
+            let bytes = unpack_bytes(hr);
+            let count = (hr.payload[1] as usize).clamp(1, bytes.len());
+            let mut block = [0_u8; 9];
+            block[0] = bytes[0];
+            block[1] = count as u8 - 1;
+            block[2..count + 1].copy_from_slice(&bytes[1..count]);
+            I2cTransfer::build(addr, &block[..count + 1], 0, pec)
+        }
+        _ => return Err(BridgeError::InvalidOperation),
+    })
+}
+
+// LINEAR11 word: 5 bit two's complement exponent over an 11 bit two's complement mantissa
+pub fn linear11(word: u16) -> (i32, i8) {
+    let mantissa = ((word << 5) as i16 >> 5) as i32;
+    let exponent = (word as i16 >> 11) as i8;
+    (mantissa, exponent)
+}
+
+// LINEAR16 word: unsigned mantissa, the exponent in the low 5 bits of VOUT_MODE. Other modes than linear,
+// in the high 3 bits, are not decoded
+pub fn linear16(word: u16, vout_mode: u8) -> Result<(i32, i8), BridgeError> {
+    if vout_mode >> 5 != 0 {
+        return Err(BridgeError::OutOfRange)
+    }
+    Ok((word as i32, ((vout_mode << 3) as i8) >> 3))
+}
+
+// Write mantissa * 2^exponent with 3 decimals, rounded
+pub fn write_linear<W: Write>(out: &mut W, mantissa: i32, exponent: i8) -> fmt::Result {
+    let scaled = mantissa as i64 * 1000;
+    let millis = if exponent >= 0 {
+        scaled << exponent
+    }
+    else {
+        (scaled + (1 << (-exponent - 1))) >> -exponent
+    };
+    let sign = if millis < 0 { "-" } else { "" };
+    write!(out, "{}{}.{:03}", sign, millis.unsigned_abs() / 1000, millis.unsigned_abs() % 1000)
+}
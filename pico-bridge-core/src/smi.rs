@@ -0,0 +1,521 @@
+// Multi transaction SMI operations
+//
+// An SmiSequence is a request that needs the result of one read to decide what to send next. The firmware
+// sends the frames of each step to the SMI state machine and feeds the result of the read that ends it back
+// through on_read, until the sequence is done. While a sequence runs no other SMI request is started, so it
+// is atomic on the bus.
+use core::fmt::Write;
+
+use crate::c22;
+use crate::error::BridgeError;
+use crate::poll::{Poll, PollStep};
+use crate::protocol::encode_smi;
+use crate::protocol::host::{Clean, HostRequest, ValidInterfaces, ValidOps};
+use crate::stream::{Chunk, StreamOut};
+
+// PHY identifier registers
+pub const PHYID1_REG: u8 = 2;
+pub const PHYID2_REG: u8 = 3;
+// Number of addresses on an MDIO bus, and of Clause 22 registers per PHY
+pub const PHY_ADDRESSES: u8 = 32;
+pub const PHY_REGISTERS: u8 = 32;
+// Snapshots kept in RAM, the oldest is replaced when a new name is stored
+pub const MAX_SNAPSHOTS: usize = 4;
+// Page select register of a PHY until it is configured, as on Realtek PHYs. Marvell PHYs use 22
+pub const DEFAULT_PAGE_REG: u8 = 31;
+
+// Frames of one step, sent back to back. Only the last one reads
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Frames {
+    words: [u32; 4],
+    len: usize,
+}
+
+impl Frames {
+    pub fn read(phy_addr: u8, reg_addr: u8) -> Frames {
+        Frames { words: [encode_smi(true, phy_addr, reg_addr, 0), 0, 0, 0], len: 1 }
+    }
+
+    // Frames sent back to back, at most 4
+    pub fn new(frames: &[u32]) -> Frames {
+        let mut words = [0; 4];
+        words[..frames.len()].copy_from_slice(frames);
+        Frames { words, len: frames.len() }
+    }
+
+    // Write a register and read it back
+    pub fn write_read(phy_addr: u8, reg_addr: u8, data: u16) -> Frames {
+        Frames {
+            words: [encode_smi(false, phy_addr, reg_addr, data), encode_smi(true, phy_addr, reg_addr, 0), 0, 0],
+            len: 2,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u32] {
+        &self.words[..self.len]
+    }
+}
+
+// What the firmware does after feeding a read result to a sequence
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SmiStep {
+    // Send the frames of the next step
+    Next(Frames),
+    // Send the frames of the next step after this many us
+    Wait(u32, Frames),
+    // Finished, the value and its size in bytes for the SlaveResponse, or the error
+    Done(Result<(u32, u8), BridgeError>),
+}
+
+// Read PHYID1/PHYID2 at every address. Addresses that answer are reported with their 32 bit
+// OUI/model/revision, and the result is a bitmap of them
+#[derive(Debug)]
+pub struct Scan {
+    phy_addr: u8,
+    // PHYID1 of the current address, once read
+    id1: Option<u16>,
+    found: u32,
+}
+
+impl Scan {
+    fn step(&self) -> Frames {
+        match self.id1 {
+            None => Frames::read(self.phy_addr, PHYID1_REG),
+            Some(_) => Frames::read(self.phy_addr, PHYID2_REG),
+        }
+    }
+
+    fn on_read<W: Write>(&mut self, result: Result<u16, BridgeError>, report: &mut W) -> SmiStep {
+        match (self.id1, result) {
+            (None, Ok(id1)) => {
+                self.id1 = Some(id1);
+                return SmiStep::Next(self.step())
+            }
+            (Some(id1), Ok(id2)) => {
+                self.found |= 1 << self.phy_addr;
+                let _ = write!(report, "PHY {:#04x}: {:#010x}\n\r", self.phy_addr, (id1 as u32) << 16 | id2 as u32);
+            }
+            // Nothing at this address
+            (_, Err(BridgeError::NoPhy)) => {}
+            (_, Err(err)) => return SmiStep::Done(Err(err)),
+        }
+        self.phy_addr += 1;
+        self.id1 = None;
+        if self.phy_addr == PHY_ADDRESSES {
+            return SmiStep::Done(Ok((self.found, 4)))
+        }
+        SmiStep::Next(self.step())
+    }
+}
+
+// Values of a register range of one PHY, stored under a name of up to 4 characters
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub name: u32,
+    pub phy_addr: u8,
+    pub start: u8,
+    len: u8,
+    values: [u16; PHY_REGISTERS as usize],
+}
+
+impl Snapshot {
+    fn new(name: u32, phy_addr: u8, start: u8) -> Snapshot {
+        Snapshot { name, phy_addr, start, len: 0, values: [0; PHY_REGISTERS as usize] }
+    }
+
+    pub fn values(&self) -> &[u16] {
+        &self.values[..self.len as usize]
+    }
+
+    fn push(&mut self, value: u16) {
+        self.values[self.len as usize] = value;
+        self.len += 1;
+    }
+}
+
+pub struct Snapshots {
+    slots: [Option<Snapshot>; MAX_SNAPSHOTS],
+    // Slot replaced by the next new name when all are taken
+    next: usize,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshots {
+    pub const fn new() -> Snapshots {
+        Snapshots { slots: [None; MAX_SNAPSHOTS], next: 0 }
+    }
+
+    pub fn get(&self, name: u32) -> Option<&Snapshot> {
+        self.slots.iter().flatten().find(|snapshot| snapshot.name == name)
+    }
+
+    // Store a snapshot, replacing the one with the same name or else the oldest
+    pub fn store(&mut self, snapshot: Snapshot) {
+        let index = match self.slots.iter().position(|slot| matches!(slot, Some(old) if old.name == snapshot.name)) {
+            Some(index) => index,
+            None => {
+                let index = self.slots.iter().position(Option::is_none).unwrap_or(self.next);
+                self.next = (index + 1) % MAX_SNAPSHOTS;
+                index
+            }
+        };
+        self.slots[index] = Some(snapshot);
+    }
+}
+
+// Read a register range, streaming the value of each register. A named dump is stored as a snapshot when done.
+// Diffing reads the range of a snapshot again and streams only the registers that changed, as the register, the old
+// and the new value. Both answer with the number of registers streamed
+#[derive(Debug)]
+pub struct Dump {
+    reg: u8,
+    end: u8,
+    read: Snapshot,
+    // Store the values read under their name when done
+    store: bool,
+    baseline: Option<Snapshot>,
+    changed: u32,
+    out: StreamOut,
+}
+
+impl Dump {
+    fn step(&self) -> Frames {
+        Frames::read(self.read.phy_addr, self.reg)
+    }
+
+    // A chunk holds two changed registers, the firmware writes it out before the next step
+    fn on_read(&mut self, result: Result<u16, BridgeError>) -> SmiStep {
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => return SmiStep::Done(Err(err)),
+        };
+        let index = self.read.len as usize;
+        match &self.baseline {
+            Some(baseline) => {
+                let old = baseline.values()[index];
+                if old != value {
+                    self.changed += 1;
+                    for word in [self.reg as u16, old, value] {
+                        self.out.push(&word.to_le_bytes());
+                    }
+                }
+            }
+            None => self.out.push(&value.to_le_bytes()),
+        }
+        self.read.push(value);
+        if self.reg == self.end {
+            self.out.finish();
+            return SmiStep::Done(match self.baseline {
+                // Number of registers that changed
+                Some(_) => Ok((self.changed, 1)),
+                // Number of registers read
+                None => Ok((self.read.len as u32, 1)),
+            })
+        }
+        self.reg += 1;
+        SmiStep::Next(self.step())
+    }
+}
+
+// Read a register, write it back with the bits in mask replaced by those of value, and read it again.
+// The result is the old value in the high half and the value read back in the low half
+#[derive(Debug)]
+pub struct Rmw {
+    phy_addr: u8,
+    reg: u8,
+    mask: u16,
+    value: u16,
+    // Value before the write, once read
+    old: Option<u16>,
+}
+
+impl Rmw {
+    fn step(&self) -> Frames {
+        match self.old {
+            None => Frames::read(self.phy_addr, self.reg),
+            Some(old) => Frames::write_read(self.phy_addr, self.reg, (old & !self.mask) | (self.value & self.mask)),
+        }
+    }
+
+    fn on_read<W: Write>(&mut self, result: Result<u16, BridgeError>, report: &mut W) -> SmiStep {
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => return SmiStep::Done(Err(err)),
+        };
+        match self.old {
+            None => {
+                self.old = Some(value);
+                SmiStep::Next(self.step())
+            }
+            Some(old) => {
+                let _ = write!(report, "{:#04x}: {:#06x} -> {:#06x}\n\r", self.reg, old, value);
+                SmiStep::Done(Ok(((old as u32) << 16 | value as u32, 4)))
+            }
+        }
+    }
+}
+
+// Read a register until the bits in mask equal the expected value. The result is the last value read in the
+// high half and the ms it took in the low half
+#[derive(Debug)]
+pub struct SmiPoll {
+    phy_addr: u8,
+    reg: u8,
+    poll: Poll,
+}
+
+impl SmiPoll {
+    fn step(&self) -> Frames {
+        Frames::read(self.phy_addr, self.reg)
+    }
+
+    fn on_read<W: Write>(&mut self, result: Result<u16, BridgeError>, now_us: u64, report: &mut W) -> SmiStep {
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => return SmiStep::Done(Err(err)),
+        };
+        match self.poll.check(value, now_us) {
+            PollStep::Again(delay_us) => SmiStep::Wait(delay_us, self.step()),
+            PollStep::Done(Ok(answer)) => {
+                let _ = write!(report, "{:#04x}: {:#06x} after {} ms\n\r", self.reg, value, answer & 0xFFFF);
+                SmiStep::Done(Ok((answer, 4)))
+            }
+            PollStep::Done(Err(err)) => SmiStep::Done(Err(err)),
+        }
+    }
+}
+
+// Page select register of each PHY address
+pub struct PageSelect {
+    regs: [u8; PHY_ADDRESSES as usize],
+}
+
+impl Default for PageSelect {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PageSelect {
+    pub const fn new() -> PageSelect {
+        PageSelect { regs: [DEFAULT_PAGE_REG; PHY_ADDRESSES as usize] }
+    }
+
+    pub fn get(&self, phy_addr: u8) -> u8 {
+        self.regs[(phy_addr % PHY_ADDRESSES) as usize]
+    }
+
+    pub fn set(&mut self, phy_addr: u32, reg: u32) -> Result<(), BridgeError> {
+        if phy_addr >= PHY_ADDRESSES as u32 || reg >= PHY_REGISTERS as u32 {
+            return Err(BridgeError::OutOfRange)
+        }
+        self.regs[phy_addr as usize] = reg as u8;
+        Ok(())
+    }
+}
+
+// Access a register on a page. The current page is read first, the target page selected for the access and
+// the page read first selected again. A read returns the register, a write nothing
+#[derive(Debug)]
+pub struct Paged {
+    phy_addr: u8,
+    page_reg: u8,
+    page: u16,
+    reg: u8,
+    // Data of a write
+    data: Option<u16>,
+    // Page selected before the access, once read
+    old_page: Option<u16>,
+    // Register read on the page
+    value: Option<u16>,
+}
+
+impl Paged {
+    fn select(&self, page: u16) -> u32 {
+        encode_smi(false, self.phy_addr, self.page_reg, page)
+    }
+
+    fn step(&self) -> Frames {
+        let read_page = encode_smi(true, self.phy_addr, self.page_reg, 0);
+        match (self.old_page, self.data, self.value) {
+            (None, _, _) => Frames::new(&[read_page]),
+            (Some(_), None, None) => Frames::new(&[self.select(self.page), encode_smi(true, self.phy_addr, self.reg, 0)]),
+            // Reading the page back ends the step
+            (Some(old), None, Some(_)) => Frames::new(&[self.select(old), read_page]),
+            (Some(old), Some(data), _) => {
+                Frames::new(&[self.select(self.page), encode_smi(false, self.phy_addr, self.reg, data), self.select(old), read_page])
+            }
+        }
+    }
+
+    fn on_read(&mut self, result: Result<u16, BridgeError>) -> SmiStep {
+        let value = match result {
+            Ok(value) => value,
+            Err(err) => return SmiStep::Done(Err(err)),
+        };
+        match (self.old_page, self.data, self.value) {
+            (None, _, _) => self.old_page = Some(value),
+            (Some(_), None, None) => self.value = Some(value),
+            (Some(_), None, Some(read)) => return SmiStep::Done(Ok((read as u32, 2))),
+            (Some(_), Some(_), _) => return SmiStep::Done(Ok((0, 0))),
+        }
+        SmiStep::Next(self.step())
+    }
+}
+
+// Read a register and report its Clause 22 fields
+#[derive(Debug)]
+pub struct Decode {
+    phy_addr: u8,
+    reg: u8,
+}
+
+impl Decode {
+    fn step(&self) -> Frames {
+        Frames::read(self.phy_addr, self.reg)
+    }
+
+    fn on_read<W: Write>(&mut self, result: Result<u16, BridgeError>, report: &mut W) -> SmiStep {
+        if let Ok(value) = result {
+            let _ = c22::decode(self.reg, value, report);
+        }
+        SmiStep::Done(result.map(|value| (value as u32, 2)))
+    }
+}
+
+#[derive(Debug)]
+pub enum SmiSequence {
+    Scan(Scan),
+    Dump(Dump),
+    Rmw(Rmw),
+    Poll(SmiPoll),
+    Decode(Decode),
+    Paged(Paged),
+}
+
+impl SmiSequence {
+    // The sequence for a request, with the frames of its first step. Diffs look their snapshot up in snapshots,
+    // paged accesses their page select register in pages
+    pub fn start(hr: &HostRequest<Clean>, snapshots: &Snapshots, pages: &PageSelect)
+        -> Result<Option<(SmiSequence, Frames)>, BridgeError> {
+        let sequence = match (hr.interface, hr.operation) {
+            (ValidInterfaces::SMI, ValidOps::Scan) => {
+                SmiSequence::Scan(Scan { phy_addr: 0, id1: None, found: 0 })
+            }
+            // PHY address, first and last register, and the name to store the snapshot under if any
+            (ValidInterfaces::SMI, ValidOps::Dump) => {
+                let read = Snapshot::new(hr.payload[3], hr.payload[0] as u8, hr.payload[1] as u8);
+                SmiSequence::Dump(Dump {
+                    reg: read.start,
+                    end: hr.payload[2] as u8,
+                    read,
+                    store: hr.size == 4,
+                    baseline: None,
+                    changed: 0,
+                    out: StreamOut::new(2),
+                })
+            }
+            (ValidInterfaces::SMI, ValidOps::Diff) => {
+                let baseline = *snapshots.get(hr.payload[0]).ok_or(BridgeError::NotFound)?;
+                SmiSequence::Dump(Dump {
+                    reg: baseline.start,
+                    end: baseline.start + baseline.len - 1,
+                    read: Snapshot::new(baseline.name, baseline.phy_addr, baseline.start),
+                    store: false,
+                    baseline: Some(baseline),
+                    changed: 0,
+                    out: StreamOut::new(2),
+                })
+            }
+            // PHY address, register, mask and value, set and clear bits are rewritten to these by init_clean
+            (ValidInterfaces::SMI, ValidOps::Rmw | ValidOps::SetBits | ValidOps::ClrBits) => {
+                SmiSequence::Rmw(Rmw {
+                    phy_addr: hr.payload[0] as u8,
+                    reg: hr.payload[1] as u8,
+                    mask: hr.payload[2] as u16,
+                    value: hr.payload[3] as u16,
+                    old: None,
+                })
+            }
+            // PHY address and register, mask and expected value, timeout in ms and interval in us
+            (ValidInterfaces::SMI, ValidOps::Poll) => {
+                let (condition, timeout_ms, interval_us) = (hr.payload[1], hr.payload[2], hr.payload[3]);
+                SmiSequence::Poll(SmiPoll {
+                    phy_addr: hr.payload[0] as u8,
+                    reg: (hr.payload[0] >> 16) as u8,
+                    poll: Poll::new(condition as u16, (condition >> 16) as u16, timeout_ms, interval_us)?,
+                })
+            }
+            (ValidInterfaces::SMI, ValidOps::Decode) => {
+                SmiSequence::Decode(Decode { phy_addr: hr.payload[0] as u8, reg: hr.payload[1] as u8 })
+            }
+            // PHY address, page and register, and the data of a write
+            (ValidInterfaces::SMI, ValidOps::PagedRead | ValidOps::PagedWrite) => {
+                let phy_addr = hr.payload[0] as u8;
+                SmiSequence::Paged(Paged {
+                    phy_addr,
+                    page_reg: pages.get(phy_addr),
+                    page: hr.payload[1] as u16,
+                    reg: hr.payload[2] as u8,
+                    data: (hr.operation == ValidOps::PagedWrite).then_some(hr.payload[3] as u16),
+                    old_page: None,
+                    value: None,
+                })
+            }
+            _ => return Ok(None),
+        };
+        let frames = sequence.step();
+        Ok(Some((sequence, frames)))
+    }
+
+    fn step(&self) -> Frames {
+        match self {
+            SmiSequence::Scan(scan) => scan.step(),
+            SmiSequence::Dump(dump) => dump.step(),
+            SmiSequence::Rmw(rmw) => rmw.step(),
+            SmiSequence::Poll(poll) => poll.step(),
+            SmiSequence::Decode(decode) => decode.step(),
+            SmiSequence::Paged(paged) => paged.step(),
+        }
+    }
+
+    // The snapshot to store once a named dump is done
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        match self {
+            SmiSequence::Dump(dump) if dump.store => Some(&dump.read),
+            _ => None,
+        }
+    }
+
+    // Data streamed ahead of the answer, to write out before the step on_read returned goes on
+    pub fn chunk(&self) -> Option<&Chunk> {
+        match self {
+            SmiSequence::Dump(dump) => dump.out.chunk(),
+            _ => None,
+        }
+    }
+
+    pub fn chunk_written(&mut self) {
+        if let SmiSequence::Dump(dump) = self {
+            dump.out.written();
+        }
+    }
+
+    // Feed the result of the read that ended the last step, and when it was read in us. Lines for console hosts
+    // are written to report
+    pub fn on_read<W: Write>(&mut self, result: Result<u16, BridgeError>, now_us: u64, report: &mut W) -> SmiStep {
+        match self {
+            SmiSequence::Scan(scan) => scan.on_read(result, report),
+            SmiSequence::Dump(dump) => dump.on_read(result),
+            SmiSequence::Rmw(rmw) => rmw.on_read(result, report),
+            SmiSequence::Poll(poll) => poll.on_read(result, now_us, report),
+            SmiSequence::Decode(decode) => decode.on_read(result, report),
+            SmiSequence::Paged(paged) => paged.on_read(result),
+        }
+    }
+}
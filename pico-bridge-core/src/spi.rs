@@ -0,0 +1,217 @@
+// SPI master transfers
+//
+// The SPI state machine runs one of the two PIO SPI programs of the pico examples, picked by the clock phase, with
+// the clock polarity set by inverting the SCK output. It shifts a word of the configured width out of each TX FIFO
+// word while it shifts one in, and pushes it to the RX FIFO. Chip selects are GPIOs driven by the firmware, low
+// from the first word of a request to the last, or on until a request on another chip select with the hold flag.
+use crate::error::BridgeError;
+use crate::protocol::host::{Clean, HostRequest, ValidOps};
+use crate::stream::{Chunk, StreamIn, StreamOut};
+
+// PIO cycles per SCK period of both programs
+pub const SPI_CYCLES_PER_BIT: u32 = 4;
+pub const SPI_DEFAULT_HZ: u32 = 1_000_000;
+pub const SPI_MAX_HZ: u32 = 30_000_000;
+pub const MAX_CS: usize = 4;
+// Flag of the chip select argument that keeps it asserted after the request
+pub const SPI_HOLD: u32 = 0x100;
+// Words clocked by one transfer or read
+pub const MAX_WORDS: u32 = 0xFFFF;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SpiConfig {
+    // CPOL in bit 1, CPHA in bit 0
+    pub mode: u8,
+    pub bits: u8,
+    pub lsb_first: bool,
+}
+
+impl SpiConfig {
+    pub const DEFAULT: SpiConfig = SpiConfig { mode: 0, bits: 8, lsb_first: false };
+
+    pub fn new(mode: u32, bits: u32, lsb_first: u32) -> Result<SpiConfig, BridgeError> {
+        if mode > 3 || !(1..=32).contains(&bits) || lsb_first > 1 {
+            return Err(BridgeError::OutOfRange)
+        }
+        Ok(SpiConfig { mode: mode as u8, bits: bits as u8, lsb_first: lsb_first != 0 })
+    }
+
+    pub fn cpol(&self) -> bool {
+        self.mode & 2 != 0
+    }
+
+    pub fn cpha(&self) -> bool {
+        self.mode & 1 != 0
+    }
+
+    pub fn mask(&self) -> u32 {
+        u32::MAX >> (32 - self.bits)
+    }
+
+    // Bytes a word takes in a response
+    fn word_bytes(&self) -> u32 {
+        (self.bits as u32).div_ceil(8)
+    }
+
+    // TX FIFO word of an SPI word. The OSR shifts out from its top MSB first, from its bottom LSB first
+    pub fn tx_word(&self, word: u32) -> u32 {
+        if self.lsb_first { word } else { word << (32 - self.bits) }
+    }
+
+    // SPI word of an RX FIFO word. The ISR fills from its bottom MSB first, from its top LSB first
+    pub fn rx_word(&self, word: u32) -> u32 {
+        if self.lsb_first { word >> (32 - self.bits) } else { word & self.mask() }
+    }
+}
+
+// A full-duplex transfer of words streamed from the host, or a read clocking out a fill word. The MISO words are
+// returned packed if they fit the response, otherwise streamed to the host
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SpiJob {
+    config: SpiConfig,
+    cs: u8,
+    hold: bool,
+    read: bool,
+    fill: u32,
+    count: u32,
+    sent: u32,
+    received: u32,
+    // MOSI word taken from the input, sent next
+    next: Option<u32>,
+    // MISO words packed for the response, first in the low bytes
+    data: u32,
+    // MISO words of a request longer than the response
+    stream: Option<StreamOut>,
+    failed: Option<BridgeError>,
+}
+
+impl SpiJob {
+    // Chip select with the hold flag and the count of words, then the fill word of a read
+    pub fn start(hr: &HostRequest<Clean>, config: SpiConfig) -> Result<SpiJob, BridgeError> {
+        let cs = hr.payload[0] & !SPI_HOLD;
+        if cs as usize >= MAX_CS {
+            return Err(BridgeError::OutOfRange)
+        }
+        let mut job = SpiJob {
+            config,
+            cs: cs as u8,
+            hold: hr.payload[0] & SPI_HOLD != 0,
+            read: hr.operation == ValidOps::Read,
+            fill: config.mask(),
+            count: hr.payload[1],
+            sent: 0,
+            received: 0,
+            next: None,
+            data: 0,
+            stream: None,
+            failed: None,
+        };
+        match (hr.operation, hr.size) {
+            (ValidOps::Transfer, 2) | (ValidOps::Read, 2) => {}
+            (ValidOps::Read, 3) => job.fill = hr.payload[2],
+            (ValidOps::Transfer | ValidOps::Read, _) => return Err(BridgeError::BadArgCount),
+            _ => return Err(BridgeError::InvalidOperation),
+        }
+        if job.count == 0 || job.count > MAX_WORDS {
+            return Err(BridgeError::OutOfRange)
+        }
+        if job.fill & !config.mask() != 0 {
+            return Err(BridgeError::NumberTooLarge)
+        }
+        if job.count * config.word_bytes() > 4 {
+            job.stream = Some(StreamOut::new(config.word_bytes() as u8));
+        }
+        Ok(job)
+    }
+
+    pub fn cs(&self) -> u8 {
+        self.cs
+    }
+
+    // The chip select stays asserted after the request
+    pub fn hold(&self) -> bool {
+        self.hold
+    }
+
+    // Bytes of MOSI words the request takes from the host, little endian
+    pub fn input(&self) -> Option<u32> {
+        (!self.read).then_some(self.count * self.config.word_bytes())
+    }
+
+    // The next TX FIFO word, None once all are sent, while the MISO words clocked fill the stream, or until the input
+    // has the next MOSI word. A host that overran the input fails the request, nothing more is sent
+    pub fn pending(&mut self, input: &mut StreamIn) -> Option<u32> {
+        if self.sent == self.count || self.failed.is_some() {
+            return None
+        }
+        let bytes = self.config.word_bytes();
+        if let Some(stream) = &self.stream {
+            if self.sent * bytes >= stream.limit() {
+                return None
+            }
+        }
+        if self.read {
+            return Some(self.config.tx_word(self.fill))
+        }
+        if input.overrun() {
+            self.failed = Some(BridgeError::QueueFull);
+            self.count = self.sent;
+            return None
+        }
+        if self.next.is_none() && input.len() >= bytes as usize {
+            let word = (0..bytes).fold(0, |word, i| word | (input.pop().unwrap_or(0) as u32) << (8 * i));
+            // Bits past the width are dropped
+            self.next = Some(word & self.config.mask());
+        }
+        self.next.map(|word| self.config.tx_word(word))
+    }
+
+    pub fn sent(&mut self) {
+        self.sent = (self.sent + 1).min(self.count);
+        self.next = None;
+    }
+
+    // An RX FIFO word, one per word sent
+    pub fn on_rx(&mut self, word: u32) {
+        if self.received == self.count {
+            return
+        }
+        let word = self.config.rx_word(word);
+        let bytes = self.config.word_bytes();
+        match self.stream.as_mut() {
+            Some(stream) => stream.push(&word.to_le_bytes()[..bytes as usize]),
+            None => self.data |= word << (8 * bytes * self.received),
+        }
+        self.received += 1;
+        if let (Some(stream), true) = (self.stream.as_mut(), self.received == self.count) {
+            stream.finish();
+        }
+    }
+
+    // The chunk of MISO words to write out, nothing more is sent until chunk_written
+    pub fn chunk(&self) -> Option<&Chunk> {
+        self.stream.as_ref()?.chunk()
+    }
+
+    pub fn chunk_written(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            stream.written();
+        }
+    }
+
+    // All words are received and written out
+    pub fn done(&self) -> bool {
+        self.received == self.count && (self.failed.is_some() || self.stream.iter().all(StreamOut::done))
+    }
+
+    // The MISO words packed with their size in bytes, or the count of words streamed
+    pub fn result(&self) -> Result<(u32, u8), BridgeError> {
+        if let Some(err) = self.failed {
+            return Err(err)
+        }
+        match self.stream {
+            Some(_) => Ok((self.count, 4)),
+            None => Ok((self.data, (self.count * self.config.word_bytes()) as u8)),
+        }
+    }
+}
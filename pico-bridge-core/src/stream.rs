@@ -0,0 +1,331 @@
+// Data streamed between the host and a request
+//
+// Data that does not fit the 4 byte payload of a response, like the bytes of a long I2C read, goes out ahead of the
+// response as a run of chunks numbered from 0, tied to the proc_id of the request. The consoles get a line per
+// chunk, the data as words of the width the request reads in. Binary mode gets a data frame per chunk (see frame),
+// and the SPI slave an 18 byte frame:
+//
+//      Proc ID (1) | DATA_STATUS (1) | Checksum (1) | Seq (2) | Length (1) | Data (CHUNK_LEN)
+//
+// The checksum is the wrapping checksum of the other frames over the status byte and every byte after it. The
+// response that ends the run follows the last chunk.
+//
+// Data a request takes from the host, like the MOSI words of an SPI transfer, comes in after the request and is queued
+// in a StreamIn until the request takes it. The consoles send it as raw bytes, binary mode as data frames with the
+// proc_id of the request, and the SPI master as the frame above with Proc ID 0. The host waits for XON before the first
+// byte and stops on XOFF until the next XON, binary mode and the SPI slave get them as an empty data frame with the
+// flow byte as its Seq.
+use core::fmt::Write;
+
+use crate::error::BridgeError;
+use crate::fmt::FmtBuf;
+use crate::frame::{encode_data, Data, MAX_ENCODED_FRAME};
+use crate::protocol::{HostTransport, ValidHostInterfaces};
+
+// Data bytes of a chunk, the width of every word a request reads in divides it
+pub const CHUNK_LEN: usize = 12;
+// Status byte of a data frame, out of the range of the BridgeError codes
+pub const DATA_STATUS: u8 = 0xFF;
+// Bytes received ahead of the request
+pub const INPUT_LEN: usize = 4096;
+// Software flow control, XOFF once the input is half full and XON once it has drained
+pub const XON: u8 = 0x11;
+pub const XOFF: u8 = 0x13;
+const XOFF_AT: usize = INPUT_LEN / 2;
+const XON_AT: usize = INPUT_LEN / 8;
+// Ctrl-D ends an input of no set length, like SVF text
+pub const INPUT_END: u8 = 0x04;
+
+// Piece of the data of a request, offset bytes into it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Chunk {
+    pub seq: u16,
+    pub offset: u32,
+    // Bytes per word on the consoles, the words are little endian
+    pub width: u8,
+    pub len: u8,
+    pub data: [u8; CHUNK_LEN],
+}
+
+impl Chunk {
+    pub fn bytes(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    // Console line of the chunk: its offset, then its words in hex
+    pub fn format_text(&self) -> FmtBuf<64> {
+        let mut text = FmtBuf::new();
+        let _ = write!(text, "{:06x}:", self.offset);
+        for word in self.bytes().chunks(self.width as usize) {
+            let value = word.iter().rev().fold(0_u32, |value, byte| value << 8 | *byte as u32);
+            let _ = write!(text, " {:0width$x}", value, width = 2 * word.len());
+        }
+        let _ = write!(text, "\n\r");
+        text
+    }
+
+    // Frame shifted out to the SPI master
+    pub fn encode_8bit_spi(&self, proc_id: u8) -> [u8; 18] {
+        let mut buf = [0_u8; 18];
+        buf[0] = proc_id;
+        buf[1] = DATA_STATUS;
+        buf[3..5].copy_from_slice(&self.seq.to_le_bytes());
+        buf[5] = self.len;
+        buf[6..6 + self.len as usize].copy_from_slice(self.bytes());
+        let sum = buf[1..].iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+        buf[2] = sum.wrapping_neg();
+        buf
+    }
+}
+
+// Write a chunk to the host of the request with proc_id. QueueFull while the SPI slave has frames the master did not
+// clock out yet, the chunk is written again later
+pub fn write_chunk<H: HostTransport>(host: &mut H, host_config: ValidHostInterfaces, proc_id: u8, chunk: &Chunk)
+    -> Result<(), BridgeError> {
+    match host_config {
+        ValidHostInterfaces::Serial => host.write_serial(chunk.format_text().as_bytes()),
+        ValidHostInterfaces::UART => host.write_uart(chunk.format_text().as_bytes()),
+        ValidHostInterfaces::SerialFramed => {
+            let mut frame = [0_u8; MAX_ENCODED_FRAME];
+            let len = encode_data(proc_id, chunk, &mut frame);
+            host.write_serial(&frame[..len])
+        }
+        ValidHostInterfaces::SPI => host.queue_spi(chunk.encode_8bit_spi(proc_id)),
+        ValidHostInterfaces::None => Err(BridgeError::NoResponse),
+    }
+}
+
+// Tell the host feeding the input of the request with proc_id to stop or go on. QueueFull as write_chunk
+pub fn write_flow<H: HostTransport>(host: &mut H, host_config: ValidHostInterfaces, proc_id: u8, flow: u8)
+    -> Result<(), BridgeError> {
+    let chunk = Chunk { seq: flow as u16, offset: 0, width: 1, len: 0, data: [0; CHUNK_LEN] };
+    match host_config {
+        ValidHostInterfaces::Serial => host.write_serial(&[flow]),
+        ValidHostInterfaces::UART => host.write_uart(&[flow]),
+        _ => write_chunk(host, host_config, proc_id, &chunk),
+    }
+}
+
+// A data frame from the SPI master, Proc ID 0 then the layout of those shifted out to it
+pub fn decode_spi_data(buf: &[u8; 18]) -> Result<Data, BridgeError> {
+    if buf[0] != 0 || buf[1] != DATA_STATUS {
+        return Err(BridgeError::InvalidFrame)
+    }
+    if buf[1..].iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(BridgeError::ChecksumMismatch)
+    }
+    let len = buf[5] as usize;
+    if len > CHUNK_LEN {
+        return Err(BridgeError::InvalidFrame)
+    }
+    let mut data = [0_u8; CHUNK_LEN];
+    data[..len].copy_from_slice(&buf[6..6 + len]);
+    Ok(Data { proc_id: 0, seq: u16::from_le_bytes([buf[3], buf[4]]), len: len as u8, data })
+}
+
+// The data a request streams, gathered a chunk at a time. A request asks for no more data than fits up to limit(),
+// and goes on once the full chunk is written out
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct StreamOut {
+    chunk: Chunk,
+    // No more data comes, the last chunk goes out even if it is not full
+    finished: bool,
+}
+
+impl StreamOut {
+    pub const fn new(width: u8) -> StreamOut {
+        StreamOut {
+            chunk: Chunk { seq: 0, offset: 0, width, len: 0, data: [0; CHUNK_LEN] },
+            finished: false,
+        }
+    }
+
+    // Offset in the data up to which it fits, until the chunk being filled is written out
+    pub fn limit(&self) -> u32 {
+        self.chunk.offset + CHUNK_LEN as u32
+    }
+
+    // Bytes pushed so far
+    pub fn len(&self) -> u32 {
+        self.chunk.offset + self.chunk.len as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Data past the limit is dropped
+    pub fn push(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if self.chunk.len as usize == CHUNK_LEN {
+                return
+            }
+            self.chunk.data[self.chunk.len as usize] = *byte;
+            self.chunk.len += 1;
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    // The chunk to write out once it is full, or the last one
+    pub fn chunk(&self) -> Option<&Chunk> {
+        let full = self.chunk.len as usize == CHUNK_LEN;
+        (full || self.finished && self.chunk.len > 0).then_some(&self.chunk)
+    }
+
+    pub fn written(&mut self) {
+        if self.chunk().is_some() {
+            self.chunk.offset += self.chunk.len as u32;
+            self.chunk.seq = self.chunk.seq.wrapping_add(1);
+            self.chunk.len = 0;
+        }
+    }
+
+    // Finished and all written out
+    pub fn done(&self) -> bool {
+        self.finished && self.chunk.len == 0
+    }
+}
+
+// Bytes from the host waiting for the request that takes them
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct StreamIn {
+    buf: [u8; INPUT_LEN],
+    head: usize,
+    len: usize,
+    // Host and request the input goes to, ValidHostInterfaces::None while no request takes input
+    host: ValidHostInterfaces,
+    proc_id: u8,
+    // Bytes still to come, None up to Ctrl-D
+    left: Option<u32>,
+    ended: bool,
+    paused: bool,
+    overrun: bool,
+}
+
+impl StreamIn {
+    pub const fn new() -> StreamIn {
+        StreamIn {
+            buf: [0; INPUT_LEN],
+            head: 0,
+            len: 0,
+            host: ValidHostInterfaces::None,
+            proc_id: 0,
+            left: None,
+            ended: false,
+            paused: false,
+            overrun: false,
+        }
+    }
+
+    // Bytes from the host go to the request with proc_id, len of them or up to Ctrl-D. The host is told to go with
+    // the first flow
+    pub fn start(&mut self, host: ValidHostInterfaces, proc_id: u8, len: Option<u32>) {
+        *self = StreamIn { host, proc_id, left: len, ended: len == Some(0), paused: true, ..StreamIn::new() };
+    }
+
+    pub fn stop(&mut self) {
+        *self = StreamIn::new();
+    }
+
+    pub fn active(&self) -> bool {
+        self.host != ValidHostInterfaces::None
+    }
+
+    pub fn host(&self) -> ValidHostInterfaces {
+        self.host
+    }
+
+    pub fn proc_id(&self) -> u8 {
+        self.proc_id
+    }
+
+    // Bytes received from host go to the input, until it ends
+    pub fn takes(&self, host: ValidHostInterfaces) -> bool {
+        self.active() && self.host == host && !self.ended
+    }
+
+    // A byte from the host. Bytes past a full input are lost
+    pub fn push(&mut self, byte: u8) {
+        if self.ended {
+            return
+        }
+        match self.left.as_mut() {
+            Some(left) => {
+                *left -= 1;
+                self.ended = *left == 0;
+            }
+            None if byte == INPUT_END => {
+                self.ended = true;
+                return
+            }
+            None => {}
+        }
+        if self.len == INPUT_LEN {
+            self.overrun = true;
+        }
+        else {
+            self.buf[(self.head + self.len) % INPUT_LEN] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % INPUT_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    // Bytes waiting
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // All bytes are received and read
+    pub fn ended(&self) -> bool {
+        self.ended && self.len == 0
+    }
+
+    // The host did not stop on XOFF and bytes were lost
+    pub fn overrun(&self) -> bool {
+        self.overrun
+    }
+
+    // The XOFF or XON to send the host once the input crossed a level, until flow_written
+    pub fn flow(&self) -> Option<u8> {
+        if !self.active() || self.ended {
+            None
+        }
+        else if !self.paused && self.len >= XOFF_AT {
+            Some(XOFF)
+        }
+        else if self.paused && self.len <= XON_AT {
+            Some(XON)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn flow_written(&mut self) {
+        if self.flow().is_some() {
+            self.paused = !self.paused;
+        }
+    }
+}
+
+impl Default for StreamIn {
+    fn default() -> Self {
+        StreamIn::new()
+    }
+}
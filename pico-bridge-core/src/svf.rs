@@ -0,0 +1,875 @@
+// SVF player
+//
+// Serial Vector Format text streamed from the host is queued in the StreamIn as it arrives and read by the player a
+// byte at a time. Each statement runs as soon as its ';' is read: SIR and SDR become a Shift on the JTAG state machine,
+// with the header and trailer bits around them, RUNTEST and STATE a Shift that holds or moves the TAP, FREQUENCY a new
+// TCK divisor, and the others only change what later statements do. Hex digits go straight into the bits of their
+// field as they are read, and the TDO bits of a scan are compared under MASK as they come back. The first mismatch, or
+// bad statement, ends the run and the rest of the input is dropped. The error response carries the line of the
+// statement, and a mismatch streams the expected, got and mask words around it to the host.
+//
+// TDI goes out LSB first, which is the last hex digit, and TDO and MASK follow it, so a scan shifts once its ';' is
+// read and holds at most MAX_SVF_BITS bits of SIR or SDR.
+use core::fmt::Write;
+
+use crate::clock::ClockDivisor;
+use crate::error::BridgeError;
+use crate::jtag::{Shift, TapState, TmsPath, JTAG_CYCLES_PER_BIT, JTAG_DEFAULT_HZ};
+use crate::stream::{Chunk, StreamIn, StreamOut};
+
+// Bits of an SIR or SDR, and of a header or trailer
+pub const MAX_SVF_BITS: u32 = 8192;
+pub const MAX_PAD_BITS: u32 = 256;
+// TCKs of a RUNTEST
+const MAX_RUN_TCKS: u32 = 1 << 31;
+// States of a STATE statement
+const MAX_PATH: usize = 16;
+const WORD_LEN: usize = 24;
+const SCAN_WORDS: usize = (MAX_SVF_BITS / 32) as usize;
+const PAD_WORDS: usize = (MAX_PAD_BITS / 32) as usize;
+
+// What the firmware does once the shift running is back, or more input came in
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SvfStep {
+    // Send the TCKs of the next shift, or write out the chunk of a mismatch
+    Next,
+    // Wait for more input
+    Input,
+    // Set the TCK divisor, then go on
+    Divisor(ClockDivisor),
+    // Finished, the number of statements run, or the error of the statement at failed_line
+    Done(Result<(u32, u8), BridgeError>),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Reg {
+    Hir,
+    Sir,
+    Tir,
+    Hdr,
+    Sdr,
+    Tdr,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Field {
+    Tdi,
+    Tdo,
+    Mask,
+    Smask,
+}
+
+impl Field {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Command {
+    None,
+    Scan(Reg),
+    EndIr,
+    EndDr,
+    State,
+    RunTest,
+    Frequency,
+    Trst,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Lex {
+    Space,
+    Word,
+    Hex,
+    // A '/' that must start a comment
+    Slash,
+    Comment,
+}
+
+// TDI, expected TDO and MASK bits of a scan, header or trailer, LSB first. TDI and MASK are kept for the next
+// statement of the same length
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Pattern<const W: usize> {
+    len: u32,
+    tdi: [u32; W],
+    tdo: [u32; W],
+    mask: [u32; W],
+    // TDO is compared, set by a TDO for the header and trailer, and for each scan that gives one
+    compare: bool,
+}
+
+impl<const W: usize> Pattern<W> {
+    const EMPTY: Pattern<W> = Pattern { len: 0, tdi: [0; W], tdo: [0; W], mask: [0; W], compare: false };
+
+    fn view(&self) -> View<'_> {
+        View { len: self.len, tdi: &self.tdi, tdo: &self.tdo, mask: &self.mask, compare: self.compare }
+    }
+
+    fn field(&mut self, field: Field) -> Option<&mut [u32]> {
+        match field {
+            Field::Tdi => Some(&mut self.tdi),
+            Field::Tdo => Some(&mut self.tdo),
+            Field::Mask => Some(&mut self.mask),
+            Field::Smask => None,
+        }
+    }
+
+    // A new length drops the bits of the old one, all are compared until a MASK says otherwise
+    fn set_len(&mut self, len: u32) -> bool {
+        if len == self.len {
+            return false
+        }
+        *self = Pattern { len, ..Pattern::EMPTY };
+        for i in 0..len {
+            set_bit(&mut self.mask, i, true);
+        }
+        true
+    }
+}
+
+#[derive(Copy, Clone)]
+struct View<'a> {
+    len: u32,
+    tdi: &'a [u32],
+    tdo: &'a [u32],
+    mask: &'a [u32],
+    compare: bool,
+}
+
+fn bit(words: &[u32], i: u32) -> bool {
+    words[(i / 32) as usize] >> (i % 32) & 1 != 0
+}
+
+fn set_bit(words: &mut [u32], i: u32, value: bool) {
+    let word = &mut words[(i / 32) as usize];
+    *word = if value { *word | 1 << (i % 32) } else { *word & !(1 << (i % 32)) };
+}
+
+fn nibble(words: &[u32], j: usize) -> u32 {
+    words[j / 8] >> (j % 8 * 4) & 0xF
+}
+
+fn set_nibble(words: &mut [u32], j: usize, value: u32) {
+    let word = &mut words[j / 8];
+    *word = *word & !(0xF << (j % 8 * 4)) | value << (j % 8 * 4);
+}
+
+// Up to 32 bits from bit i
+fn bits(words: &[u32], i: u32, len: u32) -> u32 {
+    (0..len.min(32)).fold(0, |word, j| word | (bit(words, i + j) as u32) << j)
+}
+
+fn svf_state(word: &str) -> Option<TapState> {
+    const NAMES: [(&str, TapState); 16] = [
+        ("RESET", TapState::TestLogicReset),
+        ("IDLE", TapState::RunTestIdle),
+        ("DRSELECT", TapState::SelectDr),
+        ("DRCAPTURE", TapState::CaptureDr),
+        ("DRSHIFT", TapState::ShiftDr),
+        ("DREXIT1", TapState::Exit1Dr),
+        ("DRPAUSE", TapState::PauseDr),
+        ("DREXIT2", TapState::Exit2Dr),
+        ("DRUPDATE", TapState::UpdateDr),
+        ("IRSELECT", TapState::SelectIr),
+        ("IRCAPTURE", TapState::CaptureIr),
+        ("IRSHIFT", TapState::ShiftIr),
+        ("IREXIT1", TapState::Exit1Ir),
+        ("IRPAUSE", TapState::PauseIr),
+        ("IREXIT2", TapState::Exit2Ir),
+        ("IRUPDATE", TapState::UpdateIr),
+    ];
+    NAMES.iter().find(|(name, _)| word.eq_ignore_ascii_case(name)).map(|(_, state)| *state)
+}
+
+// The states a scan or RUNTEST may end in
+fn stable_state(word: &str) -> Result<TapState, BridgeError> {
+    match svf_state(word) {
+        Some(state) if state.stable() && state != TapState::ShiftDr && state != TapState::ShiftIr => Ok(state),
+        _ => Err(BridgeError::SvfSyntax),
+    }
+}
+
+// A real number, RUNTEST counts and times may have a fraction and an exponent
+fn real(word: &str) -> Option<f32> {
+    if !word.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '+') {
+        return None
+    }
+    word.parse().ok()
+}
+
+fn length(word: &str) -> Result<u32, BridgeError> {
+    word.parse().map_err(|_| BridgeError::SvfSyntax)
+}
+
+// The statement being read
+#[derive(Copy, Clone, PartialEq, Debug)]
+struct Statement {
+    command: Command,
+    // Scans: the length, the field the next hex goes to, and the fields given
+    len: Option<u32>,
+    field: Option<Field>,
+    given: u8,
+    resized: bool,
+    // ENDIR, ENDDR, and the ENDSTATE of RUNTEST
+    end: Option<TapState>,
+    // STATE
+    path: [TapState; MAX_PATH],
+    path_len: usize,
+    // RUNTEST and FREQUENCY: a number waiting for its unit
+    number: Option<f32>,
+    run_state: Option<TapState>,
+    count: Option<u32>,
+    min_time: Option<f32>,
+    maximum: bool,
+    endstate: bool,
+    hz: Option<f32>,
+}
+
+impl Statement {
+    const NONE: Statement = Statement {
+        command: Command::None,
+        len: None,
+        field: None,
+        given: 0,
+        resized: false,
+        end: None,
+        path: [TapState::TestLogicReset; MAX_PATH],
+        path_len: 0,
+        number: None,
+        run_state: None,
+        count: None,
+        min_time: None,
+        maximum: false,
+        endstate: false,
+        hz: None,
+    };
+}
+
+// The scan running, its TDO bits compared as they come back
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Scan {
+    ir: bool,
+    compare: bool,
+    line: u32,
+    // TDO bits of the 32 bit word of the section coming back
+    word: u32,
+    // The first mismatch: its section, its bit, and the bits of its word
+    mismatch: Option<(usize, u32, u32)>,
+}
+
+impl Scan {
+    // TDO bit i of the shift, the header, scan and trailer bits in turn
+    fn on_tdo(&mut self, sections: &[View<'_>; 3], mut i: u32, tdo: bool) {
+        let mut k = 0;
+        while i >= sections[k].len {
+            i -= sections[k].len;
+            k += 1;
+            if k == sections.len() {
+                return
+            }
+        }
+        let view = sections[k];
+        if i.is_multiple_of(32) {
+            self.word = 0;
+        }
+        self.word |= (tdo as u32) << (i % 32);
+        let compare = if k == 1 { self.compare } else { view.compare };
+        match self.mismatch.as_mut() {
+            None if compare && bit(view.mask, i) && bit(view.tdo, i) != tdo => self.mismatch = Some((k, i, self.word)),
+            Some((section, first, got)) if *section == k && *first / 32 == i / 32 => *got = self.word,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SvfPlayer {
+    sys_hz: u32,
+    tck_hz: u32,
+    running: bool,
+    ready: bool,
+    state: Option<TapState>,
+    endir: TapState,
+    enddr: TapState,
+    run_state: TapState,
+    run_end: TapState,
+    hir: Pattern<PAD_WORDS>,
+    sir: Pattern<SCAN_WORDS>,
+    tir: Pattern<PAD_WORDS>,
+    hdr: Pattern<PAD_WORDS>,
+    sdr: Pattern<SCAN_WORDS>,
+    tdr: Pattern<PAD_WORDS>,
+    lex: Lex,
+    word: [u8; WORD_LEN],
+    word_len: usize,
+    // Hex digits of the field being read
+    nibble_count: usize,
+    line: u32,
+    statement_line: u32,
+    statement: Statement,
+    shift: Option<Shift>,
+    scan: Option<Scan>,
+    statements: u32,
+    failed: Option<BridgeError>,
+    failed_line: u32,
+    // Expected, got and mask words of a mismatch
+    out: StreamOut,
+}
+
+impl SvfPlayer {
+    // TCK starts at the JTAG default
+    pub fn new(sys_hz: u32) -> SvfPlayer {
+        let tck_hz = ClockDivisor::for_frequency(sys_hz, JTAG_DEFAULT_HZ, JTAG_CYCLES_PER_BIT)
+            .map_or(JTAG_DEFAULT_HZ, |div| div.frequency(sys_hz, JTAG_CYCLES_PER_BIT));
+        SvfPlayer {
+            sys_hz,
+            tck_hz,
+            running: false,
+            ready: false,
+            state: None,
+            endir: TapState::RunTestIdle,
+            enddr: TapState::RunTestIdle,
+            run_state: TapState::RunTestIdle,
+            run_end: TapState::RunTestIdle,
+            hir: Pattern::EMPTY,
+            sir: Pattern::EMPTY,
+            tir: Pattern::EMPTY,
+            hdr: Pattern::EMPTY,
+            sdr: Pattern::EMPTY,
+            tdr: Pattern::EMPTY,
+            lex: Lex::Space,
+            word: [0; WORD_LEN],
+            word_len: 0,
+            nibble_count: 0,
+            line: 1,
+            statement_line: 1,
+            statement: Statement::NONE,
+            shift: None,
+            scan: None,
+            statements: 0,
+            failed: None,
+            failed_line: 0,
+            out: StreamOut::new(4),
+        }
+    }
+
+    // A new run from the state the TAP is in, the TCK frequency a FREQUENCY set stays
+    pub fn start(&mut self, state: Option<TapState>) {
+        *self = SvfPlayer { state, running: true, tck_hz: self.tck_hz, ..SvfPlayer::new(self.sys_hz) };
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    // The state the TAP is left in
+    pub fn state(&self) -> Option<TapState> {
+        self.state
+    }
+
+    pub fn tck_hz(&self) -> u32 {
+        self.tck_hz
+    }
+
+    pub fn pending(&self) -> Option<u32> {
+        self.shift.as_ref()?.pending_with(|i| self.tdi_bit(i))
+    }
+
+    pub fn sent(&mut self) {
+        if let Some(shift) = self.shift.as_mut() {
+            shift.sent();
+        }
+    }
+
+    pub fn on_rx(&mut self, word: u32) {
+        let SvfPlayer { shift, scan, hir, sir, tir, hdr, sdr, tdr, .. } = self;
+        if let Some(shift) = shift.as_mut() {
+            let sections = match scan {
+                Some(Scan { ir: true, .. }) => [hir.view(), sir.view(), tir.view()],
+                _ => [hdr.view(), sdr.view(), tdr.view()],
+            };
+            shift.on_rx_with(word, |i, tdo| {
+                if let Some(scan) = scan.as_mut() {
+                    scan.on_tdo(&sections, i, tdo);
+                }
+            });
+        }
+    }
+
+    // The shift running is back and a mismatch written out, time for step
+    pub fn done(&self) -> bool {
+        self.shift.as_ref().is_none_or(Shift::done) && self.out.chunk().is_none()
+    }
+
+    // The chunk of a mismatch to write out, nothing more is read until chunk_written
+    pub fn chunk(&self) -> Option<Chunk> {
+        self.out.chunk().copied()
+    }
+
+    pub fn chunk_written(&mut self) {
+        self.out.written()
+    }
+
+    // Line of the statement that failed the run, for the error response
+    pub fn failed_line(&self) -> u32 {
+        self.failed_line
+    }
+
+    // Header, scan and trailer, in the order they are shifted
+    fn sections(&self, ir: bool) -> [View<'_>; 3] {
+        if ir {
+            [self.hir.view(), self.sir.view(), self.tir.view()]
+        }
+        else {
+            [self.hdr.view(), self.sdr.view(), self.tdr.view()]
+        }
+    }
+
+    fn tdi_bit(&self, mut i: u32) -> bool {
+        let ir = self.scan.is_some_and(|scan| scan.ir);
+        for view in self.sections(ir) {
+            if i < view.len {
+                return bit(view.tdi, i)
+            }
+            i -= view.len;
+        }
+        false
+    }
+
+    // Report the first mismatch of a scan that is back, with the 32 bit words around it
+    fn compare<W: Write>(&mut self, scan: Scan, report: &mut W) -> Result<(), BridgeError> {
+        let (k, i, got) = match scan.mismatch {
+            Some(mismatch) => mismatch,
+            None => return Ok(()),
+        };
+        let names = if scan.ir { ["HIR", "SIR", "TIR"] } else { ["HDR", "SDR", "TDR"] };
+        let view = self.sections(scan.ir)[k];
+        let start = i / 32 * 32;
+        let len = (view.len - start).min(32);
+        let (expected, mask) = (bits(view.tdo, start, len), bits(view.mask, start, len));
+        let _ = write!(report, "Line {} {}: TDO mismatch at bit {}, ", scan.line, names[k], i);
+        let _ = write!(report, "expected {:#010x} got {:#010x} mask {:#010x}\n\r", expected, got, mask);
+        // The console text only reaches the consoles
+        for word in [expected, got, mask] {
+            self.out.push(&word.to_le_bytes());
+        }
+        self.out.finish();
+        self.failed_line = scan.line;
+        Err(BridgeError::TdoMismatch)
+    }
+
+    // Go on once the shift running is back or more input came in, reading statements until one needs the state
+    // machine. Mismatches and bad statements are reported on the console with their line
+    pub fn step<W: Write>(&mut self, input: &mut StreamIn, report: &mut W) -> SvfStep {
+        if !self.ready {
+            self.ready = true;
+            let _ = write!(report, "SVF ready, end with Ctrl-D\n\r");
+        }
+        if let Some(shift) = self.shift.take() {
+            self.state = Some(shift.end_state());
+            if let Some(scan) = self.scan.take() {
+                if let Err(err) = self.compare(scan, report) {
+                    self.failed = Some(err);
+                    // The mismatch goes out before the response
+                    return SvfStep::Next
+                }
+            }
+        }
+        if input.overrun() && self.failed.is_none() {
+            let _ = write!(report, "Line {}: SVF input overrun, the host must follow XON/XOFF\n\r", self.line);
+            self.failed = Some(BridgeError::QueueFull);
+            self.failed_line = self.line;
+        }
+        while let Some(byte) = input.pop() {
+            // The rest of a failed run is dropped
+            if self.failed.is_some() {
+                continue
+            }
+            match self.read(byte) {
+                Ok(None) => {}
+                Ok(Some(step)) => return step,
+                Err(err) => self.fail(err, report),
+            }
+        }
+        if !input.ended() {
+            return SvfStep::Input
+        }
+        if self.failed.is_none() && (self.lex != Lex::Space && self.lex != Lex::Comment
+            || self.statement.command != Command::None) {
+            self.fail(BridgeError::SvfSyntax, report);
+        }
+        self.running = false;
+        match self.failed {
+            Some(err) => SvfStep::Done(Err(err)),
+            None => SvfStep::Done(Ok((self.statements, 4))),
+        }
+    }
+
+    fn fail<W: Write>(&mut self, err: BridgeError, report: &mut W) {
+        let _ = write!(report, "Line {}: {}\n\r", self.statement_line, err);
+        self.failed = Some(err);
+        self.failed_line = self.statement_line;
+    }
+
+    // A byte of input, a statement that needs the state machine once its ';' is read
+    fn read(&mut self, byte: u8) -> Result<Option<SvfStep>, BridgeError> {
+        if byte == b'\n' {
+            self.line += 1;
+        }
+        match self.lex {
+            Lex::Comment => {
+                if byte == b'\n' {
+                    self.lex = Lex::Space;
+                }
+                return Ok(None)
+            }
+            Lex::Slash if byte == b'/' => {
+                self.lex = Lex::Comment;
+                return Ok(None)
+            }
+            Lex::Slash => return Err(BridgeError::SvfSyntax),
+            // Hex may run over several lines
+            Lex::Hex => {
+                match byte {
+                    b')' => {
+                        self.lex = Lex::Space;
+                        self.hex_done()?;
+                    }
+                    b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                        let digit = (byte as char).to_digit(16).unwrap_or(0);
+                        let count = self.nibble_count;
+                        if let Some(words) = self.field_words() {
+                            if count == 8 * words.len() {
+                                return Err(BridgeError::OutOfRange)
+                            }
+                            set_nibble(words, count, digit);
+                        }
+                        self.nibble_count += 1;
+                    }
+                    _ if byte.is_ascii_whitespace() => {}
+                    _ => return Err(BridgeError::SvfSyntax),
+                }
+                return Ok(None)
+            }
+            Lex::Space | Lex::Word => {}
+        }
+        match byte {
+            _ if byte.is_ascii_whitespace() => self.word_done()?,
+            b'!' => {
+                self.word_done()?;
+                self.lex = Lex::Comment;
+            }
+            b'/' => {
+                self.word_done()?;
+                self.lex = Lex::Slash;
+            }
+            b'(' => {
+                self.word_done()?;
+                if self.statement.field.is_none() {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                self.nibble_count = 0;
+                if let Some(words) = self.field_words() {
+                    words.fill(0);
+                }
+                self.lex = Lex::Hex;
+            }
+            b';' => {
+                self.word_done()?;
+                let step = self.run_statement();
+                self.statement = Statement::NONE;
+                return step
+            }
+            _ => {
+                if self.lex == Lex::Space {
+                    if self.statement.command == Command::None {
+                        self.statement_line = self.line;
+                    }
+                    self.word_len = 0;
+                    self.lex = Lex::Word;
+                }
+                if self.word_len == WORD_LEN {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                self.word[self.word_len] = byte;
+                self.word_len += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    fn word_done(&mut self) -> Result<(), BridgeError> {
+        if self.lex != Lex::Word {
+            return Ok(())
+        }
+        self.lex = Lex::Space;
+        let word = self.word;
+        let word = core::str::from_utf8(&word[..self.word_len]).map_err(|_| BridgeError::SvfSyntax)?;
+        self.on_word(word)
+    }
+
+    fn pattern_len(&mut self, reg: Reg, len: u32) -> Result<bool, BridgeError> {
+        let max = if matches!(reg, Reg::Sir | Reg::Sdr) { MAX_SVF_BITS } else { MAX_PAD_BITS };
+        if len > max {
+            return Err(BridgeError::OutOfRange)
+        }
+        Ok(match reg {
+            Reg::Hir => self.hir.set_len(len),
+            Reg::Sir => self.sir.set_len(len),
+            Reg::Tir => self.tir.set_len(len),
+            Reg::Hdr => self.hdr.set_len(len),
+            Reg::Sdr => self.sdr.set_len(len),
+            Reg::Tdr => self.tdr.set_len(len),
+        })
+    }
+
+    fn on_word(&mut self, word: &str) -> Result<(), BridgeError> {
+        let statement = &mut self.statement;
+        match statement.command {
+            Command::None => {
+                const COMMANDS: [(&str, Command); 12] = [
+                    ("SIR", Command::Scan(Reg::Sir)),
+                    ("SDR", Command::Scan(Reg::Sdr)),
+                    ("HIR", Command::Scan(Reg::Hir)),
+                    ("HDR", Command::Scan(Reg::Hdr)),
+                    ("TIR", Command::Scan(Reg::Tir)),
+                    ("TDR", Command::Scan(Reg::Tdr)),
+                    ("ENDIR", Command::EndIr),
+                    ("ENDDR", Command::EndDr),
+                    ("STATE", Command::State),
+                    ("RUNTEST", Command::RunTest),
+                    ("FREQUENCY", Command::Frequency),
+                    ("TRST", Command::Trst),
+                ];
+                statement.command = COMMANDS.iter().find(|(name, _)| word.eq_ignore_ascii_case(name))
+                    .map(|(_, command)| *command)
+                    .ok_or(BridgeError::SvfSyntax)?;
+            }
+            Command::Scan(reg) => match (statement.len, statement.field) {
+                (None, _) => {
+                    let len = length(word)?;
+                    statement.len = Some(len);
+                    self.statement.resized = self.pattern_len(reg, len)?;
+                }
+                (Some(_), None) => {
+                    const FIELDS: [(&str, Field); 4] =
+                        [("TDI", Field::Tdi), ("TDO", Field::Tdo), ("MASK", Field::Mask), ("SMASK", Field::Smask)];
+                    let field = FIELDS.iter().find(|(name, _)| word.eq_ignore_ascii_case(name))
+                        .map(|(_, field)| *field)
+                        .ok_or(BridgeError::SvfSyntax)?;
+                    if statement.given & field.bit() != 0 {
+                        return Err(BridgeError::SvfSyntax)
+                    }
+                    statement.field = Some(field);
+                }
+                // The hex of a field
+                (Some(_), Some(_)) => return Err(BridgeError::SvfSyntax),
+            },
+            Command::EndIr | Command::EndDr if statement.end.is_none() => statement.end = Some(stable_state(word)?),
+            Command::State if statement.path_len < MAX_PATH => {
+                statement.path[statement.path_len] = svf_state(word).ok_or(BridgeError::SvfSyntax)?;
+                statement.path_len += 1;
+            }
+            Command::RunTest => {
+                if statement.endstate {
+                    statement.endstate = false;
+                    statement.end = Some(stable_state(word)?);
+                }
+                else if let Some(number) = real(word) {
+                    if statement.number.replace(number).is_some() {
+                        return Err(BridgeError::SvfSyntax)
+                    }
+                }
+                else if word.eq_ignore_ascii_case("TCK") || word.eq_ignore_ascii_case("SCK") {
+                    // Counts of the system clock are clocked as TCKs
+                    let count = statement.number.take().ok_or(BridgeError::SvfSyntax)?;
+                    statement.count = Some(count as u32);
+                }
+                else if word.eq_ignore_ascii_case("SEC") {
+                    let time = statement.number.take().ok_or(BridgeError::SvfSyntax)?;
+                    // The longest time a device may need is not a limit here
+                    if !core::mem::take(&mut statement.maximum) {
+                        statement.min_time = Some(time);
+                    }
+                }
+                else if word.eq_ignore_ascii_case("MAXIMUM") {
+                    statement.maximum = true;
+                }
+                else if word.eq_ignore_ascii_case("ENDSTATE") {
+                    statement.endstate = true;
+                }
+                else if statement.count.is_none() && statement.min_time.is_none() && statement.run_state.is_none() {
+                    statement.run_state = Some(stable_state(word)?);
+                }
+                else {
+                    return Err(BridgeError::SvfSyntax)
+                }
+            }
+            Command::Frequency => {
+                if let Some(number) = real(word) {
+                    if statement.number.replace(number).is_some() {
+                        return Err(BridgeError::SvfSyntax)
+                    }
+                }
+                else if word.eq_ignore_ascii_case("HZ") && statement.hz.is_none() {
+                    statement.hz = Some(statement.number.take().ok_or(BridgeError::SvfSyntax)?);
+                }
+                else {
+                    return Err(BridgeError::SvfSyntax)
+                }
+            }
+            // No TRST pin, the statement is read and nothing done
+            Command::Trst => {}
+            _ => return Err(BridgeError::SvfSyntax),
+        }
+        Ok(())
+    }
+
+    // The bits of the field the hex being read goes to, None for SMASK
+    fn field_words(&mut self) -> Option<&mut [u32]> {
+        let (reg, field) = match (self.statement.command, self.statement.field) {
+            (Command::Scan(reg), Some(field)) => (reg, field),
+            _ => return None,
+        };
+        match reg {
+            Reg::Hir => self.hir.field(field),
+            Reg::Sir => self.sir.field(field),
+            Reg::Tir => self.tir.field(field),
+            Reg::Hdr => self.hdr.field(field),
+            Reg::Sdr => self.sdr.field(field),
+            Reg::Tdr => self.tdr.field(field),
+        }
+    }
+
+    // The digits of a field went in first at the lowest nibble, turn them around so the last is the least significant,
+    // and drop the bits past the length
+    fn hex_done(&mut self) -> Result<(), BridgeError> {
+        let (field, len) = match (self.statement.command, self.statement.field) {
+            (Command::Scan(_), Some(field)) => (field, self.statement.len.unwrap_or(0)),
+            _ => return Err(BridgeError::SvfSyntax),
+        };
+        let count = self.nibble_count;
+        if let Some(words) = self.field_words() {
+            for j in 0..count / 2 {
+                let (low, high) = (nibble(words, j), nibble(words, count - 1 - j));
+                set_nibble(words, j, high);
+                set_nibble(words, count - 1 - j, low);
+            }
+            for i in len..4 * count as u32 {
+                set_bit(words, i, false);
+            }
+        }
+        self.statement.field = None;
+        self.statement.given |= field.bit();
+        Ok(())
+    }
+
+    fn run_statement(&mut self) -> Result<Option<SvfStep>, BridgeError> {
+        let statement = self.statement;
+        if statement.command != Command::None {
+            self.statements += 1;
+        }
+        match statement.command {
+            Command::None | Command::Trst => Ok(None),
+            Command::Scan(reg) => {
+                let len = statement.len.ok_or(BridgeError::SvfSyntax)?;
+                // A new length needs its TDI bits
+                let tdi = statement.given & Field::Tdi.bit() != 0;
+                if statement.field.is_some() || statement.resized && len > 0 && !tdi {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                let tdo = statement.given & Field::Tdo.bit() != 0;
+                let ir = match reg {
+                    Reg::Sir => true,
+                    Reg::Sdr => false,
+                    Reg::Hir => {
+                        self.hir.compare |= tdo;
+                        return Ok(None)
+                    }
+                    Reg::Tir => {
+                        self.tir.compare |= tdo;
+                        return Ok(None)
+                    }
+                    Reg::Hdr => {
+                        self.hdr.compare |= tdo;
+                        return Ok(None)
+                    }
+                    Reg::Tdr => {
+                        self.tdr.compare |= tdo;
+                        return Ok(None)
+                    }
+                };
+                let total = self.sections(ir).iter().map(|view| view.len).sum::<u32>();
+                if total == 0 {
+                    return Ok(None)
+                }
+                let (shift, end) = if ir { (TapState::ShiftIr, self.endir) } else { (TapState::ShiftDr, self.enddr) };
+                self.shift = Some(Shift::scan(self.state, shift, total, end)?.quiet());
+                self.scan = Some(Scan { ir, compare: tdo, line: self.statement_line, word: 0, mismatch: None });
+                Ok(Some(SvfStep::Next))
+            }
+            Command::EndIr => {
+                self.endir = statement.end.ok_or(BridgeError::SvfSyntax)?;
+                Ok(None)
+            }
+            Command::EndDr => {
+                self.enddr = statement.end.ok_or(BridgeError::SvfSyntax)?;
+                Ok(None)
+            }
+            // Through each state in turn, the last a stable one
+            Command::State => {
+                let states = &statement.path[..statement.path_len];
+                let end = *states.last().ok_or(BridgeError::SvfSyntax)?;
+                if !end.stable() {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                let mut path = TmsPath::EMPTY;
+                let mut from = self.state;
+                for state in states {
+                    let step = TmsPath::between(from, *state);
+                    if path.len + step.len > 16 {
+                        return Err(BridgeError::OutOfRange)
+                    }
+                    path = path.join(step);
+                    from = Some(*state);
+                }
+                self.shift = Some(Shift::path(path, end));
+                Ok(Some(SvfStep::Next))
+            }
+            // At least the count given and as many TCKs as the time takes, in the run state
+            Command::RunTest => {
+                if statement.number.is_some() || statement.endstate
+                    || statement.count.is_none() && statement.min_time.is_none() {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                let run_state = statement.run_state.unwrap_or(self.run_state);
+                let end = statement.end.or(statement.run_state).unwrap_or(self.run_end);
+                (self.run_state, self.run_end) = (run_state, end);
+                let mut count = statement.count.unwrap_or(0);
+                if let Some(time) = statement.min_time {
+                    let tcks = time * self.tck_hz as f32;
+                    let whole = tcks as u32;
+                    count = count.max(if (whole as f32) < tcks { whole + 1 } else { whole });
+                }
+                self.shift = Some(Shift::run(self.state, run_state, count.min(MAX_RUN_TCKS), end)?);
+                Ok(Some(SvfStep::Next))
+            }
+            // The nearest divisor, up to the fastest TCK. Without a frequency back to the default
+            Command::Frequency => {
+                if statement.number.is_some() {
+                    return Err(BridgeError::SvfSyntax)
+                }
+                let fastest = self.sys_hz / JTAG_CYCLES_PER_BIT;
+                let hz = match statement.hz {
+                    Some(hz) if hz < 1.0 => return Err(BridgeError::OutOfRange),
+                    Some(hz) => (hz as u32).min(fastest),
+                    None => JTAG_DEFAULT_HZ.min(fastest),
+                };
+                let div = ClockDivisor::for_frequency(self.sys_hz, hz, JTAG_CYCLES_PER_BIT)?;
+                self.tck_hz = div.frequency(self.sys_hz, JTAG_CYCLES_PER_BIT);
+                Ok(Some(SvfStep::Divisor(div)))
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! OpenOCD remote_bitbang streams played through scans and raw TCK requests, against the same chain clocked directly.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::bitbang::{Bitbang, BitbangEvent, BitbangRequest, MAX_BATCH};
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::jtag::{JtagJob, JtagStep, TapState};
+use pico_bridge_core::protocol::host::ValidOps;
+
+mod common;
+use common::{chain_tck, input_for, Tap, IDCODE};
+
+// Devices nearest TDO first, in Run-Test/Idle as a session starts
+fn chain() -> Vec<Tap> {
+    let mut taps = vec![Tap::new(4, Some(0x4BA0_0477)), Tap::new(6, Some(0x0362_D093))];
+    for tap in &mut taps {
+        tap.state = TapState::RunTestIdle;
+    }
+    taps
+}
+
+// What the TCKs left in the chain, the TCKs padding batches in Run-Test/Idle aside
+fn held(taps: &[Tap]) -> Vec<(TapState, u32, u64, u32)> {
+    taps.iter().map(|tap| (tap.state, tap.ir, tap.shift, tap.shift_len)).collect()
+}
+
+// The firmware side: a request run on the chain from the state the last one left, with the bytes the host streams
+// after it. Returns the response and the bytes streamed to the host
+fn run_request(command: &str, tdi: &[u8], state: &mut Option<TapState>, taps: &mut [Tap])
+    -> Result<(u32, Vec<u8>), BridgeError> {
+    let mut job = JtagJob::start(&message_parse_build(command)?.init_clean()?, *state)?;
+    let mut input = input_for(job.input(), tdi);
+    let mut tdo_bytes = Vec::new();
+    loop {
+        while let Some(word) = job.pending(&mut input) {
+            job.sent();
+            let mut tdo = 0;
+            for bit in 0..16 {
+                let pair = word >> (2 * bit);
+                tdo |= (chain_tck(taps, pair & 1 != 0, pair & 2 != 0) as u32) << (16 + bit);
+            }
+            job.on_rx(tdo);
+            if let Some(chunk) = job.chunk() {
+                tdo_bytes.extend_from_slice(chunk.bytes());
+                job.chunk_written();
+            }
+        }
+        if let JtagStep::Done(result) = job.on_done() {
+            *state = job.state();
+            return result.map(|(tdo, _)| (tdo, tdo_bytes))
+        }
+    }
+}
+
+fn tck_request(words: [u32; 3], state: &mut Option<TapState>, taps: &mut [Tap]) -> Result<u32, BridgeError> {
+    let command = format!("jtag tck {} {:#x} {:#x}", words[0], words[1], words[2]);
+    run_request(&command, &[], state, taps).map(|(tdo, _)| tdo)
+}
+
+// A request of a batch as the host sends it, answered into the batch
+fn clock(bitbang: &mut Bitbang, request: BitbangRequest, state: &mut Option<TapState>, taps: &mut [Tap]) {
+    let mut command = String::from(match request.operation {
+        ValidOps::ShiftIr => "jtag ir",
+        ValidOps::ShiftDr => "jtag dr",
+        _ => "jtag tck",
+    });
+    for word in request.payload() {
+        command.push_str(&format!(" {:#x}", word));
+    }
+    let tdi: Vec<u8> = bitbang.input(&request).collect();
+    assert_eq!(tdi.len() as u32, request.input().unwrap_or(0));
+    let (tdo, tdo_bytes) = run_request(&command, &tdi, state, taps).unwrap();
+    bitbang.on_data(&tdo_bytes);
+    bitbang.on_tdo(tdo);
+}
+
+// What OpenOCD sends: pin writes, each TCK a falling then a rising edge, and R before the rising edge to read TDO
+#[derive(Default)]
+struct OpenOcd {
+    bytes: Vec<u8>,
+}
+
+impl OpenOcd {
+    fn write(&mut self, tck: bool, tms: bool, tdi: bool) {
+        self.bytes.push(b'0' + ((tck as u8) << 2 | (tms as u8) << 1 | tdi as u8));
+    }
+
+    fn clock(&mut self, tms: bool, tdi: bool, read: bool) {
+        self.write(false, tms, tdi);
+        if read {
+            self.bytes.push(b'R');
+        }
+        self.write(true, tms, tdi);
+    }
+
+    fn tms(&mut self, bits: &[u8]) {
+        for tms in bits {
+            self.clock(*tms != 0, false, false);
+        }
+    }
+
+    // From Run-Test/Idle through Shift-IR or Shift-DR and back, reading every bit
+    fn scan(&mut self, ir: bool, len: u32, tdi: u64) {
+        self.tms(if ir { &[1, 1, 0, 0] } else { &[1, 0, 0] });
+        for i in 0..len {
+            self.clock(i == len - 1, tdi >> i & 1 != 0, true);
+        }
+        self.tms(&[1, 0]);
+    }
+}
+
+// The stream played on a chain directly, TDO answered on the rising edge after each R
+fn reference(bytes: &[u8], taps: &mut [Tap]) -> String {
+    let (mut tck, mut sample, mut answers) = (false, false, String::new());
+    for byte in bytes {
+        match byte {
+            b'0'..=b'7' => {
+                let pins = byte - b'0';
+                if pins & 4 != 0 && !tck {
+                    let tdo = chain_tck(taps, pins & 1 != 0, pins & 2 != 0);
+                    if sample {
+                        answers.push(if tdo { '1' } else { '0' });
+                    }
+                    sample = false;
+                }
+                tck = pins & 4 != 0;
+            }
+            b'R' => sample = true,
+            _ => {}
+        }
+    }
+    answers
+}
+
+// The stream fed chunk bytes at a time, batches cut whenever a chunk ends as if OpenOCD waited there. Returns the
+// answers and the number of scan requests
+fn play(bytes: &[u8], chunk: usize, taps: &mut [Tap]) -> (String, usize) {
+    let mut bitbang = Bitbang::new();
+    let mut state = Some(TapState::RunTestIdle);
+    let mut answers = String::new();
+    let mut scans = 0;
+    let mut flush = |bitbang: &mut Bitbang, answers: &mut String| {
+        while bitbang.cut(answers) {
+            while let Some(request) = bitbang.pending() {
+                bitbang.sent();
+                scans += (request.operation != ValidOps::Tck) as usize;
+                clock(bitbang, request, &mut state, taps);
+            }
+            assert!(bitbang.done());
+            bitbang.finish(answers);
+        }
+    };
+    for bytes in bytes.chunks(chunk) {
+        for byte in bytes {
+            if bitbang.full() {
+                flush(&mut bitbang, &mut answers);
+            }
+            if bitbang.push(*byte) == BitbangEvent::Quit {
+                flush(&mut bitbang, &mut answers);
+                return (answers, scans)
+            }
+        }
+        flush(&mut bitbang, &mut answers);
+    }
+    (answers, scans)
+}
+
+fn session() -> Vec<u8> {
+    let mut openocd = OpenOcd::default();
+    // Reset, the IR capture bits, both IDCODEs, then both in BYPASS and a pattern through them
+    openocd.tms(&[1, 1, 1, 1, 1, 0]);
+    openocd.scan(true, 10, 0x3FF);
+    openocd.tms(&[1, 1, 1, 1, 1, 0]);
+    openocd.scan(false, 64, 0);
+    openocd.scan(true, 10, 0x3FF);
+    openocd.scan(false, 40, 0x5A_C3A5_0F96);
+    // A DR scan ending in Pause-DR, resumed through Exit2
+    openocd.tms(&[1, 0, 0]);
+    for i in 0..5 {
+        openocd.clock(i == 4, true, true);
+    }
+    openocd.tms(&[0, 0, 1, 0]);
+    for i in 0..5 {
+        openocd.clock(i == 4, false, true);
+    }
+    openocd.tms(&[1, 0]);
+    openocd.bytes.push(b'Q');
+    openocd.bytes
+}
+
+#[test]
+fn samples_match_the_chain_clocked_directly() {
+    let bytes = session();
+    let mut direct = chain();
+    let expected = reference(&bytes, &mut direct);
+    // The IR capture bits, nearest TDO first
+    assert!(expected.starts_with("1000100000"));
+    for chunk in (1..=40).chain([bytes.len()]) {
+        let mut taps = chain();
+        assert_eq!(play(&bytes, chunk, &mut taps).0, expected, "chunks of {}", chunk);
+        assert_eq!(held(&taps), held(&direct), "chunks of {}", chunk);
+    }
+    // In one batch every scan back to Run-Test/Idle is a scan request, the one ending in Pause-DR raw TCKs
+    assert_eq!(play(&bytes, bytes.len(), &mut chain()).1, 5);
+}
+
+#[test]
+fn long_scans_stream_their_tdi_and_tdo() {
+    let mut openocd = OpenOcd::default();
+    openocd.scan(true, 10, 0x3FF);
+    openocd.tms(&[1, 0, 0]);
+    let len = 300;
+    for i in 0..len {
+        openocd.clock(i == len - 1, i % 7 < 3, true);
+    }
+    openocd.tms(&[1, 0]);
+    let mut direct = chain();
+    let expected = reference(&openocd.bytes, &mut direct);
+    let mut taps = chain();
+    assert_eq!(play(&openocd.bytes, openocd.bytes.len(), &mut taps), (expected, 2));
+    assert_eq!(held(&taps), held(&direct));
+
+    // Past the IR scan, the first 3 words go with the DR scan and the rest of its TDI bits are streamed
+    let mut bitbang = Bitbang::new();
+    for byte in &openocd.bytes {
+        bitbang.push(*byte);
+    }
+    assert!(bitbang.cut(&mut String::new()));
+    bitbang.sent();
+    let request = bitbang.pending().unwrap();
+    assert_eq!((request.operation, request.payload().len(), request.input()), (ValidOps::ShiftDr, 4, Some(26)));
+    assert_eq!(request.payload()[..2], [len, 0x70E1_C387]);
+}
+
+#[test]
+fn long_streams_are_cut_when_the_queue_is_full() {
+    let mut openocd = OpenOcd::default();
+    openocd.scan(true, 10, 0x3FF);
+    openocd.tms(&[1, 0, 0]);
+    let len = MAX_BATCH * 3;
+    for i in 0..len {
+        openocd.clock(i == len - 1, i % 3 == 0, true);
+    }
+    openocd.tms(&[1, 0]);
+    let mut direct = chain();
+    let expected = reference(&openocd.bytes, &mut direct);
+    let mut taps = chain();
+    assert_eq!(play(&openocd.bytes, openocd.bytes.len(), &mut taps).0, expected);
+    assert_eq!(held(&taps), held(&direct));
+}
+
+#[test]
+fn loops_through_update_are_cut_in_run_test_idle() {
+    // Round Select-DR, Capture-DR, Exit1-DR and Update-DR past a full queue, reading TDO on the way, then the IDCODEs
+    let mut openocd = OpenOcd::default();
+    openocd.tms(&[1]);
+    for i in 0..2 * MAX_BATCH {
+        openocd.clock(i % 4 != 0, false, i % 5 == 0);
+    }
+    openocd.tms(&[0, 1, 1, 0]);
+    openocd.scan(false, 64, 0);
+    let mut direct = chain();
+    let expected = reference(&openocd.bytes, &mut direct);
+    for chunk in [7, openocd.bytes.len()] {
+        let mut taps = chain();
+        assert_eq!(play(&openocd.bytes, chunk, &mut taps).0, expected, "chunks of {}", chunk);
+        assert_eq!(held(&taps), held(&direct), "chunks of {}", chunk);
+    }
+}
+
+#[test]
+fn samples_with_no_cut_after_them_are_answered_at_once() {
+    // Into Capture-DR reading TDO, then OpenOCD waits for the sample
+    let mut openocd = OpenOcd::default();
+    openocd.tms(&[1]);
+    openocd.clock(false, false, true);
+    let mut bitbang = Bitbang::new();
+    for byte in &openocd.bytes {
+        bitbang.push(*byte);
+    }
+    let mut answers = String::new();
+    assert!(!bitbang.cut(&mut answers));
+    assert_eq!(answers, "0");
+
+    // The TCKs go out with the next cut, the sample is not answered again
+    openocd.bytes.clear();
+    openocd.tms(&[1, 1, 0]);
+    for byte in &openocd.bytes {
+        bitbang.push(*byte);
+    }
+    assert!(bitbang.cut(&mut answers));
+    while let Some(request) = bitbang.pending() {
+        bitbang.sent();
+        clock(&mut bitbang, request, &mut Some(TapState::RunTestIdle), &mut chain());
+    }
+    bitbang.finish(&mut answers);
+    assert_eq!(answers, "0");
+    assert!(!bitbang.cut(&mut answers));
+}
+
+#[test]
+fn trst_resets_the_tap_with_tms() {
+    let mut openocd = OpenOcd::default();
+    openocd.scan(true, 10, 0x3FF);
+    openocd.bytes.extend(b"tr");
+    openocd.tms(&[0]);
+    openocd.scan(false, 64, 0);
+    let mut taps = chain();
+    let (answers, _) = play(&openocd.bytes, 16, &mut taps);
+    assert_eq!(u32::from_str_radix(&answers[10..42].chars().rev().collect::<String>(), 2), Ok(0x4BA0_0477));
+    assert!(taps.iter().all(|tap| tap.ir == IDCODE));
+}
+
+#[test]
+fn raw_tcks_are_padded_only_where_it_changes_nothing() {
+    let mut taps = chain();
+    let mut state = Some(TapState::RunTestIdle);
+    // Into Shift-DR, a whole word, the IDCODE comes out from the fourth TCK
+    assert_eq!(tck_request([16, 0b001, 0], &mut state, &mut taps), Ok((0x4BA0_0477 & 0x1FFF) << 3));
+    assert_eq!(state, Some(TapState::ShiftDr));
+    // Out to Pause-DR, padded
+    assert_eq!(tck_request([3, 0b001, 0], &mut state, &mut taps), Ok(0x4BA0_0477 >> 13 & 1));
+    assert_eq!(state, Some(TapState::PauseDr));
+    // Padding in Shift-DR would shift
+    assert_eq!(tck_request([2, 0b01, 0], &mut state, &mut taps), Err(BridgeError::OutOfRange));
+    assert_eq!(tck_request([0, 0, 0], &mut state, &mut taps), Err(BridgeError::OutOfRange));
+    assert_eq!(tck_request([33, 0, 0], &mut state, &mut taps), Err(BridgeError::OutOfRange));
+
+    // From an unknown state five TMS high tell
+    let mut state = None;
+    assert_eq!(tck_request([16, 0, 0], &mut state, &mut taps), Ok(0));
+    assert_eq!(state, None);
+    assert_eq!(tck_request([6, 0b011111, 0], &mut state, &mut taps), Ok(0));
+    assert_eq!(state, Some(TapState::RunTestIdle));
+    assert_eq!(tck_request([5, 0, 0], &mut None, &mut taps), Err(BridgeError::OutOfRange));
+}
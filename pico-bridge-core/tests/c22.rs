@@ -0,0 +1,55 @@
+//! Clause 22 register names and field decoding.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::c22::{decode, register_name, register_number, BMSR, ESR, GBCR};
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::protocol::encode_smi;
+
+#[test]
+fn register_names_in_any_case() {
+    assert_eq!(register_number("bmsr"), Some(BMSR));
+    assert_eq!(register_number("Ctrl1000"), Some(GBCR));
+    assert_eq!(register_number("ESTATUS"), Some(ESR));
+    assert_eq!(register_number("BMXR"), None);
+    // Aliases print with the first name
+    assert_eq!(register_name(GBCR), Some("GBCR"));
+    assert_eq!(register_name(16), None);
+}
+
+#[test]
+fn commands_take_register_names() {
+    let hr = message_parse_build("smi r 9 BMSR ").unwrap().init_clean().unwrap();
+    assert_eq!(hr.payload[0], encode_smi(true, 9, 1, 0));
+
+    let hr = message_parse_build("smi dump 1 bmcr..bmxr ");
+    assert_eq!(hr.unwrap_err(), BridgeError::InvalidNumber);
+    let hr = message_parse_build("smi dump 1 BMCR..ANER ").unwrap();
+    assert_eq!(hr.payload[..3], [1, 0, 6]);
+    // Only register arguments take names
+    assert_eq!(message_parse_build("smi r BMSR 1 ").unwrap_err(), BridgeError::InvalidNumber);
+    assert_eq!(message_parse_build("mmd r 1 BMSR 1 ").unwrap_err(), BridgeError::InvalidNumber);
+}
+
+#[test]
+fn bmsr_fields() {
+    let mut out = String::new();
+    decode(BMSR, 0x796d, &mut out).unwrap();
+    let lines: Vec<&str> = out.split("\n\r").collect();
+    assert_eq!(lines[0], "BMSR (0x01): 0x796d");
+    assert!(lines.contains(&"  Link Status: 1"));
+    assert!(lines.contains(&"  AN Complete: 1"));
+    assert!(lines.contains(&"  100BASE-T4: 0"));
+    assert_eq!(lines.len(), 18);
+}
+
+#[test]
+fn multi_bit_fields_and_unnamed_registers() {
+    let mut out = String::new();
+    decode(3, 0xc0f1, &mut out).unwrap();
+    assert_eq!(out, "PHYID2 (0x03): 0xc0f1\n\r  OUI bits 19-24: 0x30\n\r  Model: 0xf\n\r  Revision: 0x1\n\r");
+
+    out.clear();
+    decode(0x1f, 0x1234, &mut out).unwrap();
+    assert_eq!(out, "0x1f: 0x1234\n\r");
+}
@@ -0,0 +1,37 @@
+//! PIO clock divisor calculation.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::clock::ClockDivisor;
+use pico_bridge_core::error::BridgeError;
+
+// 800 MHz VCO / 3 / 2, the system clock set up by the firmware
+const SYS_HZ: u32 = 133_333_333;
+// PIO cycles per MDC period of the SMI program
+const CYCLES: u32 = 10;
+
+#[test]
+fn divisor_for_mdc_frequencies() {
+    let div = ClockDivisor::for_frequency(SYS_HZ, 2_500_000, CYCLES).unwrap();
+    // 5.3333 in 16.8 fixed point
+    assert_eq!(div, ClockDivisor { int: 5, frac: 85 });
+    assert_eq!(div.frequency(SYS_HZ, CYCLES), 2_500_610);
+
+    let div = ClockDivisor::for_frequency(SYS_HZ, 1_000_000, CYCLES).unwrap();
+    assert_eq!((div.int, div.frac), (13, 85));
+
+    // Exact divisors report the requested frequency back
+    let div = ClockDivisor::for_frequency(125_000_000, 2_500_000, CYCLES).unwrap();
+    assert_eq!(div, ClockDivisor { int: 5, frac: 0 });
+    assert_eq!(div.frequency(125_000_000, CYCLES), 2_500_000);
+}
+
+#[test]
+fn out_of_range_frequencies_are_rejected() {
+    // Faster than one PIO cycle per program cycle
+    assert_eq!(ClockDivisor::for_frequency(SYS_HZ, 20_000_000, CYCLES), Err(BridgeError::OutOfRange));
+    assert_eq!(ClockDivisor::for_frequency(SYS_HZ, 0, CYCLES), Err(BridgeError::OutOfRange));
+    // Slower than the largest divisor
+    assert_eq!(ClockDivisor::for_frequency(SYS_HZ, 100, CYCLES), Err(BridgeError::OutOfRange));
+    // The fastest the program can run
+    assert_eq!(ClockDivisor::for_frequency(SYS_HZ, SYS_HZ / CYCLES, CYCLES).unwrap().int, 1);
+}
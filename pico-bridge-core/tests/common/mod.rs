@@ -0,0 +1,78 @@
+//! A simulated JTAG TAP, shared by the JTAG, SVF and remote_bitbang tests, and the input of the requests that take
+//! bytes from the host. Each test uses only some of it.
+#![allow(dead_code)]
+
+use pico_bridge_core::jtag::TapState;
+use pico_bridge_core::protocol::ValidHostInterfaces;
+use pico_bridge_core::stream::StreamIn;
+
+pub const IDCODE: u32 = 0xE;
+
+// One device: an IR capturing 0b01 unless set otherwise, and a 32 bit IDCODE or a BYPASS data register selected by
+// it. Devices without an IDCODE come out of reset in BYPASS
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tap {
+    pub state: TapState,
+    pub ir: u32,
+    pub ir_len: u32,
+    pub capture: u64,
+    pub shift: u64,
+    pub shift_len: u32,
+    pub idcode: Option<u32>,
+    // TCKs in Run-Test/Idle
+    pub idle: u32,
+}
+
+impl Tap {
+    pub fn new(ir_len: u32, idcode: Option<u32>) -> Tap {
+        Tap {
+            state: TapState::TestLogicReset,
+            ir: IDCODE,
+            ir_len,
+            capture: 0b01,
+            shift: 0,
+            shift_len: 0,
+            idcode,
+            idle: 0,
+        }
+    }
+
+    // A TCK: TDO is sampled and TDI shifted in on the rising edge
+    pub fn tck(&mut self, tdi: bool, tms: bool) -> bool {
+        // TDO is only driven while shifting
+        let tdo = matches!(self.state, TapState::ShiftIr | TapState::ShiftDr) && self.shift & 1 != 0;
+        match self.state {
+            TapState::TestLogicReset => self.ir = IDCODE,
+            TapState::RunTestIdle => self.idle += 1,
+            TapState::CaptureIr => (self.shift, self.shift_len) = (self.capture, self.ir_len),
+            TapState::CaptureDr => match self.idcode {
+                Some(idcode) if self.ir == IDCODE => (self.shift, self.shift_len) = (idcode as u64, 32),
+                _ => (self.shift, self.shift_len) = (0, 1),
+            },
+            TapState::ShiftIr | TapState::ShiftDr => {
+                self.shift = self.shift >> 1 | (tdi as u64) << (self.shift_len - 1);
+            }
+            TapState::UpdateIr => self.ir = self.shift as u32,
+            _ => {}
+        }
+        self.state = self.state.next(tms);
+        tdo
+    }
+}
+
+// Devices nearest TDO first, TDI goes to the last
+pub fn chain_tck(taps: &mut [Tap], tdi: bool, tms: bool) -> bool {
+    taps.iter_mut().rev().fold(tdi, |tdi, tap| tap.tck(tdi, tms))
+}
+
+// The input of a request taking len bytes from the host, holding the bytes the host sent
+pub fn input_for(len: Option<u32>, bytes: &[u8]) -> StreamIn {
+    let mut input = StreamIn::new();
+    if len.is_some() {
+        input.start(ValidHostInterfaces::Serial, 0, len);
+    }
+    for byte in bytes {
+        input.push(*byte);
+    }
+    input
+}
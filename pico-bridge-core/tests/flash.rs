@@ -0,0 +1,329 @@
+//! SPI NOR flash commands run against a simulated chip.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::flash::{crc32, FlashGeometry, FlashJob, FlashStep, VERIFY_PROGRESS};
+use pico_bridge_core::spi::{SpiConfig, MAX_CS};
+use pico_bridge_core::stream::StreamIn;
+
+mod common;
+use common::input_for;
+
+const SIZE: usize = 1 << 20;
+
+// 1 MiB chip with SFDP, 4 KiB and 64 KiB erases and 256 byte pages, busy for a few status reads after each change
+struct Chip {
+    memory: Vec<u8>,
+    sfdp: Vec<u8>,
+    command: Vec<u8>,
+    write_enabled: bool,
+    busy: u32,
+    // Status reads a change keeps the chip busy for
+    busy_reads: u32,
+}
+
+impl Chip {
+    fn new() -> Chip {
+        let mut sfdp = vec![0xFF; 0x80];
+        // Signature, revision 1.6, one parameter header, then the BFPT header: ID 0, revision 1.6, 11 dwords at 0x30
+        sfdp[..16].copy_from_slice(&[b'S', b'F', b'D', b'P', 6, 1, 0, 0xFF, 0, 6, 1, 11, 0x30, 0, 0, 0xFF]);
+        let bfpt: [u32; 11] = [0, 8 * SIZE as u32 - 1, 0, 0, 0, 0, 0, 0xD810_200C, 0, 0, 0x80];
+        for (i, dword) in bfpt.iter().enumerate() {
+            sfdp[0x30 + 4 * i..0x34 + 4 * i].copy_from_slice(&dword.to_le_bytes());
+        }
+        Chip { memory: vec![0xFF; SIZE], sfdp, command: Vec::new(), write_enabled: false, busy: 0, busy_reads: 3 }
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        self.command.push(mosi);
+        let at = self.command.len() - 1;
+        match self.command[0] {
+            0x9F if at > 0 => [0xEF, 0x40, 0x14][(at - 1).min(2)],
+            0x05 if at > 0 => {
+                self.busy = self.busy.saturating_sub(1);
+                (self.busy != 0) as u8 | (self.write_enabled as u8) << 1
+            }
+            0x03 if at > 3 => self.memory[(self.addr_of(&self.command) + at - 4) % SIZE],
+            0x5A if at > 4 => self.sfdp.get(self.addr_of(&self.command) + at - 5).copied().unwrap_or(0xFF),
+            _ => 0xFF,
+        }
+    }
+
+    fn deselect(&mut self) {
+        let command = std::mem::take(&mut self.command);
+        let erase = |size: usize| (self.addr_of(&command) & !(size - 1), size);
+        // A chip select without a command changes nothing
+        let range = match command.first().copied().unwrap_or(0xFF) {
+            0x06 => {
+                self.write_enabled = true;
+                return
+            }
+            0x02 if self.write_enabled => {
+                // Programs wrap within the page
+                let addr = self.addr_of(&command);
+                for (i, byte) in command[4..].iter().enumerate() {
+                    let at = addr & !0xFF | (addr + i) & 0xFF;
+                    self.memory[at] &= byte;
+                }
+                None
+            }
+            0x20 if self.write_enabled => Some(erase(0x1000)),
+            0xD8 if self.write_enabled => Some(erase(0x10000)),
+            0xC7 if self.write_enabled => Some((0, SIZE)),
+            _ => return,
+        };
+        if let Some((start, size)) = range {
+            self.memory[start..start + size].fill(0xFF);
+        }
+        self.write_enabled = false;
+        self.busy = self.busy_reads;
+    }
+
+    fn addr_of(&self, command: &[u8]) -> usize {
+        u32::from_be_bytes([0, command[1], command[2], command[3]]) as usize
+    }
+}
+
+fn job(command: &str, chips: &[FlashGeometry; MAX_CS]) -> Result<FlashJob, BridgeError> {
+    FlashJob::start(&message_parse_build(command)?.init_clean()?, SpiConfig::DEFAULT, chips)
+}
+
+// Clock the bytes of the command the job has ready
+fn exchange(job: &mut FlashJob, chip: &mut Chip, input: &mut StreamIn) {
+    while let Some(word) = job.pending(input) {
+        job.sent();
+        job.on_rx(chip.exchange((word >> 24) as u8) as u32);
+    }
+}
+
+// Run a job to the end against the chip, returning its result, the console output with the chunks of a read as the
+// consoles get them, and the waits between commands
+fn run(job: &mut FlashJob, chip: &mut Chip, input: &mut StreamIn) -> (Result<(u32, u8), BridgeError>, String, u32) {
+    let mut console = String::new();
+    let mut now_us = 0;
+    let mut waits = 0;
+    loop {
+        while !job.command_done() {
+            exchange(job, chip, input);
+            if job.line(&mut console) {
+                job.line_written();
+            }
+            if let Some(chunk) = job.chunk() {
+                console.push_str(chunk.format_text().as_str());
+                job.chunk_written();
+            }
+        }
+        chip.deselect();
+        match job.next(now_us, &mut console) {
+            FlashStep::Next => {}
+            FlashStep::Wait(us) => {
+                now_us += us as u64;
+                waits += 1;
+            }
+            FlashStep::Done(result) => return (result, console, waits),
+        }
+    }
+}
+
+#[test]
+fn crc32_matches_zlib() {
+    assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+}
+
+#[test]
+fn id_learns_the_geometry_from_sfdp() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    let mut id = job("flash id 2", &chips).unwrap();
+    assert_eq!(id.cs(), 2);
+    let (result, console, _) = run(&mut id, &mut chip, &mut StreamIn::new());
+    assert_eq!(result, Ok((0xEF4014, 3)));
+    assert_eq!(console, "JEDEC ID 0xef4014\n\r1 MiB, 256 byte pages, erase 4 KiB 0x20, 64 KiB 0xd8\n\r");
+    let geometry = id.geometry();
+    assert_eq!(geometry.size, SIZE as u32);
+    assert_eq!(geometry.erase_opcode(0x8000), None);
+
+    // Without SFDP the geometry stays as it was
+    chip.sfdp.fill(0xFF);
+    let mut id = job("flash id 0", &chips).unwrap();
+    let (_, console, _) = run(&mut id, &mut chip, &mut StreamIn::new());
+    assert_eq!(console, "JEDEC ID 0xef4014\n\rNo SFDP\n\r");
+    assert_eq!(id.geometry(), FlashGeometry::DEFAULT);
+}
+
+#[test]
+fn reads_are_streamed_a_chunk_at_a_time() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    for (i, byte) in chip.memory[0x100..0x114].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let mut input = StreamIn::new();
+    let mut read = job("flash read 0 0x100 20", &chips).unwrap();
+    assert_eq!(read.input(), None);
+    // The chunk must be written out before more is read
+    exchange(&mut read, &mut chip, &mut input);
+    let chunk = *read.chunk().unwrap();
+    assert_eq!((chunk.seq, chunk.offset), (0, 0));
+    assert_eq!(chunk.bytes(), (0..12).collect::<Vec<u8>>());
+    assert!(read.pending(&mut input).is_none());
+    let (result, console, _) = run(&mut read, &mut chip, &mut input);
+    // The count of bytes streamed
+    assert_eq!(result, Ok((20, 4)));
+    assert_eq!(console, "000000: 00 01 02 03 04 05 06 07 08 09 0a 0b\n\r00000c: 0c 0d 0e 0f 10 11 12 13\n\r");
+
+    // Short reads are returned packed
+    let mut read = job("flash read 0 0x102 3", &chips).unwrap();
+    let (result, console, _) = run(&mut read, &mut chip, &mut input);
+    assert_eq!(result, Ok((0x04_0302, 3)));
+    assert_eq!(console, "");
+}
+
+#[test]
+fn writes_split_at_page_boundaries_and_poll_until_done() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    let mut write = job("flash write 1 0x1fd 6", &chips).unwrap();
+    assert_eq!(write.input(), Some(6));
+    let mut input = input_for(write.input(), &[1, 2, 3, 4, 5, 6]);
+    let (result, console, waits) = run(&mut write, &mut chip, &mut input);
+    assert_eq!(result, Ok((0, 0)));
+    assert_eq!(console, "Programmed 6 bytes at 0x0001fd\n\r");
+    // Two page programs, each busy for two status reads
+    assert_eq!(waits, 4);
+    assert_eq!(chip.memory[0x1FC..0x204], [0xFF, 1, 2, 3, 4, 5, 6, 0xFF]);
+
+    let crc = crc32(0, &[1, 2, 3, 4, 5, 6]);
+    let mut verify = job(&format!("flash verify 1 0x1fd 6 {:#x}", crc), &chips).unwrap();
+    let (result, console, _) = run(&mut verify, &mut chip, &mut input);
+    assert_eq!(result, Ok((crc, 4)));
+    assert_eq!(console, format!("Verified 0x000006 of 0x000006\n\rCRC-32 0x{:08x}\n\r", crc));
+    let mut verify = job("flash verify 1 0x1fd 6 0", &chips).unwrap();
+    assert_eq!(run(&mut verify, &mut chip, &mut input).0, Err(BridgeError::VerifyMismatch));
+}
+
+#[test]
+fn page_programs_wait_for_their_bytes() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    let mut write = job("flash write 0 0xfe 4", &chips).unwrap();
+    let mut input = input_for(write.input(), &[0x11]);
+    // The first page program takes 2 bytes, nothing is sent until both are in
+    assert!(write.starting() && write.pending(&mut input).is_none());
+    input.push(0x22);
+    exchange(&mut write, &mut chip, &mut input);
+    assert!(write.command_done());
+    input.push(0x33);
+    input.push(0x44);
+    let (result, _, _) = run(&mut write, &mut chip, &mut input);
+    assert_eq!(result, Ok((0, 0)));
+    assert_eq!(chip.memory[0xFE..0x102], [0x11, 0x22, 0x33, 0x44]);
+    assert!(input.ended());
+}
+
+#[test]
+fn long_writes_list_their_progress() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    chip.busy_reads = 1;
+    let len = 2 * VERIFY_PROGRESS + 0x100;
+    let mut write = job(&format!("flash write 0 0x80 {:#x}", len), &chips).unwrap();
+    // The host sends more as the input drains
+    let mut input = input_for(write.input(), &[]);
+    let mut fed = 0;
+    let mut console = String::new();
+    let result = loop {
+        while !write.command_done() {
+            while fed < len && input.len() < 0x400 {
+                input.push(fed as u8);
+                fed += 1;
+            }
+            exchange(&mut write, &mut chip, &mut input);
+        }
+        chip.deselect();
+        if let FlashStep::Done(result) = write.next(0, &mut console) {
+            break result
+        }
+    };
+    assert_eq!(result, Ok((0, 0)));
+    // A line each time the bytes programmed pass a multiple of VERIFY_PROGRESS, the first page being a short one
+    assert_eq!(console, "Programmed 0x010080 of 0x020100\n\rProgrammed 0x020080 of 0x020100\n\r\
+        Programmed 131328 bytes at 0x000080\n\r");
+    assert!(chip.memory[0x80..0x80 + len as usize].iter().enumerate().all(|(i, byte)| *byte == i as u8));
+}
+
+#[test]
+fn an_overrun_input_fails_the_write() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    let mut write = job("flash write 0 0 0x2000", &chips).unwrap();
+    let mut input = input_for(write.input(), &[0x5A; 0x1001]);
+    assert!(input.overrun());
+    let (result, _, _) = run(&mut write, &mut chip, &mut input);
+    assert_eq!(result, Err(BridgeError::QueueFull));
+    // Nothing was programmed
+    assert!(chip.memory.iter().all(|byte| *byte == 0xFF));
+}
+
+#[test]
+fn erases_take_the_sizes_of_the_chip() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    chip.memory[0x1000..0x3000].fill(0);
+    let mut erase = job("flash erase 0 0x1000 4096", &chips).unwrap();
+    let (result, console, _) = run(&mut erase, &mut chip, &mut StreamIn::new());
+    assert_eq!(result, Ok((0, 0)));
+    assert!(console.starts_with("Erased 4 KiB at 0x001000 in "));
+    assert!(chip.memory[0x1000..0x2000].iter().all(|byte| *byte == 0xFF));
+    assert!(chip.memory[0x2000..0x3000].iter().all(|byte| *byte == 0));
+
+    let mut erase = job("flash erase 0", &chips).unwrap();
+    assert_eq!(run(&mut erase, &mut chip, &mut StreamIn::new()).0, Ok((0, 0)));
+    assert!(chip.memory.iter().all(|byte| *byte == 0xFF));
+
+    // Unaligned, or a size the chip does not erase
+    assert_eq!(job("flash erase 0 0x800 4096", &chips).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("flash erase 0 0 0x2000", &chips).unwrap_err(), BridgeError::OutOfRange);
+}
+
+#[test]
+fn a_chip_that_stays_busy_times_out() {
+    let chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let mut chip = Chip::new();
+    chip.busy_reads = u32::MAX;
+    let mut write = job("flash w 0 0 1", &chips).unwrap();
+    let mut input = input_for(write.input(), &[0]);
+    let (result, _, waits) = run(&mut write, &mut chip, &mut input);
+    assert_eq!(result, Err(BridgeError::Timeout));
+    // Polled every 100 us for 100 ms
+    assert_eq!(waits, 1000);
+}
+
+#[test]
+fn arguments_are_checked() {
+    let mut chips = [FlashGeometry::DEFAULT; MAX_CS];
+    let parse = |command: &str| message_parse_build(command).and_then(|hr| hr.init_clean()).map(|_| ());
+    assert_eq!(parse("flash id"), Err(BridgeError::BadArgCount));
+    assert_eq!(parse("flash id 4"), Err(BridgeError::OutOfRange));
+    assert_eq!(parse("flash read 0 0xfffff0 0x20"), Err(BridgeError::OutOfRange));
+    assert_eq!(parse("flash write 0 0x100"), Err(BridgeError::BadArgCount));
+    assert_eq!(parse("flash write 0 0x100 0"), Err(BridgeError::OutOfRange));
+    assert_eq!(parse("flash write 0 0xffff00 0x101"), Err(BridgeError::OutOfRange));
+    assert_eq!(parse("flash write 0 0x100 1 2"), Err(BridgeError::BadArgCount));
+    assert_eq!(parse("flash verify 0 0 16"), Err(BridgeError::BadArgCount));
+    assert_eq!(parse("flash xfer 0 1"), Err(BridgeError::InvalidOperation));
+    // Reads past the end of a chip of known size
+    chips[1].size = 0x1000;
+    assert_eq!(job("flash read 1 0xff0 0x20", &chips).unwrap_err(), BridgeError::OutOfRange);
+    assert!(job("flash read 0 0xff0 0x20", &chips).is_ok());
+    // Flash needs 8 bit words, MSB first, in mode 0 or 3
+    let hr = message_parse_build("flash id 0").unwrap().init_clean().unwrap();
+    assert_eq!(FlashJob::start(&hr, SpiConfig::new(0, 16, 0).unwrap(), &chips).unwrap_err(), BridgeError::OutOfRange);
+    for mode in [1, 2] {
+        let config = SpiConfig::new(mode, 8, 0).unwrap();
+        assert_eq!(FlashJob::start(&hr, config, &chips).unwrap_err(), BridgeError::OutOfRange);
+    }
+    assert!(FlashJob::start(&hr, SpiConfig::new(3, 8, 0).unwrap(), &chips).is_ok());
+}
@@ -0,0 +1,153 @@
+//! Binary USB serial framing: COBS, CRC-16 and request/response frame layout
+
+use pico_bridge_core::frame::{cobs_decode, cobs_encode, crc16, decode_data, decode_response, encode_data, encode_request,
+    encode_response, encode_status, FrameEvent, FrameReader, Response, BINARY_MODE_EXIT, MAX_ENCODED_FRAME};
+use pico_bridge_core::stream::StreamOut;
+use pico_bridge_core::protocol::ValidHostInterfaces;
+use pico_bridge_core::protocol::host::{ValidInterfaces, ValidOps};
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::protocol::slave::SlaveResponse;
+
+#[test]
+fn crc16_ccitt_false_check_value() {
+    assert_eq!(crc16(b"123456789"), 0x29B1);
+}
+
+#[test]
+fn cobs_round_trip() {
+    let mut encoded = [0_u8; 300];
+    let mut decoded = [0_u8; 300];
+
+    let len = cobs_encode(&[0x11, 0x00, 0x00, 0x22], &mut encoded).unwrap();
+    assert_eq!(&encoded[..len], &[0x02, 0x11, 0x01, 0x02, 0x22]);
+    let len = cobs_decode(&encoded[..len], &mut decoded).unwrap();
+    assert_eq!(&decoded[..len], &[0x11, 0x00, 0x00, 0x22]);
+
+    // Runs longer than 254 non-zero bytes need an extra code byte
+    let long = [0x5A_u8; 260];
+    let len = cobs_encode(&long, &mut encoded).unwrap();
+    assert!(!encoded[..len].contains(&0));
+    let len = cobs_decode(&encoded[..len], &mut decoded).unwrap();
+    assert_eq!(&decoded[..len], &long[..]);
+
+    assert!(cobs_decode(&[0x03, 0x11], &mut decoded).is_none());
+}
+
+fn feed(reader: &mut FrameReader, bytes: &[u8]) -> Option<FrameEvent> {
+    let mut event = None;
+    for byte in bytes {
+        if let Some(e) = reader.push(*byte) {
+            event = Some(e);
+        }
+    }
+    event
+}
+
+#[test]
+fn request_frame_round_trip() {
+    let mut out = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_request(7, ValidInterfaces::SMI, ValidOps::Read, &[9, 1], &mut out);
+    assert_eq!(out[len - 1], 0);
+    assert!(!out[..len - 1].contains(&0));
+
+    let mut reader = FrameReader::new();
+    match feed(&mut reader, &out[..len]) {
+        Some(FrameEvent::Request(hr)) => {
+            assert_eq!(hr.interface, ValidInterfaces::SMI);
+            assert_eq!(hr.operation, ValidOps::Read);
+            assert_eq!(hr.size, 2);
+            assert_eq!(hr.payload, [9, 1, 0, 0]);
+            assert!(hr.init_clean().is_ok());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn corrupted_frames_are_rejected() {
+    let mut out = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_request(7, ValidInterfaces::SMI, ValidOps::Write, &[9, 1, 0xAA55], &mut out);
+    // Corrupt the length byte, the CRC is checked before any field
+    out[4] ^= 0x10;
+    let mut reader = FrameReader::new();
+    match feed(&mut reader, &out[..len]) {
+        Some(FrameEvent::Rejected { proc_id: 7, error: BridgeError::ChecksumMismatch }) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // An oversized frame is dropped and the reader recovers on the next delimiter
+    let garbage = [0x42_u8; 2 * MAX_ENCODED_FRAME];
+    assert!(feed(&mut reader, &garbage).is_none());
+    assert!(matches!(feed(&mut reader, &[0]), Some(FrameEvent::Rejected { error: BridgeError::InvalidFrame, .. })));
+    let len = encode_request(8, ValidInterfaces::GPIO, ValidOps::Write, &[1], &mut out);
+    assert!(matches!(feed(&mut reader, &out[..len]), Some(FrameEvent::Request(_))));
+
+    // Exit frame
+    assert!(matches!(feed(&mut reader, &[0x02, BINARY_MODE_EXIT, 0x00]), Some(FrameEvent::ExitBinary)));
+}
+
+#[test]
+fn response_frame_layout() {
+    let mut sr = SlaveResponse::new();
+    sr.set_proc_id(7);
+    sr.set_host_config(ValidHostInterfaces::SerialFramed);
+    sr.set_payload(0x796D);
+    let sr = sr.init_ready().unwrap();
+
+    let mut out = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_response(&sr, &mut out);
+    let mut frame = [0_u8; MAX_ENCODED_FRAME];
+    let size = cobs_decode(&out[..len - 1], &mut frame).unwrap();
+    assert_eq!(&frame[..7], &[7, 0, 4, 0x6D, 0x79, 0, 0]);
+    assert_eq!(crc16(&frame[..7]).to_le_bytes(), [frame[7], frame[8]]);
+    assert_eq!(size, 9);
+    assert_eq!(decode_response(&frame[..size]), Ok(Response { proc_id: 7, status: None, payload: 0x796D }));
+
+    // As the host reads errors and corrupted frames
+    let len = encode_status(9, Some(BridgeError::OutOfRange), 0, &mut out);
+    let size = cobs_decode(&out[..len - 1], &mut frame).unwrap();
+    assert_eq!(decode_response(&frame[..size]).unwrap().status, Some(BridgeError::OutOfRange));
+    frame[3] ^= 1;
+    assert_eq!(decode_response(&frame[..size]), Err(BridgeError::ChecksumMismatch));
+    assert_eq!(decode_response(&frame[..size - 1]), Err(BridgeError::InvalidFrame));
+}
+
+#[test]
+fn data_frame_layout() {
+    let mut out = StreamOut::new(1);
+    out.push(&[0x11, 0x00, 0x22]);
+    out.finish();
+    let chunk = *out.chunk().unwrap();
+
+    let mut encoded = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_data(5, &chunk, &mut encoded);
+    let mut frame = [0_u8; MAX_ENCODED_FRAME];
+    let size = cobs_decode(&encoded[..len - 1], &mut frame).unwrap();
+    assert_eq!(&frame[..8], &[5, 0xFF, 3, 0, 0, 0x11, 0x00, 0x22]);
+    assert_eq!(size, 10);
+    let data = decode_data(&frame[..size]).unwrap();
+    assert_eq!((data.proc_id, data.seq, data.bytes()), (5, 0, &[0x11, 0x00, 0x22][..]));
+
+    // Data frames are not responses, and responses are not data frames
+    assert_eq!(decode_response(&frame[..size]), Err(BridgeError::InvalidFrame));
+    let len = encode_status(5, None, 0, &mut encoded);
+    let size = cobs_decode(&encoded[..len - 1], &mut frame).unwrap();
+    assert_eq!(decode_data(&frame[..size]), Err(BridgeError::InvalidFrame));
+}
+
+#[test]
+fn data_frames_from_the_host_go_to_the_input() {
+    let mut out = StreamOut::new(1);
+    out.push(&[0xA5; 12]);
+    let chunk = *out.chunk().unwrap();
+    let mut encoded = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_data(9, &chunk, &mut encoded);
+    let mut reader = FrameReader::new();
+    match feed(&mut reader, &encoded[..len]) {
+        Some(FrameEvent::Data(data)) => assert_eq!((data.proc_id, data.bytes()), (9, &[0xA5; 12][..])),
+        other => panic!("{:?}", other),
+    }
+    encoded[8] ^= 1;
+    assert!(matches!(feed(&mut reader, &encoded[..len]),
+        Some(FrameEvent::Rejected { proc_id: 9, error: BridgeError::ChecksumMismatch })));
+}
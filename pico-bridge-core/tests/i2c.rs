@@ -0,0 +1,220 @@
+//! I2C master transfers as streamed to and from the PIO I2C program.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::i2c::{I2cJob, I2cStep, I2cTransfer};
+use pico_bridge_core::stream::CHUNK_LEN;
+
+fn records(transfer: &mut I2cTransfer) -> Vec<u16> {
+    std::iter::from_fn(|| {
+        let record = transfer.pending()?;
+        transfer.sent();
+        Some(record)
+    }).collect()
+}
+
+fn transfer(command: &str) -> Result<I2cTransfer, BridgeError> {
+    I2cTransfer::from_request(&message_parse_build(command)?.init_clean()?)
+}
+
+// RX word of a byte: the bits seen on SDA and the ACK bit
+fn rx(byte: u8, ack: bool) -> u32 {
+    (byte as u32) << 1 | !ack as u32
+}
+
+#[test]
+fn write_streams_start_bytes_and_stop() {
+    let mut write = transfer("i2c w 0x50 0x00 0x10 0xab ").unwrap();
+    assert_eq!(records(&mut write), [
+        // START
+        0x0400, 0xFF80, 0xF780,
+        // Address and bytes, SDA released for the device to ACK
+        0x0141, 0x0001, 0x0021, 0x0157,
+        // STOP and the IRQ ending the transfer
+        0x0C00, 0xF780, 0xFF80, 0xFF81, 0xC010,
+    ]);
+    for byte in [0xA0, 0x00, 0x10, 0xab] {
+        write.on_rx(rx(byte, true));
+    }
+    assert_eq!(write.result(), Ok((0, 0)));
+}
+
+#[test]
+fn write_read_uses_a_repeated_start_and_acks_all_but_the_last_byte() {
+    let mut wr = transfer("i2c wr 0x50 0x10 2 ").unwrap();
+    let stream = records(&mut wr);
+    assert_eq!(stream[3..10], [0x0141, 0x0021, 0x0C00, 0xF781, 0xFF81, 0xFF80, 0xF780]);
+    assert_eq!(stream[10..13], [0x0143, 0x01FE, 0x01FF]);
+
+    for word in [rx(0xA0, true), rx(0x10, true), rx(0xA1, true), rx(0x34, true), rx(0x12, false)] {
+        wr.on_rx(word);
+    }
+    assert_eq!(wr.result(), Ok((0x1234, 2)));
+}
+
+#[test]
+fn nack_and_missing_bytes_are_errors() {
+    let mut read = transfer("i2c r 0x51 1 ").unwrap();
+    read.on_rx(rx(0xA3, false));
+    read.on_rx(rx(0xFF, false));
+    assert_eq!(read.result(), Err(BridgeError::Nack));
+
+    let mut read = transfer("i2c r 0x51 1 ").unwrap();
+    read.on_rx(rx(0xA3, true));
+    assert_eq!(read.result(), Err(BridgeError::NoResponse));
+}
+
+#[test]
+fn i2c_arguments() {
+    let hr = message_parse_build("i2c w 0x50 1 2 3 4 5 ").unwrap();
+    assert_eq!(hr.payload, [0x50, 5, 0x0403_0201, 5]);
+    // Probing an address writes no bytes
+    assert_eq!(message_parse_build("i2c w 0x50 ").unwrap().payload, [0x50, 0, 0, 0]);
+    assert_eq!(message_parse_build("i2c w 0x50 0x100 ").unwrap_err(), BridgeError::NumberTooLarge);
+    assert_eq!(message_parse_build("i2c w 0x50 1 2 3 4 5 6 7 8 9 ").unwrap_err(), BridgeError::BadArgCount);
+
+    assert_eq!(transfer("i2c r 0x80 1 ").unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(transfer("i2c r 0x50 0x10000 ").unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(transfer("i2c r 0x50 0 ").unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(transfer("i2c wr 0x50 1 ").unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn long_reads_stream_their_bytes_a_chunk_at_a_time() {
+    let mut read = job("i2c wr 0x50 0x00 20 ");
+    // START, address, register, repeated START and address, then the reads up to the first chunk
+    let stream: Vec<u16> = std::iter::from_fn(|| {
+        let record = read.pending()?;
+        read.sent();
+        Some(record)
+    }).collect();
+    assert_eq!(stream.len(), 3 + 2 + 5 + 1 + CHUNK_LEN);
+    assert!(stream[11..].iter().all(|record| *record == 0x1FE));
+    for word in [rx(0xA0, true), rx(0x00, true), rx(0xA1, true)] {
+        read.on_rx(word);
+    }
+    for byte in 0..CHUNK_LEN as u8 {
+        assert!(read.chunk().is_none());
+        read.on_rx(rx(byte, true));
+    }
+    // Nothing more is read until the full chunk is written out
+    assert_eq!(read.pending(), None);
+    let chunk = *read.chunk().unwrap();
+    assert_eq!((chunk.seq, chunk.offset), (0, 0));
+    assert_eq!(chunk.bytes(), (0..CHUNK_LEN as u8).collect::<Vec<u8>>());
+    read.chunk_written();
+
+    // The last byte read is NAKed, then STOP
+    let rest: Vec<u16> = std::iter::from_fn(|| {
+        let record = read.pending()?;
+        read.sent();
+        Some(record)
+    }).collect();
+    assert!(rest[..7].iter().all(|record| *record == 0x1FE));
+    assert_eq!(rest[7], 0x1FF);
+    assert_eq!(rest[8..], [0x0C00, 0xF780, 0xFF80, 0xFF81, 0xC010]);
+    for byte in 12..20 {
+        read.on_rx(rx(byte, byte < 19));
+    }
+    assert_eq!(read.on_irq(&mut String::new()), I2cStep::Done(Ok((20, 4))));
+    // The answer waits for the last chunk
+    assert_eq!(read.answer(), None);
+    let chunk = *read.chunk().unwrap();
+    assert_eq!((chunk.seq, chunk.offset), (1, 12));
+    assert_eq!(chunk.bytes(), (12..20).collect::<Vec<u8>>());
+    read.chunk_written();
+    assert_eq!(read.answer(), Some(Ok((20, 4))));
+}
+
+fn job(command: &str) -> I2cJob {
+    I2cJob::start(&message_parse_build(command).unwrap().init_clean().unwrap(), false).unwrap()
+}
+
+#[test]
+fn scan_probes_each_address_and_streams_the_ack_bitmap() {
+    let mut scan = job("i2c scan ");
+    let mut report = String::new();
+    let mut probed = Vec::new();
+    let mut bitmap = Vec::new();
+    let result = loop {
+        // Address record of the probe, after START
+        let stream: Vec<u16> = std::iter::from_fn(|| {
+            let record = scan.pending()?;
+            scan.sent();
+            Some(record)
+        }).collect();
+        if stream.is_empty() {
+            // No more addresses are probed until the full chunk of the bitmap is written out
+            bitmap.extend_from_slice(scan.chunk().unwrap().bytes());
+            scan.chunk_written();
+            continue;
+        }
+        let addr = (stream[3] >> 2) as u8;
+        probed.push(addr);
+        scan.on_rx(rx(addr << 1, addr == 0x50 || addr == 0x57 || addr == 0x08));
+        match scan.on_irq(&mut report) {
+            I2cStep::Next => continue,
+            I2cStep::Done(result) => break result,
+        }
+    };
+    assert_eq!(probed, (0x08..=0x77).collect::<Vec<u8>>());
+    assert_eq!(result, Ok((3, 1)));
+    assert_eq!(report, "I2C 0x08\n\rI2C 0x50\n\rI2C 0x57\n\r");
+    assert_eq!(scan.answer(), None);
+    bitmap.extend_from_slice(scan.chunk().unwrap().bytes());
+    scan.chunk_written();
+    assert_eq!(scan.answer(), Some(Ok((3, 1))));
+    // Bit n of byte k for address 8 * k + n
+    let mut expected = [0_u8; 16];
+    expected[1] = 0x01;
+    expected[10] = 0x81;
+    assert_eq!(bitmap, expected);
+
+    assert_eq!(message_parse_build("i2c scan 2 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
+
+fn job_records(job: &mut I2cJob) -> Vec<u16> {
+    std::iter::from_fn(|| {
+        let record = job.pending()?;
+        job.sent();
+        Some(record)
+    }).collect()
+}
+
+#[test]
+fn recovery_pulses_until_sda_is_released() {
+    let mut recover = job("i2c recover ");
+    // SDA sampled before the first pulse
+    assert_eq!(job_records(&mut recover), [1 << 10, 0x4001, 0x4068]);
+    // SDA low, a pulse and another sample
+    recover.on_rx(0);
+    assert_eq!(job_records(&mut recover), [3 << 10, 0xF781, 0xFF81, 0x4001, 0x4068]);
+    recover.on_rx(0);
+    assert_eq!(job_records(&mut recover).len(), 5);
+    // Released after the second pulse, no more pulses and STOP
+    recover.on_rx(0x100);
+    assert_eq!(job_records(&mut recover), [0x0C00, 0xF780, 0xFF80, 0xFF81, 0xC010]);
+    assert_eq!(recover.on_irq(&mut String::new()), I2cStep::Done(Ok((2, 1))));
+
+    let mut idle = job("i2c recover ");
+    job_records(&mut idle);
+    idle.on_rx(0x100);
+    assert_eq!(job_records(&mut idle), [0x0C00, 0xF780, 0xFF80, 0xFF81, 0xC010]);
+    assert_eq!(idle.on_irq(&mut String::new()), I2cStep::Done(Ok((0, 1))));
+
+    // Nine pulses at most
+    let mut stuck = job("i2c recover ");
+    let mut pulses = 0;
+    loop {
+        let sent = job_records(&mut stuck);
+        // STOP starts with SCL and SDA low, a pulse with SDA released
+        if sent[1] == 0xF780 {
+            break;
+        }
+        pulses += (sent[1] == 0xF781) as usize;
+        stuck.on_rx(0);
+    }
+    assert_eq!(pulses, 9);
+    assert_eq!(stuck.on_irq(&mut String::new()), I2cStep::Done(Err(BridgeError::Timeout)));
+}
@@ -0,0 +1,80 @@
+//! In-flight table matching state machine completions to their requests.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::inflight::{InFlight, StateMachine};
+use pico_bridge_core::protocol::slave::{NotReady, SlaveResponse};
+use pico_bridge_core::protocol::ValidHostInterfaces;
+
+const SMI: StateMachine = StateMachine::new(0, 0);
+const I2C: StateMachine = StateMachine::new(1, 0);
+
+fn response(proc_id: u8) -> SlaveResponse<NotReady> {
+    response_from(ValidHostInterfaces::Serial, proc_id)
+}
+
+fn response_from(host: ValidHostInterfaces, proc_id: u8) -> SlaveResponse<NotReady> {
+    let mut sr = SlaveResponse::new();
+    sr.set_host_config(host);
+    sr.set_proc_id(proc_id);
+    sr
+}
+
+const SERIAL: ValidHostInterfaces = ValidHostInterfaces::Serial;
+
+#[test]
+fn completions_match_the_oldest_request_of_their_state_machine() {
+    let mut table: InFlight<4> = InFlight::new();
+    table.insert(SMI, response(7)).unwrap();
+    table.insert(I2C, response(3)).unwrap();
+    table.insert(SMI, response(1)).unwrap();
+    assert_eq!(table.len(), 3);
+
+    // The I2C machine finishes first, out of order with the SMI requests
+    assert_eq!(table.complete(I2C).unwrap().proc_id, 3);
+    assert_eq!(table.complete(SMI).unwrap().proc_id, 7);
+    assert_eq!(table.complete(SMI).unwrap().proc_id, 1);
+    assert!(table.complete(SMI).is_none());
+    assert!(table.is_empty());
+}
+
+#[test]
+fn duplicate_proc_id_is_rejected_per_state_machine() {
+    let mut table: InFlight<4> = InFlight::new();
+    table.insert(SMI, response(5)).unwrap();
+    assert_eq!(table.check(SMI, SERIAL, 5), Err(BridgeError::DuplicateProcId));
+    assert_eq!(table.insert(SMI, response(5)), Err(BridgeError::DuplicateProcId));
+    // The same proc_id on another state machine is a different key
+    table.insert(I2C, response(5)).unwrap();
+
+    assert_eq!(table.take(SMI, SERIAL, 5).unwrap().proc_id, 5);
+    assert!(!table.is_pending(SMI, SERIAL, 5));
+    assert!(table.is_pending(I2C, SERIAL, 5));
+}
+
+#[test]
+fn full_table_reports_queue_full() {
+    let mut table: InFlight<2> = InFlight::new();
+    table.insert(SMI, response(1)).unwrap();
+    table.insert(SMI, response(2)).unwrap();
+    assert_eq!(table.check(I2C, SERIAL, 3), Err(BridgeError::QueueFull));
+    table.complete(SMI).unwrap();
+    // A freed slot is reused, and order is kept across slots
+    table.insert(SMI, response(3)).unwrap();
+    assert_eq!(table.complete(SMI).unwrap().proc_id, 2);
+    assert_eq!(table.complete(SMI).unwrap().proc_id, 3);
+}
+
+#[test]
+fn hosts_number_their_requests_on_their_own() {
+    let mut table: InFlight<4> = InFlight::new();
+    // The UART console and the USB console both count from 0
+    table.insert(SMI, response_from(ValidHostInterfaces::UART, 0)).unwrap();
+    table.insert(SMI, response_from(SERIAL, 0)).unwrap();
+    assert_eq!(table.check(SMI, ValidHostInterfaces::SPI, 0), Ok(()));
+    assert_eq!(table.check(SMI, SERIAL, 0), Err(BridgeError::DuplicateProcId));
+
+    assert_eq!(table.complete(SMI).unwrap().host_config, ValidHostInterfaces::UART);
+    assert_eq!(table.take(SMI, SERIAL, 0).unwrap().host_config, SERIAL);
+    assert!(table.is_empty());
+}
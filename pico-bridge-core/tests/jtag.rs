@@ -0,0 +1,264 @@
+//! JTAG TAP paths and scans, run against a simulated TAP.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::jtag::{manufacturer, JtagJob, JtagStep, TapState, TmsPath};
+use pico_bridge_core::stream::StreamIn;
+
+mod common;
+use common::{chain_tck, input_for, Tap, IDCODE};
+
+fn tap() -> Tap {
+    Tap::new(4, Some(0x1BA0_1477))
+}
+
+fn job(command: &str, state: Option<TapState>) -> Result<JtagJob, BridgeError> {
+    JtagJob::start(&message_parse_build(command)?.init_clean()?, state)
+}
+
+// Clock the TCKs the job has ready through the chain
+fn clock(job: &mut JtagJob, taps: &mut [Tap], input: &mut StreamIn) {
+    while let Some(word) = job.pending(input) {
+        job.sent();
+        let mut tdo = 0;
+        for bit in 0..16 {
+            let pair = word >> (2 * bit);
+            tdo |= (chain_tck(taps, pair & 1 != 0, pair & 2 != 0) as u32) << (16 + bit);
+        }
+        job.on_rx(tdo);
+    }
+}
+
+// Clock a job through the chain to the end with the TDI bytes given, returning its result and the console output
+// with the chunks of TDO bits as the consoles get them
+fn run_with(job: &mut JtagJob, taps: &mut [Tap], tdi: &[u8]) -> (Result<(u32, u8), BridgeError>, String) {
+    let mut console = String::new();
+    let mut input = input_for(job.input(), tdi);
+    loop {
+        while !job.done() {
+            clock(job, taps, &mut input);
+            if job.line(&mut console) {
+                job.line_written();
+            }
+            if let Some(chunk) = job.chunk() {
+                console.push_str(chunk.format_text().as_str());
+                job.chunk_written();
+            }
+        }
+        if let JtagStep::Done(result) = job.on_done() {
+            return (result, console)
+        }
+    }
+}
+
+// Clock a chain scan through the chain to the end, returning its result, the console output and the words streamed
+fn run_chain(job: &mut JtagJob, taps: &mut [Tap]) -> (Result<(u32, u8), BridgeError>, String, Vec<u32>) {
+    let mut console = String::new();
+    let mut words = Vec::new();
+    let mut input = StreamIn::new();
+    loop {
+        while !job.done() {
+            clock(job, taps, &mut input);
+            if job.line(&mut console) {
+                job.line_written();
+            }
+            if let Some(chunk) = job.chunk() {
+                let bytes = chunk.bytes();
+                words.extend(bytes.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])));
+                job.chunk_written();
+            }
+        }
+        if let JtagStep::Done(result) = job.on_done() {
+            return (result, console, words)
+        }
+    }
+}
+
+fn run(job: &mut JtagJob, tap: &mut Tap, tdi: &[u8]) -> (Result<(u32, u8), BridgeError>, String) {
+    run_with(job, core::slice::from_mut(tap), tdi)
+}
+
+#[test]
+fn paths_are_the_shortest_between_any_two_states() {
+    for from in TapState::ALL {
+        for to in TapState::ALL {
+            let path = TmsPath::between(Some(from), to);
+            let end = (0..path.len as u32).fold(from, |state, i| state.next(path.tms(i)));
+            assert_eq!(end, to, "{:?} to {:?}", from, to);
+            // No shorter sequence gets there
+            let reaches = |len: u32, bits: u32| (0..len).fold(from, |state, i| state.next(bits >> i & 1 != 0)) == to;
+            let shortest = (0..=8).find(|len| (0..1 << len).any(|bits| reaches(*len, bits)));
+            assert_eq!(shortest, Some(path.len as u32));
+        }
+    }
+    let idle_to_shift_ir = TmsPath::between(Some(TapState::RunTestIdle), TapState::ShiftIr);
+    assert_eq!(idle_to_shift_ir, TmsPath { bits: 0b0011, len: 4 });
+    assert_eq!(TmsPath::between(Some(TapState::ShiftDr), TapState::ShiftDr).len, 0);
+    // From an unknown state through Test-Logic-Reset
+    let unknown = TmsPath::between(None, TapState::RunTestIdle);
+    assert_eq!(unknown, TmsPath { bits: 0x1F, len: 6 });
+}
+
+#[test]
+fn reset_leaves_the_tap_in_run_test_idle() {
+    let mut tap = tap();
+    tap.state = TapState::PauseIr;
+    tap.ir = 0x3;
+    let mut reset = job("jtag reset", None).unwrap();
+    assert_eq!(run(&mut reset, &mut tap, &[]).0, Ok((0, 0)));
+    assert_eq!((tap.state, tap.ir), (TapState::RunTestIdle, IDCODE));
+    assert_eq!(reset.end_state(), TapState::RunTestIdle);
+}
+
+#[test]
+fn scans_return_the_tdo_bits() {
+    let mut tap = tap();
+    let mut ir = job("jtag ir 4", None).unwrap();
+    assert_eq!(ir.input(), Some(1));
+    assert_eq!(run(&mut ir, &mut tap, &[0xE]), (Ok((0b0001, 1)), String::new()));
+    assert_eq!(tap.state, TapState::RunTestIdle);
+
+    let mut dr = job("jtag dr 32", Some(ir.end_state())).unwrap();
+    assert_eq!(run(&mut dr, &mut tap, &[0; 4]).0, Ok((0x1BA0_1477, 4)));
+
+    // BYPASS is a single bit captured as 0, the TDI bits come out behind it
+    let mut bypass = job("jtag ir 4", Some(dr.end_state())).unwrap();
+    assert_eq!(run(&mut bypass, &mut tap, &[0xF]).0, Ok((0b0001, 1)));
+    let mut dr = job("jtag dr 9", Some(bypass.end_state())).unwrap();
+    assert_eq!(run(&mut dr, &mut tap, &[0x55, 0]).0, Ok((0x55 << 1, 2)));
+}
+
+#[test]
+fn long_scans_stream_the_tdo_bits() {
+    let mut tap = tap();
+    let mut ir = job("jtag ir 4", None).unwrap();
+    assert_eq!(run(&mut ir, &mut tap, &[0xF]).0, Ok((0b0001, 1)));
+    // Through BYPASS, every TDI bit streamed comes out one TCK later
+    let mut dr = job("jtag dr 300", Some(TapState::RunTestIdle)).unwrap();
+    assert_eq!(dr.input(), Some(38));
+    let mut tdi = vec![0x78, 0x56, 0x34, 0x12];
+    tdi.resize(38, 0xFF);
+    let (result, console) = run(&mut dr, &mut tap, &tdi);
+    assert_eq!(console, "000000: 2468acf0 fffffffe ffffffff\n\r\
+        00000c: ffffffff ffffffff ffffffff\n\r\
+        000018: ffffffff ffffffff ffffffff\n\r\
+        000024: 0fff\n\r");
+    // The count of bits streamed
+    assert_eq!(result, Ok((300, 4)));
+    assert_eq!(tap.state, TapState::RunTestIdle);
+}
+
+#[test]
+fn short_scans_take_their_tdi_bits_with_the_request() {
+    let mut tap = tap();
+    let mut ir = job("jtag ir 4 0xe", None).unwrap();
+    assert_eq!(ir.input(), None);
+    assert_eq!(run(&mut ir, &mut tap, &[]).0, Ok((0b0001, 1)));
+    assert_eq!(tap.ir, 0xE);
+    // The fifth bit is the first TDI bit back out of the 4 bit IR
+    let mut ir = job("jtag ir 5 0x1f", Some(ir.end_state())).unwrap();
+    assert_eq!(run(&mut ir, &mut tap, &[]).0, Ok((0b1_0001, 1)));
+
+    // Through BYPASS, the last bit given is repeated up to the length
+    let mut ir = job("jtag ir 4 0xf", Some(ir.end_state())).unwrap();
+    assert_eq!(run(&mut ir, &mut tap, &[]).0, Ok((0b0001, 1)));
+    let mut dr = job("jtag dr 40 0x80000055", Some(ir.end_state())).unwrap();
+    assert_eq!(dr.input(), None);
+    let (result, console) = run(&mut dr, &mut tap, &[]);
+    assert_eq!(console, "000000: 000000aa ff\n\r");
+    assert_eq!(result, Ok((40, 4)));
+
+    // Past the 3 words the bits are streamed after those given
+    let mut dr = job("jtag dr 100 0x12345678 0 0", Some(dr.end_state())).unwrap();
+    assert_eq!(dr.input(), Some(1));
+    let (_, console) = run(&mut dr, &mut tap, &[0xF]);
+    assert_eq!(console, "000000: 2468acf0 00000000 00000000\n\r00000c: 0e\n\r");
+}
+
+#[test]
+fn scans_wait_for_their_tdi_bits() {
+    let mut tap = tap();
+    tap.state = TapState::RunTestIdle;
+    let mut dr = job("jtag dr 32", Some(TapState::RunTestIdle)).unwrap();
+    let mut input = input_for(dr.input(), &[0x77, 0x14]);
+    // The TMS path and the 13 bits in the first word go out, the next word needs the third byte
+    clock(&mut dr, core::slice::from_mut(&mut tap), &mut input);
+    assert_eq!(tap.state, TapState::ShiftDr);
+    assert!(dr.pending(&mut input).is_none());
+    input.push(0xA0);
+    input.push(0x1B);
+    clock(&mut dr, core::slice::from_mut(&mut tap), &mut input);
+    assert!(dr.done());
+    assert_eq!(dr.on_done(), JtagStep::Done(Ok((0x1BA0_1477, 4))));
+    assert!(input.ended());
+
+    // A host that overran the input fails the scan, the TAP still goes back to Run-Test/Idle
+    let mut dr = job("jtag dr 0x10000", Some(TapState::RunTestIdle)).unwrap();
+    assert_eq!(run(&mut dr, &mut tap, &[0; 0x1001]).0, Err(BridgeError::QueueFull));
+    assert_eq!(tap.state, TapState::RunTestIdle);
+}
+
+#[test]
+fn chain_scan_finds_the_devices_and_their_ir_lengths() {
+    // A Cortex-M DAP, a device without IDCODE and an Artix-7, from TDO
+    let mut taps = [Tap::new(4, Some(0x4BA0_0477)), Tap::new(5, None), Tap::new(6, Some(0x0362_D093))];
+    taps[1].state = TapState::PauseDr;
+    let mut scan = job("jtag scan", Some(TapState::ShiftDr)).unwrap();
+    let (result, console, words) = run_chain(&mut scan, &mut taps);
+    assert_eq!(result, Ok((3 | 15 << 8, 3)));
+    assert_eq!(console, "0: 0x4ba00477 ARM Ltd part 0xba00 version 4 IR 4\n\r\
+        1: BYPASS IR 5\n\r\
+        2: 0x0362d093 Xilinx part 0x362d version 0 IR 6\n\r\
+        3 devices, IR 15 bits\n\r");
+    // The IDCODE and IR length of each device, for binary and SPI hosts
+    assert_eq!(words, [0x4BA0_0477, 4, 0, 5, 0x0362_D093, 6]);
+    // Every device is left in BYPASS
+    assert!(taps.iter().all(|tap| tap.state == TapState::RunTestIdle && tap.ir == (1 << tap.ir_len) - 1));
+    assert_eq!(scan.end_state(), TapState::RunTestIdle);
+
+    // Nothing is streamed for a device asked for
+    let mut scan = job("jtag scan 2", None).unwrap();
+    let (result, _, words) = run_chain(&mut scan, &mut taps);
+    assert_eq!((result, words), (Ok((0x0362_D093, 4)), vec![]));
+    let mut scan = job("jtag scan 1 1", None).unwrap();
+    assert_eq!(run_chain(&mut scan, &mut taps).0, Ok((5, 2)));
+    let mut scan = job("jtag scan 3", None).unwrap();
+    assert_eq!(run_chain(&mut scan, &mut taps).0, Err(BridgeError::NotFound));
+}
+
+#[test]
+fn chain_scan_ir_lengths_may_be_unknown() {
+    // The first IR captures 0b0101, which reads as two devices
+    let mut taps = [Tap::new(4, Some(0x1BA0_1477)), Tap::new(4, None)];
+    taps[0].capture = 0b0101;
+    let mut scan = job("jtag scan 0 1", None).unwrap();
+    let (result, console, _) = run_chain(&mut scan, &mut taps);
+    assert_eq!(result, Ok((0, 2)));
+    assert!(console.starts_with("0: 0x1ba01477 ARM Ltd part 0xba01 version 1 IR ?\n\r1: BYPASS IR ?\n\r"));
+    // A lone device has all of it
+    let mut scan = job("jtag scan 0 1", None).unwrap();
+    assert_eq!(run_chain(&mut scan, &mut taps[..1]).0, Ok((4, 2)));
+}
+
+#[test]
+fn a_chain_without_devices_does_not_respond() {
+    // TDI straight to TDO, the ones shifted in come right back
+    let mut scan = job("jtag scan", None).unwrap();
+    assert_eq!(run_chain(&mut scan, &mut []).0, Err(BridgeError::NoResponse));
+    assert_eq!(manufacturer(0x4BA0_0477), Some("ARM Ltd"));
+    assert_eq!(manufacturer(0x0000_0FFF), None);
+}
+
+#[test]
+fn arguments_are_checked() {
+    assert_eq!(job("jtag reset 1", None).unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(job("jtag ir", None).unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(job("jtag ir 4 1 2 3 4", None).unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(job("jtag dr 0", None).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("jtag dr 0x10001", None).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("jtag r 1 0", None).unwrap_err(), BridgeError::InvalidOperation);
+    assert_eq!(job("jtag scan 15", None).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("jtag scan 0 2", None).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("jtag scan 0 0 0", None).unwrap_err(), BridgeError::BadArgCount);
+}
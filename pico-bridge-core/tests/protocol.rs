@@ -0,0 +1,218 @@
+//! Wire format regression tests for HostRequest decoding and the serial command parser.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::{bytes_to_number, message_parse_build, parse_command, Command};
+use pico_bridge_core::protocol::{checksum, decode_smi_read, encode_mmd, encode_smi, encode_smi45, Clause45Op, HostTransport, Respond, ValidHostInterfaces};
+use pico_bridge_core::protocol::slave::{Ready, SlaveResponse};
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::protocol::host::{encode_8bit_spi, HostRequest, ValidInterfaces, ValidOps};
+
+#[test]
+fn bytes_to_number_parses_hex_and_decimal() {
+    assert_eq!(bytes_to_number("0xFF"), Ok(255));
+    assert_eq!(bytes_to_number("0x1f"), Ok(31));
+    assert_eq!(bytes_to_number("25"), Ok(25));
+    assert_eq!(bytes_to_number("0xFFFFFFFFF"), Err(BridgeError::NumberTooLarge));
+    assert_eq!(bytes_to_number("12a"), Err(BridgeError::InvalidNumber));
+    assert_eq!(bytes_to_number("zz"), Err(BridgeError::InvalidNumber));
+}
+
+#[test]
+fn encode_smi_clause22_frames() {
+    // Read of PHY 9 register 1: read flag in bit 0, then ST 01 OP 10 PHYAD 01001 REGAD 00001 LSB first
+    assert_eq!(encode_smi(true, 0x9, 0x1, 0), 0x0000_424D);
+    // Write: ST 01 OP 01, then the data MSB first from bit 15
+    assert_eq!(encode_smi(false, 0x9, 0x1, 0x8000), 0x0000_C254);
+}
+
+#[test]
+fn decode_smi_read_checks_turnaround() {
+    assert_eq!(decode_smi_read(0x0000_796D), Ok(0x796D));
+    // A register really holding 0xFFFF is told apart from an empty address by the turnaround bit
+    assert_eq!(decode_smi_read(0x0000_FFFF), Ok(0xFFFF));
+    assert_eq!(decode_smi_read(0x0001_FFFF), Err(BridgeError::NoPhy));
+}
+
+#[test]
+fn encode_smi_clause45_frames() {
+    // Address frame: ST 00 OP 00 PORTAD 00011 DEVAD 00001, register 0x0001 in the data bits
+    assert_eq!(encode_smi45(Clause45Op::Address, 3, 1, 0x0001), 0x4000_4300);
+    // Read and post-read-increment set the read flag and carry no data
+    assert_eq!(encode_smi45(Clause45Op::Read, 3, 1, 0xFFFF), 0x0000_4319);
+    assert_eq!(encode_smi45(Clause45Op::PostReadIncrement, 3, 1, 0), 0x0000_4309);
+    assert_eq!(encode_smi45(Clause45Op::Write, 3, 1, 0x8000), 0x0000_C310);
+}
+
+#[test]
+fn mmd_is_the_register_13_14_sequence() {
+    let clean = message_parse_build("mmd r 1 7 0x3c ").unwrap().init_clean().unwrap();
+    assert!(matches!(clean.interface, ValidInterfaces::MMD));
+    assert_eq!(clean.size, 4);
+    assert_eq!(clean.payload, [
+        encode_smi(false, 1, 13, 0x0007),
+        encode_smi(false, 1, 14, 0x003C),
+        encode_smi(false, 1, 13, 0x4007),
+        encode_smi(true, 1, 14, 0),
+    ]);
+    // Only the last frame reads, so the sequence completes with a single IRQ
+    assert_eq!(clean.payload.iter().filter(|frame| *frame & 1 != 0).count(), 1);
+
+    let clean = message_parse_build("mmd w 1 7 0x3c 0x6 ").unwrap().init_clean().unwrap();
+    assert_eq!(clean.payload, encode_mmd(1, 7, 0x3C, Some(6)));
+    assert_eq!(clean.payload[3], encode_smi(false, 1, 14, 0x0006));
+    assert_eq!(message_parse_build("mmd w 1 7 0x3c ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn message_parse_build_smi45() {
+    let clean = message_parse_build("smi45 r 3 1 0x0001 ").unwrap().init_clean().unwrap();
+    assert!(matches!(clean.interface, ValidInterfaces::SMI45));
+    assert_eq!(clean.size, 2);
+    assert_eq!(clean.payload[..2], [encode_smi45(Clause45Op::Address, 3, 1, 1), encode_smi45(Clause45Op::Read, 3, 1, 0)]);
+
+    let clean = message_parse_build("smi45 w 3 1 0x0001 0x8000 ").unwrap().init_clean().unwrap();
+    assert_eq!(clean.payload[1], encode_smi45(Clause45Op::Write, 3, 1, 0x8000));
+
+    // Post-read-increment without a register continues from the current address
+    let clean = message_parse_build("smi45 ri 3 1 ").unwrap().init_clean().unwrap();
+    assert_eq!(clean.operation, ValidOps::ReadInc);
+    assert_eq!(clean.size, 1);
+    assert_eq!(message_parse_build("smi45 r 3 1 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn message_parse_build_smi_read() {
+    let hr = message_parse_build("smi r 0x9 0x1 ").unwrap();
+    assert!(matches!(hr.interface, ValidInterfaces::SMI));
+    assert_eq!(hr.operation, ValidOps::Read);
+    assert_eq!(hr.size, 2);
+    assert_eq!(hr.payload, [9, 1, 0, 0]);
+
+    let clean = hr.init_clean().unwrap();
+    assert_eq!(clean.size, 1);
+    assert_eq!(clean.payload[0], encode_smi(true, 9, 1, 0));
+}
+
+#[test]
+fn message_parse_build_rejects_bad_commands() {
+    assert_eq!(message_parse_build("uart r 1 ").unwrap_err(), BridgeError::InvalidInterface);
+    assert_eq!(message_parse_build("smi x 1 2 ").unwrap_err(), BridgeError::InvalidOperation);
+    assert_eq!(message_parse_build("smi w 1 2 3 4 5 ").unwrap_err(), BridgeError::BadArgCount);
+    assert!(message_parse_build("smi r 1 2 ").unwrap().init_clean().is_ok());
+    assert_eq!(message_parse_build("smi r 1 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn parse_command_menu_is_not_an_error() {
+    assert!(matches!(parse_command("menu\0\0"), Ok(Command::Menu)));
+    assert!(matches!(parse_command("smi r 1 2 "), Ok(Command::Request(_))));
+}
+
+#[test]
+fn bridge_error_codes_are_stable() {
+    use core::convert::TryFrom;
+    assert_eq!(BridgeError::InvalidInterface.code(), 1);
+    assert_eq!(BridgeError::ChecksumMismatch.code(), 6);
+    assert_eq!(BridgeError::NoResponse.code(), 10);
+    for code in 1..=19 {
+        assert_eq!(BridgeError::try_from(code).unwrap().code(), code);
+    }
+    assert!(BridgeError::try_from(0).is_err());
+    assert_eq!(format!("{}", BridgeError::Timeout), "Timed out");
+}
+
+#[test]
+fn build_from_8bit_spi_decodes_header() {
+    let buf = encode_8bit_spi(ValidInterfaces::SMI, ValidOps::Read, &[9, 1]);
+    // Interface SMI (1), operation Read (1), size 2
+    assert_eq!(buf[0], 0b0010_0101);
+    let hr = HostRequest::new().build_from_8bit_spi(&buf).unwrap();
+    assert!(matches!(hr.interface, ValidInterfaces::SMI));
+    assert_eq!(hr.operation, ValidOps::Read);
+    assert_eq!(hr.payload[0], encode_smi(true, 9, 1, 0));
+
+    // Operation 7 does not exist
+    let mut buf = buf;
+    buf[0] |= 0b0001_1100;
+    assert!(HostRequest::new().build_from_8bit_spi(&buf).is_err());
+}
+
+#[test]
+fn checksum_sums_frame_to_zero() {
+    let buf = encode_8bit_spi(ValidInterfaces::SMI, ValidOps::Write, &[9, 1, 0x1234]);
+    let sum = buf.iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte));
+    assert_eq!(sum, 0);
+    assert_eq!(checksum(0x29, &[]), 0xD7);
+}
+
+#[test]
+fn init_clean_rejects_checksum_mismatch() {
+    let mut buf = encode_8bit_spi(ValidInterfaces::SMI, ValidOps::Write, &[9, 1, 0x1234]);
+    // Flip a data bit in transit
+    buf[10] ^= 0x04;
+    assert_eq!(HostRequest::new().build_from_8bit_spi(&buf).unwrap_err(), BridgeError::ChecksumMismatch);
+
+    // 16-bit frames carry the same header and checksum in the first packet
+    let buf = encode_8bit_spi(ValidInterfaces::GPIO, ValidOps::Write, &[1]);
+    let packets = [(buf[0] as u16) << 8 | buf[1] as u16, 1, 0];
+    assert!(HostRequest::new().build_from_16bit_spi(&packets).is_ok());
+    let packets = [(buf[0] as u16) << 8 | buf[1] as u16, 0, 0];
+    assert_eq!(HostRequest::new().build_from_16bit_spi(&packets).unwrap_err(), BridgeError::ChecksumMismatch);
+}
+
+#[derive(Default)]
+struct MockHost {
+    serial: Vec<u8>,
+    uart: Vec<u8>,
+    spi: Vec<[u8; 18]>,
+}
+
+impl HostTransport for MockHost {
+    fn write_serial(&mut self, bytes: &[u8]) -> Result<(), BridgeError> {
+        self.serial.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_uart(&mut self, bytes: &[u8]) -> Result<(), BridgeError> {
+        self.uart.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn queue_spi(&mut self, frame: [u8; 18]) -> Result<(), BridgeError> {
+        self.spi.push(frame);
+        Ok(())
+    }
+}
+
+fn ready(host: ValidHostInterfaces, size: u8, payload: u32, err: Option<BridgeError>) -> SlaveResponse<Ready> {
+    let mut sr = SlaveResponse::new();
+    sr.set_proc_id(3);
+    sr.set_host_config(host);
+    sr.set_size(size);
+    sr.set_payload(payload);
+    if let Some(err) = err {
+        sr.set_error(err);
+    }
+    sr.init_ready().unwrap()
+}
+
+#[test]
+fn respond_to_host_routes_by_transport() {
+    let mut host = MockHost::default();
+
+    ready(ValidHostInterfaces::Serial, 2, 0x796D, None).respond_to_host(&mut host).unwrap();
+    assert_eq!(host.serial, b"0x796d\n\r->");
+
+    ready(ValidHostInterfaces::UART, 0, 0, Some(BridgeError::Timeout)).respond_to_host(&mut host).unwrap();
+    assert_eq!(host.uart, b"Timed out\n\r->");
+
+    ready(ValidHostInterfaces::SPI, 2, 0x796D, None).respond_to_host(&mut host).unwrap();
+    assert_eq!(&host.spi[0][..7], &[3, 0, checksum(0, &[0x796D]), 0x6D, 0x79, 0, 0]);
+
+    host.serial.clear();
+    ready(ValidHostInterfaces::SerialFramed, 2, 0x796D, None).respond_to_host(&mut host).unwrap();
+    assert_eq!(host.serial.last(), Some(&0));
+    assert!(!host.serial[..host.serial.len() - 1].contains(&0));
+
+    assert_eq!(ready(ValidHostInterfaces::None, 0, 0, None).respond_to_host(&mut host), Err(BridgeError::NoResponse));
+}
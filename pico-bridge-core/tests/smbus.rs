@@ -0,0 +1,173 @@
+//! SMBus transactions with PEC and PMBus decoding, on the I2C transfers.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::i2c::{I2cJob, I2cStep};
+use pico_bridge_core::smbus::{crc8, linear11, linear16, write_linear, MAX_BLOCK};
+
+fn job(command: &str, pec: bool) -> I2cJob {
+    I2cJob::start(&message_parse_build(command).unwrap().init_clean().unwrap(), pec).unwrap()
+}
+
+// Data bytes of the records sent so far, instruction records left out
+fn bytes(job: &mut I2cJob) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut instructions = 0;
+    while let Some(record) = job.pending() {
+        job.sent();
+        if instructions > 0 {
+            instructions -= 1;
+        }
+        else if record >> 10 != 0 {
+            instructions = (record >> 10) + 1;
+        }
+        else {
+            bytes.push((record >> 1) as u8);
+        }
+    }
+    bytes
+}
+
+// Play the bus: every byte sent is seen as is, except the bytes read which the device drives. Returns the bytes
+// sent with the step
+fn answer(job: &mut I2cJob, device: &[u8]) -> (Vec<u8>, I2cStep) {
+    let mut device = device.iter();
+    let mut sent = Vec::new();
+    loop {
+        let more = bytes(job);
+        if more.is_empty() {
+            break;
+        }
+        for byte in &more {
+            let seen = if *byte == 0xFF { *device.next().unwrap() } else { *byte };
+            job.on_rx((seen as u32) << 1);
+        }
+        sent.extend(more);
+    }
+    (sent, job.on_irq(&mut String::new()))
+}
+
+#[test]
+fn crc8_matches_the_smbus_polynomial() {
+    assert_eq!(crc8(0, b"123456789"), 0xF4);
+    assert_eq!(crc8(crc8(0, b"1234"), b"56789"), 0xF4);
+}
+
+#[test]
+fn writes_append_the_pec() {
+    let mut write = job("smbus ww 0x40 0x21 0x1234 ", true);
+    assert_eq!(bytes(&mut write), [0x80, 0x21, 0x34, 0x12, crc8(0, &[0x80, 0x21, 0x34, 0x12])]);
+
+    let mut send = job("smbus send 0x40 0x03 ", false);
+    assert_eq!(bytes(&mut send), [0x80, 0x03]);
+
+    let mut block = job("smbus bw 0x40 0x30 1 2 3 ", false);
+    assert_eq!(bytes(&mut block), [0x80, 0x30, 3, 1, 2, 3]);
+}
+
+#[test]
+fn reads_check_the_pec() {
+    let pec = crc8(0, &[0x80, 0x8B, 0x81, 0x34, 0x12]);
+    let mut read = job("smbus rw 0x40 0x8b ", true);
+    assert_eq!(answer(&mut read, &[0x34, 0x12, pec]).1, I2cStep::Done(Ok((0x1234, 2))));
+
+    let mut read = job("smbus rw 0x40 0x8b ", true);
+    assert_eq!(answer(&mut read, &[0x34, 0x12, pec ^ 1]).1, I2cStep::Done(Err(BridgeError::PecMismatch)));
+}
+
+#[test]
+fn block_reads_take_their_length_from_the_count_byte() {
+    let mut read = job("smbus br 0x40 0x99 ", true);
+    // Only the count byte is read until it is received
+    assert_eq!(bytes(&mut read), [0x80, 0x99, 0x81, 0xFF]);
+    for byte in [0x80, 0x99, 0x81, 5] {
+        read.on_rx((byte as u32) << 1);
+    }
+    // Five bytes and the PEC, then STOP
+    assert_eq!(bytes(&mut read), [0xFF; 6]);
+    let data = [b'A', b'C', b'M', b'E', b'1'];
+    for byte in data {
+        read.on_rx((byte as u32) << 1);
+    }
+    read.on_rx((crc8(crc8(0, &[0x80, 0x99, 0x81, 5]), &data) as u32) << 1);
+    // More bytes than the response holds are streamed, and counted
+    assert_eq!(read.on_irq(&mut String::new()), I2cStep::Done(Ok((5, 4))));
+    assert_eq!(read.answer(), None);
+    assert_eq!(read.chunk().unwrap().bytes(), data);
+    read.chunk_written();
+    assert!(read.chunk().is_none());
+    assert_eq!(read.answer(), Some(Ok((5, 4))));
+
+    // Up to 4 are returned, first byte in the low byte
+    let mut read = job("smbus br 0x40 0x99 ", false);
+    assert_eq!(answer(&mut read, &[2, 0x34, 0x12]).1, I2cStep::Done(Ok((0x1234, 2))));
+    assert!(read.chunk().is_none());
+
+    let mut read = job("smbus br 0x40 0x99 ", false);
+    assert_eq!(answer(&mut read, &[0, 0]).1, I2cStep::Done(Err(BridgeError::OutOfRange)));
+}
+
+#[test]
+fn full_block_reads_stream_a_chunk_at_a_time() {
+    let data: Vec<u8> = (0..MAX_BLOCK as u8).collect();
+    let mut read = job("smbus br 0x40 0x99 ", false);
+    let device: Vec<u8> = [MAX_BLOCK as u8].iter().chain(&data).copied().collect();
+    assert_eq!(answer(&mut read, &device).1, I2cStep::Done(Ok((MAX_BLOCK as u32, 4))));
+    let mut streamed = Vec::new();
+    while let Some(chunk) = read.chunk() {
+        assert_eq!(chunk.offset as usize, streamed.len());
+        streamed.extend_from_slice(chunk.bytes());
+        assert_eq!(read.answer(), None);
+        read.chunk_written();
+    }
+    assert_eq!(streamed, data);
+    assert_eq!(read.answer(), Some(Ok((MAX_BLOCK as u32, 4))));
+}
+
+#[test]
+fn pmbus_linear_formats() {
+    // -1/4 * 60 = 15 and 2^-9 * 0x0300 = 1.5
+    assert_eq!(linear11(0xF03C), (60, -2));
+    assert_eq!(linear11(0x07FF), (-1, 0));
+    assert_eq!(linear16(0x0300, 0x17), Ok((0x300, -9)));
+    assert_eq!(linear16(0x0300, 0x40), Err(BridgeError::OutOfRange));
+    let mut out = String::new();
+    write_linear(&mut out, -3, -1).unwrap();
+    write_linear(&mut out, 7, 3).unwrap();
+    assert_eq!(out, "-1.50056.000");
+
+    let mut l11 = job("smbus l11 0x40 0x8c ", false);
+    let mut report = String::new();
+    l11.sent();
+    for byte in [0x80, 0x8C, 0x81, 0x3C, 0xF0] {
+        l11.on_rx((byte as u32) << 1);
+    }
+    assert_eq!(l11.on_irq(&mut report), I2cStep::Done(Ok((0xF03C, 2))));
+    assert_eq!(report, "0x8c: 0xf03c = 15.000\n\r");
+
+    // VOUT_MODE, then the reading
+    let mut l16 = job("smbus l16 0x40 0x8b ", false);
+    assert_eq!(answer(&mut l16, &[0x17]), (vec![0x80, 0x20, 0x81, 0xFF], I2cStep::Next));
+    assert_eq!(bytes(&mut l16), [0x80, 0x8B, 0x81, 0xFF, 0xFF]);
+    for byte in [0x80, 0x8B, 0x81, 0x00, 0x03] {
+        l16.on_rx((byte as u32) << 1);
+    }
+    let mut report = String::new();
+    assert_eq!(l16.on_irq(&mut report), I2cStep::Done(Ok((0x0300, 2))));
+    assert_eq!(report, "0x8b: 0x0300 = 1.500 (VOUT_MODE 0x17)\n\r");
+}
+
+#[test]
+fn smbus_arguments() {
+    let clean = |command: &str| message_parse_build(command).and_then(|hr| hr.init_clean().map(|_| ()));
+    assert_eq!(clean("smbus wb 0x40 1 0x100 "), Err(BridgeError::NumberTooLarge));
+    assert_eq!(clean("smbus rw 0x40 0x100 "), Err(BridgeError::NumberTooLarge));
+    assert_eq!(clean("smbus rw 0x80 1 "), Err(BridgeError::OutOfRange));
+    assert_eq!(clean("smbus ww 0x40 1 "), Err(BridgeError::BadArgCount));
+    assert_eq!(clean("smbus bw 0x40 1 1 2 3 4 5 6 7 "), Ok(()));
+    assert_eq!(clean("smbus bw 0x40 1 1 2 3 4 5 6 7 8 "), Err(BridgeError::BadArgCount));
+    assert_eq!(clean("smbus r 0x40 1 "), Err(BridgeError::InvalidOperation));
+    assert_eq!(clean("cfg pec 2 "), Err(BridgeError::OutOfRange));
+    assert_eq!(clean("cfg pec 1 "), Ok(()));
+}
@@ -0,0 +1,264 @@
+//! SMI sequences run by the firmware one read at a time.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::{message_parse_build, pack_name};
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::protocol::encode_smi;
+use pico_bridge_core::smi::{Frames, PageSelect, SmiSequence, SmiStep, Snapshots};
+
+// Run a sequence against a simulated MDIO bus, returns its result, the report and the words it streamed
+// Named dumps are stored in snapshots as the firmware does
+fn run_with(command: &str, snapshots: &mut Snapshots, mut bus: impl FnMut(u32) -> Result<u16, BridgeError>)
+    -> (Result<(u32, u8), BridgeError>, String, Vec<u16>) {
+    let hr = message_parse_build(command).unwrap().init_clean().unwrap();
+    let (mut seq, mut frames) = match SmiSequence::start(&hr, snapshots, &PageSelect::new()) {
+        Ok(started) => started.unwrap(),
+        Err(err) => return (Err(err), String::new(), Vec::new()),
+    };
+    let mut report = String::new();
+    let mut words = Vec::new();
+    // Simulated clock, only waits take time
+    let mut now_us = 0;
+    loop {
+        let last = *frames.as_slice().last().unwrap();
+        let step = seq.on_read(bus(last), now_us, &mut report);
+        // The chunks are written out before the next step, as the firmware does
+        while let Some(chunk) = seq.chunk() {
+            assert_eq!(chunk.width, 2);
+            words.extend(chunk.bytes().chunks(2).map(|word| u16::from_le_bytes([word[0], word[1]])));
+            seq.chunk_written();
+        }
+        match step {
+            SmiStep::Next(next) => frames = next,
+            SmiStep::Wait(delay_us, next) => {
+                now_us += delay_us as u64;
+                frames = next;
+            }
+            SmiStep::Done(result) => {
+                if let (Ok(_), Some(snapshot)) = (result, seq.snapshot()) {
+                    snapshots.store(*snapshot);
+                }
+                return (result, report, words)
+            }
+        }
+    }
+}
+
+fn run(command: &str, bus: impl FnMut(u32) -> Result<u16, BridgeError>)
+    -> (Result<(u32, u8), BridgeError>, String, Vec<u16>) {
+    run_with(command, &mut Snapshots::new(), bus)
+}
+
+// PHY 1 with register n holding value n * 0x100
+fn registers(frame: u32) -> Result<u16, BridgeError> {
+    (0..32).find(|reg| frame == encode_smi(true, 1, *reg, 0)).map(|reg| reg as u16 * 0x100).ok_or(BridgeError::NoPhy)
+}
+
+#[test]
+fn scan_reports_phys_and_returns_bitmap() {
+    let (result, report, _) = run("smi scan ", |frame| {
+        if frame == encode_smi(true, 1, 2, 0) || frame == encode_smi(true, 0x1f, 2, 0) {
+            Ok(0x0007)
+        } else if frame == encode_smi(true, 1, 3, 0) || frame == encode_smi(true, 0x1f, 3, 0) {
+            Ok(0xC0F1)
+        } else {
+            Err(BridgeError::NoPhy)
+        }
+    });
+    assert_eq!(result, Ok((1 << 1 | 1 << 31, 4)));
+    assert_eq!(report, "PHY 0x01: 0x0007c0f1\n\rPHY 0x1f: 0x0007c0f1\n\r");
+}
+
+#[test]
+fn scan_of_an_empty_bus_reads_each_address_once() {
+    let mut reads = 0;
+    let hr = message_parse_build("smi scan ").unwrap().init_clean().unwrap();
+    let (mut seq, mut frames) = SmiSequence::start(&hr, &Snapshots::new(), &PageSelect::new()).unwrap().unwrap();
+    assert_eq!(frames, Frames::read(0, 2));
+    let result = loop {
+        reads += 1;
+        match seq.on_read(Err(BridgeError::NoPhy), 0, &mut String::new()) {
+            SmiStep::Next(next) => frames = next,
+            SmiStep::Done(result) => break result,
+            SmiStep::Wait(..) => panic!("scans do not wait"),
+        }
+    };
+    assert_eq!(reads, 32);
+    assert_eq!(frames, Frames::read(31, 2));
+    assert_eq!(result, Ok((0, 4)));
+}
+
+#[test]
+fn scan_aborts_when_the_state_machine_does_not_answer() {
+    let (result, _, _) = run("smi scan ", |_| Err(BridgeError::NoResponse));
+    assert_eq!(result, Err(BridgeError::NoResponse));
+    assert_eq!(message_parse_build("smi scan 1 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn dump_streams_a_register_table() {
+    let (result, report, words) = run("smi dump 1 0x2..0x4 ", registers);
+    assert_eq!(result, Ok((3, 1)));
+    assert_eq!(words, [0x0200, 0x0300, 0x0400]);
+    assert_eq!(report, "");
+
+    // More registers than a chunk holds
+    let (result, _, words) = run("smi dump 1 0..31 ", registers);
+    assert_eq!(result, Ok((32, 1)));
+    assert_eq!(words, (0..32).map(|reg| reg * 0x100).collect::<Vec<u16>>());
+
+    assert_eq!(run("smi dump 2 0..1 ", registers).0, Err(BridgeError::NoPhy));
+    assert_eq!(message_parse_build("smi dump 1 4..2 ").unwrap().init_clean().unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(message_parse_build("smi dump 1 0..32 ").unwrap().init_clean().unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(message_parse_build("smi dump 1 0 1 2 3 ").unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn diff_reports_changed_registers_of_a_snapshot() {
+    let mut snapshots = Snapshots::new();
+    run_with("smi dump 1 0..31 base ", &mut snapshots, registers).0.unwrap();
+    assert_eq!(snapshots.get(pack_name("base").unwrap()).unwrap().values().len(), 32);
+
+    // Link came up: register 1 changed, and 0x1f
+    let (result, _, words) = run_with("smi diff base ", &mut snapshots, |frame| {
+        match frame {
+            frame if frame == encode_smi(true, 1, 1, 0) => Ok(0x0104),
+            frame if frame == encode_smi(true, 1, 0x1f, 0) => Ok(0),
+            frame => registers(frame),
+        }
+    });
+    assert_eq!(result, Ok((2, 1)));
+    assert_eq!(words, [0x01, 0x0100, 0x0104, 0x1f, 0x1f00, 0]);
+
+    assert_eq!(run_with("smi diff base ", &mut snapshots, registers), (Ok((0, 1)), String::new(), Vec::new()));
+
+    assert_eq!(run_with("smi diff none ", &mut snapshots, registers).0, Err(BridgeError::NotFound));
+}
+
+#[test]
+fn snapshot_names() {
+    assert_eq!(pack_name("base"), Ok(0x6573_6162));
+    assert_eq!(pack_name("toolong"), Err(BridgeError::OutOfRange));
+    assert_eq!(pack_name("a-b"), Err(BridgeError::InvalidNumber));
+    // Names are only taken where the snapshot goes
+    assert_eq!(message_parse_build("smi dump phy 0..1 ").unwrap_err(), BridgeError::InvalidNumber);
+
+    let mut snapshots = Snapshots::new();
+    for name in ["a", "b", "c", "d", "e"] {
+        run_with(&format!("smi dump 1 0..0 {} ", name), &mut snapshots, registers).0.unwrap();
+    }
+    // The oldest snapshot made room for the fifth
+    assert!(snapshots.get(pack_name("a").unwrap()).is_none());
+    assert!(snapshots.get(pack_name("e").unwrap()).is_some());
+}
+
+#[test]
+fn rmw_writes_the_masked_value_and_reads_it_back() {
+    let hr = message_parse_build("smi rmw 1 0 0x4200 0x0200 ").unwrap().init_clean().unwrap();
+    let (mut seq, frames) = SmiSequence::start(&hr, &Snapshots::new(), &PageSelect::new()).unwrap().unwrap();
+    assert_eq!(frames, Frames::read(1, 0));
+
+    let mut report = String::new();
+    // Loopback was set, restart autoneg was not
+    let next = seq.on_read(Ok(0x5140), 0, &mut report);
+    assert_eq!(next, SmiStep::Next(Frames::write_read(1, 0, 0x1340)));
+    // Restart autoneg clears itself
+    assert_eq!(seq.on_read(Ok(0x1140), 0, &mut report), SmiStep::Done(Ok((0x5140_1140, 4))));
+    assert_eq!(report, "0x00: 0x5140 -> 0x1140\n\r");
+}
+
+#[test]
+fn setbits_and_clrbits_are_rmw_with_the_bits_as_mask() {
+    let mut report = String::new();
+    for (command, written) in [("smi setbits 1 0 0x4000 ", 0x5140), ("smi clrbits 1 0 0x1000 ", 0x0140)] {
+        let hr = message_parse_build(command).unwrap().init_clean().unwrap();
+        let (mut seq, _) = SmiSequence::start(&hr, &Snapshots::new(), &PageSelect::new()).unwrap().unwrap();
+        assert_eq!(seq.on_read(Ok(0x1140), 0, &mut report), SmiStep::Next(Frames::write_read(1, 0, written)));
+    }
+
+    assert_eq!(message_parse_build("smi setbits 1 0 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(message_parse_build("smi rmw 1 0 0x10000 0 ").unwrap().init_clean().unwrap_err(), BridgeError::NumberTooLarge);
+    // A PHY that is not there ends the sequence before anything is written
+    assert_eq!(run("smi clrbits 2 0 0x800 ", registers).0, Err(BridgeError::NoPhy));
+}
+
+#[test]
+fn poll_reads_until_the_condition_holds() {
+    // Autonegotiation completes on the fourth read of BMSR
+    let mut reads = 0;
+    let (result, report, _) = run("smi poll 1 1 0x20 0x20 100 5000 ", |frame| {
+        assert_eq!(frame, encode_smi(true, 1, 1, 0));
+        reads += 1;
+        Ok(if reads < 4 { 0x7949 } else { 0x796d })
+    });
+    assert_eq!(result, Ok((0x796d_000f, 4)));
+    assert_eq!(report, "0x01: 0x796d after 15 ms\n\r");
+
+    // The link never comes up, the last read is at the timeout
+    let mut reads = 0;
+    let (result, _, _) = run("smi poll 1 1 0x4 0x4 12 5000 ", |_| {
+        reads += 1;
+        Ok(0x7949)
+    });
+    assert_eq!(result, Err(BridgeError::Timeout));
+    assert_eq!(reads, 4);
+}
+
+#[test]
+fn poll_arguments() {
+    let hr = message_parse_build("smi poll 1 1 0x20 0x20 100 5000 ").unwrap().init_clean().unwrap();
+    assert_eq!(hr.size, 4);
+    assert_eq!(hr.payload, [0x0001_0001, 0x0020_0020, 100, 5000]);
+
+    assert_eq!(message_parse_build("smi poll 1 1 0x10000 0 100 5000 ").unwrap_err(), BridgeError::NumberTooLarge);
+    // The expected value has bits the mask drops
+    assert_eq!(message_parse_build("smi poll 1 1 0x20 0x24 100 5000 ").unwrap().init_clean().unwrap_err(),
+        BridgeError::OutOfRange);
+    assert_eq!(message_parse_build("smi poll 1 1 0x20 0x20 70000 5000 ").unwrap().init_clean().unwrap_err(),
+        BridgeError::OutOfRange);
+    assert_eq!(message_parse_build("smi poll 1 1 0x20 0x20 100 ").unwrap_err(), BridgeError::BadArgCount);
+}
+
+#[test]
+fn decode_reads_once_and_reports_the_fields() {
+    let (result, report, _) = run("smi decode 1 ANER ", registers);
+    assert_eq!(result, Ok((0x0600, 2)));
+    assert!(report.starts_with("ANER (0x06): 0x0600\n\r"));
+    assert_eq!(run("smi decode 2 BMSR ", registers).0, Err(BridgeError::NoPhy));
+}
+
+#[test]
+fn paged_read_restores_the_page() {
+    let mut pages = PageSelect::new();
+    pages.set(4, 22).unwrap();
+    let hr = message_parse_build("smi pr 4 0xd08 0x15 ").unwrap().init_clean().unwrap();
+    let (mut seq, frames) = SmiSequence::start(&hr, &Snapshots::new(), &pages).unwrap().unwrap();
+    let mut report = String::new();
+    assert_eq!(frames, Frames::read(4, 22));
+    assert_eq!(seq.on_read(Ok(2), 0, &mut report),
+        SmiStep::Next(Frames::new(&[encode_smi(false, 4, 22, 0xd08), encode_smi(true, 4, 0x15, 0)])));
+    assert_eq!(seq.on_read(Ok(0x1234), 0, &mut report),
+        SmiStep::Next(Frames::new(&[encode_smi(false, 4, 22, 2), encode_smi(true, 4, 22, 0)])));
+    assert_eq!(seq.on_read(Ok(2), 0, &mut report), SmiStep::Done(Ok((0x1234, 2))));
+}
+
+#[test]
+fn paged_write_is_one_step_after_reading_the_page() {
+    let hr = message_parse_build("smi pw 1 0xa43 0x1b 0x8011 ").unwrap().init_clean().unwrap();
+    let (mut seq, frames) = SmiSequence::start(&hr, &Snapshots::new(), &PageSelect::new()).unwrap().unwrap();
+    let mut report = String::new();
+    // Default page select register
+    assert_eq!(frames, Frames::read(1, 31));
+    assert_eq!(seq.on_read(Ok(0), 0, &mut report), SmiStep::Next(Frames::new(&[
+        encode_smi(false, 1, 31, 0xa43),
+        encode_smi(false, 1, 0x1b, 0x8011),
+        encode_smi(false, 1, 31, 0),
+        encode_smi(true, 1, 31, 0),
+    ])));
+    assert_eq!(seq.on_read(Ok(0), 0, &mut report), SmiStep::Done(Ok((0, 0))));
+
+    assert_eq!(PageSelect::new().set(32, 22), Err(BridgeError::OutOfRange));
+    assert_eq!(PageSelect::new().set(1, 32), Err(BridgeError::OutOfRange));
+    assert_eq!(message_parse_build("smi pw 1 0xa43 0x1b ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(message_parse_build("cfg page 1 ").unwrap().init_clean().unwrap_err(), BridgeError::BadArgCount);
+}
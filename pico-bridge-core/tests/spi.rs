@@ -0,0 +1,135 @@
+//! SPI master transfers as fed to and read back from the PIO SPI programs.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::cli::message_parse_build;
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::spi::{SpiConfig, SpiJob, SPI_HOLD};
+use pico_bridge_core::stream::{CHUNK_LEN, INPUT_LEN};
+
+mod common;
+use common::input_for;
+
+fn job(command: &str, config: SpiConfig) -> Result<SpiJob, BridgeError> {
+    SpiJob::start(&message_parse_build(command)?.init_clean()?, config)
+}
+
+// A device echoing each word back inverted, the host sending the MOSI bytes one at a time and the words sent while
+// the job lets them. Returns the MISO bytes streamed
+fn run(job: &mut SpiJob, config: SpiConfig, mosi: &[u8]) -> Vec<u8> {
+    let mut input = input_for(job.input(), &[]);
+    let mut mosi = mosi.iter();
+    let mut streamed = Vec::new();
+    while !job.done() {
+        if let Some(byte) = mosi.next() {
+            input.push(*byte);
+        }
+        while let Some(word) = job.pending(&mut input) {
+            job.sent();
+            // The word as it was shifted out, and in again from the other end of the shift register
+            let bits = if config.lsb_first { word & config.mask() } else { word >> (32 - config.bits) };
+            let echo = !bits & config.mask();
+            job.on_rx(if config.lsb_first { echo << (32 - config.bits) } else { echo });
+        }
+        if let Some(chunk) = job.chunk() {
+            streamed.extend_from_slice(chunk.bytes());
+            job.chunk_written();
+        }
+    }
+    streamed
+}
+
+#[test]
+fn words_are_aligned_for_the_shift_direction() {
+    let msb = SpiConfig::new(0, 12, 0).unwrap();
+    assert_eq!(msb.tx_word(0xABC), 0xABC0_0000);
+    assert_eq!(msb.rx_word(0x0000_0ABC), 0xABC);
+    let lsb = SpiConfig::new(3, 12, 1).unwrap();
+    assert_eq!(lsb.tx_word(0xABC), 0xABC);
+    assert_eq!(lsb.rx_word(0xABC0_0000), 0xABC);
+    assert!(lsb.cpol() && lsb.cpha());
+    assert_eq!(SpiConfig::new(4, 8, 0), Err(BridgeError::OutOfRange));
+    assert_eq!(SpiConfig::new(0, 33, 0), Err(BridgeError::OutOfRange));
+}
+
+#[test]
+fn transfers_take_their_words_from_the_input() {
+    let config = SpiConfig::DEFAULT;
+    let mut xfer = job("spi xfer 1 3 ", config).unwrap();
+    assert_eq!((xfer.cs(), xfer.hold(), xfer.input()), (1, false, Some(3)));
+    // Nothing is sent before the host sends the word
+    let mut input = input_for(xfer.input(), &[]);
+    assert_eq!(xfer.pending(&mut input), None);
+    assert_eq!(run(&mut xfer, config, &[0x9F, 0, 0]), []);
+    assert_eq!(xfer.result(), Ok((0xFFFF60, 3)));
+
+    // Words are little endian, bits past the width are dropped
+    let wide = SpiConfig::new(1, 12, 0).unwrap();
+    let mut xfer = job("spi xferh 0 2 ", wide).unwrap();
+    assert_eq!((xfer.hold(), xfer.input()), (true, Some(4)));
+    run(&mut xfer, wide, &[0x34, 0xF2, 0xFF, 0x0F]);
+    assert_eq!(xfer.result(), Ok((0x0000_0DCB, 4)));
+}
+
+#[test]
+fn long_transfers_stream_the_miso_words() {
+    let config = SpiConfig::new(0, 16, 0).unwrap();
+    let mosi: Vec<u8> = (0..40).collect();
+    let mut xfer = job("spi xfer 0 20 ", config).unwrap();
+    // No more words are sent than fill the chunk being written out
+    let mut input = input_for(xfer.input(), &[]);
+    for byte in &mosi {
+        input.push(*byte);
+    }
+    let mut sent = 0;
+    while xfer.pending(&mut input).is_some() {
+        xfer.sent();
+        sent += 1;
+    }
+    assert_eq!(sent, CHUNK_LEN / 2);
+
+    let mut xfer = job("spi xfer 0 20 ", config).unwrap();
+    let miso: Vec<u8> = mosi.iter().map(|byte| !byte).collect();
+    assert_eq!(run(&mut xfer, config, &mosi), miso);
+    assert_eq!(xfer.result(), Ok((20, 4)));
+}
+
+#[test]
+fn reads_stream_the_miso_words() {
+    let config = SpiConfig::DEFAULT;
+    let mut read = job("spi r 2 18 0xf0 ", config).unwrap();
+    assert_eq!(read.input(), None);
+    assert_eq!(run(&mut read, config, &[]), [0x0F; 18]);
+    assert_eq!(read.result(), Ok((18, 4)));
+
+    let mut read = job("spi r 2 2 ", config).unwrap();
+    assert_eq!(run(&mut read, config, &[]), []);
+    assert_eq!(read.result(), Ok((0, 2)));
+}
+
+#[test]
+fn an_overrun_input_fails_the_transfer() {
+    let config = SpiConfig::DEFAULT;
+    let mut xfer = job("spi xfer 0 5000 ", config).unwrap();
+    let mut input = input_for(xfer.input(), &[]);
+    for _ in 0..=INPUT_LEN {
+        input.push(0);
+    }
+    assert_eq!(xfer.pending(&mut input), None);
+    assert!(xfer.done());
+    assert_eq!(xfer.result(), Err(BridgeError::QueueFull));
+}
+
+#[test]
+fn spi_arguments() {
+    let hr = message_parse_build("spi rh 3 4 ").unwrap();
+    assert_eq!(hr.payload[..2], [3 | SPI_HOLD, 4]);
+    assert_eq!(message_parse_build("smi rh 3 4 ").unwrap_err(), BridgeError::InvalidOperation);
+    assert_eq!(job("spi r 4 1 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("spi r 0 0 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("spi xfer 0 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(job("spi xfer 0 1 2 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::BadArgCount);
+    assert_eq!(job("spi xfer 0 0x10000 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::OutOfRange);
+    assert_eq!(job("spi r 0 1 0x100 ", SpiConfig::DEFAULT).unwrap_err(), BridgeError::NumberTooLarge);
+    assert!(message_parse_build("cfg spimode 3 16 1 ").unwrap().init_clean().is_ok());
+    assert_eq!(message_parse_build("cfg spimode 0 0 ").unwrap().init_clean().unwrap_err(), BridgeError::OutOfRange);
+}
@@ -0,0 +1,148 @@
+//! Data streamed to the host ahead of the response a chunk at a time, and from the host after the request.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::protocol::{HostTransport, ValidHostInterfaces};
+use pico_bridge_core::stream::{decode_spi_data, write_chunk, write_flow, StreamIn, StreamOut, CHUNK_LEN, DATA_STATUS,
+    INPUT_END, INPUT_LEN, XOFF, XON};
+
+#[derive(Default)]
+struct Host {
+    serial: Vec<u8>,
+    spi: Vec<[u8; 18]>,
+}
+
+impl HostTransport for Host {
+    fn write_serial(&mut self, bytes: &[u8]) -> Result<(), BridgeError> {
+        self.serial.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_uart(&mut self, _bytes: &[u8]) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    fn queue_spi(&mut self, frame: [u8; 18]) -> Result<(), BridgeError> {
+        if self.spi.len() == 2 {
+            return Err(BridgeError::QueueFull)
+        }
+        self.spi.push(frame);
+        Ok(())
+    }
+}
+
+#[test]
+fn chunks_fill_up_to_the_limit_and_are_numbered() {
+    let mut out = StreamOut::new(2);
+    assert_eq!(out.limit(), CHUNK_LEN as u32);
+    out.push(&[0x34, 0x12, 0x78, 0x56]);
+    assert!(out.chunk().is_none());
+    out.push(&[0xAA; CHUNK_LEN]);
+    // Bytes past the chunk are dropped, the request waits for it to be written out
+    assert_eq!(out.len(), CHUNK_LEN as u32);
+    let chunk = *out.chunk().unwrap();
+    assert_eq!(chunk.format_text().as_str(), "000000: 1234 5678 aaaa aaaa aaaa aaaa\n\r");
+    out.written();
+    assert_eq!((out.limit(), out.chunk()), (2 * CHUNK_LEN as u32, None));
+
+    out.push(&[0x01, 0x02]);
+    out.finish();
+    assert!(!out.done());
+    let chunk = *out.chunk().unwrap();
+    assert_eq!((chunk.seq, chunk.offset, chunk.bytes()), (1, CHUNK_LEN as u32, &[0x01, 0x02][..]));
+    out.written();
+    assert!(out.done());
+}
+
+#[test]
+fn chunks_go_to_the_host_of_the_request() {
+    let mut out = StreamOut::new(1);
+    out.push(&[0xDE, 0xAD]);
+    out.finish();
+    let chunk = *out.chunk().unwrap();
+    let mut host = Host::default();
+
+    write_chunk(&mut host, ValidHostInterfaces::Serial, 3, &chunk).unwrap();
+    assert_eq!(host.serial, b"000000: de ad\n\r");
+
+    // Proc ID, status, checksum, seq, length and the data
+    write_chunk(&mut host, ValidHostInterfaces::SPI, 3, &chunk).unwrap();
+    let frame = host.spi[0];
+    assert_eq!(frame[..8], [3, DATA_STATUS, frame[2], 0, 0, 2, 0xDE, 0xAD]);
+    assert_eq!(frame[1..].iter().fold(0_u8, |sum, byte| sum.wrapping_add(*byte)), 0);
+    // The chunk is written again once the master clocked out the frames queued
+    write_chunk(&mut host, ValidHostInterfaces::SPI, 3, &chunk).unwrap();
+    assert_eq!(write_chunk(&mut host, ValidHostInterfaces::SPI, 3, &chunk), Err(BridgeError::QueueFull));
+}
+
+#[test]
+fn input_is_flow_controlled() {
+    let mut input = StreamIn::new();
+    assert!(!input.active() && input.flow().is_none());
+    input.start(ValidHostInterfaces::Serial, 5, None);
+    assert!(input.takes(ValidHostInterfaces::Serial) && !input.takes(ValidHostInterfaces::UART));
+    // The host waits for the first XON
+    assert_eq!(input.flow(), Some(XON));
+    input.flow_written();
+    for _ in 0..INPUT_LEN / 2 - 1 {
+        input.push(b' ');
+    }
+    assert_eq!(input.flow(), None);
+    input.push(b' ');
+    assert_eq!(input.flow(), Some(XOFF));
+    input.flow_written();
+    assert_eq!(input.flow(), None);
+    while input.pop().is_some() {}
+    assert_eq!(input.flow(), Some(XON));
+    input.flow_written();
+    input.push(INPUT_END);
+    assert!(input.ended() && !input.takes(ValidHostInterfaces::Serial));
+    input.stop();
+    assert!(!input.active());
+}
+
+#[test]
+fn input_of_a_set_length_ends_after_its_bytes() {
+    let mut input = StreamIn::new();
+    input.start(ValidHostInterfaces::UART, 1, Some(3));
+    // Ctrl-D is data
+    for byte in [INPUT_END, 2, 3, 4] {
+        input.push(byte);
+    }
+    assert!(!input.takes(ValidHostInterfaces::UART) && !input.ended());
+    assert_eq!((input.len(), input.pop()), (3, Some(INPUT_END)));
+    while input.pop().is_some() {}
+    assert!(input.ended() && !input.overrun());
+
+    // Bytes past a full input are lost, and still counted
+    input.start(ValidHostInterfaces::UART, 1, Some(INPUT_LEN as u32 + 1));
+    for _ in 0..=INPUT_LEN {
+        input.push(0);
+    }
+    assert!(input.overrun() && !input.takes(ValidHostInterfaces::UART));
+    assert_eq!(input.len(), INPUT_LEN);
+}
+
+#[test]
+fn flow_goes_to_the_host_feeding_the_input() {
+    let mut host = Host::default();
+    write_flow(&mut host, ValidHostInterfaces::Serial, 4, XOFF).unwrap();
+    assert_eq!(host.serial, [XOFF]);
+    // An empty data frame with the flow byte as its seq
+    write_flow(&mut host, ValidHostInterfaces::SPI, 4, XON).unwrap();
+    assert_eq!(host.spi[0][..6], [4, DATA_STATUS, host.spi[0][2], XON, 0, 0]);
+}
+
+#[test]
+fn spi_master_data_frames() {
+    let mut out = StreamOut::new(1);
+    out.push(&[1, 2, 3]);
+    out.finish();
+    let mut frame = out.chunk().unwrap().encode_8bit_spi(0);
+    let data = decode_spi_data(&frame).unwrap();
+    assert_eq!((data.seq, data.bytes()), (0, &[1, 2, 3][..]));
+    frame[7] ^= 1;
+    assert_eq!(decode_spi_data(&frame), Err(BridgeError::ChecksumMismatch));
+    // Requests have an interface in their first byte
+    assert_eq!(decode_spi_data(&out.chunk().unwrap().encode_8bit_spi(1)), Err(BridgeError::InvalidFrame));
+}
@@ -0,0 +1,193 @@
+//! SVF statements played against a simulated JTAG chain.
+//! Run on the host with: cargo test -p pico-bridge-core --target x86_64-unknown-linux-gnu
+
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::jtag::TapState;
+use pico_bridge_core::protocol::ValidHostInterfaces;
+use pico_bridge_core::stream::{StreamIn, INPUT_END, INPUT_LEN};
+use pico_bridge_core::svf::{SvfPlayer, SvfStep};
+
+mod common;
+use common::{chain_tck, Tap, IDCODE};
+
+const SYS_HZ: u32 = 125_000_000;
+// Devices nearest TDO first
+fn chain() -> [Tap; 2] {
+    [Tap::new(4, Some(0x4BA0_0477)), Tap::new(6, Some(0x0362_D093))]
+}
+
+struct Run {
+    result: Result<(u32, u8), BridgeError>,
+    // Line of the statement that failed
+    line: u32,
+    console: String,
+    // Words streamed to the host
+    words: Vec<u32>,
+    divisors: Vec<u32>,
+}
+
+// Play the text fed chunk bytes at a time, ended with Ctrl-D
+fn play(text: &str, chunk: usize, taps: &mut [Tap]) -> Run {
+    let mut player = SvfPlayer::new(SYS_HZ);
+    let mut input = StreamIn::new();
+    input.start(ValidHostInterfaces::Serial, 0, None);
+    player.start(None);
+    let mut bytes = text.bytes().chain(core::iter::once(INPUT_END));
+    let mut run = Run { result: Ok((0, 0)), line: 0, console: String::new(), words: Vec::new(), divisors: Vec::new() };
+    loop {
+        while !player.done() {
+            if let Some(chunk) = player.chunk() {
+                let bytes = chunk.bytes();
+                run.words.extend(bytes.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])));
+                player.chunk_written();
+            }
+            while let Some(word) = player.pending() {
+                player.sent();
+                let mut tdo = 0;
+                for bit in 0..16 {
+                    let pair = word >> (2 * bit);
+                    tdo |= (chain_tck(taps, pair & 1 != 0, pair & 2 != 0) as u32) << (16 + bit);
+                }
+                player.on_rx(tdo);
+            }
+        }
+        let step = player.step(&mut input, &mut run.console);
+        match step {
+            SvfStep::Next => {}
+            SvfStep::Input => {
+                for byte in bytes.by_ref().take(chunk) {
+                    input.push(byte);
+                }
+            }
+            SvfStep::Divisor(div) => run.divisors.push(div.frequency(SYS_HZ, 4)),
+            SvfStep::Done(result) => {
+                run.result = result;
+                run.line = player.failed_line();
+                assert!(!player.running());
+                return run
+            }
+        }
+    }
+}
+
+#[test]
+fn scans_with_header_and_trailer_compare_tdo() {
+    let svf = "! Read the IDCODE of the second device, the first one in BYPASS\n\
+        TRST OFF;\n\
+        ENDIR IDLE;\n\
+        ENDDR IDLE;\n\
+        STATE RESET;\n\
+        HIR 4 TDI (f);\n\
+        HDR 1 TDI (0);\n\
+        // The IDCODE instruction, the IR capture bits come out\n\
+        SIR 6 TDI (0e) TDO (01) MASK (3f);\n\
+        SDR 32 TDI (00000000)\n    TDO (0362D093) MASK (0FFFFFFF);\n\
+        RUNTEST 16 TCK;\n";
+    let mut taps = chain();
+    let run = play(svf, 7, &mut taps);
+    assert_eq!(run.result, Ok((9, 4)));
+    assert_eq!(run.console, "SVF ready, end with Ctrl-D\n\r");
+    assert_eq!((taps[0].ir, taps[1].ir), (0xF, IDCODE));
+    assert_eq!(taps[1].state, TapState::RunTestIdle);
+}
+
+#[test]
+fn the_first_mismatch_is_reported_with_its_line_and_bits() {
+    let svf = "STATE RESET;\n\
+        SDR 64 TDI (0) TDO (0362d093 4ba00477);\n\
+        SDR 64 TDI (0) TDO (1362d093 4ba00477);\n\
+        SDR 64 TDI (0) TDO (0);\n";
+    let run = play(svf, 64, &mut chain());
+    assert_eq!(run.result, Err(BridgeError::TdoMismatch));
+    assert_eq!(run.console, "SVF ready, end with Ctrl-D\n\r\
+        Line 3 SDR: TDO mismatch at bit 60, expected 0x1362d093 got 0x0362d093 mask 0xffffffff\n\r");
+    // For binary and SPI hosts, which get no console text
+    assert_eq!(run.line, 3);
+    assert_eq!(run.words, [0x1362_D093, 0x0362_D093, 0xFFFF_FFFF]);
+
+    // Masked bits are not compared
+    let svf = "SDR 64 TDI (0) TDO (1362d093 4ba00477) MASK (0fffffff ffffffff);\n";
+    assert_eq!(play(svf, 64, &mut chain()).result, Ok((1, 4)));
+}
+
+// Hex of bits LSB first, most significant digit first, 64 digits a line
+fn hex(bits: &[bool]) -> String {
+    let mut digits: Vec<char> = bits.chunks(4)
+        .map(|nibble| nibble.iter().rev().fold(0, |value, bit| value << 1 | *bit as u32))
+        .map(|value| char::from_digit(value, 16).unwrap())
+        .collect();
+    digits.reverse();
+    digits.chunks(64).map(|line| line.iter().collect::<String>()).collect::<Vec<_>>().join("\n")
+}
+
+fn word(bits: &[bool], start: usize) -> u32 {
+    bits[start..start + 32].iter().rev().fold(0, |value, bit| value << 1 | *bit as u32)
+}
+
+#[test]
+fn long_scans_are_read_and_compared_a_bit_at_a_time() {
+    // Both devices in BYPASS, TDO is TDI two TCKs later
+    let tdi: Vec<bool> = (0..1002_u32).map(|i| i.wrapping_mul(0x9E37_79B9) >> 31 != 0).collect();
+    let tdo: Vec<bool> = [false, false].iter().chain(&tdi[..1000]).copied().collect();
+    let svf = format!("SIR 10 TDI (3ff);\nSDR 1002 TDI ({})\nTDO ({});\n", hex(&tdi), hex(&tdo));
+    assert_eq!(play(&svf, 7, &mut chain()).result, Ok((2, 4)));
+
+    let mut wrong = tdo.clone();
+    wrong[777] = !wrong[777];
+    let svf = format!("SIR 10 TDI (3ff);\nSDR 1002 TDI ({})\nTDO ({});\n", hex(&tdi), hex(&wrong));
+    let run = play(&svf, 7, &mut chain());
+    assert_eq!(run.result, Err(BridgeError::TdoMismatch));
+    assert_eq!(run.console, format!("SVF ready, end with Ctrl-D\n\r\
+        Line 2 SDR: TDO mismatch at bit 777, expected {:#010x} got {:#010x} mask 0xffffffff\n\r",
+        word(&wrong, 768), word(&tdo, 768)));
+}
+
+#[test]
+fn runtest_clocks_the_count_or_the_time() {
+    let mut taps = chain();
+    let run = play("STATE IDLE;\nRUNTEST 100 TCK;\n", 64, &mut taps);
+    assert_eq!(run.result, Ok((2, 4)));
+    // Padded to whole words of TCKs in Run-Test/Idle
+    let after_count = taps[0].idle;
+    assert!((100..100 + 32).contains(&after_count));
+
+    // 1 ms at 1 MHz, after FREQUENCY halved it 500 us
+    let run = play("RUNTEST IDLE 10 TCK 1E-3 SEC ENDSTATE IDLE;\nFREQUENCY 5E5 HZ;\nRUNTEST 1.0E-3 SEC;\n", 64, &mut taps);
+    assert_eq!(run.result, Ok((3, 4)));
+    assert_eq!(run.divisors, vec![500_000]);
+    assert!((after_count + 1500..after_count + 1500 + 48).contains(&taps[0].idle));
+
+    // RUNTEST into Pause-DR
+    let run = play("RUNTEST DRPAUSE 5 TCK ENDSTATE IDLE;\n", 64, &mut taps);
+    assert_eq!(run.result, Ok((1, 4)));
+    assert_eq!(taps[0].state, TapState::RunTestIdle);
+}
+
+#[test]
+fn bad_statements_end_the_run_and_the_rest_is_dropped() {
+    let svf = "STATE RESET;\n\nSDR 8 TDO (ff);\nSDR 8 TDI (00);\n";
+    let run = play(svf, 5, &mut chain());
+    assert_eq!(run.result, Err(BridgeError::SvfSyntax));
+    assert!(run.console.ends_with("Line 3: Bad SVF statement\n\r"));
+    assert_eq!(run.line, 3);
+
+    for svf in ["PIOMAP (IN A);", "SIR 4 TDI (1) TDO;", "ENDDR DRSHIFT;", "RUNTEST 10;", "STATE IDLE", "SDR 8 TDI (xy);"] {
+        assert_eq!(play(svf, 64, &mut chain()).result, Err(BridgeError::SvfSyntax), "{}", svf);
+    }
+    assert_eq!(play("SDR 9000 TDI (0);", 64, &mut chain()).result, Err(BridgeError::OutOfRange));
+}
+
+#[test]
+fn input_overrun_ends_the_run() {
+    // A host that does not stop loses bytes, and the run
+    let mut input = StreamIn::new();
+    let mut player = SvfPlayer::new(SYS_HZ);
+    player.start(Some(TapState::RunTestIdle));
+    input.start(ValidHostInterfaces::Serial, 0, None);
+    for _ in 0..INPUT_LEN + 1 {
+        input.push(b' ');
+    }
+    input.push(INPUT_END);
+    let mut console = String::new();
+    assert_eq!(player.step(&mut input, &mut console), SvfStep::Done(Err(BridgeError::QueueFull)));
+}
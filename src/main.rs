@@ -9,7 +9,6 @@
 //! Version: 0.0.1
 //! 
 //! TODO: PIO receive/write task
-//! TODO: Assert IRQ on SMI reads
 //! TODO: Clear SPI/UART interrupts
 //! 
 //! src/openocd -f interface/cmsis-dap.cfg -c "adapter speed 1000" -f target/rp2040.cfg -s tcl
@@ -19,8 +18,9 @@
 use defmt_rtt as _;
 use panic_halt as _;
 mod fmt;
+mod monotonic;
 mod serial;
-mod protocol;
+mod spi_master;
 
 #[rtic::app(device = rp_pico::pac, peripherals = true, dispatchers= [PWM_IRQ_WRAP, SIO_IRQ_PROC0, SIO_IRQ_PROC1, UART1_IRQ])]
 mod app {
@@ -34,6 +34,7 @@ mod app {
     use rp_pico::hal as hal;
     use rp_pico::pac;
     use heapless::spsc::{Consumer, Producer, Queue};
+    use heapless::{Deque, Vec};
 
     const UART0_ICR: *mut u32 = 0x4003_4044 as *mut u32;
     const SPI0_ICR: *mut u32 = 0x4003_c020 as *mut u32;
@@ -41,7 +42,7 @@ mod app {
     use hal::{clocks::Clock,
         uart::{UartConfig, DataBits, StopBits},
         gpio::{pin::bank0::*, Pin, FunctionUart},
-        pio::{PIOExt, ShiftDirection,PIOBuilder, SM0, PinDir,},
+        pio::{PIOExt, ShiftDirection,PIOBuilder, SM0, SM1, PinDir, PinState,},
         };
 
     use cortex_m::peripheral::NVIC;
@@ -52,29 +53,87 @@ mod app {
     // USB Communications Class Device support
     use usbd_serial::SerialPort;
     use fugit::RateExtU32;
+    use fugit::ExtU64;
 
-    use crate::serial::{match_usb_serial_buf, write_serial};
-    use crate::protocol::{Send,
+    use crate::serial::{drain_serial, match_usb_serial_buf, queue_serial, write_error, write_serial, write_status_frame,
+        SerialOut, MENU};
+    use pico_bridge_core::frame::{FrameEvent, FrameReader, BINARY_MODE_ENTER};
+    use pico_bridge_core::protocol::{Send, Respond, HostTransport, ValidHostInterfaces, decode_smi_read, write_report,
         host::{HostRequest, Clean, ValidOps, ValidInterfaces,}, 
-        slave::{NotReady, SlaveResponse}};
+        slave::{NotReady, Ready, SlaveResponse}};
+    use pico_bridge_core::stream::{decode_spi_data, write_chunk, write_flow, Chunk, StreamIn, DATA_STATUS};
+    use pico_bridge_core::error::BridgeError;
+    use pico_bridge_core::cli::{parse_command, Command};
+    use pico_bridge_core::inflight::{InFlight, StateMachine};
+    use pico_bridge_core::clock::ClockDivisor;
+    use pico_bridge_core::smi::{PageSelect, SmiSequence, SmiStep, Snapshots};
+    use pico_bridge_core::i2c::{tx_word, I2cJob, I2cStep, I2C_CYCLES_PER_BIT, I2C_DEFAULT_HZ, I2C_MAX_HZ};
+    use pico_bridge_core::spi::{SpiConfig, SpiJob, MAX_CS, SPI_CYCLES_PER_BIT, SPI_DEFAULT_HZ, SPI_MAX_HZ};
+    use pico_bridge_core::flash::{FlashGeometry, FlashJob, FlashStep};
+    use pico_bridge_core::jtag::{streams_tdi, JtagJob, JtagStep, TapState, JTAG_CYCLES_PER_BIT, JTAG_DEFAULT_HZ};
+    use pico_bridge_core::svf::{SvfPlayer, SvfStep};
+    use crate::spi_master::PioSpi;
+    use pico_bridge_core::fmt::FmtBuf;
+    use crate::monotonic::TimerMono;
 
     use core::str;
 
-    /// Clock divider for the PIO SM
-    const SMI_DEFAULT_CLKDIV: u16 =  1;//4; // (133000000 / 2500000)
-    const PIO_CLK_DIV_FRAQ: u8 =  1;//145;
+    /// MDC frequency the SMI state machine starts with, the Clause 22 maximum
+    const SMI_DEFAULT_HZ: u32 = 2_500_000;
+    /// PIO cycles per MDC period of the SMI program
+    const SMI_CYCLES_PER_MDC: u32 = 10;
+    /// I2C master pins, SCL must follow SDA for the wait on SCL
+    const I2C_SDA: u8 = 20;
+    const I2C_SCL: u8 = 21;
+    /// JTAG master pins, TMS must follow TDI as they are set together
+    const JTAG_TCK: u8 = 2;
+    const JTAG_TDI: u8 = 3;
+    const JTAG_TMS: u8 = 4;
+    const JTAG_TDO: u8 = 7;
 
     type UartTx = Pin<Gpio0, FunctionUart>;
     type UartRx = Pin<Gpio1, FunctionUart>;
+    type UartDev = hal::uart::UartPeripheral<hal::uart::Enabled, pac::UART0, (UartTx, UartRx)>;
+
+    // State machine running each device interface, the key of in flight requests
+    const SMI_SM: StateMachine = StateMachine::new(0, 0);
+    const JTAG_SM: StateMachine = StateMachine::new(0, 1);
+    const I2C_SM: StateMachine = StateMachine::new(1, 0);
+    const SPI_SM: StateMachine = StateMachine::new(1, 1);
+    // Depth of the in flight table, shared by all state machines
+    const IN_FLIGHT_DEPTH: usize = 8;
+    // Requests send_out holds back while their state machine is busy
+    const PENDING_DEPTH: usize = 8;
+    // Frames waiting for the SPI master to clock them out, responses and the data requests stream ahead of them
+    const SPI_TX_DEPTH: usize = 8;
+    // Console output of one step of a sequence, a decoded register takes the most
+    const REPORT_LEN: usize = 512;
+    // Responses and reports waiting for room on the USB serial port
+    const UNSENT_DEPTH: usize = 4;
+
+    // What a host task could not write to the USB serial port yet, sent again in order as the host reads
+    enum Unsent {
+        Response(SlaveResponse<Ready>),
+        Report(ValidHostInterfaces, FmtBuf<REPORT_LEN>),
+    }
 
     /// External high-speed crystal on the Raspberry Pi Pico board is 12 MHz. Adjust
     /// if your board has a different frequency
 
+    // us timer for requests that wait on the bridge, like smi poll
+    #[monotonic(binds = TIMER_IRQ_0, default = true)]
+    type Mono = TimerMono;
+
     #[shared]
     struct Shared {
         
         serial: SerialPort<'static, hal::usb::UsbBus>,
+        // Bytes for the serial port the CDC buffer did not take yet, and what could not be queued behind them
+        serial_out: SerialOut,
+        unsent: Deque<Unsent, UNSENT_DEPTH>,
         usb_dev: usb_device::device::UsbDevice<'static, hal::usb::UsbBus>,
+        // UART host interface, requests come in on uart0 and responses go out from respond_to_host
+        uart_dev: UartDev,
 
         pio0: hal::pio::PIO<pac::PIO0>,
         // SMI PIO StateMachine Instance
@@ -84,10 +143,45 @@ mod app {
         // SMI PIO RX FIFO
         smi_rx: hal::pio::Rx<(pac::PIO0, SM0)>,
 
+        pio1: hal::pio::PIO<pac::PIO1>,
+        // I2C master PIO StateMachine Instance and its FIFOs
+        i2c_master: hal::pio::StateMachine<(pac::PIO1, SM0), hal::pio::Running>,
+        i2c_tx: hal::pio::Tx<(pac::PIO1, SM0)>,
+        i2c_rx: hal::pio::Rx<(pac::PIO1, SM0)>,
+        // I2C request running on the I2C state machine, one at a time
+        i2c_xfer: Option<I2cJob>,
+        // SPI master on PIO1 state machine 1 and the request it runs
+        spi_master: PioSpi,
+        spi_job: Option<SpiJob>,
+        // Flash request running on the SPI master instead, and the geometry learnt for each chip select
+        flash_job: Option<FlashJob>,
+        flash_chips: [FlashGeometry; MAX_CS],
+        // JTAG master on PIO0 state machine 1, the scan it runs and the state the TAP was left in, None until the
+        // first request resets it
+        jtag_master: hal::pio::StateMachine<(pac::PIO0, SM1), hal::pio::Running>,
+        jtag_tx: hal::pio::Tx<(pac::PIO0, SM1)>,
+        jtag_rx: hal::pio::Rx<(pac::PIO0, SM1)>,
+        jtag_job: Option<JtagJob>,
+        jtag_state: Option<TapState>,
+        // SVF played on the JTAG master instead
+        svf: SvfPlayer,
+        // Bytes streamed from a host to the request taking them, one request at a time
+        input: StreamIn,
+
         // String command that will be received over serial and must be matched
         serial_buf: [u8; 64],
 
         host_producer: Producer<'static, HostRequest<Clean>, 3>,
+        // Frames for the SPI master, shifted out by spi0
+        spi_tx: Producer<'static, [u8; 18], SPI_TX_DEPTH>,
+        // SlaveResponses waiting on their state machine, filled in by the PIO IRQs
+        in_flight: InFlight<IN_FLIGHT_DEPTH>,
+        // Multi transaction SMI request running on the SMI state machine, with the host and proc_id of its request
+        smi_seq: Option<((ValidHostInterfaces, u8), SmiSequence)>,
+        // Named register dumps that smi diff compares against
+        snapshots: Snapshots,
+        // Page select register of each PHY address for paged accesses
+        pages: PageSelect,
 
         #[lock_free]
         _spi_tx_buf: [u16; 9],
@@ -99,20 +193,17 @@ mod app {
 
     #[local]
     struct Local {
-        uart_dev: hal::uart::UartPeripheral<hal::uart::Enabled, pac::UART0, (UartTx, UartRx)>,
-
-        spi_tx_producer: Producer<'static, [u8; 18], 3>,
-        spi_tx_consumer: Consumer<'static, [u8; 18], 3>,
+        spi_tx_consumer: Consumer<'static, [u8; 18], SPI_TX_DEPTH>,
 
         host_consumer: Consumer<'static, HostRequest<Clean>, 3>,
-
-        producer: Producer<'static, SlaveResponse<NotReady>, 3>,    // Statically allocated non-blocking, non critical section access to writng to queue
-        consumer: Consumer<'static, SlaveResponse<NotReady>, 3>,    // Statically allocated non-blocking, non critical section access to read to queue 
+        // System clock the PIO divisors are computed from
+        sys_clk_hz: u32,
+        // SMBus transactions carry a PEC
+        smbus_pec: bool,
     }
 
     #[init(local = [usb_bus: Option<usb_device::bus::UsbBusAllocator<hal::usb::UsbBus>> = None,
-        spi_q: Queue<[u8; 18], 3> = Queue::new(),
-        q: Queue<SlaveResponse<NotReady>, 3> = Queue::new(),
+        spi_q: Queue<[u8; 18], SPI_TX_DEPTH> = Queue::new(),
         host_q: Queue<HostRequest<Clean>, 3> = Queue::new()])]
     fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
         unsafe {
@@ -141,13 +232,13 @@ mod app {
 
         // Step 4. Set up the system PLL 
         // 
-        // Take the Crystal Oscillator  (=12Mhz) with no divider, and x126 to 
-        // give a FOUTVCO of 1512 MHz. This must be in range 750 Mhz - 1600 Mhz
-        // THe factor of 126 is calcuated automatically given the desired FOUTVCO
+        // Take the Crystal Oscillator  (=12Mhz) with no divider, and multiply to 
+        // give a FOUTVCO of 800 MHz. This must be in range 750 Mhz - 1600 Mhz
+        // The multiplier is calcuated automatically given the desired FOUTVCO
         //
-        // Next we ÷5 on the first post divider to give 302.4 MHz
+        // Next we ÷3 on the first post divider to give 266.7 MHz
         //
-        // Finally we ÷2 on the second post divider to give 151.2 Mhz
+        // Finally we ÷2 on the second post divider to give 133.3 Mhz
         //
         let pll_sys = hal::pll::setup_pll_blocking(p.PLL_SYS,  xosc.operating_frequency(), hal::pll::PLLConfig {
                 vco_freq: HertzU32::MHz(800),
@@ -179,6 +270,8 @@ mod app {
         .unwrap();
 
         let mut resets = p.RESETS;
+        // Counts from the us watchdog tick set up with the clocks
+        let mono = TimerMono::new(p.TIMER, &mut resets);
         // The single-cycle I/O block controls our GPIO pins
         let sio = hal::Sio::new(p.SIO);
 
@@ -279,57 +372,59 @@ mod app {
         // Initialization of the PIO0 and SMI state machine
         let _mdio_pin = pins.gpio8.into_mode::<hal::gpio::FunctionPio0>();
         let _mdc_pin = pins.gpio9.into_mode::<hal::gpio::FunctionPio0>();
+        // MDIO master for Clause 22 and Clause 45 frames, 10 PIO cycles per MDC period.
+        // Each TX FIFO word is one frame as built by pico_bridge_core::protocol::encode_mdio:
+        // a read flag, the 14 header bits, and for writes 16 data bits, shifted out LSB first.
+        // The program adds the preamble and turnaround. On reads it pushes the second turnaround bit followed by
+        // the 16 bits read, and raises IRQ 0
         let program = pio_proc::pio_asm!( 
         "
         .side_set 1",
         ".wrap_target",
-        "set pins, 0   side 0",
     "start:",
         "pull block side 0",
         "set pindirs, 1 side 0",
         "set x, 31 side 0",
     "preamble:",
-        "set pins, 1 side 1      [4]",
-        "set pins 1  side 0      [2]",
-        "jmp x-- preamble side 0 [2]",
-        "set pins, 0  side 1     [4]",
-        "nop side 0 [2]",
-        "set y, 11 side 0 [2]",
-        "set pins, 1 side 1 [4]",
-        "nop side 0 [1]",
-    "addr:",
-        "set x, 15 side 0 [3]",
-        "out pins, 1   side 1 [4]",
-        "jmp y-- addr side 0 [1]",
-        "set pins, 0 side 0  [3]",  
-        "out y 1 side 1 [4]",
+        "set pins, 1 side 0 [4]",
+        "jmp x-- preamble side 1 [4]",
+        "out y, 1 side 0",          // Read flag
+        "set x, 13 side 0",
+    "header:",
+        "out pins, 1 side 0 [4]",   // ST, OP, PHYAD, REGAD/DEVAD
+        "jmp x-- header side 1 [4]",
+        "jmp !y write_ta side 0 [1]",
+        "set pindirs, 0 side 0 [2]", // Release MDIO for the turnaround, the PHY drives the second bit
+        "set x, 15 side 1 [4]",
+        "nop side 0 [4]",
+        "in pins, 1 side 1 [4]",    // The PHY drives the second turnaround bit low, high means nothing answered
         "nop side 0 [4]",
-        "nop side 1 [4]",
-        "jmp y-- write_data    side 0 [2]", // If Autopull pulled in another word from our TX FIFO, we have data to write
-        "set pindirs, 0 side 0 [2]",
     "read_data:",
-        "in pins 1 side 1 [4]",
+        "in pins, 1 side 1 [4]",    // Sample on the rising edge of MDC
         "jmp x-- read_data side 0 [4]",
         "push side 0",
-        "irq 1 side 0",        // Set IRQ flag with index 1 (State machine 1)
-        "out null 19 side 0"   // // Discard remaining 19 bits of 32 bit word (we wrote first 12 which are OP/PHY/REG fields)
+        "irq 0 side 0",             // Set IRQ flag with index 0 (State machine 0)
         "jmp start side 0",
+    "write_ta:",
+        "set pins, 1 side 0 [2]",
+        "set x, 15 side 1 [4]",
+        "set pins, 0 side 0 [4]",
+        "nop side 1 [4]",
     "write_data:",
-        "nop side 0 [1]",
-        "out pins, 1 side 1 [4]",
-        "jmp x-- write_data side 0 [3]",
-        "set pins 0 side 0",        // Set IRQ flag with index 1 (State machine 1)
-        "out null 32 side 0",
+        "out pins, 1 side 0 [4]",
+        "jmp x-- write_data side 1 [4]",
         ".wrap",
         ); 
             
-        let (mut pio0, sm0, _, _, _,) = p.PIO0.split(&mut resets);
+        let sys_clk_hz = clocks.system_clock.freq().to_Hz();
+        let smi_div = ClockDivisor::for_frequency(sys_clk_hz, SMI_DEFAULT_HZ, SMI_CYCLES_PER_MDC).unwrap();
+        let (mut pio0, sm0, pio0_sm1, _, _,) = p.PIO0.split(&mut resets);
         let installed = pio0.install(&program.program).unwrap();
         let (mut sm, smi_rx, smi_tx) = PIOBuilder::from_program(installed)
             .out_pins(5, 1)
             .side_set_pin_base(6)
             .out_sticky(false)
-            .clock_divisor_fixed_point(SMI_DEFAULT_CLKDIV, PIO_CLK_DIV_FRAQ) // freq = 1 / (int + (frac/256))
+            .clock_divisor_fixed_point(smi_div.int, smi_div.frac) // freq = 1 / (int + (frac/256))
             .out_shift_direction(ShiftDirection::Right)
             .in_shift_direction(ShiftDirection::Left)
             .autopush(true)
@@ -341,17 +436,146 @@ mod app {
         sm.set_pindirs([(5, PinDir::Output)]);
         sm.set_pindirs([(6, PinDir::Output)]);
         let smi_master = sm.start();
+        // The SMI state machine raises IRQ flag 0 when a read completes
+        pio0.irq0().enable_sm_interrupt(0);
+
+        //*****
+        // Initialization of the JTAG master state machine, on PIO0 with the SMI master
+        let _jtag_tck = pins.gpio2.into_mode::<hal::gpio::FunctionPio0>();
+        let _jtag_tdi = pins.gpio3.into_mode::<hal::gpio::FunctionPio0>();
+        let _jtag_tms = pins.gpio4.into_mode::<hal::gpio::FunctionPio0>();
+        let _jtag_tdo = pins.gpio7.into_mode::<hal::gpio::FunctionPio0>();
+        // One TCK per pair of bits pulled, TDI and TMS set while TCK is low and TDO sampled on the rising edge,
+        // 4 PIO cycles per TCK period. Stalls with TCK low while the TX FIFO is empty
+        let jtag_program = pio_proc::pio_asm!(
+        ".side_set 1",
+        "out pins, 2 side 0 [1]",
+        "in pins, 1 side 1 [1]",
+        );
+        let jtag_div = ClockDivisor::for_frequency(sys_clk_hz, JTAG_DEFAULT_HZ, JTAG_CYCLES_PER_BIT).unwrap();
+        let installed = pio0.install(&jtag_program.program).unwrap();
+        let (mut sm, jtag_rx, jtag_tx) = PIOBuilder::from_program(installed)
+            .out_pins(JTAG_TDI, 2)
+            .in_pin_base(JTAG_TDO)
+            .side_set_pin_base(JTAG_TCK)
+            .clock_divisor_fixed_point(jtag_div.int, jtag_div.frac)
+            .out_shift_direction(ShiftDirection::Right)
+            .autopull(true)
+            // 32 bits, written as 0
+            .pull_threshold(0)
+            .in_shift_direction(ShiftDirection::Right)
+            .autopush(true)
+            .push_threshold(16)
+            .build(pio0_sm1);
+        sm.set_pindirs([(JTAG_TCK, PinDir::Output), (JTAG_TDI, PinDir::Output), (JTAG_TMS, PinDir::Output),
+            (JTAG_TDO, PinDir::Input)]);
+        let jtag_master = sm.start();
+        // Each 16 TCKs push a word
+        pio0.irq1().enable_rx_not_empty_interrupt(1);
+
+        //*****
+        // Initialization of the PIO1 and I2C master state machine
+        // SDA and SCL are open drain: the pins output 0 and the state machine drives their direction, with OE
+        // inverted so a pindir of 1 releases the line. The bus needs external pull-ups
+        let mut i2c_sda = pins.gpio20.into_mode::<hal::gpio::FunctionPio1>();
+        let mut i2c_scl = pins.gpio21.into_mode::<hal::gpio::FunctionPio1>();
+        i2c_sda.set_output_enable_override(hal::gpio::OutputEnableOverride::Invert);
+        i2c_scl.set_output_enable_override(hal::gpio::OutputEnableOverride::Invert);
+        // The I2C program of the pico examples, 32 PIO cycles per SCL period with clock stretching.
+        // NAKs do not stop it, the ACK bit is pushed after the 8 bits of each byte.
+        // The records it takes are built by pico_bridge_core::i2c
+        let i2c_program = pio_proc::pio_asm!(
+        "
+        .side_set 1 opt pindirs",
+        ".wrap_target",
+    "entry:",
+        "out x, 6",                 // Instruction count
+        "out null, 1",              // Final bit, unused
+        "jmp !x do_byte",
+        "out null, 32",             // Instruction records carry no data
+    "do_exec:",
+        "out exec, 16",             // Run one instruction per FIFO word
+        "jmp x-- do_exec",
+        ".wrap",
+    "do_byte:",
+        "set x, 7",
+    "bitloop:",
+        "out pindirs, 1 [7]",       // Write data, all ones when reading
+        "nop side 1 [2]",           // SCL rising edge
+        "wait 1 pin, 1 [4]",        // Allow clock stretching
+        "in pins, 1 [7]",           // Sample data in the middle of SCL high
+        "jmp x-- bitloop side 0 [7]", // SCL falling edge
+        "out pindirs, 1 [7]",       // ACK, driven by us on reads
+        "nop side 1 [7]",
+        "wait 1 pin, 1 [7]",
+        "in pins, 1 side 0 [2]",    // Sample the ACK, autopush of the byte and its ACK
+        "jmp entry",
+        );
+        let i2c_div = ClockDivisor::for_frequency(sys_clk_hz, I2C_DEFAULT_HZ, I2C_CYCLES_PER_BIT).unwrap();
+        let (mut pio1, pio1_sm0, pio1_sm1, _, _,) = p.PIO1.split(&mut resets);
+        let installed = pio1.install(&i2c_program.program).unwrap();
+        let (mut sm, i2c_rx, i2c_tx) = PIOBuilder::from_program(installed)
+            .set_pins(I2C_SDA, 1)
+            .out_pins(I2C_SDA, 1)
+            .in_pin_base(I2C_SDA)
+            .side_set_pin_base(I2C_SCL)
+            .clock_divisor_fixed_point(i2c_div.int, i2c_div.frac)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(16)
+            .in_shift_direction(ShiftDirection::Left)
+            .autopush(true)
+            .push_threshold(9)
+            .build(pio1_sm0);
+        // Both lines released
+        sm.set_pins([(I2C_SDA, PinState::Low), (I2C_SCL, PinState::Low)]);
+        sm.set_pindirs([(I2C_SDA, PinDir::Output), (I2C_SCL, PinDir::Output)]);
+        let i2c_master = sm.start();
+        // Each byte pushes a word, and the state machine raises IRQ flag 0 at the end of a transfer
+        pio1.irq0().enable_rx_not_empty_interrupt(0);
+        pio1.irq0().enable_sm_interrupt(0);
+
+        //*****
+        // Initialization of the SPI master state machine, on PIO1 with the I2C master
+        let spi_sck = pins.gpio10.into_mode::<hal::gpio::FunctionPio1>();
+        let _spi_mosi = pins.gpio11.into_mode::<hal::gpio::FunctionPio1>();
+        let _spi_miso = pins.gpio12.into_mode::<hal::gpio::FunctionPio1>();
+        let spi_cs: [hal::gpio::DynPin; MAX_CS] = [
+            pins.gpio13.into_push_pull_output().into(),
+            pins.gpio14.into_push_pull_output().into(),
+            pins.gpio15.into_push_pull_output().into(),
+            pins.gpio22.into_push_pull_output().into(),
+        ];
+        // The SPI programs of the pico examples, 4 PIO cycles per SCK period. Both stall with SCK low while the
+        // TX FIFO is empty, clock phase 0 samples MISO on the rising edge and phase 1 on the falling edge
+        let spi_cpha0 = pio_proc::pio_asm!(
+        ".side_set 1",
+        "out pins, 1 side 0 [1]",
+        "in pins, 1 side 1 [1]",
+        );
+        let spi_cpha1 = pio_proc::pio_asm!(
+        ".side_set 1",
+        "out x, 1 side 0",
+        "mov pins, x side 1 [1]",
+        "in pins, 1 side 0",
+        );
+        let spi_div = ClockDivisor::for_frequency(sys_clk_hz, SPI_DEFAULT_HZ, SPI_CYCLES_PER_BIT).unwrap();
+        let spi_master = PioSpi::new(pio1_sm1,
+            pio1.install(&spi_cpha0.program).unwrap(),
+            pio1.install(&spi_cpha1.program).unwrap(),
+            spi_sck, spi_cs, spi_div);
+        // Each word shifted pushes one
+        pio1.irq1().enable_rx_not_empty_interrupt(1);
         let serial_buf = [0_u8; 64];
         let _spi_tx_buf = [0_u16; 9];
 
-        let (mut spi_tx_producer, spi_tx_consumer) = c.local.spi_q.split();
+        let (mut spi_tx, spi_tx_consumer) = c.local.spi_q.split();
         // initialize our first buffer
-        spi_tx_producer.enqueue([0_u8; 18]).unwrap();
+        spi_tx.enqueue([0_u8; 18]).unwrap();
 
         freepin.set_low().unwrap();
-        // q has 'static lifetime so after the split and return of 'init'
+        // host_q has 'static lifetime so after the split and return of 'init'
         // it will continue to exist and be allocated
-        let (producer, consumer) = c.local.q.split();
         let (host_producer, host_consumer) = c.local.host_q.split();
 
         //spi_dev.write(&[1_u8, 2_u8, 3_u8, 4_u8, 5_u8, 6_u8, 7_u8, 8_u8]).unwrap();
@@ -360,6 +584,7 @@ mod app {
             NVIC::unmask(Interrupt::UART0_IRQ);
             // NVIC::unmask(Interrupt::SPI0_IRQ);
             NVIC::unmask(Interrupt::PIO0_IRQ_0);
+            NVIC::unmask(Interrupt::PIO1_IRQ_0);
             // NVIC::pend(Interrupt::SPI0_IRQ);
         }
         
@@ -372,7 +597,10 @@ mod app {
         (
             Shared {
                 serial,
+                serial_out: Deque::new(),
+                unsent: Deque::new(),
                 usb_dev,
+                uart_dev,
 
                 pio0,
                 smi_master,      // SMI PIO State Machine 
@@ -380,68 +608,95 @@ mod app {
                 smi_tx,          // SMI TX FIFO
                 smi_rx,          // SMI RX FIFO
 
+                pio1,
+                i2c_master,
+                i2c_tx,
+                i2c_rx,
+                i2c_xfer: None,
+                spi_master,
+                spi_job: None,
+                flash_job: None,
+                flash_chips: [FlashGeometry::DEFAULT; MAX_CS],
+                jtag_master,
+                jtag_tx,
+                jtag_rx,
+                jtag_job: None,
+                jtag_state: None,
+                svf: SvfPlayer::new(sys_clk_hz),
+                input: StreamIn::new(),
+
                 serial_buf,
                 _spi_tx_buf,
 
                 host_producer,
+                spi_tx,
+                in_flight: InFlight::new(),
+                smi_seq: None,
+                snapshots: Snapshots::new(),
+                pages: PageSelect::new(),
                 freepin,
                 spi_dev: spi_dev,
             },
             Local {
-                uart_dev: uart_dev,
-
-                spi_tx_producer,
                 spi_tx_consumer,
 
                 host_consumer,
-
-                producer,
-                consumer,
+                sys_clk_hz,
+                smbus_pec: false,
             },
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
-    #[task(binds=UART0_IRQ, priority=2, local=[uart_dev], shared=[serial, host_producer])]
+    #[task(binds=UART0_IRQ, priority=2, shared=[uart_dev, host_producer, input], local=[next_proc_id: u8 = 0])]
     fn uart0(cx: uart0::Context) {
-        let uart = cx.local.uart_dev;
+        let next_proc_id = cx.local.next_proc_id;
+        let uart_dev = cx.shared.uart_dev;
         let host_producer = cx.shared.host_producer;
+        let input = cx.shared.input;
         // RX FIFO is 32 bytes deep
         let mut buffer = [0_u8; 64];
-        let serial = cx.shared.serial;
-        (serial, host_producer).lock(|serial, host_producer| {
+        (uart_dev, host_producer, input).lock(|uart, host_producer, input| {
         match uart.read_raw(&mut buffer) {
             Err(_err) => {   
-                    write_serial(serial, "Uart RX Error", false);  
+                    uart.write_full_blocking(b"Uart RX Error\n\r");
+            }
+            // Data streamed to the request taking input from the UART
+            Ok(count) if input.takes(ValidHostInterfaces::UART) => {
+                for byte in &buffer[..count] {
+                    input.push(*byte);
+                }
+                input_received();
             }
             _ => {
-                match match_usb_serial_buf(&buffer, serial) {
-                    Ok(hr) => { // Got a Host Request from the Serial Port
-                        let clean = hr.init_clean(); // Validate it
-                        match clean {
+                let result = match parse_command(str::from_utf8(&buffer).unwrap_or("")) {
+                    Ok(Command::Request(mut hr)) => { // Got a Host Request from the UART
+                        // Respond on the UART the request came in on
+                        hr.set_host_config(ValidHostInterfaces::UART);
+                        hr.set_proc_id(*next_proc_id);
+                        *next_proc_id = next_proc_id.wrapping_add(1);
+                        match hr.init_clean() { // Validate it
                             Ok(hr) => {
-                                match host_producer.enqueue(hr) {
-                                    Ok(..) => {
-
-                                    }
-                                    Err(..) => {
-                                        write_serial(serial, "Error Pushing Host Request to queue\n\r", false);
-                                    }
-                                };
-                                send_out::spawn().unwrap(); // Send our clean host request to its destination
-                            }
-                            Err(err) =>  {
-                                write_serial(serial, err, false);
+                                host_producer.enqueue(hr)
+                                    .map(|_| { let _ = send_out::spawn(); }) // Send our clean host request to its destination
+                                    .map_err(|_| BridgeError::QueueFull)
                             }
+                            Err(err) => Err(err),
                         } 
                     }
-                    Err("Ok") => { }// We processed a simple command without constructing a Host Request
-                    Err(err) => {
-                        write_serial(serial, err, false); // Print the error back to the Serial port
+                    Ok(Command::Menu) => { // We processed a simple command without constructing a Host Request
+                        uart.write_full_blocking(MENU.as_bytes());
+                        Ok(())
                     }
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    // Print the error back to the UART
+                    uart.write_full_blocking(err.as_str().as_bytes());
+                    uart.write_full_blocking(b"\n\r");
+                }
             }
         }
-        }
     });
         
     unsafe {
@@ -455,46 +710,84 @@ mod app {
 
 
     // Task that binds to the SPI0 IRQ and handles requests. This will execute from RAM
-    // This takes a mutable reference to the SPI bus writes immediately from tx_buffer while reading 
-    // into the rx_buffer
+    // Every byte clocked in by the master shifts out the next byte of the current response frame.
+    // Once a full request frame has been received it is built into a HostRequest, a request that is
+    // rejected (invalid fields or checksum mismatch) is answered with an error response on the next frame
     #[inline(never)]
     #[link_section = ".data.bar"] // Execute from IRAM
-    #[task(binds=SPI0_IRQ, priority=2, local=[spi_tx_consumer], shared = [spi_dev, serial, host_producer, freepin])]
+    #[task(binds=SPI0_IRQ, priority=2, local=[spi_tx_consumer, spi_rx_buf: [u8; 18] = [0_u8; 18], spi_rx_count: usize = 0,
+        spi_tx_frame: [u8; 18] = [0_u8; 18], next_proc_id: u8 = 0], shared = [spi_dev, serial, host_producer, input])]
     fn spi0(cx: spi0::Context) {  
-        let mut serial = cx.shared.serial;
-        let mut freepin = cx.shared.freepin;
-        (serial, freepin).lock(|serial, freepin|
-            {
-                freepin.set_high();
-                write_serial(serial, "RX IRQ\n\r", false);
-            }); 
-        /* 
-         let spi = cx.local.spi_dev;
-         let mut buffer = [0_u8; 16];
-         match spi.transfer(&mut buffer) {
-            Err(_err) => {
+        let next_proc_id = cx.local.next_proc_id;
+        let spi_rx_buf = cx.local.spi_rx_buf;
+        let spi_rx_count = cx.local.spi_rx_count;
+        let spi_tx_frame = cx.local.spi_tx_frame;
+        let spi_tx_consumer = cx.local.spi_tx_consumer;
 
-            }
-            _ => {
-                match HostRequest::new().build_from_8bit_spi(&buffer) {
+        let spi_dev = cx.shared.spi_dev;
+        let serial = cx.shared.serial;
+        let host_producer = cx.shared.host_producer;
+        let input = cx.shared.input;
+        (spi_dev, serial, host_producer, input).lock(|spi_dev, serial, host_producer, input| {
+            while let Ok(byte) = spi_dev.read() {
+                spi_rx_buf[*spi_rx_count] = byte;
+                *spi_rx_count += 1;
+                if *spi_rx_count < spi_rx_buf.len() {
+                    let _ = spi_dev.send(spi_tx_frame[*spi_rx_count]);
+                    continue;
+                }
+                // A full request frame has been clocked in
+                *spi_rx_count = 0;
+                // An all zero frame only polls for the next frame, like the data a request streams
+                if spi_rx_buf.iter().all(|byte| *byte == 0) {
+                    *spi_tx_frame = spi_tx_consumer.dequeue().unwrap_or([0_u8; 18]);
+                    let _ = spi_dev.send(spi_tx_frame[0]);
+                    continue;
+                }
+                // Data for the request taking input from the master, shifting out the next frame as a poll does
+                if spi_rx_buf[0] == 0 && spi_rx_buf[1] == DATA_STATUS {
+                    let taken = decode_spi_data(spi_rx_buf).and_then(|data| {
+                        if !input.takes(ValidHostInterfaces::SPI) {
+                            return Err(BridgeError::NotFound)
+                        }
+                        for byte in data.bytes() {
+                            input.push(*byte);
+                        }
+                        input_received();
+                        Ok(())
+                    });
+                    *spi_tx_frame = match taken {
+                        Ok(()) => spi_tx_consumer.dequeue().unwrap_or([0_u8; 18]),
+                        Err(err) => spi_error_frame(err),
+                    };
+                    let _ = spi_dev.send(spi_tx_frame[0]);
+                    continue;
+                }
+                // Frames carry no proc_id, number them so their responses can be matched
+                let mut hr = HostRequest::new();
+                hr.set_proc_id(*next_proc_id);
+                *next_proc_id = next_proc_id.wrapping_add(1);
+                match hr.build_from_8bit_spi(spi_rx_buf) {
                     Ok(hr) => { // Host Request is clean and ready to be sent out
-                        let mut host_producer = cx.shared.host_producer;
-                        host_producer.lock(|host_producer| {
-                            match host_producer.enqueue(hr) {
-                                Ok(..) => {
-                                    send_out::spawn().unwrap();
-                                }
-                                Err(..) => {
-                                    // implement spi error handling
-                                }
+                        match host_producer.enqueue(hr) {
+                            Ok(..) => {
+                                let _ = send_out::spawn();
+                            }
+                            Err(..) => {
+                                write_error(serial, BridgeError::QueueFull);
                             }
-                        })
+                        }
+                        // Shift out the next response that is ready, if any
+                        *spi_tx_frame = spi_tx_consumer.dequeue().unwrap_or([0_u8; 18]);
                     }
-                    Err(_err) => { // Print the error to serial port if Host Request is invalid
+                    Err(err) => {
+                        // Let the master know the request was dropped on its next transfer
+                        *spi_tx_frame = spi_error_frame(err);
                     }
                 }
+                let _ = spi_dev.send(spi_tx_frame[0]);
             }
-         } */
+        });
         // SPI0_IRQ State
         // Debug Breakpoint
         // Test points:
@@ -506,62 +799,40 @@ mod app {
         //      SPI0_SMIS_0x4003c014 Expect: 0xc, RX/TX IM are unmasked
         //      SPI0_SRIS_0x4003c018: This will tell what interrupt source asserted SPI0_IRQ
 
-        // let mut serial = cx.shared.serial;
-        // let _spi_dev = cx.local.spi_dev;
-        //serial.lock(|serial|
-        // {
-        //     write_serial(serial, "Assert IRQ", false);
-        //     let _rx_buf = [0_u8; 1];
-        // }); 
-        
-
-        //if spi_dev.ssm() {
-        //    serial.lock(|serial|
-        //        {
-        //            write_serial(serial, "RX IRQ", false);
-        //        }); 
-        //}
-            /*let mut tx_buf = [5u8; 18];
-            // Write/Read words back to slave. Received words will replace contents in tx_buf
-            if let Some(mut tx_buf) = cx.local.spi_tx_consumer.dequeue(){}
-            match cx.local.spi_dev.transfer(&mut tx_buf) {
-            Ok(tx_buf) => {
-
-                      // Received words, Now build our HostRequest
-                match HostRequest::new().build_from_8bit_spi(&tx_buf) {
-                    Ok(hr) => { // Host Request is clean and ready to be sent out
-                        send_out::spawn(hr);
-                    }
-                    Err(err) => { // Print the error to serial port if Host Request is invalid
-                    }
-                }
-            }
-
-            _ => {
-                }
-            } */
-            
-        //unsafe {
+        unsafe {
             // Clear the SPI0_IRQ in the NVIC
-        //    NVIC::unpend(pac::Interrupt::SPI0_IRQ);
-            // Clear the UART RX/TX interrupt on the peripheral
-         //   core::ptr::write_volatile(SPI0_ICR, 0x3);
-        // }
+            NVIC::unpend(pac::Interrupt::SPI0_IRQ);
+            // Clear the SPI RX overrun/timeout interrupts on the peripheral
+            core::ptr::write_volatile(SPI0_ICR, 0x3);
+        }
     }
 
     // USB interrupt handler hardware task. Runs every time host requests new data
     #[inline(never)]
     #[link_section = ".data.bar"] // Execute from IRAM
-    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [serial, usb_dev, serial_buf, freepin, host_producer])]
+    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [serial, serial_out, unsent, usb_dev, serial_buf, freepin,
+        host_producer, input],
+        local = [binary_mode: bool = false, frame_reader: FrameReader = FrameReader::new(), next_proc_id: u8 = 0])]
     fn usb_rx(cx: usb_rx::Context) {
+        let binary_mode = cx.local.binary_mode;
+        let next_proc_id = cx.local.next_proc_id;
+        let frame_reader = cx.local.frame_reader;
         let usb_dev = cx.shared.usb_dev;
         let serial = cx.shared.serial;
         let serial_buf = cx.shared.serial_buf;
         let freepin = cx.shared.freepin;
         let host_producer = cx.shared.host_producer;
-
-        (usb_dev, serial, serial_buf, freepin, host_producer).lock(
-            |usb_dev_a, serial_a, serial_buf, freepin, host_producer| {
+        let input = cx.shared.input;
+        let serial_out = cx.shared.serial_out;
+        let unsent = cx.shared.unsent;
+
+        (usb_dev, serial, serial_buf, freepin, host_producer, input, serial_out, unsent).lock(
+            |usb_dev_a, serial_a, serial_buf, freepin, host_producer, input, serial_out, unsent| {
+                // The host read, the bytes waiting go out, then what could not be queued behind them
+                drain_serial(serial_a, serial_out);
+                if !unsent.is_empty() {
+                    let _ = resend_to_host::spawn();
+                }
                 // Check for new data
                 if  usb_dev_a.poll(&mut [serial_a]) {
                     let mut buf = [0u8; 64];
@@ -574,9 +845,70 @@ mod app {
                             let _ = serial_a.write(b"Didn't received data.\n\r");
                             let _ = serial_a.flush();
                         }
+                        // Data streamed to the request taking input from the console, until it has all its bytes
+                        // or Ctrl-D. The request tells the host to stop with XOFF while it catches up
+                        Ok(count) if !*binary_mode && input.takes(ValidHostInterfaces::Serial) => {
+                            for byte in &buf[..count] {
+                                input.push(*byte);
+                            }
+                            input_received();
+                        }
+                        // Binary mode, every byte received is part of a COBS frame
+                        Ok(count) if *binary_mode => {
+                            for byte in &buf[..count] {
+                                match frame_reader.push(*byte) {
+                                    Some(FrameEvent::Request(hr)) => {
+                                        let proc_id = hr.proc_id();
+                                        match hr.init_clean() {
+                                            Ok(hr) => {
+                                                match host_producer.enqueue(hr) {
+                                                    Ok(..) => {
+                                                        let _ = send_out::spawn();
+                                                    }
+                                                    Err(..) => {
+                                                        write_status_frame(serial_a, serial_out, proc_id, Some(BridgeError::QueueFull));
+                                                    }
+                                                }
+                                            }
+                                            Err(err) => {
+                                                write_status_frame(serial_a, serial_out, proc_id, Some(err));
+                                            }
+                                        }
+                                    }
+                                    Some(FrameEvent::Rejected { proc_id, error }) => {
+                                        write_status_frame(serial_a, serial_out, proc_id, Some(error));
+                                    }
+                                    // Data frames go to the input of their request
+                                    Some(FrameEvent::Data(data)) => {
+                                        if input.takes(ValidHostInterfaces::SerialFramed) && data.proc_id == input.proc_id() {
+                                            for byte in data.bytes() {
+                                                input.push(*byte);
+                                            }
+                                            input_received();
+                                        }
+                                        else {
+                                            write_status_frame(serial_a, serial_out, data.proc_id, Some(BridgeError::NotFound));
+                                        }
+                                    }
+                                    Some(FrameEvent::ExitBinary) => {
+                                        *binary_mode = false;
+                                        write_serial(serial_a, "\n\rText mode\n\r", false);
+                                    }
+                                    None => {}
+                                }
+                            }
+                        }
                         // TODO Add backspace function
                         Ok(_count) => {
                             match buf[0] {
+                                // Switch the port to binary mode, dropping any partially typed command
+                                BINARY_MODE_ENTER => {
+                                    *binary_mode = true;
+                                    *frame_reader = FrameReader::new();
+                                    for elem in serial_buf.iter_mut() {
+                                        *elem = 0;
+                                    }
+                                }
                                 // Check if return key was given \n, if so a command was given.
                                 b'\r' => { 
                                     //freepin.set_high().unwrap();
@@ -590,7 +922,10 @@ mod app {
                                         }
                                     }
                                     match match_usb_serial_buf(serial_buf, serial_a) {
-                                        Ok(hr) => { // Got a Host Request from the Serial Port
+                                        Ok(Command::Request(mut hr)) => { // Got a Host Request from the Serial Port
+                                            // Typed commands carry no proc_id, number them so their responses can be matched
+                                            hr.set_proc_id(*next_proc_id);
+                                            *next_proc_id = next_proc_id.wrapping_add(1);
                                             let clean = hr.init_clean(); // Validate it
                                             match clean {
                                                 Ok(hr) => {
@@ -599,19 +934,19 @@ mod app {
 
                                                         }
                                                         Err(..) => {
-                                                            write_serial(serial_a, "Error Pushing Host Request to queue\n\r", false);
+                                                            write_error(serial_a, BridgeError::QueueFull);
                                                         }
                                                     };
-                                                    send_out::spawn().unwrap(); // Send our clean host request to its destination
+                                                    let _ = send_out::spawn(); // Send our clean host request to its destination
                                                 }
                                                 Err(err) =>  {
-                                                    write_serial(serial_a, err, false);
+                                                    write_error(serial_a, err);
                                                 }
                                             } 
                                         }
-                                        Err("Ok") => { }// We processed a simple command without constructing a Host Request
+                                        Ok(Command::Menu) => { }// We processed a simple command without constructing a Host Request
                                         Err(err) => {
-                                            write_serial(serial_a, err, false); // Print the error back to the Serial port
+                                            write_error(serial_a, err); // Print the error back to the Serial port
                                         }
                                     }
                                     // Reset serial buffer
@@ -643,178 +978,1180 @@ mod app {
 
     // Software task that sends clean HostRequest to its destination (SysConfig or state machine)
     // Must validate that Associated State Machine is available and ready before sending, if not, return an Err
-    // Pushes a SlaveResponse<NotReady> to process queue, that PIO_IRQ will build when response is gotten from state machine  
-    #[task(priority = 3, local = [producer, host_consumer], shared = [serial, smi_master, smi_tx, smi_rx, freepin])]
+    // Tracks a SlaveResponse<NotReady> in the in flight table, that PIO_IRQ will build when response is gotten from state machine  
+    // Requests that complete here are answered right away through respond_to_host
+    // Drains the host queue, so a spawn while one is already pending can be dropped
+    #[task(priority = 3, local = [host_consumer, sys_clk_hz, smbus_pec,
+        pending: Vec<HostRequest<Clean>, PENDING_DEPTH> = Vec::new()], shared = [serial, smi_master, smi_tx, in_flight, smi_seq, snapshots, pages, freepin,
+        i2c_master, i2c_tx, i2c_xfer, spi_master, spi_job, flash_job, flash_chips, jtag_tx, jtag_job, jtag_state, svf,
+        input])]
     fn send_out(cx: send_out::Context) {
 
-        let mut slave_response = false;
+        let sys_clk_hz = *cx.local.sys_clk_hz;
+        let host_consumer = cx.local.host_consumer;
+        let smbus_pec = cx.local.smbus_pec;
+        let pending = cx.local.pending;
 
         let freepin = cx.shared.freepin;
         let smi_tx = cx.shared.smi_tx;
-        let smi_rx = cx.shared.smi_rx;
         let smi_master = cx.shared.smi_master;
+        let in_flight = cx.shared.in_flight;
+        let smi_seq = cx.shared.smi_seq;
+        let snapshots = cx.shared.snapshots;
+        let pages = cx.shared.pages;
         let serial = cx.shared.serial; 
-
-        let producer = cx.local.producer;
-
-        let mut return_string = "\n\r->";
-        let mut hr = cx.local.host_consumer.dequeue();
-        match hr  {
-            Some(mut hr) => {
-                (freepin, smi_tx, smi_rx, smi_master, serial).lock(|freepin, smi_tx, smi_rx, smi_master, serial| {
-                match hr.interface {
-                    // For each additional supported interface, add another match arm that sends to the interface
-                    // Take handle of its TX FIFO and send payload word by word according to the size
-                    ValidInterfaces::SMI => {
-                        // Send 32 bit word of for either read or write to SMI TX FIFO
-                        smi_tx.write(hr.payload[0]);
-                        smi_rx.read(); // for now we will empty the RX FIFO
-                        slave_response = true;
-                    }
-                    ValidInterfaces::Config => {
-                        if hr.operation == ValidOps::SmiSet {
-                            if hr.payload[0] == 25 {
-                                    smi_master.set_clock_divisor(4.56640625);
-                                    // smi_master.clock_divisor_fixed_point(4, 145);
-                                    return_string = "\n\rSMI Clock Rate Set 2.5Mhz\n\r->";
+        // Locked on their own where the I2C requests need them
+        let mut i2c_master = cx.shared.i2c_master;
+        let mut i2c_tx = cx.shared.i2c_tx;
+        let mut i2c_xfer = cx.shared.i2c_xfer;
+        let mut spi_master = cx.shared.spi_master;
+        let mut spi_job = cx.shared.spi_job;
+        let mut flash_job = cx.shared.flash_job;
+        let mut flash_chips = cx.shared.flash_chips;
+        let mut jtag_tx = cx.shared.jtag_tx;
+        let mut jtag_job = cx.shared.jtag_job;
+        let mut jtag_state = cx.shared.jtag_state;
+        let mut svf = cx.shared.svf;
+        let mut input = cx.shared.input;
+
+        (freepin, smi_tx, smi_master, in_flight, smi_seq, snapshots, pages, serial).lock(
+            |freepin, smi_tx, smi_master, in_flight, smi_seq, snapshots, pages, serial| {
+        // State machines with a request held back, the requests behind it for the same machine wait too
+        let mut blocked: Vec<StateMachine, 4> = Vec::new();
+        let mut index = 0;
+        loop {
+            // Requests held back stay in order ahead of the new ones
+            while !pending.is_full() {
+                match host_consumer.dequeue() {
+                    Some(hr) => { let _ = pending.push(hr); }
+                    None => break,
+                }
+            }
+            let (machine, streamed) = match pending.get(index) {
+                Some(hr) => (runs_on(hr), takes_input(hr)),
+                None => break,
+            };
+            // SMI requests wait for a running sequence to finish, pio_sm_rx spawns us again when it does.
+            // I2C and SMBus requests wait the same way for the running transfer. Requests for other
+            // state machines go around the held ones
+            let held = match machine {
+                Some(sm) if blocked.contains(&sm) => true,
+                Some(SMI_SM) => smi_seq.is_some(),
+                Some(I2C_SM) => i2c_xfer.lock(|i2c_xfer| i2c_xfer.is_some()),
+                // Flash requests run on the SPI master too
+                Some(SPI_SM) => spi_job.lock(|spi_job| spi_job.is_some()) || flash_job.lock(|flash_job| flash_job.is_some()),
+                // SVF runs on the JTAG master too
+                Some(JTAG_SM) => jtag_job.lock(|jtag_job| jtag_job.is_some()) || svf.lock(|svf| svf.running()),
+                _ => false,
+            };
+            // Requests taking input from their host wait for the one taking it
+            let held = held || streamed && input.lock(|input| input.active());
+            if held {
+                if let Some(sm) = machine {
+                    if !blocked.contains(&sm) {
+                        let _ = blocked.push(sm);
+                    }
+                }
+                index += 1;
+                continue;
+            }
+            let mut hr = pending.remove(index);
+
+            // State machine that will answer the request, None if it completes here
+            let mut awaiting: Option<StateMachine> = None;
+            let mut status: Option<BridgeError> = None;
+            // Value returned by requests that complete here
+            let mut reply: Option<u32> = None;
+
+            match hr.interface {
+                // For each additional supported interface, add another match arm that sends to the interface
+                // Take handle of its TX FIFO and send payload word by word according to the size
+                // SMI requests are rewritten into MDIO frames by init_clean. They go to the TX FIFO back to back
+                // so no other request can interleave, and only a sequence ending in a read is answered by the IRQ
+                ValidInterfaces::SMI | ValidInterfaces::SMI45 | ValidInterfaces::MMD => {
+                    // Sequences decide their frames as they go, starting with those of their first step
+                    let started = match SmiSequence::start(&hr, snapshots, pages) {
+                        Ok(started) => started,
+                        Err(err) => {
+                            status = Some(err);
+                            None
+                        }
+                    };
+                    let frames = match &started {
+                        Some((_, frames)) => frames.as_slice(),
+                        // Nothing to send for a sequence that could not start
+                        None if status.is_some() => &hr.payload[..0],
+                        None => &hr.payload[..hr.size as usize],
+                    };
+                    // Bit 0 of a frame is its read flag
+                    let read = frames.last().map_or(false, |frame| frame & 1 != 0);
+                    // Make sure the answer can be tracked before the state machine starts on it
+                    match in_flight.check(SMI_SM, hr.host_config(), hr.proc_id()) {
+                        Err(err) if read => {
+                            status = Some(err);
+                        }
+                        _ if status.is_some() => {}
+                        _ => {
+                            write_smi_frames(smi_tx, frames);
+                            if read {
+                                awaiting = Some(SMI_SM);
+                            }
+                            *smi_seq = started.map(|(seq, _)| ((hr.host_config(), hr.proc_id()), seq));
+                        }
+                    }
+                }
+                ValidInterfaces::Config => {
+                    if hr.operation == ValidOps::SmiSet {
+                        // Set the MDC frequency in Hz, and report back the frequency the divisor gives
+                        match ClockDivisor::for_frequency(sys_clk_hz, hr.payload[0], SMI_CYCLES_PER_MDC) {
+                            Ok(div) => {
+                                smi_master.clock_divisor_fixed_point(div.int, div.frac);
+                                reply = Some(div.frequency(sys_clk_hz, SMI_CYCLES_PER_MDC));
                             }
-                            else if hr.payload[0] == 10 {
-                                smi_master.clock_divisor_fixed_point(1, 145);
-                                return_string = "\n\rSMI Clock Rate Set 10Mhz\n\r->";
+                            Err(err) => {
+                                status = Some(err);
                             }
-                            else {smi_master.clock_divisor_fixed_point(hr.payload[0] as u16, 0);}
                         }
                     }
-                    ValidInterfaces::GPIO => {
-
-                            if hr.payload[0] != 0 {freepin.set_high().unwrap();}
-                            else {freepin.set_low().unwrap();}
-                            // We do not do slave response on set/config commands
+                    else if hr.operation == ValidOps::I2cSet {
+                        // Set the SCL frequency in Hz, up to 1 MHz, and report back the frequency the divisor gives
+                        match ClockDivisor::for_frequency(sys_clk_hz, hr.payload[0], I2C_CYCLES_PER_BIT) {
+                            Ok(div) if hr.payload[0] <= I2C_MAX_HZ => {
+                                i2c_master.lock(|i2c_master| i2c_master.clock_divisor_fixed_point(div.int, div.frac));
+                                reply = Some(div.frequency(sys_clk_hz, I2C_CYCLES_PER_BIT));
+                            }
+                            Ok(_) => {
+                                status = Some(BridgeError::OutOfRange);
+                            }
+                            Err(err) => {
+                                status = Some(err);
+                            }
+                        }
+                    }
+                    else if hr.operation == ValidOps::PageSel {
+                        // Page select register of a PHY, used by the paged accesses started after this
+                        status = pages.set(hr.payload[0], hr.payload[1]).err();
+                    }
+                    else if hr.operation == ValidOps::PecSet {
+                        *smbus_pec = hr.payload[0] != 0;
+                    }
+                    else if hr.operation == ValidOps::SpiMode {
+                        // Mode, word width and LSB first, rebuilding the SPI state machine
+                        let lsb_first = if hr.size == 3 {hr.payload[2]} else {0};
+                        match SpiConfig::new(hr.payload[0], hr.payload[1], lsb_first) {
+                            Ok(config) => spi_master.lock(|spi_master| spi_master.configure(config)),
+                            Err(err) => status = Some(err),
+                        }
+                    }
+                    else if hr.operation == ValidOps::SpiSet {
+                        // Set the SCK frequency in Hz and report back the frequency the divisor gives
+                        match ClockDivisor::for_frequency(sys_clk_hz, hr.payload[0], SPI_CYCLES_PER_BIT) {
+                            Ok(div) if hr.payload[0] <= SPI_MAX_HZ => {
+                                spi_master.lock(|spi_master| spi_master.set_divisor(div));
+                                reply = Some(div.frequency(sys_clk_hz, SPI_CYCLES_PER_BIT));
+                            }
+                            Ok(_) => {
+                                status = Some(BridgeError::OutOfRange);
+                            }
+                            Err(err) => {
+                                status = Some(err);
+                            }
+                        }
+                    }
+                }
+                // I2C requests run one at a time, their records are streamed to the TX FIFO as it makes room.
+                // SMBus transactions are I2C transfers
+                ValidInterfaces::I2C | ValidInterfaces::SMBus => {
+                    let started = I2cJob::start(&hr, *smbus_pec)
+                        .and_then(|job| in_flight.check(I2C_SM, hr.host_config(), hr.proc_id()).map(|_| job));
+                    match started {
+                        Ok(mut job) => {
+                            i2c_tx.lock(|i2c_tx| feed_i2c(i2c_tx, &mut job));
+                            i2c_xfer.lock(|i2c_xfer| *i2c_xfer = Some(job));
+                            awaiting = Some(I2C_SM);
+                        }
+                        Err(err) => {
+                            status = Some(err);
+                        }
+                    }
+                }
+                // SPI requests run one at a time, their chip select asserted before the first word
+                ValidInterfaces::SPI => {
+                    let started = spi_master.lock(|spi_master| SpiJob::start(&hr, spi_master.config()))
+                        .and_then(|job| in_flight.check(SPI_SM, hr.host_config(), hr.proc_id()).map(|_| job));
+                    match started {
+                        Ok(mut job) => {
+                            // The MOSI words of a transfer come from the host, spi_resume tells it to send them
+                            if let Some(len) = job.input() {
+                                input.lock(|input| input.start(hr.host_config(), hr.proc_id(), Some(len)));
+                                let _ = spi_resume::spawn();
+                            }
+                            spi_master.lock(|spi_master| {
+                                spi_master.select(job.cs());
+                                input.lock(|input| feed_spi(spi_master, &mut job, input));
+                            });
+                            spi_job.lock(|spi_job| *spi_job = Some(job));
+                            awaiting = Some(SPI_SM);
+                        }
+                        Err(err) => {
+                            status = Some(err);
+                        }
+                    }
+                }
+                // Flash requests are sequences of SPI commands, the first sent here and the rest as each one is done
+                ValidInterfaces::Flash => {
+                    let config = spi_master.lock(|spi_master| spi_master.config());
+                    let started = flash_chips.lock(|flash_chips| FlashJob::start(&hr, config, flash_chips))
+                        .and_then(|job| in_flight.check(SPI_SM, hr.host_config(), hr.proc_id()).map(|_| job));
+                    match started {
+                        Ok(mut job) => {
+                            // The bytes of a write come from the host, spi_resume tells it to send them
+                            if let Some(len) = job.input() {
+                                input.lock(|input| input.start(hr.host_config(), hr.proc_id(), Some(len)));
+                                let _ = spi_resume::spawn();
+                            }
+                            spi_master.lock(|spi_master| {
+                                spi_master.select(job.cs());
+                                input.lock(|input| feed_flash(spi_master, &mut job, input));
+                            });
+                            flash_job.lock(|flash_job| *flash_job = Some(job));
+                            awaiting = Some(SPI_SM);
+                        }
+                        Err(err) => {
+                            status = Some(err);
+                        }
+                    }
+                }
+                // The bytes received go to the player until Ctrl-D, it says it is ready once the request is in flight
+                ValidInterfaces::JTAG if hr.operation == ValidOps::Svf => {
+                    match in_flight.check(JTAG_SM, hr.host_config(), hr.proc_id()) {
+                        Ok(_) => {
+                            let state = jtag_state.lock(|jtag_state| *jtag_state);
+                            svf.lock(|svf| svf.start(state));
+                            input.lock(|input| input.start(hr.host_config(), hr.proc_id(), None));
+                            awaiting = Some(JTAG_SM);
+                            let _ = jtag_resume::spawn();
+                        }
+                        Err(err) => {
+                            status = Some(err);
+                        }
+                    }
+                }
+                // Scans run one at a time from the state the last one left the TAP in
+                ValidInterfaces::JTAG => {
+                    let started = jtag_state.lock(|jtag_state| JtagJob::start(&hr, *jtag_state))
+                        .and_then(|job| in_flight.check(JTAG_SM, hr.host_config(), hr.proc_id()).map(|_| job));
+                    match started {
+                        Ok(mut job) => {
+                            // The TDI bits of a scan come from the host, jtag_resume tells it to send them
+                            if let Some(len) = job.input() {
+                                input.lock(|input| input.start(hr.host_config(), hr.proc_id(), Some(len)));
+                                let _ = jtag_resume::spawn();
+                            }
+                            jtag_tx.lock(|jtag_tx| input.lock(|input| feed_jtag(jtag_tx, &mut job, input)));
+                            jtag_job.lock(|jtag_job| *jtag_job = Some(job));
+                            awaiting = Some(JTAG_SM);
+                        }
+                        Err(err) => {
+                            status = Some(err);
+                        }
                     }
-                    _ => {}
                 }
-                write_serial(serial, return_string, false);
-                
-                if slave_response {
-                    // Exchange our Host Request for slave response that needs to be ready
-                    let slave_response = hr.exchange_for_slave_response();
-                    match slave_response {
-                        Ok(val) => {
-                            // enqueue our new slave response
-                            match producer.enqueue(val) {
-                                Ok(sr) => {
+                ValidInterfaces::GPIO => {
 
-                                }
-                                Err(err) => {
-                                    write_serial(serial, "Consumer queue is full", false);
-                                }
+                        if hr.payload[0] != 0 {freepin.set_high().unwrap();}
+                        else {freepin.set_low().unwrap();}
+                        // We do not do slave response on set/config commands
+                }
+                _ => {
+                    status = Some(BridgeError::InvalidInterface);
+                }
+            }
+            
+            // Exchange our Host Request for slave response
+            match hr.exchange_for_slave_response() {
+                Ok(sr) if awaiting.is_some() => {
+                    // Track our new slave response, PIO_IRQ will fill it in and make it ready
+                    if let Some(sm) = awaiting {
+                        if let Err(err) = in_flight.insert(sm, sr) {
+                            write_error(serial, err);
+                        }
+                    }
+                }
+                Ok(mut sr) => {
+                    // Nothing more will come from the device, respond now
+                    if let Some(err) = status {
+                        sr.set_error(err);
+                    }
+                    else if let Some(value) = reply {
+                        sr.set_payload(value);
+                        sr.set_size(4);
+                    }
+                    match sr.init_ready() {
+                        Ok(sr) => {
+                            if respond_to_host::spawn(sr).is_err() {
+                                write_error(serial, BridgeError::QueueFull);
                             }
                         }
-                        Err(_err) => {
-                        // This should never happen
+                        Err(err) => {
+                            write_error(serial, err);
                         }
                     }
                 }
-                });
+                Err(err) => {
+                    write_error(serial, err);
+                }
+            }
+        }
+        });
+    }
+
+    // State machine a request runs on, its requests are held back while another one runs there.
+    // The MDC, SCL and SPI settings only change between the requests of their state machine
+    fn runs_on(hr: &HostRequest<Clean>) -> Option<StateMachine> {
+        match hr.interface {
+            interface if is_smi(interface) => Some(SMI_SM),
+            ValidInterfaces::Config if hr.operation == ValidOps::SmiSet => Some(SMI_SM),
+            ValidInterfaces::I2C | ValidInterfaces::SMBus => Some(I2C_SM),
+            ValidInterfaces::Config if hr.operation == ValidOps::I2cSet => Some(I2C_SM),
+            ValidInterfaces::SPI | ValidInterfaces::Flash => Some(SPI_SM),
+            ValidInterfaces::Config if matches!(hr.operation, ValidOps::SpiMode | ValidOps::SpiSet) => Some(SPI_SM),
+            ValidInterfaces::JTAG => Some(JTAG_SM),
+            _ => None,
+        }
+    }
+
+    // Requests that take data streamed from their host after the request
+    fn takes_input(hr: &HostRequest<Clean>) -> bool {
+        matches!((hr.interface, hr.operation), (ValidInterfaces::SPI, ValidOps::Transfer)
+            | (ValidInterfaces::Flash, ValidOps::Write)
+            | (ValidInterfaces::JTAG, ValidOps::Svf))
+            || (hr.interface == ValidInterfaces::JTAG && streams_tdi(hr))
+    }
+
+    // Interfaces whose requests run on the SMI state machine
+    fn is_smi(interface: ValidInterfaces) -> bool {
+        matches!(interface, ValidInterfaces::SMI | ValidInterfaces::SMI45 | ValidInterfaces::MMD)
+    }
+
+    fn write_smi_frames(smi_tx: &mut hal::pio::Tx<(pac::PIO0, SM0)>, frames: &[u32]) {
+        for frame in frames {
+            // Wait for the state machine to pull the previous frames
+            while !smi_tx.write(*frame) {}
+        }
+    }
+
+    // Handle the answer to an SMI read. Returns the value and its size in bytes for the SlaveResponse,
+    // or None while a sequence goes on with its next step
+    fn smi_answer(read: Result<u16, BridgeError>, in_flight: &InFlight<IN_FLIGHT_DEPTH>,
+        smi_seq: &mut Option<((ValidHostInterfaces, u8), SmiSequence)>, snapshots: &mut Snapshots,
+        smi_tx: &mut hal::pio::Tx<(pac::PIO0, SM0)>, host: &mut HostPorts) -> Option<Result<(u32, u8), BridgeError>> {
+        let oldest = in_flight.oldest(SMI_SM);
+        let seq = match (smi_seq.as_mut(), oldest) {
+            // A running sequence owns the answers to its own reads, older requests are answered first
+            (Some((key, seq)), Some(sr)) if *key == (sr.host_config, sr.proc_id) => seq,
+            _ => return Some(read.map(|value| (value as u32, 2))),
+        };
+        let mut report = FmtBuf::<REPORT_LEN>::new();
+        let step = seq.on_read(read, monotonics::now().ticks(), &mut report);
+        if !report.as_bytes().is_empty() {
+            if let Some(sr) = oldest {
+                // Console output only, dropped if the hosts are not keeping up
+                let _ = report_to_host::spawn(sr.host_config, report);
+            }
+        }
+        smi_step(step, in_flight, smi_seq, snapshots, smi_tx, host)
+    }
+
+    // Go on with the step of a sequence once the data it streamed is written out. None while the sequence goes on
+    fn smi_step(step: SmiStep, in_flight: &InFlight<IN_FLIGHT_DEPTH>,
+        smi_seq: &mut Option<((ValidHostInterfaces, u8), SmiSequence)>, snapshots: &mut Snapshots,
+        smi_tx: &mut hal::pio::Tx<(pac::PIO0, SM0)>, host: &mut HostPorts) -> Option<Result<(u32, u8), BridgeError>> {
+        let (_, seq) = smi_seq.as_mut()?;
+        while let Some(chunk) = seq.chunk() {
+            // Nothing more is read until the chunk is out, try again once the host made room
+            if !write_stream(host, in_flight.oldest(SMI_SM), chunk) {
+                let _ = smi_resume::spawn_after(1_000_u64.micros(), step);
+                return None;
             }
-            None => {}
+            seq.chunk_written();
+        }
+        match step {
+            SmiStep::Next(frames) => {
+                write_smi_frames(smi_tx, frames.as_slice());
+                None
+            }
+            SmiStep::Wait(delay_us, frames) => {
+                // Only one sequence runs at a time, so there is always room for its next step
+                let _ = smi_resume::spawn_after((delay_us as u64).micros(), SmiStep::Next(frames));
+                None
+            }
+            SmiStep::Done(result) => {
+                if let (Ok(_), Some(snapshot)) = (result, seq.snapshot()) {
+                    snapshots.store(*snapshot);
+                }
+                *smi_seq = None;
+                // Start the SMI requests held back while the sequence ran
+                let _ = send_out::spawn();
+                Some(result)
+            }
+        }
+    }
+
+    // Software task that goes on with a sequence that waits between steps, like smi poll, or held back by a full
+    // host queue. The sequence still holds back other SMI requests while it waits
+    #[task(priority = 3, shared = [serial, serial_out, uart_dev, spi_tx, smi_tx, in_flight, smi_seq, snapshots])]
+    fn smi_resume(cx: smi_resume::Context, step: SmiStep) {
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let spi_tx = cx.shared.spi_tx;
+        let smi_tx = cx.shared.smi_tx;
+        let in_flight = cx.shared.in_flight;
+        let smi_seq = cx.shared.smi_seq;
+        let snapshots = cx.shared.snapshots;
+
+        (serial, serial_out, uart_dev, spi_tx, smi_tx, in_flight, smi_seq, snapshots).lock(
+            |serial, serial_out, uart, spi_tx, smi_tx, in_flight, smi_seq, snapshots| {
+                let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                if let Some(answer) = smi_step(step, in_flight, smi_seq, snapshots, smi_tx, &mut host) {
+                    complete_request(in_flight, SMI_SM, answer, host.serial);
+                }
             }
+        )
     }
 
     // Hardware task associated with PIO0_IRQ_0
     // Takes control of shared state machine and rx fifo of PIO_0 SM_0 
-    // Reads rx fifo into buffer and pushed to queue, spawn software task to return value
-    #[task(binds = PIO0_IRQ_0, priority = 3, shared = [serial, pio0, smi_rx], local = [consumer])]
+    // Matches each state machine that raised its IRQ flag to its oldest in flight request and reads the answer
+    // from its rx fifo, spawn software task to return value
+    #[task(binds = PIO0_IRQ_0, priority = 3,
+        shared = [serial, serial_out, uart_dev, spi_tx, pio0, smi_rx, smi_tx, in_flight, smi_seq, snapshots])]
     fn pio_sm_rx(cx: pio_sm_rx::Context) {
         // All statemachines implement IRQ flags, of which the first 0-3 LSB 
-        let mut serial = cx.shared.serial;
-        (serial).lock(|serial| {
-            write_serial(serial, "fired", false);
-        });
-        if let Some(mut slave_response) = cx.local.consumer.dequeue() {
-
-            let pio0 = cx.shared.pio0;
-            let rx = cx.shared.smi_rx;
-            // let serial = cx.shared.serial;
-
-            // Eventually lock all implemented state machines and rx fifos
-            (pio0, rx, serial).lock(
-                |pio0, rx_a, serial,| {
-                    // First, read the index of the state machine IRQ flag 
-                    // This determines which state machine flagged an IRQ
-                    let index = pio0.get_irq_raw();
-                    match index {
-                        // This is the SMI state machine
-                        1 => {
-                            match rx_a.read() {
-                                Some(word) => {
-                                    // We got a word from the SMI RX FIFO
-                                    slave_response.set_payload(word);
-                                }
-                                _ => {
-                                    // No word received
-                                }
+        let serial = cx.shared.serial;
+        let mut serial_out = cx.shared.serial_out;
+        let mut uart_dev = cx.shared.uart_dev;
+        let mut spi_tx = cx.shared.spi_tx;
+        let pio0 = cx.shared.pio0;
+        let rx = cx.shared.smi_rx;
+        let smi_tx = cx.shared.smi_tx;
+        let in_flight = cx.shared.in_flight;
+        let smi_seq = cx.shared.smi_seq;
+        let snapshots = cx.shared.snapshots;
+
+        // Eventually lock all implemented state machines and rx fifos
+        (pio0, rx, smi_tx, in_flight, smi_seq, snapshots, serial).lock(
+            |pio0, rx_a, smi_tx, in_flight, smi_seq, snapshots, serial| {
+                (&mut serial_out, &mut uart_dev, &mut spi_tx).lock(|serial_out, uart, spi_tx| {
+                    let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                    // First, read the state machine IRQ flags
+                    // Flag n is raised by state machine n
+                    let flags = pio0.get_irq_raw();
+                    // Clear the PIO0 IRQ flags we are about to handle
+                    pio0.clear_irq(flags & 0xF);
+                    for sm in 0..4 {
+                        if flags & (1 << sm) == 0 {
+                            continue;
+                        }
+                        let state_machine = StateMachine::new(0, sm);
+                        if in_flight.oldest(state_machine).is_none() {
+                            // Our IRQ fired from State machine but no slave response object, drop what it sent
+                            if state_machine == SMI_SM {
+                                while rx_a.read().is_some() {}
                             }
+                            continue;
                         }
-                        // For now just implement SMI
-                        _ => {
+                        let answer = match state_machine {
+                            // This is the SMI state machine
+                            SMI_SM => {
+                                // The 16 bit register contents, NoPhy if the PHY did not drive the turnaround
+                                // and NoResponse if no word was received
+                                let read = rx_a.read().map_or(Err(BridgeError::NoResponse), decode_smi_read);
+                                match smi_answer(read, in_flight, smi_seq, snapshots, smi_tx, &mut host) {
+                                    Some(answer) => answer,
+                                    None => continue,
+                                }
+                            }
+                            // For now just implement SMI
+                            _ => {
+                                Err(BridgeError::InvalidInterface)
+                            }
+                        };
+                        complete_request(in_flight, state_machine, answer, host.serial);
+                    }
+                });
+            }
+        )
+    }
+
+    // Fill in the oldest response waiting on a state machine with its answer and send it to the host
+    fn complete_request(in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, state_machine: StateMachine,
+        answer: Result<(u32, u8), BridgeError>, serial: &mut SerialPort<'static, hal::usb::UsbBus>) {
+        complete_request_with(in_flight, state_machine, answer, 0, serial)
+    }
+
+    // Answer the oldest request of the state machine, an error carrying err_payload
+    fn complete_request_with(in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, state_machine: StateMachine,
+        answer: Result<(u32, u8), BridgeError>, err_payload: u32,
+        serial: &mut SerialPort<'static, hal::usb::UsbBus>) {
+        let mut slave_response = match in_flight.complete(state_machine) {
+            Some(sr) => sr,
+            None => return,
+        };
+        match answer {
+            Ok((value, size)) => {
+                slave_response.set_payload(value);
+                slave_response.set_size(size);
+            }
+            Err(err) => {
+                slave_response.set_payload(err_payload);
+                slave_response.set_error(err);
+            }
+        }
+        // Exchange our NotReady Slave Response for a Ready one
+        match slave_response.init_ready() {
+            Ok(sr) => {
+                if respond_to_host::spawn(sr).is_err() {
+                    write_error(serial, BridgeError::QueueFull);
+                }
+            }
+            Err(err) => {
+                write_error(serial, err);
+            }
+        }
+    }
+
+    // Write a chunk of the data a request streams to its host. False while the host queue has no room for it, the
+    // request waits and writes it again
+    fn write_stream(host: &mut HostPorts, sr: Option<&SlaveResponse<NotReady>>, chunk: &Chunk) -> bool {
+        match sr {
+            Some(sr) => write_chunk(host, sr.host_config, sr.proc_id, chunk) != Err(BridgeError::QueueFull),
+            None => true,
+        }
+    }
+
+    // Tell the host feeding the input to go on or stop. False while the host queue has no room for it, it is written
+    // again later
+    fn input_flow(host: &mut HostPorts, input: &mut StreamIn) -> bool {
+        if let Some(flow) = input.flow() {
+            if write_flow(host, input.host(), input.proc_id(), flow) == Err(BridgeError::QueueFull) {
+                return false;
+            }
+            input.flow_written();
+        }
+        true
+    }
+
+    // Bytes came in for the request taking input, it goes on with them
+    fn input_received() {
+        let _ = spi_resume::spawn();
+        let _ = jtag_resume::spawn();
+    }
+
+    // Response frame telling the SPI master its last frame was dropped
+    fn spi_error_frame(err: BridgeError) -> [u8; 18] {
+        let mut sr = SlaveResponse::new();
+        sr.set_host_config(ValidHostInterfaces::SPI);
+        sr.set_error(err);
+        sr.init_ready().map_or([0_u8; 18], |sr| sr.encode_8bit_spi())
+    }
+
+    // Send the records of an I2C transfer while the TX FIFO has room, the PIO1 IRQ sends the rest as bytes complete
+    fn feed_i2c(i2c_tx: &mut hal::pio::Tx<(pac::PIO1, SM0)>, job: &mut I2cJob) {
+        while let Some(record) = job.pending() {
+            if !i2c_tx.write(tx_word(record)) {
+                break;
+            }
+            job.sent();
+        }
+    }
+
+    // Write out the data an I2C read streams, send the records that waited for room, and answer the request once the
+    // IRQ ended it and its data is out
+    fn i2c_progress(i2c_tx: &mut hal::pio::Tx<(pac::PIO1, SM0)>, i2c_xfer: &mut Option<I2cJob>,
+        in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, host: &mut HostPorts) {
+        let job = match i2c_xfer.as_mut() {
+            Some(job) => job,
+            None => return,
+        };
+        while let Some(chunk) = job.chunk() {
+            // Nothing more is read until the chunk is out, try again once the SPI master clocked out the frames queued
+            if !write_stream(host, in_flight.oldest(I2C_SM), chunk) {
+                let _ = i2c_resume::spawn_after(1_000_u64.micros());
+                return;
+            }
+            job.chunk_written();
+        }
+        feed_i2c(i2c_tx, job);
+        if let Some(answer) = job.answer() {
+            *i2c_xfer = None;
+            complete_request(in_flight, I2C_SM, answer, host.serial);
+            // Start the I2C requests held back while the request ran
+            let _ = send_out::spawn();
+        }
+    }
 
+    // Hardware task associated with PIO1_IRQ_0
+    // The I2C state machine pushes a word per byte, which makes room in its TX FIFO for the next records, and raises
+    // IRQ flag 0 once the STOP of its transfer is done. Scans run a transfer per address from here
+    #[task(binds = PIO1_IRQ_0, priority = 3,
+        shared = [serial, serial_out, uart_dev, spi_tx, pio1, i2c_rx, i2c_tx, i2c_xfer, in_flight])]
+    fn pio1_sm_rx(cx: pio1_sm_rx::Context) {
+        let serial = cx.shared.serial;
+        let mut serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let spi_tx = cx.shared.spi_tx;
+        let pio1 = cx.shared.pio1;
+        let i2c_rx = cx.shared.i2c_rx;
+        let i2c_tx = cx.shared.i2c_tx;
+        let i2c_xfer = cx.shared.i2c_xfer;
+        let in_flight = cx.shared.in_flight;
+
+        (pio1, i2c_rx, i2c_tx, i2c_xfer, in_flight, serial, uart_dev, spi_tx).lock(
+            |pio1, i2c_rx, i2c_tx, i2c_xfer, in_flight, serial, uart, spi_tx| serial_out.lock(|serial_out| {
+                let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                let mut drain = |i2c_xfer: &mut Option<I2cJob>| {
+                    while let Some(word) = i2c_rx.read() {
+                        if let Some(job) = i2c_xfer.as_mut() {
+                            job.on_rx(word);
                         }
                     }
-                    // Clear all PIO0 IRQ flags
-                    pio0.clear_irq(0xF);
-                    // Exchange our NotReady Slave Response for a Ready one
-                    // TODO Add match case for this
-                    match slave_response.init_ready() {
-                        Ok(sr) => {
-                            write_serial(serial, "Hi", false);
-                            // respond_to_host::spawn(sr);
-                        }
-                        Err(err) => {
-                                    write_serial(serial, err, false);
-                        }
+                };
+                drain(i2c_xfer);
+                if pio1.get_irq_raw() & 1 == 0 {
+                    i2c_progress(i2c_tx, i2c_xfer, in_flight, &mut host);
+                    return;
+                }
+                pio1.clear_irq(1);
+                // The bytes of a transfer are all pushed before its IRQ, the last may have come after the drain
+                drain(i2c_xfer);
+                let job = match i2c_xfer.as_mut() {
+                    Some(job) => job,
+                    None => return,
+                };
+                let mut report = FmtBuf::<REPORT_LEN>::new();
+                let step = job.on_irq(&mut report);
+                if !report.as_bytes().is_empty() {
+                    if let Some(sr) = in_flight.oldest(I2C_SM) {
+                        // Console output only, dropped if the hosts are not keeping up
+                        let _ = report_to_host::spawn(sr.host_config, report);
                     }
                 }
-            )
+                // The next transfer of a scan starts, or the request is answered once its data is out
+                if step == I2cStep::Next {
+                    feed_i2c(i2c_tx, job);
+                }
+                i2c_progress(i2c_tx, i2c_xfer, in_flight, &mut host);
+            })
+        )
+    }
+
+    // Software task that goes on with an I2C read held back by a full host queue
+    #[task(priority = 3, shared = [serial, serial_out, uart_dev, spi_tx, i2c_tx, i2c_xfer, in_flight])]
+    fn i2c_resume(cx: i2c_resume::Context) {
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let spi_tx = cx.shared.spi_tx;
+        let i2c_tx = cx.shared.i2c_tx;
+        let i2c_xfer = cx.shared.i2c_xfer;
+        let in_flight = cx.shared.in_flight;
+
+        (i2c_tx, i2c_xfer, in_flight, serial, serial_out, uart_dev, spi_tx).lock(
+            |i2c_tx, i2c_xfer, in_flight, serial, serial_out, uart, spi_tx| {
+                let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                i2c_progress(i2c_tx, i2c_xfer, in_flight, &mut host);
+            }
+        )
+    }
+
+    // Send the words of an SPI request while the TX FIFO has room, the PIO1 IRQ 1 sends the rest as words come back
+    fn feed_spi(spi_master: &mut PioSpi, job: &mut SpiJob, input: &mut StreamIn) {
+        while let Some(word) = job.pending(input) {
+            if !spi_master.write(word) {
+                break;
+            }
+            job.sent();
+        }
+    }
+
+    // Write out the MISO words an SPI request streams, send the next words as the input has them, and answer the
+    // request once all words are back
+    fn spi_progress(spi_master: &mut PioSpi, spi_job: &mut Option<SpiJob>, input: &mut StreamIn,
+        in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, host: &mut HostPorts) {
+        let job = match spi_job.as_mut() {
+            Some(job) => job,
+            None => return,
+        };
+        while let Some(chunk) = job.chunk() {
+            // Nothing more is clocked until the chunk is out, try again once the SPI master clocked out the frames queued
+            if !write_stream(host, in_flight.oldest(SPI_SM), chunk) {
+                let _ = spi_resume::spawn_after(1_000_u64.micros());
+                return;
+            }
+            job.chunk_written();
+        }
+        feed_spi(spi_master, job, input);
+        if !input_flow(host, input) {
+            let _ = spi_resume::spawn_after(1_000_u64.micros());
         }
-        else { 
-            // If this happens, our IRQ fired from State machine but no slave response object
-            // print that we had no slave response ready
+        if job.done() {
+            spi_master.release(job.cs(), job.hold());
+            let answer = job.result();
+            if job.input().is_some() {
+                input.stop();
+            }
+            *spi_job = None;
+            complete_request(in_flight, SPI_SM, answer, host.serial);
+            // Start the SPI requests held back while the request ran
+            let _ = send_out::spawn();
         }
     }
 
-    // Software task that sends clean HostRequest to its destination (SysConfig or state machine)
-    // Must validate that Associated State Machine is available and ready before sending, if not, return an Err
-    // Pushes a SlaveResponse<NotReady> to process queue, that PIO_IRQ will build when response is gotten from state machine
-    #[task(priority = 3, shared = [serial], local =[spi_tx_producer])]
-    fn respond_to_host(cx: respond_to_host::Context, sr: SlaveResponse<crate::protocol::slave::Ready>) {
-        // If Host Response was SPI, we need to update the slave TX Buffer
-        // This slave response will go out when the Master requests it again.
-        /* let serial = cx.shared.serial;
-        (serial).lock(|serial| {});
-            if sr.host_config == ValidHostInterfaces::SPI {
-                let mut tx_buf = [0_u8; 18];
-                // Push the relevant slave response fields to the spi tx buffer
-                tx_buf[0] = sr.proc_id as u8;
-
-                //let split_u32_to_u16 = |word: u32| -> (u16, u16) {
-                //        ((word >> 16) as u16, word as u16)
-                //    };
-                // Split the 32-bit word from the FIFO to 16 bits, we have 16-bit SPI
-                //(tx_buf[1], tx_buf[2]) = split_u32_to_u16(sr.payload);
-
-                let split_u32_to_u8 = |word: u32| -> (u8, u8, u8, u8) {
-                    (((word >> 24) & 0xff) as u8, ((word >> 16) & 0xff) as u8, ((word >> 8) & 0xff) as u8, (word & 0xff) as u8)
-                };
+    fn feed_flash(spi_master: &mut PioSpi, job: &mut FlashJob, input: &mut StreamIn) {
+        while let Some(word) = job.pending(input) {
+            if !spi_master.write(word) {
+                break;
+            }
+            job.sent();
+        }
+    }
+
+    // Write out the chunk of a flash read or the progress of a verify, send the next bytes of the command as the input
+    // has them, and once all are back release the chip select and go on with the next command, or answer the request
+    fn flash_progress(spi_master: &mut PioSpi, flash_job: &mut Option<FlashJob>, flash_chips: &mut [FlashGeometry; MAX_CS],
+        input: &mut StreamIn, in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, host: &mut HostPorts) {
+        let job = match flash_job.as_mut() {
+            Some(job) => job,
+            None => return,
+        };
+        loop {
+            while let Some(chunk) = job.chunk() {
+                // Nothing more is clocked until the chunk is out, try again once the SPI master clocked out the frames queued
+                if !write_stream(host, in_flight.oldest(SPI_SM), chunk) {
+                    let _ = spi_resume::spawn_after(1_000_u64.micros());
+                    return;
+                }
+                job.chunk_written();
+            }
+            let mut line = FmtBuf::<REPORT_LEN>::new();
+            if job.line(&mut line) {
+                if let Some(sr) = in_flight.oldest(SPI_SM) {
+                    // Nothing more is clocked until the line is out, try again once the console caught up
+                    if report_to_host::spawn(sr.host_config, line).is_err() {
+                        let _ = spi_resume::spawn_after(1_000_u64.micros());
+                        return;
+                    }
+                }
+                job.line_written();
+            }
+            // A command sent after a wait
+            if job.starting() {
+                spi_master.select(job.cs());
+            }
+            feed_flash(spi_master, job, input);
+            if !input_flow(host, input) {
+                let _ = spi_resume::spawn_after(1_000_u64.micros());
+            }
+            if !job.command_done() {
+                return;
+            }
+            spi_master.release(job.cs(), false);
+            let mut report = FmtBuf::<REPORT_LEN>::new();
+            let step = job.next(monotonics::now().ticks(), &mut report);
+            if !report.as_bytes().is_empty() {
+                if let Some(sr) = in_flight.oldest(SPI_SM) {
+                    // Console output only, dropped if the hosts are not keeping up
+                    let _ = report_to_host::spawn(sr.host_config, report);
+                }
+            }
+            match step {
+                FlashStep::Next => {}
+                FlashStep::Wait(delay_us) => {
+                    // Only one SPI request runs at a time, input coming in meanwhile only polls the status sooner
+                    let _ = spi_resume::spawn_after((delay_us as u64).micros());
+                    return;
+                }
+                FlashStep::Done(answer) => {
+                    if let (Ok(_), true) = (answer, job.op() == ValidOps::Id) {
+                        flash_chips[job.cs() as usize] = job.geometry();
+                    }
+                    if job.input().is_some() {
+                        input.stop();
+                    }
+                    *flash_job = None;
+                    complete_request(in_flight, SPI_SM, answer, host.serial);
+                    // Start the SPI requests held back while the request ran
+                    let _ = send_out::spawn();
+                    return;
+                }
+            }
+        }
+    }
+
+    // Hardware task associated with PIO1_IRQ_1
+    // The SPI state machine pushes a word for each word it shifts out, which makes room for the next
+    #[task(binds = PIO1_IRQ_1, priority = 3,
+        shared = [serial, serial_out, uart_dev, spi_tx, spi_master, spi_job, flash_job, flash_chips, in_flight, input])]
+    fn pio1_spi_rx(cx: pio1_spi_rx::Context) {
+        let serial = cx.shared.serial;
+        let mut serial_out = cx.shared.serial_out;
+        let mut uart_dev = cx.shared.uart_dev;
+        let mut spi_tx = cx.shared.spi_tx;
+        let spi_master = cx.shared.spi_master;
+        let spi_job = cx.shared.spi_job;
+        let flash_job = cx.shared.flash_job;
+        let flash_chips = cx.shared.flash_chips;
+        let in_flight = cx.shared.in_flight;
+        let mut input = cx.shared.input;
+
+        (spi_master, spi_job, flash_job, flash_chips, in_flight, serial).lock(
+            |spi_master, spi_job, flash_job, flash_chips, in_flight, serial| {
+                while let Some(word) = spi_master.read() {
+                    if let Some(job) = spi_job.as_mut() {
+                        job.on_rx(word);
+                    }
+                    else if let Some(job) = flash_job.as_mut() {
+                        job.on_rx(word);
+                    }
+                }
+                (&mut serial_out, &mut uart_dev, &mut spi_tx, &mut input).lock(|serial_out, uart, spi_tx, input| {
+                    let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                    spi_progress(spi_master, spi_job, input, in_flight, &mut host);
+                    flash_progress(spi_master, flash_job, flash_chips, input, in_flight, &mut host);
+                });
+            }
+        )
+    }
+
+    // Software task that goes on with an SPI request held back by a full host queue or waiting for its input,
+    // or with a flash request once its wait is over
+    #[task(priority = 3,
+        shared = [serial, serial_out, uart_dev, spi_tx, spi_master, spi_job, flash_job, flash_chips, in_flight, input])]
+    fn spi_resume(cx: spi_resume::Context) {
+        let serial = cx.shared.serial;
+        let mut serial_out = cx.shared.serial_out;
+        let mut uart_dev = cx.shared.uart_dev;
+        let mut spi_tx = cx.shared.spi_tx;
+        let spi_master = cx.shared.spi_master;
+        let spi_job = cx.shared.spi_job;
+        let flash_job = cx.shared.flash_job;
+        let flash_chips = cx.shared.flash_chips;
+        let in_flight = cx.shared.in_flight;
+        let mut input = cx.shared.input;
+
+        (spi_master, spi_job, flash_job, flash_chips, in_flight, serial).lock(
+            |spi_master, spi_job, flash_job, flash_chips, in_flight, serial| {
+                (&mut serial_out, &mut uart_dev, &mut spi_tx, &mut input).lock(|serial_out, uart, spi_tx, input| {
+                    let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                    spi_progress(spi_master, spi_job, input, in_flight, &mut host);
+                    flash_progress(spi_master, flash_job, flash_chips, input, in_flight, &mut host);
+                });
+            }
+        )
+    }
+
+    // Send the TCKs of a scan while the TX FIFO has room, the PIO0 IRQ 1 sends the rest as TDO words come back
+    fn feed_jtag(jtag_tx: &mut hal::pio::Tx<(pac::PIO0, SM1)>, job: &mut JtagJob, input: &mut StreamIn) {
+        while let Some(word) = job.pending(input) {
+            if !jtag_tx.write(word) {
+                break;
+            }
+            job.sent();
+        }
+    }
+
+    // Write out the chunk of TDO bits, or of the devices found, once it is full, and the console line of each device,
+    // send the next TCKs as the input has their TDI bits, and go on with the next shift or answer the request once all
+    // TDO words are back
+    fn jtag_progress(jtag_tx: &mut hal::pio::Tx<(pac::PIO0, SM1)>, jtag_job: &mut Option<JtagJob>,
+        jtag_state: &mut Option<TapState>, input: &mut StreamIn, in_flight: &mut InFlight<IN_FLIGHT_DEPTH>,
+        host: &mut HostPorts) {
+        let job = match jtag_job.as_mut() {
+            Some(job) => job,
+            None => return,
+        };
+        loop {
+            while let Some(chunk) = job.chunk() {
+                // Nothing more is clocked until the chunk is out, try again once the SPI master clocked out the frames queued
+                if !write_stream(host, in_flight.oldest(JTAG_SM), &chunk) {
+                    let _ = jtag_resume::spawn_after(1_000_u64.micros());
+                    return;
+                }
+                job.chunk_written();
+            }
+            let mut line = FmtBuf::<REPORT_LEN>::new();
+            if job.line(&mut line) {
+                if let Some(sr) = in_flight.oldest(JTAG_SM) {
+                    // Nothing more is clocked until the line is out, try again once the console caught up
+                    if report_to_host::spawn(sr.host_config, line).is_err() {
+                        let _ = jtag_resume::spawn_after(1_000_u64.micros());
+                        return;
+                    }
+                }
+                job.line_written();
+                // The devices of a chain scan are listed with no TCKs to wait for
+                continue;
+            }
+            feed_jtag(jtag_tx, job, input);
+            if !input_flow(host, input) {
+                let _ = jtag_resume::spawn_after(1_000_u64.micros());
+            }
+            if !job.done() {
+                return;
+            }
+            match job.on_done() {
+                JtagStep::Next => {}
+                JtagStep::Done(answer) => {
+                    *jtag_state = job.state();
+                    if job.input().is_some() {
+                        input.stop();
+                    }
+                    *jtag_job = None;
+                    complete_request(in_flight, JTAG_SM, answer, host.serial);
+                    // Start the JTAG requests held back while the scan ran
+                    let _ = send_out::spawn();
+                    return;
+                }
+            }
+        }
+    }
+
+    // Send the TCKs of an SVF shift while the TX FIFO has room
+    fn feed_svf(jtag_tx: &mut hal::pio::Tx<(pac::PIO0, SM1)>, svf: &mut SvfPlayer) {
+        while let Some(word) = svf.pending() {
+            if !jtag_tx.write(word) {
+                break;
+            }
+            svf.sent();
+        }
+    }
+
+    // Send the TCKs of the SVF shift running, once they are back go on with the statements the host streamed, and
+    // answer the request at the end of the input
+    fn svf_progress(jtag_master: &mut hal::pio::StateMachine<(pac::PIO0, SM1), hal::pio::Running>,
+        jtag_tx: &mut hal::pio::Tx<(pac::PIO0, SM1)>, svf: &mut SvfPlayer, input: &mut StreamIn,
+        jtag_state: &mut Option<TapState>, in_flight: &mut InFlight<IN_FLIGHT_DEPTH>, host: &mut HostPorts) {
+        if !svf.running() {
+            return;
+        }
+        loop {
+            if let Some(chunk) = svf.chunk() {
+                // Nothing more is read until the mismatch is out, try again once the host queue has room
+                if !write_stream(host, in_flight.oldest(JTAG_SM), &chunk) {
+                    let _ = jtag_resume::spawn_after(1_000_u64.micros());
+                    return;
+                }
+                svf.chunk_written();
+            }
+            feed_svf(jtag_tx, svf);
+            if !svf.done() {
+                return;
+            }
+            let mut report = FmtBuf::<REPORT_LEN>::new();
+            let step = svf.step(input, &mut report);
+            if !report.as_bytes().is_empty() {
+                if let Some(sr) = in_flight.oldest(JTAG_SM) {
+                    // Console output only, dropped if the hosts are not keeping up
+                    let _ = report_to_host::spawn(sr.host_config, report);
+                }
+            }
+            // The host may send again once the player caught up
+            if !input_flow(host, input) {
+                let _ = jtag_resume::spawn_after(1_000_u64.micros());
+            }
+            match step {
+                SvfStep::Next => {}
+                SvfStep::Input => return,
+                // Between shifts, the state machine waits on its TX FIFO
+                SvfStep::Divisor(div) => jtag_master.clock_divisor_fixed_point(div.int, div.frac),
+                SvfStep::Done(answer) => {
+                    *jtag_state = svf.state();
+                    input.stop();
+                    // A failed run answers with the line of the statement
+                    complete_request_with(in_flight, JTAG_SM, answer, svf.failed_line(), host.serial);
+                    // Start the JTAG requests held back while the SVF ran
+                    let _ = send_out::spawn();
+                    return;
+                }
+            }
+        }
+    }
+
+    // Hardware task associated with PIO0_IRQ_1
+    // The JTAG state machine pushes a word of TDO bits every 16 TCKs, which makes room for the next
+    #[task(binds = PIO0_IRQ_1, priority = 3,
+        shared = [serial, serial_out, uart_dev, spi_tx, jtag_master, jtag_tx, jtag_rx, jtag_job, jtag_state, svf, input,
+        in_flight])]
+    fn pio0_jtag_rx(cx: pio0_jtag_rx::Context) {
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let mut uart_dev = cx.shared.uart_dev;
+        let mut spi_tx = cx.shared.spi_tx;
+        let mut jtag_master = cx.shared.jtag_master;
+        let jtag_tx = cx.shared.jtag_tx;
+        let jtag_rx = cx.shared.jtag_rx;
+        let jtag_job = cx.shared.jtag_job;
+        let jtag_state = cx.shared.jtag_state;
+        let svf = cx.shared.svf;
+        let mut input = cx.shared.input;
+        let in_flight = cx.shared.in_flight;
+
+        (jtag_tx, jtag_rx, jtag_job, jtag_state, svf, in_flight, serial, serial_out).lock(
+            |jtag_tx, jtag_rx, jtag_job, jtag_state, svf, in_flight, serial, serial_out| {
+                while let Some(word) = jtag_rx.read() {
+                    if let Some(job) = jtag_job.as_mut() {
+                        job.on_rx(word);
+                    }
+                    else {
+                        svf.on_rx(word);
+                    }
+                }
+                (&mut jtag_master, &mut input, &mut uart_dev, &mut spi_tx).lock(|jtag_master, input, uart, spi_tx| {
+                    let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                    jtag_progress(jtag_tx, jtag_job, jtag_state, input, in_flight, &mut host);
+                    svf_progress(jtag_master, jtag_tx, svf, input, jtag_state, in_flight, &mut host);
+                });
+            }
+        )
+    }
+
+    // Software task that goes on with a scan held back by a full host queue or waiting for its input, or with SVF
+    // once more of it came in
+    #[task(priority = 3, shared = [serial, serial_out, uart_dev, spi_tx, jtag_master, jtag_tx, jtag_job, jtag_state,
+        svf, input, in_flight])]
+    fn jtag_resume(cx: jtag_resume::Context) {
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let mut uart_dev = cx.shared.uart_dev;
+        let mut spi_tx = cx.shared.spi_tx;
+        let mut jtag_master = cx.shared.jtag_master;
+        let jtag_tx = cx.shared.jtag_tx;
+        let jtag_job = cx.shared.jtag_job;
+        let jtag_state = cx.shared.jtag_state;
+        let svf = cx.shared.svf;
+        let mut input = cx.shared.input;
+        let in_flight = cx.shared.in_flight;
+
+        (jtag_tx, jtag_job, jtag_state, svf, in_flight, serial, serial_out).lock(
+            |jtag_tx, jtag_job, jtag_state, svf, in_flight, serial, serial_out| {
+                (&mut jtag_master, &mut input, &mut uart_dev, &mut spi_tx).lock(|jtag_master, input, uart, spi_tx| {
+                    let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+                    jtag_progress(jtag_tx, jtag_job, jtag_state, input, in_flight, &mut host);
+                    svf_progress(jtag_master, jtag_tx, svf, input, jtag_state, in_flight, &mut host);
+                });
+            }
+        )
+    }
+
+    // The host facing interfaces, borrowed while their locks are held. report_to_host only reaches the consoles
+    struct HostPorts<'a> {
+        serial: &'a mut SerialPort<'static, hal::usb::UsbBus>,
+        serial_out: &'a mut SerialOut,
+        uart: &'a mut UartDev,
+        spi_tx: Option<&'a mut Producer<'static, [u8; 18], SPI_TX_DEPTH>>,
+    }
+
+    impl HostTransport for HostPorts<'_> {
+        fn write_serial(&mut self, bytes: &[u8]) -> Result<(), BridgeError> {
+            // All or nothing, a frame is never cut. The caller writes it again once the host read
+            queue_serial(self.serial, self.serial_out, bytes)
+        }
+
+        fn write_uart(&mut self, bytes: &[u8]) -> Result<(), BridgeError> {
+            self.uart.write_full_blocking(bytes);
+            Ok(())
+        }
+
+        fn queue_spi(&mut self, frame: [u8; 18]) -> Result<(), BridgeError> {
+            // This slave response will go out when the Master requests it again.
+            match self.spi_tx.as_mut() {
+                Some(spi_tx) => spi_tx.enqueue(frame).map_err(|_| BridgeError::QueueFull),
+                None => Err(BridgeError::NoResponse),
+            }
+        }
+    }
 
-                (tx_buf[1], tx_buf[2], tx_buf[3], tx_buf[4]) = split_u32_to_u8(sr.payload);
+    // Software task that delivers a ready SlaveResponse back on the host interface its request came from
+    // If Host Response was SPI, we need to update the slave TX Buffer
+    #[task(priority = 3, capacity = 4, shared = [serial, serial_out, uart_dev, spi_tx, unsent])]
+    fn respond_to_host(cx: respond_to_host::Context, sr: SlaveResponse<Ready>) {
+        let spi_tx = cx.shared.spi_tx;
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let unsent = cx.shared.unsent;
+        (serial, serial_out, uart_dev, spi_tx, unsent).lock(|serial, serial_out, uart, spi_tx, unsent| {
+            let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+            let serial_host = on_serial(sr.host_config);
+            // Behind the responses still waiting for the USB serial port
+            let written = match serial_host && !unsent.is_empty() {
+                true => Err(BridgeError::QueueFull),
+                false => sr.respond_to_host(&mut host),
+            };
+            match written {
+                Ok(()) => {}
+                Err(BridgeError::QueueFull) if serial_host => {
+                    if unsent.push_back(Unsent::Response(sr)).is_err() {
+                        write_error(host.serial, BridgeError::QueueFull);
+                    }
+                }
+                Err(err) => write_error(host.serial, err),
+            }
+        });
+    }
 
-                cx.local.spi_tx_producer.enqueue(tx_buf).unwrap();
+    // Software task that writes the intermediate output of a long request, like the PHYs found by a scan,
+    // to the console it came from
+    #[task(priority = 3, capacity = 4, shared = [serial, serial_out, uart_dev, unsent])]
+    fn report_to_host(cx: report_to_host::Context, host_config: ValidHostInterfaces, text: FmtBuf<REPORT_LEN>) {
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let unsent = cx.shared.unsent;
+        (serial, serial_out, uart_dev, unsent).lock(|serial, serial_out, uart, unsent| {
+            let mut host = HostPorts { serial, serial_out, uart, spi_tx: None };
+            let written = match on_serial(host_config) && !unsent.is_empty() {
+                true => Err(BridgeError::QueueFull),
+                false => write_report(&mut host, host_config, text.as_bytes()),
+            };
+            // Console output only, dropped once too much of it waits
+            if written == Err(BridgeError::QueueFull) {
+                let _ = unsent.push_back(Unsent::Report(host_config, text));
             }
+        });
+    }
 
-            else if sr.host_config == ValidHostInterfaces::Serial {
+    // Software task that writes what waited for room on the USB serial port, oldest first, once the host read.
+    // What still does not fit waits for the next read
+    #[task(priority = 3, shared = [serial, serial_out, uart_dev, spi_tx, unsent])]
+    fn resend_to_host(cx: resend_to_host::Context) {
+        let spi_tx = cx.shared.spi_tx;
+        let serial = cx.shared.serial;
+        let serial_out = cx.shared.serial_out;
+        let uart_dev = cx.shared.uart_dev;
+        let unsent = cx.shared.unsent;
+        (serial, serial_out, uart_dev, spi_tx, unsent).lock(|serial, serial_out, uart, spi_tx, unsent| {
+            let mut host = HostPorts { serial, serial_out, uart, spi_tx: Some(spi_tx) };
+            while let Some(item) = unsent.front() {
+                let written = match item {
+                    Unsent::Response(sr) => sr.respond_to_host(&mut host),
+                    Unsent::Report(host_config, text) => write_report(&mut host, *host_config, text.as_bytes()),
+                };
+                match written {
+                    Err(BridgeError::QueueFull) => return,
+                    Err(err) => write_error(host.serial, err),
+                    Ok(()) => {}
+                }
+                unsent.pop_front();
+            }
+        });
+    }
 
-            } */
+    // The USB serial port hosts, their output waits for the host to read
+    fn on_serial(host_config: ValidHostInterfaces) -> bool {
+        matches!(host_config, ValidHostInterfaces::Serial | ValidHostInterfaces::SerialFramed)
     }
 
     // Task with least priority that only runs when nothing else is running.
@@ -0,0 +1,61 @@
+//! RTIC monotonic on the RP2040 TIMER
+//! The TIMER counts us in 64 bits from the watchdog tick, ALARM0 wakes the timer queue through TIMER_IRQ_0
+
+use rp_pico::pac;
+use rtic::Monotonic;
+use fugit::{TimerDurationU64, TimerInstantU64};
+
+pub struct TimerMono {
+    timer: pac::TIMER,
+}
+
+impl TimerMono {
+    // Take the TIMER out of reset. The watchdog tick must already run at 1 MHz
+    pub fn new(timer: pac::TIMER, resets: &mut pac::RESETS) -> TimerMono {
+        resets.reset.modify(|_, w| w.timer().clear_bit());
+        while resets.reset_done.read().timer().bit_is_clear() {}
+        TimerMono { timer }
+    }
+
+    fn ticks(&self) -> u64 {
+        // The high word may roll over between the two reads
+        loop {
+            let high = self.timer.timerawh.read().bits();
+            let low = self.timer.timerawl.read().bits();
+            if self.timer.timerawh.read().bits() == high {
+                return (high as u64) << 32 | low as u64
+            }
+        }
+    }
+}
+
+impl Monotonic for TimerMono {
+    type Instant = TimerInstantU64<1_000_000>;
+    type Duration = TimerDurationU64<1_000_000>;
+
+    fn now(&mut self) -> Self::Instant {
+        Self::Instant::from_ticks(self.ticks())
+    }
+
+    fn set_compare(&mut self, instant: Self::Instant) {
+        // ALARM0 only compares the low word, an instant further out fires early and RTIC sets it again
+        self.timer.alarm0.write(|w| unsafe { w.bits(instant.ticks() as u32) });
+        // An instant already passed would not fire until the low word wraps
+        if instant.ticks() <= self.ticks() {
+            self.timer.intf.modify(|_, w| w.alarm_0().set_bit());
+        }
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.timer.intf.modify(|_, w| w.alarm_0().clear_bit());
+        self.timer.intr.write(|w| w.alarm_0().set_bit());
+    }
+
+    fn zero() -> Self::Instant {
+        Self::Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.timer.inte.modify(|_, w| w.alarm_0().set_bit());
+    }
+}
@@ -1,4 +1,6 @@
-use crate::protocol::{ValidHostInterfaces,  host::{self, HostRequest, ValidInterfaces, ValidOps}};
+use pico_bridge_core::error::BridgeError;
+use pico_bridge_core::cli::{parse_command, Command};
+use pico_bridge_core::frame::{encode_status, MAX_ENCODED_FRAME};
 
 use rp_pico::hal as hal;
 // USB Device support 
@@ -6,8 +8,13 @@ use usb_device::{class_prelude::*};
 // USB Communications Class Device support
 use usbd_serial::SerialPort;
 
-use core::{str, u32};
-use core::str::SplitWhitespace;
+use core::str;
+
+use heapless::Deque;
+
+// Bytes for the USB serial port waiting for room in the CDC buffer, written out as the host reads
+pub const SERIAL_OUT_LEN: usize = 1024;
+pub type SerialOut = Deque<u8, SERIAL_OUT_LEN>;
 
 
 // Helper function to ensure all data is written across the serial interface
@@ -23,7 +30,15 @@ pub fn write_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &st
     while index < write_ptr.len() && write_ptr[index] != 0 {
         index += 1;
     }
-    let mut write_ptr = &write_ptr[0..index];
+    write_serial_bytes(serial, &write_ptr[0..index], block);
+}
+
+// Write raw bytes across the serial interface. Unlike write_serial zero bytes are sent,
+// binary mode frames are delimited by them
+#[inline(never)]
+#[link_section = ".data.bar"] // Execute from IRAM
+pub fn write_serial_bytes(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &[u8], block: bool) {
+    let mut write_ptr = buf;
 
     while !write_ptr.is_empty() {
         match serial.write(write_ptr) {
@@ -41,170 +56,128 @@ pub fn write_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, buf: &st
     let _ = serial.flush();
 }
 
+// Write out what the CDC buffer takes of the bytes queued, the rest goes once the host reads
+pub fn drain_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, out: &mut SerialOut) {
+    while !out.is_empty() {
+        let mut buf = [0_u8; 64];
+        let count = out.iter().zip(buf.iter_mut()).map(|(byte, slot)| *slot = *byte).count();
+        match serial.write(&buf[..count]) {
+            Ok(len) if len > 0 => {
+                for _ in 0..len {
+                    out.pop_front();
+                }
+            }
+            // The CDC buffer is full, or the port is gone
+            _ => break,
+        }
+    }
+    let _ = serial.flush();
+}
+
+// Queue all of the bytes behind those waiting, or none of them. QueueFull while the host has not read enough, the
+// caller writes them again later
+pub fn queue_serial(serial: &mut SerialPort<'static, hal::usb::UsbBus>, out: &mut SerialOut, bytes: &[u8])
+    -> Result<(), BridgeError> {
+    drain_serial(serial, out);
+    if out.capacity() - out.len() < bytes.len() {
+        return Err(BridgeError::QueueFull)
+    }
+    for byte in bytes {
+        let _ = out.push_back(*byte);
+    }
+    drain_serial(serial, out);
+    Ok(())
+}
+
+// Answer a binary mode request that did not produce a SlaveResponse with just its status, None on success. Dropped
+// if the host reads nothing
+pub fn write_status_frame(serial: &mut SerialPort<'static, hal::usb::UsbBus>, out: &mut SerialOut, proc_id: u8,
+    status: Option<BridgeError>) {
+    let mut frame = [0_u8; MAX_ENCODED_FRAME];
+    let len = encode_status(proc_id, status, 0, &mut frame);
+    let _ = queue_serial(serial, out, &frame[..len]);
+}
+
 // Match the Serial Input commands to a hardware/software request
 #[inline(never)]
 #[link_section = ".data.bar"] // Execute from IRAM
 pub fn match_usb_serial_buf( buf: &[u8; 64],
     serial: &mut SerialPort<'static, hal::usb::UsbBus> ) 
-    -> Result<HostRequest<host::Unclean>, &'static str> {
+    -> Result<Command, BridgeError> {
     let buf = str::from_utf8(buf).unwrap();
     write_serial(serial, "\n\r", false);
 
-    if slice_contains(buf, "menu") {
-        print_menu(serial);
-        Err("Ok")
-    }
-    else {
-        write_serial(serial, "\n\r", false);
-        message_parse_build(buf)
+    let command = parse_command(buf)?;
+    match command {
+        Command::Menu => print_menu(serial),
+        Command::Request(_) => write_serial(serial, "\n\r", false),
     }
+    Ok(command)
 }
 
-pub fn print_menu(serial: &mut SerialPort<'static, hal::usb::UsbBus>){
-    let mut _buf = [0u8; 273];
-    // Create the Menu.
-    let menu_str = "*****************\n\r
+// Print a pipeline error back to the serial console
+pub fn write_error(serial: &mut SerialPort<'static, hal::usb::UsbBus>, err: BridgeError) {
+    write_serial(serial, err.as_str(), false);
+    write_serial(serial, "\n\r", false);
+}
+
+// The menu printed on the serial consoles
+pub const MENU: &str = "*****************\n\r
 *  pico-bridge USB Serial Interface\n\r
 *  Send system or device interface commands\n\r
 *  Menu:\n\r
 *  M / m - Print menu\n\r
 *    - smi r phyAddr RegAddr\n\r
 *    - smi w phyAddr RegAddr Data\n\r
-*    - smi setclk frequency\n\r
+*    - smi decode phyAddr RegAddr|RegName\n\r
+*    - smi pr phyAddr Page RegAddr\n\r
+*    - smi pw phyAddr Page RegAddr Data\n\r
+*    - smi scan\n\r
+*    - smi dump phyAddr Start..End [name]\n\r
+*    - smi diff name\n\r
+*    - smi rmw phyAddr RegAddr Mask Value\n\r
+*    - smi setbits phyAddr RegAddr Bits\n\r
+*    - smi clrbits phyAddr RegAddr Bits\n\r
+*    - smi poll phyAddr RegAddr Mask Expected TimeoutMs IntervalUs\n\r
+*    - smi45 r phyAddr Mmd RegAddr\n\r
+*    - smi45 w phyAddr Mmd RegAddr Data\n\r
+*    - smi45 ri phyAddr Mmd [RegAddr]\n\r
+*    - mmd r phyAddr Devad RegAddr\n\r
+*    - mmd w phyAddr Devad RegAddr Data\n\r
+*    - i2c w addr [bytes...]\n\r
+*    - i2c r addr n\n\r
+*    - i2c wr addr Reg n\n\r
+*    - i2c scan\n\r
+*    - i2c recover\n\r
+*    - smbus send addr byte\n\r
+*    - smbus rb|rw addr Cmd\n\r
+*    - smbus wb|ww|pc addr Cmd data\n\r
+*    - smbus br addr Cmd\n\r
+*    - smbus bw addr Cmd [bytes...]\n\r
+*    - smbus l11|l16 addr Cmd\n\r
+*    - spi xfer|xferh cs n (then the MOSI bytes)\n\r
+*    - spi r|rh cs n [fill]\n\r
+*    - jtag reset\n\r
+*    - jtag ir|dr len [bits...]\n\r
+*    - jtag scan [device [field]]\n\r
+*    - jtag svf (then SVF text, Ctrl-D to end)\n\r
+*    - jtag tck count tms tdi\n\r
+*    - flash id cs\n\r
+*    - flash read cs Addr n\n\r
+*    - flash erase cs [Addr Size]\n\r
+*    - flash write cs Addr n (then the bytes)\n\r
+*    - flash verify cs Addr n Crc32\n\r
+*    - cfg spimode mode bits [lsbFirst]\n\r
+*    - cfg spiset frequencyHz\n\r
+*    - cfg pec 0|1\n\r
+*    - cfg i2cset frequencyHz\n\r
+*    - cfg page phyAddr PageRegAddr\n\r
+*    - cfg smiset frequencyHz\n\r
 *    - gpio set level\n\r 
+*  Ctrl-B - Switch to binary (COBS/CRC-16 framed) mode\n\r
 *****************\n\r
 Enter option: ";
 
-    write_serial(serial, menu_str, true);
-}
-
-pub fn slice_contains(haystack: &str, needle: &str) -> bool {
-    if haystack.len() < needle.len() {
-        return false;
-    }
-
-    for i in 0..=(haystack.len() - needle.len()) {
-        if &haystack[i..(i + needle.len())] == needle {
-            return true;
-        }
-    }
-    false
-}
-
-// Helper function that takes list of bytes and deconstructs
-// into HostRequest fields. 
-// NOTE: Preliminary behavior is to drop message and log to serial an invalid message
-// if fields are missing or invalid
-#[inline(never)]
-#[link_section = ".data.bar"] // Execute from IRAM
-pub fn message_parse_build<'input>(input: &'input str) 
-    -> Result<HostRequest<host::Unclean>, &'static str>{
-    let mut payload = [0u32; 4];
-
-    // Split up the given string
-    let mut hr = HostRequest::new();
-    hr.set_host_config(ValidHostInterfaces::Serial);
-
-    let words = |input: &'input str| -> SplitWhitespace<'input>  {input.split_whitespace()};
-    let mut command = words(input);
-    let command_count = command.clone().count();
-    if command_count > 6 {
-        return Err("Too many arguments\n\r")
-    }
-    // Match on the first word
-    match command.next() {
-        Some("smi" | "SMI") => {
-            hr.set_interface(ValidInterfaces::SMI);
-        }
-        Some("cfg" | "CFG") => {
-            hr.set_interface(ValidInterfaces::Config);
-        }
-        Some("gpio" | "GPIO") => {
-            hr.set_interface(ValidInterfaces::GPIO);
-        }
-        Some("jtag" | "JTAG") => {
-            hr.set_interface(ValidInterfaces::JTAG);
-        }
-        Some("spi" | "SPI") => {
-            hr.set_interface(ValidInterfaces::SPI);
-        }
-        _ => {
-            return Err("Invalid Interface\n\r")
-        }
-    }
-    // Match on the second word. This should be an operation. If not log incorrect
-    match command.next() {
-        Some("r" | "R") => {
-            hr.set_operation(ValidOps::Read);
-        }
-        Some("w" | "W") => {
-            hr.set_operation(ValidOps::Write);
-        }
-        Some("smiset" | "SMISET") => {
-            hr.set_operation(ValidOps::SmiSet);
-        }
-        _ => {
-            return Err("Invalid Operation\n\r");
-        }
-    }
-    let mut size: u8 = 0;
-    while size < (command_count - 3) as u8 {
-        let val = command.nth(0).unwrap();
-            match bytes_to_number(val) {
-                Ok(value) => {
-                    payload[size as usize] = value;
-                }
-                Err(err) => {
-                    return Err(err) 
-                }
-        }
-        size+=1;
-    }
-    hr.set_size(size);
-    hr.set_payload(payload);
-    Ok(hr)
+pub fn print_menu(serial: &mut SerialPort<'static, hal::usb::UsbBus>){
+    write_serial(serial, MENU, true);
 }
-
-// Helper function to take &str in decimal or hex form
-// and return u32.
-// ie: s = "0xFF"  will return decimal value 255
-#[inline(never)]
-#[link_section = ".data.bar"] // Execute from IRAM
-pub fn bytes_to_number(s: &str) -> Result<u32, &'static str> {
-    let mut result: u32 = 0;
-    // Check if the input is hex or decimal
-    let mut chars = s.chars();
-    if let Some(c) = chars.next() {
-        if c != '0' || chars.next() != Some('x') {
-            if '0' <= c && c <= '9' {
-                result += c as u32 - '0' as u32;
-                for c in chars {
-                    let digit= match c {
-                        '0'..='9' => c as u32 - '0' as u32,
-                        _ => return Err("Invalid decimal character\n\r"),
-                    };
-                    if result >= 429_496_720 {
-                        return Err("Integer number too large!\n\r")
-                    }
-                    result = result * 10 + digit;
-                    
-                }
-                return Ok(result)
-            }
-            return Err("Not a hex or decimal string\n\r")
-        }
-    }
-    if chars.clone().count() > 8 {
-        return Err("Integer number too large!\n\r")
-    }
-    for c in chars {
-        let digit =  match c {
-            '0'..='9' => c as u32 - '0' as u32,
-            'a'..='f' => c as u32 - 'a' as u32 + 10,
-            'A'..='F' => c as u32 - 'A' as u32 + 10,
-            _ => return Err("Invalid hex character\n\r"),
-        };
-        result = result * 16 + digit;
-    }
-    Ok(result)
-}
\ No newline at end of file
@@ -0,0 +1,128 @@
+//! PIO SPI master on PIO1 state machine 1
+//! Both SPI programs stay installed, the state machine is rebuilt with the program of the clock phase when the mode,
+//! word width or bit order change. Chip selects are plain GPIOs, active low
+
+use embedded_hal::digital::v2::OutputPin;
+use rp_pico::hal;
+use rp_pico::pac;
+use hal::gpio::{bank0::Gpio10, DynPin, FunctionPio1, OutputOverride, Pin};
+use hal::pio::{InstalledProgram, PIOBuilder, PinDir, Running, Rx, ShiftDirection, StateMachine, Tx,
+    UninitStateMachine, SM1};
+use pico_bridge_core::clock::ClockDivisor;
+use pico_bridge_core::spi::{SpiConfig, MAX_CS};
+
+type Sm = (pac::PIO1, SM1);
+
+pub const SPI_SCK: u8 = 10;
+pub const SPI_MOSI: u8 = 11;
+pub const SPI_MISO: u8 = 12;
+
+pub struct PioSpi {
+    sm: Option<(StateMachine<Sm, Running>, Rx<Sm>, Tx<Sm>)>,
+    // Program of the other clock phase
+    spare: Option<InstalledProgram<pac::PIO1>>,
+    sck: Pin<Gpio10, FunctionPio1>,
+    cs: [DynPin; MAX_CS],
+    // Chip select left asserted by a request with the hold flag
+    held: Option<u8>,
+    config: SpiConfig,
+    div: ClockDivisor,
+}
+
+impl PioSpi {
+    // Start in mode 0 with 8 bit words, MSB first. cs are push-pull outputs
+    pub fn new(sm: UninitStateMachine<Sm>, cpha0: InstalledProgram<pac::PIO1>, cpha1: InstalledProgram<pac::PIO1>,
+        sck: Pin<Gpio10, FunctionPio1>, cs: [DynPin; MAX_CS], div: ClockDivisor) -> PioSpi {
+        let mut spi = PioSpi { sm: None, spare: Some(cpha1), sck, cs, held: None, config: SpiConfig::DEFAULT, div };
+        for pin in spi.cs.iter_mut() {
+            let _ = pin.set_high();
+        }
+        spi.build(sm, cpha0);
+        spi
+    }
+
+    fn build(&mut self, sm: UninitStateMachine<Sm>, program: InstalledProgram<pac::PIO1>) {
+        let config = self.config;
+        let direction = if config.lsb_first { ShiftDirection::Right } else { ShiftDirection::Left };
+        // A threshold of 32 is written as 0
+        let threshold = config.bits % 32;
+        let (mut sm, rx, tx) = PIOBuilder::from_program(program)
+            .out_pins(SPI_MOSI, 1)
+            .in_pin_base(SPI_MISO)
+            .side_set_pin_base(SPI_SCK)
+            .clock_divisor_fixed_point(self.div.int, self.div.frac)
+            .out_shift_direction(direction)
+            .in_shift_direction(direction)
+            .autopull(true)
+            .pull_threshold(threshold)
+            .autopush(true)
+            .push_threshold(threshold)
+            .build(sm);
+        sm.set_pindirs([(SPI_SCK, PinDir::Output), (SPI_MOSI, PinDir::Output), (SPI_MISO, PinDir::Input)]);
+        // The programs idle with SCK low, inverted it idles high for CPOL 1
+        self.sck.set_output_override(if config.cpol() { OutputOverride::Invert } else { OutputOverride::DontInvert });
+        self.sm = Some((sm.start(), rx, tx));
+    }
+
+    pub fn config(&self) -> SpiConfig {
+        self.config
+    }
+
+    // Rebuild the state machine for a new mode, width or bit order. Only between requests
+    pub fn configure(&mut self, config: SpiConfig) {
+        let (sm, rx, tx) = match self.sm.take() {
+            Some(parts) => parts,
+            None => return,
+        };
+        let (sm, program) = sm.stop().uninit(rx, tx);
+        // Swap in the program of the other clock phase
+        let program = match self.spare.take() {
+            Some(other) if config.cpha() != self.config.cpha() => {
+                self.spare = Some(program);
+                other
+            }
+            spare => {
+                self.spare = spare;
+                program
+            }
+        };
+        self.config = config;
+        self.build(sm, program);
+    }
+
+    pub fn set_divisor(&mut self, div: ClockDivisor) {
+        self.div = div;
+        if let Some((sm, _, _)) = self.sm.as_mut() {
+            sm.clock_divisor_fixed_point(div.int, div.frac);
+        }
+    }
+
+    // Assert a chip select, releasing the one an earlier request left held
+    pub fn select(&mut self, cs: u8) {
+        if let Some(held) = self.held.take() {
+            if held != cs {
+                let _ = self.cs[held as usize].set_high();
+            }
+        }
+        let _ = self.cs[cs as usize].set_low();
+    }
+
+    // End of a request, the chip select stays asserted with hold
+    pub fn release(&mut self, cs: u8, hold: bool) {
+        if hold {
+            self.held = Some(cs);
+        }
+        else {
+            let _ = self.cs[cs as usize].set_high();
+        }
+    }
+
+    // Returns false if the TX FIFO is full
+    pub fn write(&mut self, word: u32) -> bool {
+        self.sm.as_mut().map_or(false, |(_, _, tx)| tx.write(word))
+    }
+
+    pub fn read(&mut self) -> Option<u32> {
+        self.sm.as_mut().and_then(|(_, rx, _)| rx.read())
+    }
+}